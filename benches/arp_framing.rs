@@ -0,0 +1,79 @@
+//! Throughput of ARP frames with the data written into both protocol addresses
+//! and split between them. <br>
+//! arpchat-rs is a binary, so the modules on the way are built right here.
+
+// Only the send and receive path of the modules is used,
+// and their tests are left out without the test harness.
+#![allow(dead_code, unused_imports)]
+
+use divan::Bencher;
+use divan::counter::{BytesCount, ItemsCount};
+use net::reassembly::Reassembler;
+use net::{arp, ktp};
+use pnet::util::MacAddr;
+use std::time::Instant;
+
+#[path = "../src/error"]
+mod error {
+    pub mod ktp;
+    pub mod net;
+}
+
+#[path = "../src/net"]
+mod net {
+    pub mod arp;
+    pub mod capabilities;
+    pub mod crypto;
+    pub mod ethernet;
+    pub mod ktp;
+    pub mod reassembly;
+    pub mod replay;
+}
+
+fn main() {
+    divan::main();
+}
+
+/// Every version reads these frames.
+#[divan::bench]
+fn duplicated(bencher: Bencher) {
+    round_trip(bencher, ktp::PACKET_DATA_SIZE);
+}
+
+/// Peers with `Capabilities::SPLIT_ARP` get twice as much data in a frame.
+#[divan::bench]
+fn split(bencher: Bencher) {
+    round_trip(bencher, arp::SPLIT_PART_SIZE);
+}
+
+// Sends the largest packet and reads it back, frames are counted as items.
+fn round_trip(bencher: Bencher, part_size: usize) {
+    let data = vec![7; ktp::MAX_PACKET_SIZE];
+    let sender_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+    let frames = ktp::fragment(0, &data, part_size, false).unwrap().len();
+
+    bencher
+        .counter(BytesCount::new(data.len()))
+        .counter(ItemsCount::new(frames))
+        .bench(|| {
+            let mut reassembler = Reassembler::default();
+            let now = Instant::now();
+
+            let mut packet = None;
+            for frame in ktp::fragment(0, &data, part_size, false).unwrap() {
+                let ethernet_frame = arp::build(
+                    arp::OPCODE_REQUEST,
+                    MacAddr::broadcast(),
+                    sender_mac,
+                    MacAddr::zero(),
+                    &[0x08, 0x00],
+                    &frame,
+                );
+                let frame = arp::parse(&ethernet_frame).unwrap().unwrap();
+                packet = reassembler
+                    .accept(&frame.data, frame.sender_mac, now)
+                    .unwrap();
+            }
+            packet.unwrap()
+        });
+}
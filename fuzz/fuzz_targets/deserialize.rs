@@ -0,0 +1,12 @@
+#![no_main]
+
+use arpchat_rs_fuzz::net::ktp;
+use libfuzzer_sys::fuzz_target;
+
+// The first byte is the tag.
+fuzz_target!(|data: &[u8]| {
+    if let Some((&tag, data)) = data.split_first() {
+        let _ = ktp::Packet::deserialize(tag, data);
+        let _ = ktp::decode(tag, data, None, None);
+    }
+});
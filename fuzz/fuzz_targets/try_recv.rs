@@ -0,0 +1,35 @@
+#![no_main]
+
+use arpchat_rs_fuzz::net::reassembly::Reassembler;
+use arpchat_rs_fuzz::net::{arp, ethernet, ktp, ndp};
+use libfuzzer_sys::fuzz_target;
+use std::time::Instant;
+
+// Same steps as `Channel::try_recv`, for a run of Ethernet frames,
+// each one prefixed with its length.
+fuzz_target!(|data: &[u8]| {
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+
+    let mut data = data;
+    while let Some((&len, rest)) = data.split_first() {
+        let (frame, rest) = rest.split_at(rest.len().min(len as usize));
+        data = rest;
+
+        let frame = if let Ok(Some(frame)) = arp::parse(frame) {
+            frame
+        } else if let Ok(Some(frame)) = ethernet::parse(frame, &[0x88, 0xB5]) {
+            frame
+        } else if let Ok(Some(frame)) = ndp::parse(frame) {
+            frame
+        } else {
+            continue;
+        };
+        if let Ok(Some((tag, data))) =
+            reassembler.accept(&frame.data, frame.sender_mac, now)
+        {
+            let _ = ktp::decode(tag, &data, None, None);
+        }
+    }
+    reassembler.poll(now);
+});
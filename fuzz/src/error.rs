@@ -0,0 +1,4 @@
+#[path = "../../src/error/ktp.rs"]
+pub mod ktp;
+#[path = "../../src/error/net.rs"]
+pub mod net;
@@ -0,0 +1,4 @@
+//! The receive path of arpchat-rs, built on its own, since arpchat-rs is a binary.
+
+pub mod error;
+pub mod net;
@@ -0,0 +1,16 @@
+#[path = "../../src/net/arp.rs"]
+pub mod arp;
+#[path = "../../src/net/capabilities.rs"]
+pub mod capabilities;
+#[path = "../../src/net/crypto.rs"]
+pub mod crypto;
+#[path = "../../src/net/ethernet.rs"]
+pub mod ethernet;
+#[path = "../../src/net/ktp.rs"]
+pub mod ktp;
+#[path = "../../src/net/ndp.rs"]
+pub mod ndp;
+#[path = "../../src/net/reassembly.rs"]
+pub mod reassembly;
+#[path = "../../src/net/replay.rs"]
+pub mod replay;
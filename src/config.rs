@@ -1,9 +1,13 @@
 use crate::error::config::ConfigError;
+use crate::net::arp_framing::ArpFraming;
+use crate::net::carrier::Carrier;
 use crate::net::ether_type::EtherType;
+use crate::net::transfer;
 use crate::session_settings;
-use directories::ProjectDirs;
+use directories::{ProjectDirs, UserDirs};
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::str;
 use std::str::FromStr;
@@ -18,10 +22,26 @@ pub const DEFAULT_LOG_LEVEL_FILTER: LevelFilter = LevelFilter::Warn;
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Config {
+    pub arp_framing: Option<ArpFraming>,
+    pub carrier: Option<Carrier>,
     pub ether_type: Option<EtherType>,
     pub interface_name: Option<String>,
     pub language: Option<String>,
     pub log_level: Option<String>,
+    // In bytes, larger received files are declined. Up to 64 MiB by default.
+    pub max_file_size: Option<u64>,
+    // In bytes, applies to messages both sent and received.
+    pub max_message_size: Option<usize>,
+    // Received files are saved here, the downloads directory by default.
+    pub receive_directory: Option<PathBuf>,
+    pub room_passphrase: Option<String>,
+    // Joined besides the main chat, joined again on start.
+    pub rooms: Option<Vec<String>>,
+    // Clients with access to the link relay between it and the UDP clients.
+    pub udp_bridge: Option<bool>,
+    // Broadcast on the interface network, if there is no multicast group.
+    pub udp_group: Option<Ipv4Addr>,
+    pub udp_port: Option<u16>,
     pub username: Option<String>,
 }
 
@@ -81,22 +101,37 @@ impl Config {
     }
 
     fn get_config_path() -> Result<PathBuf, ConfigError> {
+        Ok(Self::get_config_directory()?.join(CONFIG_FILENAME))
+    }
+
+    /// Other files of the program, like the identity key, are kept next to the config.
+    pub fn get_config_directory() -> Result<PathBuf, ConfigError> {
         let dirs = ProjectDirs::from("dev", "xairaven", "arpchat-rs");
         match dirs {
-            None => Ok(Self::get_current_directory()?),
-            Some(value) => Ok(value.config_dir().join(CONFIG_FILENAME)),
+            None => env::current_dir()
+                .map_err(|err| ConfigError::CurrentDirFetchFailed(err.to_string())),
+            Some(value) => Ok(value.config_dir().to_path_buf()),
         }
     }
-
-    fn get_current_directory() -> Result<PathBuf, ConfigError> {
-        let mut current_dir = env::current_dir()
-            .map_err(|err| ConfigError::CurrentDirFetchFailed(err.to_string()))?;
-        current_dir.push(CONFIG_FILENAME);
-        Ok(current_dir)
-    }
 }
 
 /// Getters with locking.
+pub fn lock_get_arp_framing() -> ArpFraming {
+    CONFIG
+        .try_lock()
+        .ok()
+        .and_then(|locked_config| locked_config.arp_framing)
+        .unwrap_or_default()
+}
+
+pub fn lock_get_carrier() -> Carrier {
+    CONFIG
+        .try_lock()
+        .ok()
+        .and_then(|locked_config| locked_config.carrier)
+        .unwrap_or_default()
+}
+
 pub fn lock_get_ether_type() -> EtherType {
     CONFIG
         .try_lock()
@@ -115,6 +150,39 @@ pub fn lock_get_log_level() -> LevelFilter {
     DEFAULT_LOG_LEVEL_FILTER
 }
 
+pub fn lock_get_max_file_size() -> u64 {
+    CONFIG
+        .try_lock()
+        .ok()
+        .and_then(|locked_config| locked_config.max_file_size)
+        .unwrap_or(transfer::MAX_FILE_SIZE)
+}
+
+pub fn lock_get_receive_directory() -> PathBuf {
+    CONFIG
+        .try_lock()
+        .ok()
+        .and_then(|locked_config| locked_config.receive_directory.clone())
+        .or_else(|| Some(UserDirs::new()?.download_dir()?.to_path_buf()))
+        .or_else(|| env::current_dir().ok())
+        .unwrap_or_default()
+}
+
+pub fn lock_get_room_passphrase() -> Option<String> {
+    CONFIG
+        .try_lock()
+        .ok()
+        .and_then(|locked_config| locked_config.room_passphrase.clone())
+}
+
+pub fn lock_get_rooms() -> Vec<String> {
+    CONFIG
+        .try_lock()
+        .ok()
+        .and_then(|locked_config| locked_config.rooms.clone())
+        .unwrap_or_default()
+}
+
 pub fn lock_get_username() -> String {
     CONFIG
         .try_lock()
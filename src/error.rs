@@ -1,3 +1,4 @@
 pub mod config;
+pub mod ktp;
 pub mod logger;
 pub mod net;
@@ -11,6 +11,9 @@ pub enum ConfigError {
     #[error("Current directory does not exist or there are insufficient permissions to access it.")]
     CurrentDirFetchFailed(String),
 
+    #[error("Identity key file is corrupted.")]
+    InvalidIdentityFile,
+
     #[error("TOML Serializer failed while saving file.")]
     SerializerFailed(String),
 }
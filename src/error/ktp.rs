@@ -0,0 +1,32 @@
+use thiserror::Error;
+
+/// Why a received KTP frame or packet was rejected.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum KtpError {
+    #[error("Frame or packet is cut short")]
+    Truncated,
+
+    #[error("Packet has more data than its kind carries")]
+    TrailingData,
+
+    #[error("Unknown packet tag {0}")]
+    BadTag(u8),
+
+    #[error("Frame {seq} is out of {total} frames of the packet")]
+    SeqOutOfRange { seq: u16, total: u16 },
+
+    #[error("Packet of a room carries no room name")]
+    MissingRoom,
+
+    #[error("Text isn't valid UTF-8")]
+    BadUtf8,
+
+    #[error("Couldn't decompress message text")]
+    DecompressionFailed,
+
+    #[error("Unknown compression {0} of message text")]
+    BadCompression(u8),
+
+    #[error("Frame of protocol version {0} is too old to be read")]
+    UnsupportedVersion(u8),
+}
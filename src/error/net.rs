@@ -1,37 +1,74 @@
+use crate::error::ktp::KtpError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum NetError {
-    #[error("Couldn't send ARP packet")]
-    ARPSendFailed,
-
-    #[error("Couldn't serialize ARP packet")]
-    ARPSerializeFailed,
-
-    #[error("Couldn't deserialize ARP packet")]
-    ARPDeserializeFailed,
-
     #[error("Couldn't capture packet")]
     CaptureFailed,
 
     #[error("Error getting channel, might be missing permissions")]
     ChannelGettingError(#[from] std::io::Error),
 
+    #[error("Couldn't read the config, it was left broken by a crash")]
+    ConfigUnavailable,
+
+    #[error("Couldn't encrypt packet")]
+    EncryptionFailed,
+
     #[error("Couldn't parse frame as Ethernet")]
     EthernetParseFailed,
 
+    #[error("Couldn't send frame")]
+    FrameSendFailed,
+
+    #[error("No privileges for ARP and Ethernet II, switched to UDP. Only UDP clients are reachable.")]
+    FellBackToUdp,
+
+    #[error("Couldn't read the file. {0}")]
+    FileReadFailed(std::io::Error),
+
+    #[error("File too large to send.")]
+    FileTooLarge,
+
     #[error("Tried to set interface, but interface is already initialized")]
     InterfaceAlreadySet,
 
+    #[error("Couldn't decode KTP packet. {0}")]
+    InvalidPacket(#[from] KtpError),
+
     #[error("Invalid interface {0}")]
     InvalidInterface(String),
 
+    #[error("Couldn't derive a key from the room passphrase")]
+    KeyDerivationFailed,
+
+    #[error("Couldn't agree on a key with the peer, direct messages weren't sent.")]
+    KeyExchangeFailed,
+
     #[error("Message too long to send.")]
     MessageTooLong,
 
     #[error("No MAC Address.")]
     NoMac,
 
+    #[error("Interface has no IPv4 address for UDP.")]
+    NoIpv4,
+
+    #[error("Peer's client is too old for direct messages.")]
+    PeerTooOld,
+
+    #[error("Peer's client is too old for file transfer.")]
+    PeerTooOldForFiles,
+
+    #[error("Room name too long to send.")]
+    RoomNameTooLong,
+
+    #[error("Couldn't open UDP socket. {0}")]
+    SocketFailed(std::io::Error),
+
+    #[error("Peer address is unknown, maybe the peer is offline.")]
+    UnknownPeer,
+
     #[error("Unknown channel type, only ethernet is supported")]
     UnknownChannelType,
 }
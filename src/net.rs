@@ -1,8 +1,34 @@
 pub mod arp;
+pub mod arp_framing;
+pub mod bridge;
+pub mod capabilities;
+pub mod carrier;
 pub mod channel;
+pub mod clock;
 pub mod commands;
+pub mod contacts;
 pub mod core;
+pub mod crypto;
+pub mod delivery;
+pub mod e2e;
+pub mod ethernet;
 pub mod ether_type;
+pub mod history;
+pub mod identity;
 pub mod interface;
 pub mod ktp;
+pub mod ndp;
+#[cfg(test)]
+pub mod memory;
+pub mod peers;
 pub mod presence;
+pub mod reassembly;
+pub mod replay;
+pub mod rooms;
+pub mod send_queue;
+#[cfg(test)]
+pub mod simulator;
+pub mod transfer;
+pub mod transport;
+pub mod typing;
+pub mod udp;
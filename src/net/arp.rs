@@ -1,8 +1,203 @@
+use crate::error::ktp::KtpError;
+use crate::net::ethernet;
+use crate::net::ethernet::Frame;
+use crate::net::ktp;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::util::MacAddr;
+use std::borrow::Cow;
+
 // ARP Hardware type. Ethernet - IEEE 802 Numbers
 pub const HARDWARE_TYPE_ETHERNET: &[u8] = &[0x00, 0x01];
 
 // ARP Hardware length in bytes. MAC - 6 bytes
 pub const HARDWARE_ADDRESS_LENGTH: u8 = 6;
 
-// ARP Operation codes. Reply - 0x1
+// ARP Operation codes. Request - 0x1, Reply - 0x2
 pub const OPCODE_REQUEST: &[u8] = &[0, 1];
+pub const OPCODE_REPLY: &[u8] = &[0, 2];
+
+// Ethernet header, then ARP fields up to the sender hardware address.
+const ETHERNET_HEADER_SIZE: usize = 14;
+const ARP_HEADER_SIZE: usize = 8;
+
+// The length of a protocol address is a single byte.
+const PROTOCOL_ADDRESS_MAX_SIZE: usize = u8::MAX as usize;
+
+// Split data starts with the number of padding bytes at its end,
+// while KTP frames start with the packet prefix.
+const SPLIT_MAX_PADDING: u8 = 1;
+
+/// The most data a frame carries, when it's split between both protocol address fields.
+pub const SPLIT_DATA_SIZE: usize = 2 * PROTOCOL_ADDRESS_MAX_SIZE - 1;
+pub const SPLIT_PART_SIZE: usize = SPLIT_DATA_SIZE - ktp::PACKET_HEADER_SIZE;
+
+/// KTP data that fits into a protocol address goes into both fields,
+/// so every version reads it. <br>
+/// Longer data, up to `SPLIT_DATA_SIZE`, goes on from the sender field
+/// into the target one, only peers with `Capabilities::SPLIT_ARP` read it.
+pub fn build(
+    opcode: &[u8], destination: MacAddr, sender_mac: MacAddr,
+    target_hardware_address: MacAddr, protocol_type: &[u8], data: &[u8],
+) -> Vec<u8> {
+    // The length of the data must fit in both fields. This should also
+    // guarantee that we'll be inside the MTU.
+    debug_assert!(
+        data.len() <= SPLIT_DATA_SIZE,
+        "Part data is too large ({} > {})",
+        data.len(),
+        SPLIT_DATA_SIZE
+    );
+
+    let split;
+    let (sender_protocol_address, target_protocol_address) =
+        if data.len() <= PROTOCOL_ADDRESS_MAX_SIZE {
+            (data, data)
+        } else {
+            // Both fields are as long as each other.
+            let padding = (data.len() + 1) % 2;
+            split = [&[padding as u8], data, &[0][..padding]].concat();
+            split.split_at(split.len() / 2)
+        };
+
+    let arp_bytes = [
+        HARDWARE_TYPE_ETHERNET,
+        protocol_type,
+        &[HARDWARE_ADDRESS_LENGTH, sender_protocol_address.len() as u8],
+        opcode,
+        &sender_mac.octets(),              // Sender hardware address
+        sender_protocol_address,           // Sender protocol address
+        &target_hardware_address.octets(), // Target hardware address
+        target_protocol_address,           // Target protocol address
+    ]
+    .concat();
+
+    ethernet::build(
+        destination,
+        sender_mac,
+        &EtherTypes::Arp.0.to_be_bytes(),
+        &arp_bytes,
+    )
+}
+
+/// Returns `None` for frames that aren't ARP requests or replies of Ethernet addresses. <br>
+/// KTP data is carried in the protocol address fields, see `build`. <br>
+/// Never panics, whatever the frame is.
+pub fn parse(ethernet_frame: &[u8]) -> Result<Option<Frame<'_>>, KtpError> {
+    let Some(ethernet) = EthernetPacket::new(ethernet_frame) else {
+        return Ok(None);
+    };
+    if ethernet.get_ethertype() != EtherTypes::Arp {
+        return Ok(None);
+    }
+
+    let payload = &ethernet_frame[ETHERNET_HEADER_SIZE..];
+    let Some(header) = payload.get(..ARP_HEADER_SIZE) else {
+        return Err(KtpError::Truncated);
+    };
+    // Early filter for packets that aren't relevant.
+    if (&header[6..8] != OPCODE_REQUEST && &header[6..8] != OPCODE_REPLY)
+        || &header[..2] != HARDWARE_TYPE_ETHERNET
+        || header[4] != HARDWARE_ADDRESS_LENGTH
+    {
+        return Ok(None);
+    }
+
+    let address_len = header[5] as usize;
+    let addresses = &payload[ARP_HEADER_SIZE..];
+    let hardware_len = HARDWARE_ADDRESS_LENGTH as usize;
+    let (Some(sender_hardware), Some(sender_protocol), Some(target_hardware)) = (
+        addresses.get(..hardware_len),
+        addresses.get(hardware_len..hardware_len + address_len),
+        addresses.get(hardware_len + address_len..2 * hardware_len + address_len),
+    ) else {
+        return Err(KtpError::Truncated);
+    };
+
+    let data = match sender_protocol.first() {
+        Some(&padding) if padding <= SPLIT_MAX_PADDING => {
+            let target_protocol = addresses
+                .get(2 * hardware_len + address_len..2 * (hardware_len + address_len))
+                .ok_or(KtpError::Truncated)?;
+            let mut split = [sender_protocol, target_protocol].concat();
+            split.truncate(split.len() - padding as usize);
+            split.remove(0);
+            Cow::Owned(split)
+        },
+        _ => Cow::Borrowed(sender_protocol),
+    };
+
+    // Gratuitous replies point at the sender, the target is in the Ethernet header.
+    let target_mac = match mac(target_hardware) {
+        target_mac if target_hardware != sender_hardware => target_mac,
+        _ if ethernet.get_destination().is_broadcast() => MacAddr::zero(),
+        _ => ethernet.get_destination(),
+    };
+
+    Ok(Some(Frame {
+        sender_mac: ethernet.get_source(),
+        target_mac,
+        data,
+    }))
+}
+
+fn mac(octets: &[u8]) -> MacAddr {
+    MacAddr::new(
+        octets[0], octets[1], octets[2], octets[3], octets[4], octets[5],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(data in prop::collection::vec(any::<u8>(), 0..128)) {
+            let _ = parse(&data);
+
+            // Most random frames stop at the EtherType, ARP ones go further.
+            let mut arp_frame = data;
+            arp_frame.resize(arp_frame.len().max(14), 0);
+            arp_frame[12..14].copy_from_slice(&[0x08, 0x06]);
+            let _ = parse(&arp_frame);
+        }
+    }
+
+    #[test]
+    fn short_ktp_request_is_truncated() {
+        let frame = [
+            [0xff; 6].as_slice(),
+            &[2; 6],
+            &[0x08, 0x06],
+            HARDWARE_TYPE_ETHERNET,
+            &[0x08, 0x00, HARDWARE_ADDRESS_LENGTH, 200],
+            OPCODE_REQUEST,
+            &[2; 6],
+            b"ktp",
+        ]
+        .concat();
+
+        assert!(matches!(parse(&frame), Err(KtpError::Truncated)));
+    }
+
+    #[test]
+    fn long_data_is_split_between_addresses() {
+        let sender_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+
+        for len in [1, 255, 256, 257, SPLIT_DATA_SIZE] {
+            let data: Vec<u8> = b"ktp".iter().copied().cycle().take(len).collect();
+            let frame = build(
+                OPCODE_REQUEST,
+                MacAddr::broadcast(),
+                sender_mac,
+                MacAddr::zero(),
+                &[0x08, 0x00],
+                &data,
+            );
+
+            let parsed = parse(&frame).unwrap().unwrap();
+            assert_eq!(*parsed.data, data, "{len} bytes");
+        }
+    }
+}
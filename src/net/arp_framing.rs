@@ -0,0 +1,79 @@
+use crate::net::arp;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter};
+
+/// Kind of ARP packets the KTP frames are sent in. <br>
+/// Some switches rate-limit requests, but not replies. <br>
+/// Receivers accept all of them, whatever they send with.
+#[derive(
+    Default, Display, EnumIter, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq,
+)]
+pub enum ArpFraming {
+    #[strum(serialize = "Request")]
+    #[default]
+    Request,
+
+    #[strum(serialize = "Reply")]
+    Reply,
+
+    /// Reply with the sender's own hardware address as the target, RFC 2002.
+    #[strum(serialize = "Gratuitous reply")]
+    Gratuitous,
+}
+
+impl ArpFraming {
+    /// Opcode, Ethernet destination and target hardware address of a frame
+    /// for the target. Zero target means everybody.
+    pub fn addresses(
+        &self, sender_mac: MacAddr, target_mac: MacAddr,
+    ) -> (&'static [u8], MacAddr, MacAddr) {
+        match self {
+            ArpFraming::Request => {
+                (arp::OPCODE_REQUEST, MacAddr::broadcast(), target_mac)
+            },
+            ArpFraming::Reply => (arp::OPCODE_REPLY, MacAddr::broadcast(), target_mac),
+            // The target moves to the Ethernet header.
+            ArpFraming::Gratuitous => (
+                arp::OPCODE_REPLY,
+                match target_mac {
+                    mac if mac.is_zero() => MacAddr::broadcast(),
+                    mac => mac,
+                },
+                sender_mac,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn every_framing_is_received() {
+        let sender_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+
+        for framing in ArpFraming::iter() {
+            for target_mac in [MacAddr::zero(), peer_mac] {
+                let (opcode, destination, target_hardware_address) =
+                    framing.addresses(sender_mac, target_mac);
+                let frame = arp::build(
+                    opcode,
+                    destination,
+                    sender_mac,
+                    target_hardware_address,
+                    &[0x88, 0xB5],
+                    b"ktp frame",
+                );
+
+                let parsed = arp::parse(&frame).unwrap().unwrap();
+                assert_eq!(*parsed.data, *b"ktp frame", "{framing}");
+                assert_eq!(parsed.sender_mac, sender_mac, "{framing}");
+                assert_eq!(parsed.target_mac, target_mac, "{framing}");
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::error::net::NetError;
+use crate::net::arp_framing::ArpFraming;
+use crate::net::capabilities::Capabilities;
+use crate::net::carrier::Carrier;
+use crate::net::crypto::RoomKey;
+use crate::net::ether_type::EtherType;
+use crate::net::ktp;
+use crate::net::replay::RecentIds;
+use crate::net::send_queue::SendProgress;
+use crate::net::transport::Transport;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+// Packets of this many last receives are remembered to drop their copies.
+const RELAYED_CAPACITY: usize = 4096;
+
+/// Joins the clients of a link carrier and of UDP into one chat. <br>
+/// Packets are sent over both, and the ones received over one are relayed
+/// over the other, signatures and all. A packet is relayed once,
+/// so its copies coming back, or from another bridge, go nowhere. <br>
+/// Packets without a sender can't be told from their copies, they aren't
+/// relayed. Neither are packets of rooms the bridging client isn't in.
+pub struct Bridge<L, U> {
+    link: L,
+    udp: U,
+    is_udp_turn: bool,
+    // Carrier of the last received packet, where its sender is learned.
+    is_last_from_udp: bool,
+
+    relayed: RecentIds,
+    // Our own packets come back over both, but are ours to send.
+    sent: RecentIds,
+}
+
+impl<L: Transport, U: Transport> Bridge<L, U> {
+    pub fn new(link: L, udp: U) -> Self {
+        Self {
+            link,
+            udp,
+            is_udp_turn: false,
+            is_last_from_udp: false,
+            relayed: RecentIds::with_capacity(RELAYED_CAPACITY),
+            sent: RecentIds::with_capacity(RELAYED_CAPACITY),
+        }
+    }
+}
+
+// Packets carry a counter of their sender, so copies of one packet,
+// and only they, serialize the same.
+fn digest(packet: &ktp::Packet) -> ktp::Id {
+    let hash = Sha256::digest(packet.serialize());
+    let mut digest = ktp::Id::default();
+    digest.copy_from_slice(&hash[..size_of::<ktp::Id>()]);
+    digest
+}
+
+impl<L: Transport, U: Transport> Transport for Bridge<L, U> {
+    /// Packets for a single peer only go where it was heard from.
+    fn try_send(&mut self, packet: ktp::Packet) -> Result<(), NetError> {
+        if packet.sender().is_some() {
+            self.sent.insert(digest(&packet));
+        }
+
+        let Some(target) = packet.target() else {
+            let result = self.link.try_send(packet.clone());
+            return self.udp.try_send(packet).and(result);
+        };
+        match self.udp.peer_address(&target) {
+            Some(_) => self.udp.try_send(packet),
+            None => self.link.try_send(packet),
+        }
+    }
+
+    fn try_recv(&mut self) -> Result<Option<ktp::Packet>, NetError> {
+        // Turns alternate, so a busy carrier doesn't hold up the other one.
+        self.is_udp_turn = !self.is_udp_turn;
+        let (from, to): (&mut dyn Transport, &mut dyn Transport) = match self.is_udp_turn
+        {
+            true => (&mut self.udp, &mut self.link),
+            false => (&mut self.link, &mut self.udp),
+        };
+
+        let Some(packet) = from.try_recv()? else {
+            return Ok(None);
+        };
+        if packet.sender().is_none() {
+            return Ok(Some(packet));
+        }
+        let digest = digest(&packet);
+        if !self.relayed.insert(digest) {
+            return Ok(None);
+        }
+
+        let is_for_other_side = packet
+            .target()
+            .is_none_or(|target| to.peer_address(&target).is_some());
+        if !self.sent.contains(&digest) && is_for_other_side {
+            if let Err(err) = to.try_send(packet.clone()) {
+                log::debug!("Couldn't relay packet: {err}");
+            }
+        }
+
+        self.is_last_from_udp = self.is_udp_turn;
+        Ok(Some(packet))
+    }
+
+    fn poll_send(&mut self) -> Result<Option<SendProgress>, NetError> {
+        let link_progress = self.link.poll_send()?;
+        let udp_progress = self.udp.poll_send()?;
+        Ok(link_progress.or(udp_progress))
+    }
+
+    fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.link.set_max_packet_size(max_packet_size);
+        self.udp.set_max_packet_size(max_packet_size);
+    }
+
+    fn set_ether_type(&mut self, ether_type: EtherType) {
+        self.link.set_ether_type(ether_type);
+    }
+
+    fn set_arp_framing(&mut self, arp_framing: ArpFraming) {
+        self.link.set_arp_framing(arp_framing);
+    }
+
+    fn set_carrier(&mut self, carrier: Carrier) {
+        self.link.set_carrier(carrier);
+    }
+
+    fn set_peer_capabilities(&mut self, capabilities: Capabilities) {
+        self.link.set_peer_capabilities(capabilities);
+        self.udp.set_peer_capabilities(capabilities);
+    }
+
+    fn peer_address(&self, id: &ktp::Id) -> Option<String> {
+        self.link
+            .peer_address(id)
+            .or_else(|| self.udp.peer_address(id))
+    }
+
+    fn learn_peer(&mut self, id: &ktp::Id) {
+        match self.is_last_from_udp {
+            true => self.udp.learn_peer(id),
+            false => self.link.learn_peer(id),
+        }
+    }
+
+    fn forget_peer(&mut self, id: &ktp::Id) {
+        self.link.forget_peer(id);
+        self.udp.forget_peer(id);
+    }
+
+    fn is_from_peer(&self, id: &ktp::Id) -> bool {
+        match self.is_last_from_udp {
+            true => self.udp.is_from_peer(id),
+            false => self.link.is_from_peer(id),
+        }
+    }
+
+    fn set_room_key(&mut self, room_key: Option<RoomKey>) {
+        self.link.set_room_key(room_key.clone());
+        self.udp.set_room_key(room_key);
+    }
+
+    fn set_rooms(&mut self, rooms: HashSet<String>) {
+        self.link.set_rooms(rooms.clone());
+        self.udp.set_rooms(rooms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::{self, MemorySegment};
+
+    #[test]
+    fn packets_cross_the_bridge_once() {
+        let (link, udp) = (MemorySegment::default(), MemorySegment::default());
+        let mut bridge = Bridge::new(link.attach(), udp.attach());
+        let mut alice = link.attach();
+        let mut bob = udp.attach();
+        let receive = |bridge: &mut Bridge<_, _>| -> Vec<ktp::Packet> {
+            (0..100)
+                .filter_map(|_| bridge.try_recv().unwrap())
+                .collect()
+        };
+
+        let (alice_id, bob_id) = (ktp::generate_id(), ktp::generate_id());
+        let message = ktp::Packet::Message {
+            id: alice_id,
+            counter: 1,
+            room: String::new(),
+            message_id: ktp::generate_id(),
+            reply_to: None,
+            compression: ktp::Compression::Smaz,
+            message_text: "anybody over udp?".into(),
+            signature: [0; 64],
+        };
+        alice.try_send(message.clone()).unwrap();
+        assert_eq!(receive(&mut bridge), vec![message.clone()]);
+        bridge.learn_peer(&alice_id);
+        assert_eq!(memory::receive_all(&mut bob), vec![message.clone()]);
+        // Neither copy of the relayed packet comes back to the link.
+        assert_eq!(receive(&mut bridge), vec![]);
+        assert_eq!(memory::receive_all(&mut alice), vec![message]);
+
+        // Replies find their way back to the addressee.
+        let ack = ktp::Packet::Ack {
+            id: bob_id,
+            counter: 1,
+            target: alice_id,
+            message_id: ktp::generate_id(),
+            signature: [0; 64],
+        };
+        bob.try_send(ack.clone()).unwrap();
+        assert_eq!(receive(&mut bridge), vec![ack.clone()]);
+        assert_eq!(memory::receive_all(&mut alice), vec![ack]);
+    }
+}
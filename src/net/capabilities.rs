@@ -0,0 +1,90 @@
+/// Features a client supports, announced in its presence. <br>
+/// A feature is only used with peers that support it, and bits unknown to
+/// this version are kept as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Message text compressed with smaz.
+    pub const SMAZ: Self = Self(1);
+    /// End-to-end encrypted direct messages.
+    pub const DIRECT_MESSAGES: Self = Self(1 << 1);
+    /// Acknowledged delivery of messages.
+    pub const ACKS: Self = Self(1 << 2);
+    /// ARP frames with data split between both protocol addresses.
+    pub const SPLIT_ARP: Self = Self(1 << 3);
+    /// Packets of more than 256 frames.
+    pub const LARGE_PACKETS: Self = Self(1 << 4);
+    /// Files sent in chunks, see `transfer`.
+    pub const FILE_TRANSFER: Self = Self(1 << 5);
+    /// Messages and presence of rooms other than the main one.
+    pub const ROOMS: Self = Self(1 << 6);
+    /// Typing indicators, see `typing`.
+    pub const TYPING: Self = Self(1 << 7);
+    /// Messages edited or deleted by their sender.
+    pub const EDITS: Self = Self(1 << 8);
+    /// Messages that quote the one they answer.
+    pub const REPLIES: Self = Self(1 << 9);
+
+    /// Everything this version of the client is able to do.
+    pub const SUPPORTED: Self = Self(
+        Self::SMAZ.0
+            | Self::DIRECT_MESSAGES.0
+            | Self::ACKS.0
+            | Self::SPLIT_ARP.0
+            | Self::LARGE_PACKETS.0
+            | Self::FILE_TRANSFER.0
+            | Self::ROOMS.0
+            | Self::TYPING.0
+            | Self::EDITS.0
+            | Self::REPLIES.0,
+    );
+
+    // Locale keys of the known features.
+    const NAMES: [(Self, &'static str); 10] = [
+        (Self::SMAZ, "text.feature_smaz"),
+        (Self::DIRECT_MESSAGES, "text.feature_direct_messages"),
+        (Self::ACKS, "text.feature_acks"),
+        (Self::SPLIT_ARP, "text.feature_split_arp"),
+        (Self::LARGE_PACKETS, "text.feature_large_packets"),
+        (Self::FILE_TRANSFER, "text.feature_file_transfer"),
+        (Self::ROOMS, "text.feature_rooms"),
+        (Self::TYPING, "text.feature_typing"),
+        (Self::EDITS, "text.feature_edits"),
+        (Self::REPLIES, "text.feature_replies"),
+    ];
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Features of `self` that `other` lacks.
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Locale keys of the known features in the set.
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
@@ -0,0 +1,47 @@
+use crate::net::{ethernet, ktp, ndp, udp};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter};
+
+/// How KTP frames are put on the wire. <br>
+/// Frames of both link carriers are received, whichever one is chosen.
+/// UDP doesn't share the socket with them, so switching to or from it
+/// takes effect after a restart.
+#[derive(
+    Default, Display, EnumIter, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq,
+)]
+pub enum Carrier {
+    /// Protocol address fields of ARP requests, up to 255 bytes,
+    /// or twice as much when every peer reads `Capabilities::SPLIT_ARP`.
+    #[strum(serialize = "ARP")]
+    #[default]
+    Arp,
+
+    /// Payload of Ethernet II frames with the chosen EtherType, up to 1400 bytes.
+    #[strum(serialize = "Ethernet II")]
+    Ethernet,
+
+    /// Option of ICMPv6 Neighbor Solicitations to all nodes, for IPv6-only links.
+    #[strum(serialize = "IPv6 NDP")]
+    Ndp,
+
+    /// Broadcast or multicast datagrams, needs no privileges.
+    #[strum(serialize = "UDP")]
+    Udp,
+}
+
+impl Carrier {
+    /// Size of the packet data in one KTP frame.
+    pub fn part_size(&self) -> usize {
+        match self {
+            Carrier::Arp => ktp::PACKET_DATA_SIZE,
+            Carrier::Ethernet => ethernet::PART_SIZE,
+            Carrier::Ndp => ndp::PART_SIZE,
+            Carrier::Udp => udp::PART_SIZE,
+        }
+    }
+
+    /// Carriers that need a datalink channel.
+    pub fn is_link(&self) -> bool {
+        *self != Carrier::Udp
+    }
+}
@@ -1,23 +1,43 @@
+use crate::error::ktp::KtpError;
 use crate::error::net::NetError;
+use crate::net::arp_framing::ArpFraming;
+use crate::net::capabilities::Capabilities;
+use crate::net::carrier::Carrier;
+use crate::net::clock::{Clock, SystemClock};
+use crate::net::crypto::RoomKey;
 use crate::net::ether_type::EtherType;
-use crate::net::{arp, ktp};
+use crate::net::ethernet::Frame;
+use crate::net::peers::PeerAddresses;
+use crate::net::reassembly::{FragmentCache, Reassembler};
+use crate::net::send_queue::{SendProgress, SendQueue};
+use crate::net::transport::Transport;
+use crate::net::{arp, ethernet, ktp, ndp};
 use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
-use pnet::packet::Packet;
 use pnet::util::MacAddr;
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashSet;
 use std::io::ErrorKind;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub struct Channel {
     src_mac: MacAddr,
+    carrier: Carrier,
+    arp_framing: ArpFraming,
     ether_type: EtherType,
     tx: Box<dyn DataLinkSender>,
     rx: Box<dyn DataLinkReceiver>,
+    clock: Arc<dyn Clock + Send + Sync>,
 
-    buffer: HashMap<ktp::Id, Vec<Vec<u8>>>,
+    reassembler: Reassembler<MacAddr>,
+    sent_fragments: FragmentCache<MacAddr>,
+    send_queue: SendQueue<MacAddr>,
+    max_packet_size: usize,
+    room_key: Option<RoomKey>,
+    rooms: HashSet<String>,
+    peer_capabilities: Capabilities,
 
-    recent: VecDeque<ktp::Id>,
+    // Learned from the sender hardware address of verified packets.
+    peers: PeerAddresses<MacAddr>,
 }
 
 impl Channel {
@@ -34,90 +54,157 @@ impl Channel {
             Err(err) => return Err(NetError::ChannelGettingError(err)),
         };
 
-        Ok(Self {
-            src_mac: interface.mac.ok_or(NetError::NoMac)?,
-            ether_type: EtherType::default(),
-            tx: tx_ethernet,
-            rx: rx_ethernet,
-            buffer: HashMap::new(),
-            recent: VecDeque::with_capacity(16),
-        })
+        Ok(Self::new(
+            interface.mac.ok_or(NetError::NoMac)?,
+            tx_ethernet,
+            rx_ethernet,
+            Arc::new(SystemClock),
+        ))
     }
 
-    pub fn set_ether_type(&mut self, ether_type: EtherType) {
-        self.ether_type = ether_type;
+    /// Channel over any link that carries raw Ethernet frames.
+    pub fn new(
+        src_mac: MacAddr, tx: Box<dyn DataLinkSender>, rx: Box<dyn DataLinkReceiver>,
+        clock: Arc<dyn Clock + Send + Sync>,
+    ) -> Self {
+        Self {
+            src_mac,
+            carrier: Carrier::default(),
+            arp_framing: ArpFraming::default(),
+            ether_type: EtherType::default(),
+            tx,
+            rx,
+            clock,
+            reassembler: Reassembler::with_max_packet_size(ktp::DEFAULT_MAX_PACKET_SIZE),
+            sent_fragments: FragmentCache::default(),
+            send_queue: SendQueue::default(),
+            max_packet_size: ktp::DEFAULT_MAX_PACKET_SIZE,
+            room_key: None,
+            rooms: HashSet::new(),
+            peer_capabilities: Capabilities::default(),
+            peers: PeerAddresses::default(),
+        }
     }
 
-    pub fn try_send(&mut self, packet: ktp::Packet) -> Result<(), NetError> {
-        let data = packet.serialize();
-        let mut parts: Vec<&[u8]> = data.chunks(ktp::PACKET_DATA_SIZE).collect();
+    fn try_send_part(
+        &mut self, data: &[u8], target_mac: MacAddr,
+    ) -> Result<(), NetError> {
+        let ethernet_frame = match self.carrier {
+            // The channel never carries UDP, see `set_carrier`.
+            Carrier::Arp | Carrier::Udp => self.arp_frame(data, target_mac),
+            // Packets for a single peer don't bother the rest of the segment.
+            Carrier::Ethernet => ethernet::build_ktp(
+                match target_mac {
+                    mac if mac.is_zero() => MacAddr::broadcast(),
+                    mac => mac,
+                },
+                self.src_mac,
+                self.ether_type.ethernet_bytes(),
+                data,
+            ),
+            Carrier::Ndp => ndp::build(target_mac, self.src_mac, data),
+        };
 
-        // In case of PresenceBroadcastRequest packet.
-        if packet.tag() == 1 {
-            parts.push(b".");
+        match self.tx.send_to(&ethernet_frame, None) {
+            Some(Ok(())) => Ok(()),
+            _ => Err(NetError::FrameSendFailed),
         }
+    }
 
-        if parts.len() - 1 > u8::MAX as usize {
-            return Err(NetError::MessageTooLong);
+    // Presence reaches peers we don't know yet, so every version must read it.
+    fn part_size(&self, packet: &ktp::Packet) -> usize {
+        let is_presence = matches!(
+            packet,
+            ktp::Packet::PresenceInformation { .. }
+                | ktp::Packet::PresenceBroadcastRequest
+        );
+        match self.carrier {
+            Carrier::Arp
+                if !is_presence
+                    && self.peer_capabilities.contains(Capabilities::SPLIT_ARP) =>
+            {
+                arp::SPLIT_PART_SIZE
+            },
+            carrier => carrier.part_size(),
         }
+    }
+
+    fn arp_frame(&self, data: &[u8], target_mac: MacAddr) -> Vec<u8> {
+        let (opcode, destination, target_hardware_address) =
+            self.arp_framing.addresses(self.src_mac, target_mac);
+
+        arp::build(
+            opcode,
+            destination,
+            self.src_mac,
+            target_hardware_address,
+            self.ether_type.bytes(),
+            data,
+        )
+    }
+}
 
-        let total = (parts.len() - 1) as ktp::Total;
-        let id: ktp::Id = ktp::generate_id();
-        for (seq, part) in parts.into_iter().enumerate() {
-            self.try_send_part(
-                packet.tag(),
-                seq as ktp::Seq,
-                total as ktp::Total,
-                id as ktp::Id,
-                part,
-            )?;
+// Frames of every link carrier are accepted, whichever one we send with.
+fn parse(packet: &[u8], ether_type: EtherType) -> Result<Option<Frame<'_>>, KtpError> {
+    if let Some(frame) = arp::parse(packet)? {
+        return Ok(Some(frame));
+    }
+    if let Some(frame) = ethernet::parse(packet, ether_type.ethernet_bytes())? {
+        return Ok(Some(frame));
+    }
+    ndp::parse(packet)
+}
+
+impl Transport for Channel {
+    fn try_send(&mut self, packet: ktp::Packet) -> Result<(), NetError> {
+        // Packets for a single peer are addressed to its MAC,
+        // so everybody else can drop them right away.
+        let target_mac = match packet.target() {
+            Some(target) => self.peers.get(&target).ok_or(NetError::UnknownPeer)?,
+            None => MacAddr::zero(),
+        };
+
+        let part_size = self.part_size(&packet);
+        let (tag, data) = ktp::encode(&packet, self.room_key.as_ref())?;
+        if data.len() > self.max_packet_size {
+            return Err(NetError::MessageTooLong);
+        }
+        let allow_extended = self.peer_capabilities.contains(Capabilities::LARGE_PACKETS);
+        let frames = ktp::fragment(tag, &data, part_size, allow_extended)?;
+        self.sent_fragments.insert(target_mac, &frames);
+        if let Some(frames) =
+            self.send_queue
+                .push(target_mac, packet.message_id(), frames)
+        {
+            for frame in frames {
+                self.try_send_part(&frame, target_mac)?;
+            }
         }
 
         Ok(())
     }
 
-    fn try_send_part(
-        &mut self, tag: ktp::Tag, seq: ktp::Seq, total: ktp::Total, id: ktp::Id,
-        part: &[u8],
-    ) -> Result<(), NetError> {
-        let data = &[ktp::PACKET_PREFIX, &[tag, seq, total], &id, part].concat();
-
-        // The length of the data must fit in a u8. This should also
-        // guarantee that we'll be inside the MTU.
-        debug_assert!(
-            data.len() <= u8::MAX as usize,
-            "Part data is too large ({} > {})",
-            data.len(),
-            u8::MAX
-        );
-
-        let arp_bytes = [
-            arp::HARDWARE_TYPE_ETHERNET,
-            self.ether_type.bytes(),
-            &[arp::HARDWARE_ADDRESS_LENGTH, data.len() as u8],
-            arp::OPCODE_REQUEST,
-            &self.src_mac.octets(), // Sender hardware address
-            data,                   // Sender protocol address
-            &[0; 6],                // Target hardware address
-            data,                   // Target protocol address
-        ]
-        .concat();
-
-        let mut ethernet_bytes = vec![0; 14 + arp_bytes.len()];
-        let mut ethernet_frame = MutableEthernetPacket::new(&mut ethernet_bytes[..])
-            .ok_or(NetError::ARPSerializeFailed)?;
-        ethernet_frame.set_destination(MacAddr::broadcast());
-        ethernet_frame.set_source(self.src_mac);
-        ethernet_frame.set_ethertype(EtherTypes::Arp);
-        ethernet_frame.set_payload(&arp_bytes);
-
-        match self.tx.send_to(ethernet_frame.packet(), None) {
-            Some(Ok(())) => Ok(()),
-            _ => Err(NetError::ARPSendFailed),
+    fn poll_send(&mut self) -> Result<Option<SendProgress>, NetError> {
+        let Some((target_mac, frames, progress)) = self.send_queue.next_burst() else {
+            return Ok(None);
+        };
+        for frame in frames {
+            self.try_send_part(&frame, target_mac)?;
         }
+
+        Ok(progress)
+    }
+
+    fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+        self.reassembler = Reassembler::with_max_packet_size(max_packet_size);
     }
 
-    pub fn try_recv(&mut self) -> Result<Option<ktp::Packet>, NetError> {
+    fn try_recv(&mut self) -> Result<Option<ktp::Packet>, NetError> {
+        for nack in self.reassembler.poll(self.clock.now()) {
+            self.try_send(nack)?;
+        }
+
         let packet = match self.rx.next() {
             Ok(packet) => packet,
             Err(e) => {
@@ -125,68 +212,162 @@ impl Channel {
                     Ok(None)
                 } else {
                     Err(NetError::CaptureFailed)
-                }
+                };
             },
         };
-        let packet = match EthernetPacket::new(packet) {
-            Some(packet) => packet,
-            None => return Ok(None),
+        // Anybody on the segment can send garbage, it only costs them the frame.
+        let frame = match parse(packet, self.ether_type) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                log::debug!("Dropped malformed frame: {err}");
+                return Ok(None);
+            },
         };
 
-        // Early filter for packets that aren't relevant.
-        if packet.get_ethertype() != EtherTypes::Arp
-            || &packet.payload()[6..8] != arp::OPCODE_REQUEST
-            || &packet.payload()[..2] != arp::HARDWARE_TYPE_ETHERNET
-            || packet.payload()[4] != arp::HARDWARE_ADDRESS_LENGTH
-        {
+        // Skip packets addressed to somebody else.
+        if frame.target_mac != MacAddr::zero() && frame.target_mac != self.src_mac {
             return Ok(None);
         }
 
-        let data_len = packet.payload()[5] as usize;
-        let data = &packet.payload()[14..14 + data_len];
-        if !data.starts_with(ktp::PACKET_PREFIX) {
-            return Ok(None);
+        // Packets of rooms we aren't in are dropped at their first frame.
+        if let Ok(Some(ktp_frame)) = ktp::parse_frame(&frame.data) {
+            if ktp::starts_other_room(&ktp_frame, &self.rooms) {
+                log::trace!("Skipped packet of a room we aren't in.");
+                self.reassembler.skip(ktp_frame.id);
+                return Ok(None);
+            }
         }
 
-        if let &[tag, seq, total, ref inner @ ..] = &data[ktp::PACKET_PREFIX.len()..] {
-            let id_size = size_of::<ktp::Id>();
-
-            let id: ktp::Id = inner[..id_size]
-                .try_into()
-                .map_err(|_| NetError::ARPDeserializeFailed)?;
-            // Skip if we already have this packet
-            if self.recent.contains(&id) {
+        let sender_mac = frame.sender_mac;
+        let assembled =
+            self.reassembler
+                .accept(&frame.data, sender_mac, self.clock.now());
+        let packet = match assembled.and_then(|assembled| match assembled {
+            Some((tag, data)) => {
+                ktp::decode(tag, &data, self.room_key.as_ref(), Some(&self.rooms))
+            },
+            None => Ok(None),
+        }) {
+            Ok(packet) => packet,
+            Err(err) => {
+                log::debug!("Dropped undecodable packet from {sender_mac}: {err}");
                 return Ok(None);
+            },
+        };
+        if let Some(ktp::Packet::Nack { frame_id, missing }) = &packet {
+            if let Some((target_mac, frames)) =
+                self.sent_fragments
+                    .missing(frame_id, missing, self.clock.now())
+            {
+                for frame in frames {
+                    self.try_send_part(&frame, target_mac)?;
+                }
             }
+            return Ok(None);
+        }
+        if let Some(packet) = &packet {
+            self.peers.heard(packet.sender(), sender_mac);
+        }
 
-            let inner = &inner[id_size..];
+        Ok(packet)
+    }
 
-            if let Some(parts) = self.buffer.get_mut(&id) {
-                parts[seq as usize] = inner.to_vec();
-            } else {
-                let mut parts = vec![vec![]; total as usize + 1];
-                parts[seq as usize] = inner.to_vec();
-                self.buffer.insert(id, parts);
-            }
+    fn peer_address(&self, id: &ktp::Id) -> Option<String> {
+        self.peers.get(id).map(|mac| mac.to_string())
+    }
 
-            let parts = self.buffer.get(&id).ok_or(NetError::ARPDeserializeFailed)?;
+    fn learn_peer(&mut self, id: &ktp::Id) {
+        self.peers.learn(id);
+    }
 
-            // Short-circuit if we don't have all the parts yet.
-            if !parts.iter().all(|p| !p.is_empty()) {
-                return Ok(None);
+    fn forget_peer(&mut self, id: &ktp::Id) {
+        self.peers.forget(id);
+    }
+
+    fn is_from_peer(&self, id: &ktp::Id) -> bool {
+        self.peers.is_last_from(id)
+    }
+
+    fn set_ether_type(&mut self, ether_type: EtherType) {
+        self.ether_type = ether_type;
+    }
+
+    fn set_arp_framing(&mut self, arp_framing: ArpFraming) {
+        self.arp_framing = arp_framing;
+    }
+
+    fn set_carrier(&mut self, carrier: Carrier) {
+        // The UDP socket is only opened on start.
+        if !carrier.is_link() {
+            return;
+        }
+        // Frames cached for NACKs may be too large for the new carrier.
+        if carrier != self.carrier {
+            self.sent_fragments = FragmentCache::default();
+        }
+        self.carrier = carrier;
+    }
+
+    fn set_peer_capabilities(&mut self, capabilities: Capabilities) {
+        self.peer_capabilities = capabilities;
+    }
+
+    fn set_room_key(&mut self, room_key: Option<RoomKey>) {
+        self.room_key = room_key;
+    }
+
+    fn set_rooms(&mut self, rooms: HashSet<String>) {
+        self.rooms = rooms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::memory::MemorySegment;
+    use proptest::prelude::*;
+
+    fn arp_frame(data: &[u8]) -> Vec<u8> {
+        arp::build(
+            arp::OPCODE_REQUEST,
+            MacAddr::broadcast(),
+            MacAddr::new(0x02, 0, 0, 0, 0xff, 0xff),
+            MacAddr::zero(),
+            EtherType::default().bytes(),
+            data,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn try_recv_drops_garbage(
+            payloads in prop::collection::vec(
+                prop::collection::vec(any::<u8>(), 1..arp::SPLIT_DATA_SIZE),
+                1..16,
+            ),
+            tag in any::<u8>(),
+        ) {
+            let segment = MemorySegment::default();
+            let mut channel = segment.attach();
+
+            // Raw frames, KTP frames of garbage and packets with a garbage body.
+            let mut frames = 0;
+            for payload in &payloads {
+                segment.inject(payload);
+                segment.inject(&arp_frame(payload));
+                frames += 2;
+                for part in ktp::fragment(tag, payload, ktp::PACKET_DATA_SIZE, false)
+                    .unwrap()
+                {
+                    segment.inject(&arp_frame(&part));
+                    frames += 1;
+                }
             }
 
-            // Put the packet together
-            let packet = ktp::Packet::deserialize(tag, &parts.concat());
-            if let Some(packet) = packet {
-                self.buffer.remove(&id);
-                self.recent.push_back(id);
-                Ok(Some(packet))
-            } else {
-                Err(NetError::ARPDeserializeFailed)
+            for _ in 0..frames {
+                prop_assert!(channel.try_recv().is_ok());
             }
-        } else {
-            Ok(None)
         }
     }
 }
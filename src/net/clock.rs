@@ -0,0 +1,16 @@
+use std::time::Instant;
+
+/// Source of time for the net thread. <br>
+/// Heartbeats and timeouts are measured with it, so tests can move time manually.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -1,10 +1,59 @@
+use crate::net::arp_framing::ArpFraming;
+use crate::net::carrier::Carrier;
 use crate::net::ether_type::EtherType;
+use crate::net::ktp;
+use std::path::PathBuf;
 
 pub enum NetCommand {
+    AcceptFile {
+        transfer_id: ktp::Id,
+        directory: PathBuf,
+    },
+    CancelTransfer {
+        transfer_id: ktp::Id,
+    },
+    /// Only our own messages are changed.
+    DeleteMessage {
+        room: String,
+        message_id: ktp::Id,
+    },
+    EditMessage {
+        room: String,
+        message_id: ktp::Id,
+        message_text: String,
+    },
+    JoinRoom(String),
+    PartRoom(String),
     PauseHeartbeat(bool),
-    SendMessage { message_text: String },
+    RetryFailedMessages,
+    /// The room is empty for the main one.
+    SendMessage {
+        room: String,
+        message_text: String,
+        reply_to: Option<ktp::Id>,
+    },
+    SendDirectMessage {
+        target: ktp::Id,
+        message_text: String,
+    },
+    SendFile {
+        target: ktp::Id,
+        path: PathBuf,
+    },
+    SetArpFraming(ArpFraming),
+    SetCarrier(Carrier),
     SetEtherType(EtherType),
-    SetInterface { interface_name: String },
+    SetInterface {
+        interface_name: String,
+    },
+    SetRoomPassphrase(Option<String>),
+    /// Topics are only set in rooms other than the main one.
+    SetTopic {
+        room: String,
+        topic: String,
+    },
+    /// Room of the unsent message in the input, `None` once there is none.
+    SetTyping(Option<String>),
     Terminate,
     UpdateUsername(String),
 }
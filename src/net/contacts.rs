@@ -0,0 +1,305 @@
+use crate::config::Config;
+use crate::error::config::ConfigError;
+use crate::net::{identity, ktp};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+
+pub static CONTACTS: LazyLock<Arc<Mutex<ContactBook>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(ContactBook::load())));
+const CONTACTS_FILENAME: &str = "contacts.toml";
+
+// Last seen time is saved with this precision, so heartbeats don't rewrite the file.
+const LAST_SEEN_PRECISION_SECS: i64 = 60;
+// Anybody can make up keys, so passers-by don't pile up past this.
+const MAX_CONTACTS: usize = 512;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Contact {
+    /// Identity key in hex.
+    pub public_key: String,
+    pub username: String,
+    /// Name given by the user, shown instead of the username.
+    pub alias: Option<String>,
+    pub mac: Option<String>,
+    /// Unix timestamps.
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// Fingerprint was compared out of band.
+    pub is_verified: bool,
+}
+
+impl Contact {
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.username)
+    }
+
+    /// Verified and named contacts are never forgotten on their own.
+    pub fn is_pinned(&self) -> bool {
+        self.is_verified || self.alias.is_some()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        decode_key(&self.public_key)
+            .map(|public_key| identity::fingerprint(&public_key))
+            .unwrap_or_default()
+    }
+}
+
+/// Result of seeing a peer with a signed presence.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sighting {
+    New,
+    Known {
+        is_verified: bool,
+    },
+    /// Another key was seen with this username before.
+    KeyMismatch,
+}
+
+/// Peers seen so far, trusted on first use. <br>
+/// Kept next to the config, so the keys survive restarts.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ContactBook {
+    #[serde(default)]
+    contacts: Vec<Contact>,
+
+    // Without a path, the book lives only in memory.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    is_dirty: bool,
+    // Users read it to each other to verify contacts.
+    #[serde(skip)]
+    own_fingerprint: Option<String>,
+}
+
+impl ContactBook {
+    pub fn load() -> Self {
+        match Self::get_contacts_path() {
+            Ok(path) => {
+                let data = fs::read_to_string(&path).unwrap_or_default();
+                let mut book: Self = toml::from_str(&data).unwrap_or_default();
+                book.path = Some(path);
+                book
+            },
+            Err(_) => Default::default(),
+        }
+    }
+
+    pub fn save(&mut self) -> Result<(), ConfigError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let data = toml::to_string(&self)
+            .map_err(|err| ConfigError::SerializerFailed(err.to_string()))?;
+        if let Some(parent_path) = path.parent() {
+            fs::create_dir_all(parent_path)
+                .map_err(|err| ConfigError::CannotRecreatePath(err.to_string()))?;
+        }
+        fs::write(path, data)
+            .map_err(|err| ConfigError::CannotCreateFile(err.to_string()))?;
+
+        self.is_dirty = false;
+        Ok(())
+    }
+
+    pub fn save_if_dirty(&mut self) -> Result<(), ConfigError> {
+        match self.is_dirty {
+            true => self.save(),
+            false => Ok(()),
+        }
+    }
+
+    pub fn own_fingerprint(&self) -> Option<&str> {
+        self.own_fingerprint.as_deref()
+    }
+
+    pub fn set_own_key(&mut self, public_key: &ktp::PublicKey) {
+        self.own_fingerprint = Some(identity::fingerprint(public_key));
+    }
+
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    /// Remembers the peer, or updates what is known about it. <br>
+    /// A full book forgets the contact seen the longest ago that isn't pinned,
+    /// if every contact is pinned, the peer isn't remembered.
+    pub fn record(
+        &mut self, public_key: &ktp::PublicKey, username: &str, mac: Option<String>,
+        now: i64,
+    ) -> Sighting {
+        let public_key = encode_key(public_key);
+        // Contacts are kept in the order they were seen, the first key of a username wins.
+        let position = self
+            .contacts
+            .iter()
+            .position(|contact| contact.public_key == public_key)
+            .unwrap_or(self.contacts.len());
+        let is_mismatch = self.contacts[..position]
+            .iter()
+            .any(|contact| contact.username == username);
+
+        let sighting = match self.find_mut(&public_key) {
+            Some(contact) => {
+                let is_changed = contact.username != username
+                    || (mac.is_some() && contact.mac != mac)
+                    || now - contact.last_seen >= LAST_SEEN_PRECISION_SECS;
+
+                contact.username = username.to_string();
+                contact.mac = mac.or(contact.mac.take());
+                contact.last_seen = now;
+                let is_verified = contact.is_verified;
+                self.is_dirty |= is_changed;
+
+                Sighting::Known { is_verified }
+            },
+            None => {
+                if self.contacts.len() < MAX_CONTACTS || self.forget_stalest() {
+                    self.contacts.push(Contact {
+                        public_key,
+                        username: username.to_string(),
+                        alias: None,
+                        mac,
+                        first_seen: now,
+                        last_seen: now,
+                        is_verified: false,
+                    });
+                    self.is_dirty = true;
+                }
+
+                Sighting::New
+            },
+        };
+
+        match is_mismatch {
+            true => Sighting::KeyMismatch,
+            false => sighting,
+        }
+    }
+
+    pub fn rename(&mut self, public_key: &str, alias: Option<String>) {
+        if let Some(contact) = self.find_mut(public_key) {
+            contact.alias = alias;
+            self.is_dirty = true;
+        }
+    }
+
+    pub fn set_verified(&mut self, public_key: &str, is_verified: bool) {
+        if let Some(contact) = self.find_mut(public_key) {
+            contact.is_verified = is_verified;
+            self.is_dirty = true;
+        }
+    }
+
+    pub fn forget(&mut self, public_key: &str) {
+        self.contacts
+            .retain(|contact| contact.public_key != public_key);
+        self.is_dirty = true;
+    }
+
+    // Returns `false` if every contact is pinned.
+    fn forget_stalest(&mut self) -> bool {
+        let Some(position) = self
+            .contacts
+            .iter()
+            .enumerate()
+            .filter(|(_, contact)| !contact.is_pinned())
+            .min_by_key(|(_, contact)| contact.last_seen)
+            .map(|(position, _)| position)
+        else {
+            return false;
+        };
+
+        self.contacts.remove(position);
+        true
+    }
+
+    fn find_mut(&mut self, public_key: &str) -> Option<&mut Contact> {
+        self.contacts
+            .iter_mut()
+            .find(|contact| contact.public_key == public_key)
+    }
+
+    fn get_contacts_path() -> Result<PathBuf, ConfigError> {
+        Ok(Config::get_config_directory()?.join(CONTACTS_FILENAME))
+    }
+}
+
+pub fn encode_key(public_key: &ktp::PublicKey) -> String {
+    public_key
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn decode_key(hex: &str) -> Option<ktp::PublicKey> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_username_with_new_key_is_a_mismatch() {
+        let mut book = ContactBook::default();
+        let (alice, mallory) = ([1; 32], [2; 32]);
+
+        assert_eq!(book.record(&alice, "alice", None, 0), Sighting::New);
+        book.set_verified(&encode_key(&alice), true);
+        assert_eq!(
+            book.record(&alice, "alice", Some("aa:bb:cc:dd:ee:ff".into()), 10),
+            Sighting::Known { is_verified: true }
+        );
+        assert_eq!(
+            book.record(&mallory, "alice", None, 20),
+            Sighting::KeyMismatch
+        );
+
+        book.forget(&encode_key(&alice));
+        assert_eq!(
+            book.record(&mallory, "alice", None, 30),
+            Sighting::Known { is_verified: false }
+        );
+        assert_eq!(
+            book.contacts()[0].fingerprint(),
+            identity::fingerprint(&mallory)
+        );
+    }
+
+    #[test]
+    fn full_book_forgets_the_stalest_passer_by() {
+        let mut book = ContactBook::default();
+        let key = |i: usize| -> ktp::PublicKey {
+            let mut key = [0; 32];
+            key[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            key
+        };
+
+        // The oldest contact is verified, the second oldest is only seen.
+        for i in 0..MAX_CONTACTS {
+            book.record(&key(i), &format!("user{i}"), None, i as i64);
+        }
+        book.set_verified(&encode_key(&key(0)), true);
+        book.record(&key(MAX_CONTACTS), "newcomer", None, MAX_CONTACTS as i64);
+
+        let kept: Vec<&str> = book
+            .contacts()
+            .iter()
+            .map(|c| c.username.as_str())
+            .collect();
+        assert_eq!(kept.len(), MAX_CONTACTS);
+        assert!(kept.contains(&"user0"));
+        assert!(!kept.contains(&"user1"));
+        assert!(kept.contains(&"newcomer"));
+    }
+}
@@ -1,18 +1,40 @@
+use crate::config;
 use crate::error::net::NetError;
-use crate::net::channel::Channel;
+use crate::net::capabilities::Capabilities;
+use crate::net::carrier::Carrier;
+use crate::net::clock::{Clock, SystemClock};
 use crate::net::commands::NetCommand;
+use crate::net::contacts::{ContactBook, Sighting};
 use crate::net::core::NetThreadState::NeedsInitialPresence;
-use crate::net::ktp::Packet;
+use crate::net::crypto::RoomKey;
+use crate::net::delivery::{DeliveryState, Outbox, OutgoingMessage};
+use crate::net::e2e::DirectSessions;
+use crate::net::history::MessageAuthors;
+use crate::net::identity::{Identity, Verification};
+use crate::net::ktp::{Compression, Packet, RoomTopic};
 use crate::net::presence::{
-    UpdatePresenceKind, HEARTBEAT_INTERVAL, INACTIVE_TIMEOUT, OFFLINE_TIMEOUT,
+    HEARTBEAT_INTERVAL, INACTIVE_TIMEOUT, OFFLINE_TIMEOUT, Trust, UpdatePresenceKind,
 };
-use crate::net::{interface, ktp};
+use crate::net::replay::{RecentIds, ReplayGuard, Sequencer};
+use crate::net::rooms::RoomDirectory;
+use crate::net::send_queue::SendProgress;
+use crate::net::transfer::Transfers;
+use crate::net::transport::Transport;
+use crate::net::typing::Typing;
+use crate::net::{contacts, identity, interface, ktp};
 use crate::session_settings;
 use crate::ui::commands::UICommand;
 use crossbeam::channel::{Receiver, Sender, TrySendError};
 use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+// Ids of this many received messages are remembered to drop retransmissions.
+const SEEN_MESSAGES_CAPACITY: usize = 1024;
+// Keys of offline peers are dropped once there are more keys than this.
+const MAX_KEPT_KEYS: usize = 1024;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum NetThreadState {
     NeedsUsername,
@@ -23,23 +45,27 @@ enum NetThreadState {
 pub fn start(ui_tx: Sender<UICommand>, net_rx: Receiver<NetCommand>) {
     log::info!("Net thread started.");
 
-    let session_id = ktp::generate_id();
-    let mut session_username = String::from(session_settings::INITIAL_USERNAME);
-
-    let mut last_heartbeat = Instant::now();
-    let mut online: HashMap<ktp::Id, (Instant, String)> = HashMap::new();
-    let mut offline: HashSet<ktp::Id> = HashSet::new();
-
-    let mut state = NetThreadState::NeedsUsername;
-    let mut pause_heartbeat = false;
-
-    let mut channel: Channel;
+    let channel: Box<dyn Transport>;
 
     log::info!("Interface loop started.");
     loop {
         match net_rx.try_recv() {
             Ok(NetCommand::SetInterface { interface_name }) => {
-                let result = interface::channel_from_name(interface_name);
+                let result = match interface::transport_from_name(&interface_name, None) {
+                    // Unprivileged users can still talk to each other.
+                    Err(NetError::ChannelGettingError(err))
+                        if err.kind() == ErrorKind::PermissionDenied =>
+                    {
+                        log::warn!("Datalink channel: {err}. Falling back to UDP.");
+                        let _ = ui_tx
+                            .try_send(UICommand::SendNetWarning(NetError::FellBackToUdp));
+                        interface::transport_from_name(
+                            &interface_name,
+                            Some(Carrier::Udp),
+                        )
+                    },
+                    result => result,
+                };
                 match result {
                     Ok(value) => {
                         channel = value;
@@ -47,7 +73,7 @@ pub fn start(ui_tx: Sender<UICommand>, net_rx: Receiver<NetCommand>) {
                         break;
                     },
                     Err(err) => {
-                        log::error!("{}", err.to_string());
+                        log::error!("{}", err);
                         let _ = ui_tx.try_send(UICommand::SendNetError(err));
                         return;
                     },
@@ -62,227 +88,1517 @@ pub fn start(ui_tx: Sender<UICommand>, net_rx: Receiver<NetCommand>) {
         }
     }
 
+    run(channel, ui_tx, net_rx);
+}
+
+/// Main loop of the net thread, independent of the carrier underneath.
+pub fn run(
+    channel: impl Transport, ui_tx: Sender<UICommand>, net_rx: Receiver<NetCommand>,
+) {
+    let identity = Identity::load_or_create().unwrap_or_else(|err| {
+        log::error!("Identity: {err} Using a temporary one.");
+        Identity::generate()
+    });
+    let mut net_thread = NetThread::new(
+        channel,
+        SystemClock,
+        identity,
+        contacts::CONTACTS.clone(),
+        ui_tx,
+        net_rx,
+    )
+    .with_max_file_size(config::lock_get_max_file_size());
+
     log::info!("Net Thread loop started.");
-    loop {
-        match net_rx.try_recv() {
-            Ok(NetCommand::PauseHeartbeat(pause)) => {
+    while net_thread.step() {}
+}
+
+/// State of the net thread after the channel is set up. <br>
+/// Driven one iteration at a time by `step`, so the loop can also be run
+/// by the network simulator with a manual clock.
+pub struct NetThread<T: Transport, C: Clock> {
+    channel: T,
+    clock: C,
+    ui_tx: Sender<UICommand>,
+    net_rx: Receiver<NetCommand>,
+
+    identity: Identity,
+    session_id: ktp::Id,
+    session_username: String,
+    direct_sessions: DirectSessions,
+    transfers: Transfers,
+    sequencer: Sequencer,
+    replay_guard: ReplayGuard,
+    outbox: Outbox,
+    // Messages already shown, retransmissions are only acknowledged.
+    seen_messages: RecentIds,
+    // Senders of messages, the only ones allowed to edit or delete them.
+    message_authors: MessageAuthors,
+
+    // Identity keys of peers, learned from verified presence.
+    keys: HashMap<ktp::Id, ktp::PublicKey>,
+    // Online peer that took a username first, later ones are impersonators.
+    username_owners: HashMap<String, ktp::Id>,
+    contacts: Arc<Mutex<ContactBook>>,
+    // Peers the user was already warned about.
+    key_mismatches: HashSet<ktp::Id>,
+    outdated_peers: HashSet<ktp::Id>,
+
+    // Ours are announced in presence, peers' are learned from theirs.
+    capabilities: Capabilities,
+    peer_capabilities: HashMap<ktp::Id, Capabilities>,
+
+    last_heartbeat: Instant,
+    online: HashMap<ktp::Id, (Instant, String)>,
+    offline: HashSet<ktp::Id>,
+    // Rooms joined besides the main one, with the last presence of their members.
+    rooms: HashMap<String, HashMap<ktp::Id, Instant>>,
+    // Rooms of the whole segment, with their topics.
+    room_directory: RoomDirectory,
+    typing: Typing,
+
+    state: NetThreadState,
+    pause_heartbeat: bool,
+}
+
+impl<T: Transport, C: Clock> NetThread<T, C> {
+    pub fn new(
+        channel: T, clock: C, identity: Identity, contacts: Arc<Mutex<ContactBook>>,
+        ui_tx: Sender<UICommand>, net_rx: Receiver<NetCommand>,
+    ) -> Self {
+        let session_id = identity.id();
+        if let Ok(mut contacts) = contacts.lock() {
+            contacts.set_own_key(&identity.public_key());
+        }
+
+        Self {
+            last_heartbeat: clock.now(),
+            channel,
+            clock,
+            ui_tx,
+            net_rx,
+
+            identity,
+            session_id,
+            direct_sessions: DirectSessions::new(session_id),
+            transfers: Transfers::new(session_id),
+            sequencer: Sequencer::default(),
+            replay_guard: ReplayGuard::default(),
+            outbox: Outbox::default(),
+            seen_messages: RecentIds::with_capacity(SEEN_MESSAGES_CAPACITY),
+            message_authors: MessageAuthors::with_capacity(SEEN_MESSAGES_CAPACITY),
+            session_username: String::from(session_settings::INITIAL_USERNAME),
+
+            keys: HashMap::new(),
+            username_owners: HashMap::new(),
+            contacts,
+            key_mismatches: HashSet::new(),
+            outdated_peers: HashSet::new(),
+
+            capabilities: Capabilities::SUPPORTED,
+            peer_capabilities: HashMap::new(),
+
+            online: HashMap::new(),
+            offline: HashSet::new(),
+            rooms: HashMap::new(),
+            room_directory: RoomDirectory::default(),
+            typing: Typing::default(),
+
+            state: NetThreadState::NeedsUsername,
+            pause_heartbeat: false,
+        }
+    }
+
+    /// Larger files offered by peers are declined on acceptance.
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.transfers.set_max_incoming_size(max_file_size);
+        self
+    }
+
+    /// Pretends to be an older client, which lacks some features.
+    #[cfg(test)]
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// One iteration of the net loop. Returns `false` after termination.
+    pub fn step(&mut self) -> bool {
+        if let Ok(command) = self.net_rx.try_recv() {
+            if !self.handle_command(command) {
+                return false;
+            }
+        }
+
+        match self.channel.poll_send() {
+            Ok(Some(progress)) => self.show_send_progress(progress),
+            Ok(None) => {},
+            Err(err) => log::error!("Channel send error: {}", err),
+        }
+
+        let result_recv_packet = self.channel.try_recv();
+        match result_recv_packet {
+            Ok(Some(packet)) => self.handle_packet(packet),
+            Ok(None) => {},
+            // Retransmits and heartbeats go on, whatever happened to the capture.
+            Err(err) => log::error!("Channel recv error: {}", err),
+        }
+
+        self.retransmit();
+
+        let now = self.clock.now();
+        let packets = self.transfers.poll(&mut self.sequencer, now);
+        self.send_transfer_packets(packets);
+        self.poll_typing(now);
+
+        if self.clock.now().duration_since(self.last_heartbeat) > HEARTBEAT_INTERVAL
+            && self.state == NetThreadState::Ready
+        {
+            self.heartbeat();
+        }
+
+        true
+    }
+
+    /// Returns `false` if the command terminates the thread.
+    fn handle_command(&mut self, command: NetCommand) -> bool {
+        match command {
+            NetCommand::PauseHeartbeat(pause) => {
                 log::info!("Net Command: Pause Heartbeat. Value = {pause}");
-                pause_heartbeat = pause
+                self.pause_heartbeat = pause
             },
-            Ok(NetCommand::SendMessage { message_text }) => {
-                log::info!("Net Command: Send Message. Message: {message_text}");
+            NetCommand::SendMessage {
+                room,
+                message_text,
+                reply_to,
+            } => {
+                log::debug!("Net Command: Send Message. Room: {room}.");
 
-                let result = ui_tx.try_send(UICommand::ShowMessage {
-                    id: session_id,
-                    username: session_username.clone(),
+                let message_id = ktp::generate_id();
+                // Nobody else may pass a message of theirs off as ours.
+                self.seen_messages.insert(message_id);
+                self.message_authors
+                    .record(message_id, self.session_id, room.clone());
+                let result = self.ui_tx.try_send(UICommand::ShowMessage {
+                    room: room.clone(),
+                    id: self.session_id,
+                    message_id,
+                    username: self.session_username.clone(),
                     message: message_text.clone(),
+                    reply_to,
                     is_outgoing_message: true,
                 });
                 if let Err(err) = result {
-                    log::error!("{}", err.to_string());
+                    log::error!("{}", err);
                 }
 
-                let result = channel.try_send(Packet::Message {
-                    id: session_id,
+                let peers: Vec<ktp::Id> = self
+                    .members(&room)
+                    .into_iter()
+                    .filter(|id| *id != self.session_id)
+                    .collect();
+                // Peers without acks would never confirm anything.
+                let recipients: HashSet<ktp::Id> = peers
+                    .iter()
+                    .filter(|id| self.peer_supports(id, Capabilities::ACKS))
+                    .copied()
+                    .collect();
+                let message = OutgoingMessage {
+                    room,
                     message_text,
-                });
-                if let Err(err) = result {
-                    log::error!("{}", err.to_string());
-                    send_net_error_to_ui(&ui_tx, err);
+                    reply_to,
+                };
+                if self.capabilities.contains(Capabilities::ACKS)
+                    && (peers.is_empty() || !recipients.is_empty())
+                {
+                    let now = self.clock.now();
+                    self.outbox
+                        .push(message_id, message.clone(), recipients, now);
+                }
+
+                if let Err(err) = self.send_message(message_id, message) {
+                    log::error!("{}", err);
+                    send_net_error_to_ui(&self.ui_tx, err);
                 } else {
                     log::debug!("Net Command: Sent packet!");
                 }
             },
-            Ok(NetCommand::SetInterface { .. }) => {
-                log::error!("{}", NetError::InterfaceAlreadySet.to_string());
+            NetCommand::EditMessage {
+                room,
+                message_id,
+                message_text,
+            } => {
+                log::debug!("Net Command: Edit Message: {message_id:x?}");
+
+                if !self.can_change(&message_id, &room) {
+                    return true;
+                }
+                let _ = self.ui_tx.try_send(UICommand::EditMessage {
+                    message_id,
+                    message_text: message_text.clone(),
+                });
+
+                let packet = self.identity.sign(Packet::EditMessage {
+                    id: self.session_id,
+                    counter: self.sequencer.next_counter(),
+                    room,
+                    message_id,
+                    message_text,
+                    signature: [0; 64],
+                });
+                if let Err(err) = self.channel.try_send(packet) {
+                    log::error!("After sending EditMessage: {}", err);
+                }
+            },
+            NetCommand::DeleteMessage { room, message_id } => {
+                log::info!("Net Command: Delete Message. Id: {message_id:x?}");
+
+                if !self.can_change(&message_id, &room) {
+                    return true;
+                }
+                self.message_authors.forget(&message_id);
+                let _ = self.ui_tx.try_send(UICommand::DeleteMessage { message_id });
+
+                let packet = self.identity.sign(Packet::DeleteMessage {
+                    id: self.session_id,
+                    counter: self.sequencer.next_counter(),
+                    room,
+                    message_id,
+                    signature: [0; 64],
+                });
+                if let Err(err) = self.channel.try_send(packet) {
+                    log::error!("After sending DeleteMessage: {}", err);
+                }
+            },
+            NetCommand::RetryFailedMessages => {
+                log::info!("Net Command: Retry Failed Messages.");
+
+                let now = self.clock.now();
+                for (message_id, message) in self.outbox.retry_failed(now) {
+                    let _ = self.ui_tx.try_send(UICommand::UpdateDeliveryState {
+                        message_id,
+                        state: DeliveryState::Sending,
+                    });
+                    if let Err(err) = self.send_message(message_id, message) {
+                        log::error!("{}", err);
+                    }
+                }
+            },
+            NetCommand::SendDirectMessage {
+                target,
+                message_text,
+            } => {
+                log::debug!("Net Command: Send Direct Message. Target: {target:x?}");
+
+                if !self.capabilities.contains(Capabilities::DIRECT_MESSAGES)
+                    || !self.peer_supports(&target, Capabilities::DIRECT_MESSAGES)
+                {
+                    log::warn!("{}", NetError::PeerTooOld);
+                    let _ = self
+                        .ui_tx
+                        .try_send(UICommand::SendNetWarning(NetError::PeerTooOld));
+                    return true;
+                }
+
+                // There is no loopback for direct messages, so they are shown
+                // right away.
+                let result = self.ui_tx.try_send(UICommand::ShowDirectMessage {
+                    peer: target,
+                    peer_username: self.username_of(&target),
+                    id: self.session_id,
+                    username: self.session_username.clone(),
+                    message: message_text.clone(),
+                });
+                if let Err(err) = result {
+                    log::error!("{}", err);
+                }
 
-                send_net_error_to_ui(&ui_tx, NetError::InterfaceAlreadySet)
+                let now = self.clock.now();
+                match self.direct_sessions.send(
+                    target,
+                    message_text,
+                    &mut self.sequencer,
+                    now,
+                ) {
+                    Ok(packets) => self.send_direct_packets(packets),
+                    Err(err) => {
+                        log::error!("{}", err);
+                        let _ = self.ui_tx.try_send(UICommand::SendNetWarning(err));
+                    },
+                }
             },
-            Ok(NetCommand::SetEtherType(ether_type)) => {
+            NetCommand::SendFile { target, path } => {
+                log::info!("Net Command: Send File. Path: {}", path.display());
+
+                if !self.capabilities.contains(Capabilities::FILE_TRANSFER)
+                    || !self.peer_supports(&target, Capabilities::FILE_TRANSFER)
+                {
+                    log::warn!("{}", NetError::PeerTooOldForFiles);
+                    let _ = self.ui_tx.try_send(UICommand::SendNetWarning(
+                        NetError::PeerTooOldForFiles,
+                    ));
+                    return true;
+                }
+
+                let now = self.clock.now();
+                match self
+                    .transfers
+                    .offer(target, &path, &mut self.sequencer, now)
+                {
+                    Ok((offer, packet)) => {
+                        let _ = self.ui_tx.try_send(UICommand::ShowTransfer {
+                            transfer_id: offer.transfer_id,
+                            peer_username: self.username_of(&target),
+                            name: offer.name,
+                            size: offer.size,
+                            is_outgoing: true,
+                        });
+                        self.send_transfer_packets(vec![packet]);
+                    },
+                    Err(err) => {
+                        log::error!("{}", err);
+                        let _ = self.ui_tx.try_send(UICommand::SendNetWarning(err));
+                    },
+                }
+            },
+            NetCommand::AcceptFile {
+                transfer_id,
+                directory,
+            } => {
+                log::info!("Net Command: Accept File. Into: {}", directory.display());
+
+                let packets =
+                    self.transfers
+                        .accept(transfer_id, &directory, &mut self.sequencer);
+                self.send_transfer_packets(packets);
+            },
+            NetCommand::CancelTransfer { transfer_id } => {
+                log::info!("Net Command: Cancel Transfer.");
+
+                let packet = self.transfers.cancel(transfer_id, &mut self.sequencer);
+                self.send_transfer_packets(packet.into_iter().collect());
+            },
+            NetCommand::JoinRoom(room) => {
+                log::info!("Net Command: Join Room. Room: {room}");
+
+                if room.is_empty() || self.rooms.contains_key(&room) {
+                    return true;
+                }
+                if room.len() > ktp::MAX_ROOM_NAME_SIZE {
+                    send_net_error_to_ui(&self.ui_tx, NetError::RoomNameTooLong);
+                    return true;
+                }
+                self.rooms.insert(room.clone(), HashMap::new());
+                self.channel.set_rooms(self.rooms.keys().cloned().collect());
+
+                let topic = self.room_directory.topic(&room);
+                if topic.revision > 0 {
+                    self.show_topic(topic);
+                }
+
+                // Otherwise the room is announced along with the initial presence.
+                if self.state == NetThreadState::Ready {
+                    let presence = self.presence(&room, true);
+                    if let Err(err) = self.channel.try_send(presence) {
+                        log::error!("After sending room PresenceInformation: {}", err);
+                    }
+                }
+            },
+            NetCommand::PartRoom(room) => {
+                log::info!("Net Command: Part Room. Room: {room}");
+
+                if self.rooms.remove(&room).is_none() {
+                    return true;
+                }
+                self.channel.set_rooms(self.rooms.keys().cloned().collect());
+                // Our own disconnect isn't received anymore.
+                self.room_directory.leave(&room, &self.session_id);
+                self.send_room_listings();
+
+                let disconnect = self.identity.sign(Packet::Disconnect {
+                    id: self.session_id,
+                    counter: self.sequencer.next_counter(),
+                    room,
+                    signature: [0; 64],
+                });
+                if let Err(err) = self.channel.try_send(disconnect) {
+                    log::error!("After sending room Disconnect: {}", err);
+                }
+            },
+            NetCommand::SetTyping(room) => {
+                log::debug!("Net Command: Set Typing. Room: {room:?}");
+                self.typing.set_own(room);
+            },
+            NetCommand::SetTopic { room, topic } => {
+                log::debug!("Net Command: Set Topic. Room: {room}.");
+
+                if !self.rooms.contains_key(&room) {
+                    return true;
+                }
+                let topic = self.room_directory.set_topic(&room, topic, self.session_id);
+                self.show_topic(topic);
+                self.announce_topics(vec![room]);
+                self.send_room_listings();
+            },
+            NetCommand::SetInterface { .. } => {
+                log::error!("{}", NetError::InterfaceAlreadySet);
+
+                send_net_error_to_ui(&self.ui_tx, NetError::InterfaceAlreadySet)
+            },
+            NetCommand::SetArpFraming(arp_framing) => {
+                log::info!("Net Command: Set ARP Framing. Set {}", arp_framing);
+
+                self.channel.set_arp_framing(arp_framing);
+            },
+            NetCommand::SetCarrier(carrier) => {
+                log::info!("Net Command: Set Carrier. Set {}", carrier);
+
+                self.channel.set_carrier(carrier);
+            },
+            NetCommand::SetEtherType(ether_type) => {
                 log::info!("Net Command: Set EtherType. Set {}", ether_type);
 
-                channel.set_ether_type(ether_type);
+                self.channel.set_ether_type(ether_type);
             },
-            Ok(NetCommand::Terminate) => {
+            NetCommand::SetRoomPassphrase(passphrase) => {
+                log::info!(
+                    "Net Command: Set Room Passphrase. Encrypted: {}",
+                    passphrase.is_some()
+                );
+
+                match passphrase.as_deref().map(RoomKey::derive).transpose() {
+                    Ok(room_key) => {
+                        self.channel.set_room_key(room_key);
+
+                        // Peers of the new room are unknown yet.
+                        if self.state != NetThreadState::NeedsUsername {
+                            if let Err(err) =
+                                self.channel.try_send(Packet::PresenceBroadcastRequest)
+                            {
+                                log::error!("{}", err);
+                            }
+                        }
+                    },
+                    Err(err) => {
+                        log::error!("{}", err);
+                        let _ = self.ui_tx.try_send(UICommand::SendNetWarning(err));
+                    },
+                }
+            },
+            NetCommand::Terminate => {
                 log::info!("Net Command: Terminate.");
 
-                let _ = channel.try_send(Packet::Disconnect(session_id));
-                break;
+                let _ = self
+                    .channel
+                    .try_send(self.identity.sign(Packet::Disconnect {
+                        id: self.session_id,
+                        counter: self.sequencer.next_counter(),
+                        room: String::new(),
+                        signature: [0; 64],
+                    }));
+                return false;
             },
-            Ok(NetCommand::UpdateUsername(new_username)) => {
+            NetCommand::UpdateUsername(new_username) => {
                 log::info!("Net Command: Update username: {new_username}");
 
-                session_username = new_username;
+                self.session_username = new_username;
 
-                if state == NetThreadState::NeedsUsername {
-                    if let Err(err) = channel.try_send(Packet::PresenceBroadcastRequest) {
-                        log::error!("{}", err.to_string());
+                if self.state == NetThreadState::NeedsUsername {
+                    if let Err(err) =
+                        self.channel.try_send(Packet::PresenceBroadcastRequest)
+                    {
+                        log::error!("{}", err);
                     }
-                    state = NeedsInitialPresence;
+                    self.state = NeedsInitialPresence;
                 }
             },
-            Err(_) => {},
         }
 
-        let result_recv_packet = channel.try_recv();
-        let packet = match result_recv_packet {
-            Ok(value) => value,
-            Err(err) => {
-                log::error!("Channel recv error: {}", err.to_string());
-                continue;
-            },
-        };
+        true
+    }
+
+    fn handle_packet(&mut self, packet: Packet) {
+        // Only a sender that proved its id may change its messages.
+        let mut is_verified = false;
+        if let Some(sender) = packet.sender() {
+            let verification = identity::verify(&packet, self.keys.get(&sender));
+
+            if verification == Verification::Forged {
+                log::warn!("Dropped forged packet with tag {}.", packet.tag());
+                let _ = self.ui_tx.try_send(UICommand::ShowForgeryWarning {
+                    id: sender,
+                    username: self.username_of(&sender),
+                });
+                return;
+            }
+
+            // Reassembly drops duplicate frames, this drops packets recorded and
+            // sent again. Only authenticated counters may move the window.
+            if verification == Verification::Verified && !self.accept_counter(&packet) {
+                return;
+            }
+            is_verified = verification == Verification::Verified;
+            // Anybody could claim the id, only its owner decides where it's reached.
+            if is_verified {
+                self.channel.learn_peer(&sender);
+            }
+        }
+
         match packet {
-            None => {},
-            Some(Packet::Message { id, message_text }) => {
+            Packet::Message {
+                id,
+                room,
+                message_id,
+                reply_to,
+                message_text,
+                ..
+            } => {
                 log::debug!("Channel: Message Packet received.");
 
-                let username = match online.get(&id) {
-                    Some((_, username)) => username.clone(),
-                    None => session_settings::UNKNOWN_USERNAME.to_string(),
-                };
+                // Our own broadcast coming back.
+                if id == self.session_id {
+                    return;
+                }
+
+                if self.capabilities.contains(Capabilities::ACKS)
+                    && self.peer_supports(&id, Capabilities::ACKS)
+                {
+                    let ack = self.identity.sign(Packet::Ack {
+                        id: self.session_id,
+                        counter: self.sequencer.next_counter(),
+                        target: id,
+                        message_id,
+                        signature: [0; 64],
+                    });
+                    if let Err(err) = self.channel.try_send(ack) {
+                        log::error!("After sending Ack: {}", err);
+                    }
+                }
+
+                if !self.seen_messages.insert(message_id) {
+                    log::debug!("Dropped retransmitted message.");
+                    return;
+                }
+
+                if !self.message_authors.record(message_id, id, room.clone()) {
+                    log::warn!("Dropped message with the id of another one.");
+                    return;
+                }
+                if self.typing.stop(&room, &id) {
+                    self.send_typing(room.clone());
+                }
+                let username = self.username_of(&id);
 
                 // Alerting user if there's username in message
-                if id != session_id && message_text.contains(&session_username) {
-                    let _ = ui_tx.try_send(UICommand::AlertUser);
+                if message_text.contains(&self.session_username) {
+                    let _ = self.ui_tx.try_send(UICommand::AlertUser);
                 }
 
-                let _ = ui_tx.try_send(UICommand::ShowMessage {
+                let _ = self.ui_tx.try_send(UICommand::ShowMessage {
+                    room,
                     id,
+                    message_id,
                     username,
                     message: message_text,
+                    reply_to,
                     is_outgoing_message: false,
                 });
             },
-            Some(Packet::PresenceBroadcastRequest) => {
-                log::debug!("Channel: Presence Broadcast Request received.");
+            Packet::DirectMessage {
+                id,
+                counter,
+                target,
+                ciphertext,
+            } => {
+                log::debug!("Channel: Direct Message Packet received.");
+
+                if target != self.session_id {
+                    return;
+                }
+
+                let Some(message_text) =
+                    self.direct_sessions.open(id, counter, &ciphertext)
+                else {
+                    log::warn!("Dropped direct message without a session key.");
+                    return;
+                };
+                // The counter is sealed with the text, so it can be trusted now.
+                if !self.replay_guard.accept(id, counter) {
+                    log::debug!("Dropped replayed direct message.");
+                    return;
+                }
+
+                let username = self.username_of(&id);
+                let _ = self.ui_tx.try_send(UICommand::AlertUser);
+                let _ = self.ui_tx.try_send(UICommand::ShowDirectMessage {
+                    peer: id,
+                    peer_username: username.clone(),
+                    id,
+                    username,
+                    message: message_text,
+                });
+            },
+            Packet::KeyExchange {
+                id,
+                target,
+                is_reply,
+                public_key,
+                ..
+            } => {
+                log::debug!("Channel: Key Exchange Packet received. Reply: {is_reply}");
+
+                if target != self.session_id {
+                    return;
+                }
+                // The id is derived from the key the signature is checked against,
+                // so an unverified handshake may come from anybody in the middle.
+                let Some(peer_key) = self.keys.get(&id).filter(|_| is_verified) else {
+                    log::warn!("Dropped key exchange that isn't signed by {id:x?}.");
+                    return;
+                };
+                let fingerprint = identity::fingerprint(peer_key);
 
-                let is_user_joining = state == NeedsInitialPresence;
-                let packet = Packet::PresenceInformation {
-                    id: session_id,
-                    is_join: is_user_joining,
-                    username: session_username.clone(),
+                let now = self.clock.now();
+                let exchange = match self.direct_sessions.receive_key_exchange(
+                    id,
+                    is_reply,
+                    public_key,
+                    &mut self.sequencer,
+                    now,
+                ) {
+                    Ok(exchange) => exchange,
+                    Err(err) => {
+                        log::error!("{}", err);
+                        return;
+                    },
                 };
+                self.send_direct_packets(exchange.packets);
 
-                if let Err(e) = channel.try_send(packet) {
-                    log::error!("After sending PresenceInformation: {}", e.to_string());
+                if exchange.is_established {
+                    let _ = self.ui_tx.try_send(UICommand::ShowFingerprint {
+                        peer: id,
+                        peer_username: self.username_of(&id),
+                        fingerprint,
+                    });
                 }
             },
-            Some(Packet::PresenceInformation {
+            Packet::Ack {
+                id,
+                target,
+                message_id,
+                ..
+            } => {
+                log::debug!("Channel: Ack Packet received.");
+
+                if target != self.session_id {
+                    return;
+                }
+                // Anybody could claim the message was delivered.
+                if !is_verified {
+                    log::warn!("Dropped acknowledgement from an unverified peer.");
+                    return;
+                }
+
+                if let Some(state) = self.outbox.acknowledge(&message_id, id) {
+                    let _ = self
+                        .ui_tx
+                        .try_send(UICommand::UpdateDeliveryState { message_id, state });
+                }
+            },
+            Packet::PresenceBroadcastRequest => {
+                log::debug!("Channel: Presence Broadcast Request received.");
+
+                let is_user_joining = self.state == NeedsInitialPresence;
+                let packet = self.presence("", is_user_joining);
+
+                if let Err(e) = self.channel.try_send(packet) {
+                    log::error!("After sending PresenceInformation: {}", e);
+                }
+            },
+            Packet::PresenceInformation {
+                id,
+                room,
+                is_join,
+                username,
+                public_key,
+                ..
+            } if !room.is_empty() => {
+                log::debug!("Channel: Room Presence Information packet received.");
+
+                self.handle_room_presence(id, room, is_join, username, public_key);
+            },
+            Packet::PresenceInformation {
                 id: some_id,
                 is_join,
+                version,
+                capabilities,
                 username,
-            }) => {
+                public_key,
+                ..
+            } => {
                 log::debug!("Channel: Presence Information packet received.");
 
-                match online.insert(some_id, (Instant::now(), username.clone())) {
+                // Older versions announce no extended frames, whatever their bits say.
+                let capabilities = match version < ktp::EXTENDED_FRAMES_VERSION {
+                    true => capabilities.difference(Capabilities::LARGE_PACKETS),
+                    false => capabilities,
+                };
+                self.keys.insert(some_id, public_key);
+                self.peer_capabilities.insert(some_id, capabilities);
+                let sighting = self.record_contact(some_id, &public_key, &username);
+                self.check_version(some_id, version, capabilities, &username);
+
+                match self
+                    .online
+                    .insert(some_id, (self.clock.now(), username.clone()))
+                {
                     Some((_, previous_username)) => {
-                        if let Err(err) = ui_tx.try_send(UICommand::PresenceUpdate {
+                        if previous_username != username {
+                            self.release_username(&some_id, &previous_username);
+                        }
+                        let trust = self.trust_of(some_id, &username, sighting);
+
+                        if let Err(err) = self.ui_tx.try_send(UICommand::PresenceUpdate {
+                            room: String::new(),
                             id: some_id,
                             username,
                             is_inactive: false,
+                            trust,
                             kind: UpdatePresenceKind::UsernameChange {
                                 previous_username,
                             },
                         }) {
-                            log::error!(
-                                "After sending PresenceUpdate packet: {}",
-                                err.to_string()
-                            );
+                            log::error!("After sending PresenceUpdate packet: {}", err);
                         }
                     },
                     None => {
-                        if let Err(err) = ui_tx.try_send(UICommand::PresenceUpdate {
+                        let trust = self.trust_of(some_id, &username, sighting);
+
+                        if let Err(err) = self.ui_tx.try_send(UICommand::PresenceUpdate {
+                            room: String::new(),
                             id: some_id,
                             username,
                             is_inactive: false,
-                            kind: if offline.remove(&some_id) || is_join {
+                            trust,
+                            kind: if self.offline.remove(&some_id) || is_join {
                                 UpdatePresenceKind::JoinOrReconnect
                             } else {
                                 UpdatePresenceKind::Boring
                             },
                         }) {
-                            log::error!(
-                                "After sending PresenceUpdate packet: {}",
-                                err.to_string()
-                            );
+                            log::error!("After sending PresenceUpdate packet: {}", err);
                         }
                     },
                 }
+                self.update_peer_capabilities();
+
+                if some_id == self.session_id && self.state != NetThreadState::Ready {
+                    self.state = NetThreadState::Ready;
+                    self.announce_rooms(true);
+                }
+            },
+            Packet::RoomAnnounce { id, rooms, .. } => {
+                log::debug!("Channel: Room Announce packet received.");
+
+                let now = self.clock.now();
+                for announced in rooms {
+                    let Some(topic) =
+                        self.room_directory.record(id, announced, is_verified, now)
+                    else {
+                        continue;
+                    };
+                    if self.rooms.contains_key(&topic.room) {
+                        self.show_topic(topic);
+                    }
+                }
+                self.send_room_listings();
+            },
+            Packet::EditMessage {
+                id,
+                room,
+                message_id,
+                message_text,
+                ..
+            } => {
+                log::debug!("Channel: Edit Message packet received.");
+
+                // Our own edits are shown right away.
+                if id == self.session_id {
+                    return;
+                }
+                if !is_verified
+                    || !self.message_authors.is_author(&message_id, &id, &room)
+                {
+                    log::warn!("Dropped edit of a message {id:x?} didn't send.");
+                    return;
+                }
+
+                let _ = self.ui_tx.try_send(UICommand::EditMessage {
+                    message_id,
+                    message_text,
+                });
+            },
+            Packet::DeleteMessage {
+                id,
+                room,
+                message_id,
+                ..
+            } => {
+                log::debug!("Channel: Delete Message packet received.");
+
+                if id == self.session_id {
+                    return;
+                }
+                if !is_verified
+                    || !self.message_authors.is_author(&message_id, &id, &room)
+                {
+                    log::warn!("Dropped deletion of a message {id:x?} didn't send.");
+                    return;
+                }
+
+                self.message_authors.forget(&message_id);
+                let _ = self.ui_tx.try_send(UICommand::DeleteMessage { message_id });
+            },
+            Packet::Typing { id, room, .. } => {
+                log::trace!("Channel: Typing packet received.");
+
+                if id == self.session_id
+                    || !is_verified
+                    || (!room.is_empty() && !self.rooms.contains_key(&room))
+                {
+                    return;
+                }
+                let now = self.clock.now();
+                if self.typing.record(room.clone(), id, now) {
+                    self.send_typing(room);
+                }
+            },
+            // Answered by the transport.
+            Packet::Nack { .. } => {},
+            Packet::FileOffer {
+                id,
+                target,
+                transfer_id,
+                size,
+                hash,
+                name,
+                ..
+            } => {
+                log::debug!("Channel: File Offer Packet received.");
+
+                if target != self.session_id {
+                    return;
+                }
+                // Anybody could alert the user with offers under any name.
+                if !is_verified {
+                    log::warn!("Dropped file offer from an unverified peer.");
+                    return;
+                }
+
+                let (offer, packets) = self.transfers.receive_offer(
+                    id,
+                    transfer_id,
+                    &name,
+                    size,
+                    hash,
+                    &mut self.sequencer,
+                );
+                self.send_transfer_packets(packets);
+                if let Some(offer) = offer {
+                    let _ = self.ui_tx.try_send(UICommand::AlertUser);
+                    let _ = self.ui_tx.try_send(UICommand::ShowTransfer {
+                        transfer_id,
+                        peer_username: self.username_of(&id),
+                        name: offer.name,
+                        size: offer.size,
+                        is_outgoing: false,
+                    });
+                }
+            },
+            Packet::FileAccept {
+                id,
+                target,
+                transfer_id,
+                offset,
+                ..
+            } => {
+                if target != self.session_id {
+                    return;
+                }
+
+                let now = self.clock.now();
+                self.transfers.receive_accept(id, transfer_id, offset, now);
+                self.send_transfer_packets(vec![]);
+            },
+            Packet::FileChunk {
+                id,
+                target,
+                transfer_id,
+                offset,
+                data,
+                ..
+            } => {
+                if target != self.session_id {
+                    return;
+                }
+                // Chunks aren't signed, the address they came from is all there is.
+                if !self.channel.is_from_peer(&id) {
+                    log::warn!("Dropped file chunk from an unverified address.");
+                    return;
+                }
+
+                let packets = self.transfers.receive_chunk(
+                    id,
+                    transfer_id,
+                    offset,
+                    &data,
+                    &mut self.sequencer,
+                );
+                self.send_transfer_packets(packets);
+            },
+            Packet::FileComplete {
+                id,
+                target,
+                transfer_id,
+                ..
+            } => {
+                log::debug!("Channel: File Complete Packet received.");
+
+                if target != self.session_id {
+                    return;
+                }
 
-                if some_id == session_id {
-                    state = NetThreadState::Ready;
+                self.transfers.receive_complete(id, transfer_id);
+                self.send_transfer_packets(vec![]);
+            },
+            Packet::FileCancel {
+                id,
+                target,
+                transfer_id,
+                ..
+            } => {
+                log::debug!("Channel: File Cancel Packet received.");
+
+                if target != self.session_id {
+                    return;
+                }
+
+                self.transfers.receive_cancel(id, transfer_id);
+                self.send_transfer_packets(vec![]);
+            },
+            Packet::Disconnect { id, room, .. } if !room.is_empty() => {
+                log::debug!("Channel: Room Disconnection packet received.");
+
+                self.room_directory.leave(&room, &id);
+                self.send_room_listings();
+                let is_member = self
+                    .rooms
+                    .get_mut(&room)
+                    .is_some_and(|members| members.remove(&id).is_some());
+                if is_member {
+                    let _ = self.ui_tx.try_send(UICommand::RemovePresence {
+                        room,
+                        id,
+                        username: self.username_of(&id),
+                    });
                 }
             },
-            Some(Packet::Disconnect(some_id)) => {
+            Packet::Disconnect { id: some_id, .. } => {
                 log::debug!("Channel: Disconnection packet received.");
 
-                if let Some((_, username)) = online.remove(&some_id) {
-                    if let Err(err) = ui_tx.try_send(UICommand::RemovePresence {
+                self.forget_peer(&some_id);
+                self.keys.remove(&some_id);
+
+                if let Some((_, username)) = self.online.remove(&some_id) {
+                    if let Err(err) = self.ui_tx.try_send(UICommand::RemovePresence {
+                        room: String::new(),
                         id: some_id,
                         username,
                     }) {
-                        log::error!(
-                            "After sending Disconnect packet: {}",
-                            err.to_string()
-                        );
+                        log::error!("After sending Disconnect packet: {}", err);
                     }
                 }
+                self.update_peer_capabilities();
+                self.send_transfer_packets(vec![]);
+            },
+        }
+    }
+
+    // Every transmission is signed anew, so it passes the replay guard.
+    // Peers without replies would drop them, so they get the bare message.
+    fn send_message(
+        &mut self, message_id: ktp::Id, message: OutgoingMessage,
+    ) -> Result<(), NetError> {
+        let common = self.common_capabilities();
+        let compression = if common.contains(Capabilities::SMAZ) {
+            Compression::Smaz
+        } else {
+            Compression::None
+        };
+        let packet = self.identity.sign(Packet::Message {
+            id: self.session_id,
+            counter: self.sequencer.next_counter(),
+            room: message.room,
+            message_id,
+            reply_to: message
+                .reply_to
+                .filter(|_| common.contains(Capabilities::REPLIES)),
+            compression,
+            message_text: message.message_text,
+            signature: [0; 64],
+        });
+
+        self.channel.try_send(packet)
+    }
+
+    fn show_send_progress(&mut self, progress: SendProgress) {
+        self.outbox.postpone(&progress.message_id, self.clock.now());
+
+        let state = if progress.sent < progress.total {
+            DeliveryState::Transmitting {
+                percent: (progress.sent * 100 / progress.total) as u8,
+            }
+        } else {
+            DeliveryState::Sending
+        };
+        let _ = self.ui_tx.try_send(UICommand::UpdateDeliveryState {
+            message_id: progress.message_id,
+            state,
+        });
+    }
+
+    fn retransmit(&mut self) {
+        let (resend, failed) = self.outbox.due(self.clock.now());
+
+        for (message_id, message) in resend {
+            log::debug!("Retransmitting message {message_id:x?}.");
+            if let Err(err) = self.send_message(message_id, message) {
+                log::error!("{}", err);
+            }
+        }
+        for message_id in failed {
+            let _ = self.ui_tx.try_send(UICommand::UpdateDeliveryState {
+                message_id,
+                state: DeliveryState::Failed,
+            });
+        }
+    }
+
+    fn accept_counter(&mut self, packet: &Packet) -> bool {
+        let (Some(sender), Some(counter)) = (packet.sender(), packet.counter()) else {
+            return true;
+        };
+
+        let is_accepted = self.replay_guard.accept(sender, counter);
+        if !is_accepted {
+            log::debug!("Dropped replayed packet with tag {}.", packet.tag());
+        }
+        is_accepted
+    }
+
+    // Stops at the first error, the peer can't be reached anyway.
+    // Key exchanges are signed, messages are sealed with the session key instead.
+    fn send_direct_packets(&mut self, packets: Vec<Packet>) {
+        for packet in packets {
+            let packet = match packet.signature() {
+                Some(_) => self.identity.sign(packet),
+                None => packet,
+            };
+            if let Err(err) = self.channel.try_send(packet) {
+                log::error!("{}", err);
+                let _ = self.ui_tx.try_send(UICommand::SendNetWarning(err));
+                return;
+            }
+        }
+    }
+
+    // Control packets are signed, chunks are checked against the hash of the offer.
+    // Shows whatever the packets changed about the transfers too.
+    fn send_transfer_packets(&mut self, packets: Vec<Packet>) {
+        for packet in packets {
+            let packet = match packet.signature() {
+                Some(_) => self.identity.sign(packet),
+                None => packet,
+            };
+            if let Err(err) = self.channel.try_send(packet) {
+                log::error!("After sending file transfer packet: {}", err);
+            }
+        }
+
+        for (transfer_id, state) in self.transfers.take_updates() {
+            let _ = self
+                .ui_tx
+                .try_send(UICommand::UpdateTransfer { transfer_id, state });
+        }
+    }
+
+    fn presence(&mut self, room: &str, is_join: bool) -> Packet {
+        self.identity.sign(Packet::PresenceInformation {
+            id: self.session_id,
+            counter: self.sequencer.next_counter(),
+            room: room.to_string(),
+            is_join,
+            version: ktp::PROTOCOL_VERSION,
+            capabilities: self.capabilities,
+            username: self.session_username.clone(),
+            public_key: self.identity.public_key(),
+            signature: [0; 64],
+        })
+    }
+
+    // Members of a room learn about the newcomer from its join,
+    // and the newcomer learns about them from their answers.
+    fn handle_room_presence(
+        &mut self, id: ktp::Id, room: String, is_join: bool, username: String,
+        public_key: ktp::PublicKey,
+    ) {
+        let now = self.clock.now();
+        let Some(members) = self.rooms.get_mut(&room) else {
+            return;
+        };
+        let is_new = members.insert(id, now).is_none();
+
+        // The newcomer gets the topic without waiting for the next heartbeat.
+        if is_join && id != self.session_id {
+            let presence = self.presence(&room, false);
+            if let Err(err) = self.channel.try_send(presence) {
+                log::error!("After sending room PresenceInformation: {}", err);
+            }
+            self.announce_topics(vec![room.clone()]);
+        }
+
+        let sighting = self.record_contact(id, &public_key, &username);
+        let trust = self.trust_of(id, &username, sighting);
+        let _ = self.ui_tx.try_send(UICommand::PresenceUpdate {
+            room,
+            id,
+            username,
+            is_inactive: false,
+            trust,
+            kind: match is_new && is_join {
+                true => UpdatePresenceKind::JoinOrReconnect,
+                false => UpdatePresenceKind::Boring,
             },
+        });
+    }
+
+    fn announce_rooms(&mut self, is_join: bool) {
+        let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+        for room in rooms {
+            let presence = self.presence(&room, is_join);
+            if let Err(err) = self.channel.try_send(presence) {
+                log::error!("After sending room PresenceInformation: {}", err);
+            }
         }
+    }
+
+    fn announce_topics(&mut self, rooms: Vec<String>) {
+        if rooms.is_empty() {
+            return;
+        }
+
+        let announce = self.identity.sign(Packet::RoomAnnounce {
+            id: self.session_id,
+            counter: self.sequencer.next_counter(),
+            rooms: rooms
+                .iter()
+                .map(|room| self.room_directory.topic(room))
+                .collect(),
+            signature: [0; 64],
+        });
+        if let Err(err) = self.channel.try_send(announce) {
+            log::error!("After sending RoomAnnounce: {}", err);
+        }
+    }
 
-        if last_heartbeat.elapsed() > HEARTBEAT_INTERVAL && state == NetThreadState::Ready
+    fn show_topic(&mut self, topic: RoomTopic) {
+        let username = match topic.setter == self.session_id {
+            true => self.session_username.clone(),
+            false => self.username_of(&topic.setter),
+        };
+        let _ = self.ui_tx.try_send(UICommand::ShowTopic {
+            room: topic.room,
+            topic: topic.topic,
+            username,
+        });
+    }
+
+    // Peers without edits would keep the message as it was.
+    fn can_change(&self, message_id: &ktp::Id, room: &str) -> bool {
+        self.capabilities.contains(Capabilities::EDITS)
+            && self
+                .message_authors
+                .is_author(message_id, &self.session_id, room)
+    }
+
+    // Typing is never worth an alert, only a line under the chat.
+    fn poll_typing(&mut self, now: Instant) {
+        for room in self.typing.expire(now) {
+            self.send_typing(room);
+        }
+
+        if self.state != NetThreadState::Ready
+            || !self.capabilities.contains(Capabilities::TYPING)
         {
-            if !pause_heartbeat {
-                if let Err(err) = channel.try_send(Packet::PresenceInformation {
-                    id: session_id,
-                    is_join: false,
-                    username: session_username.clone(),
-                }) {
-                    log::error!(
-                        "After sending Heartbeat PresenceInformation: {}",
-                        err.to_string()
-                    );
-                } else {
-                    log::debug!("Heartbeat: PresenceInformation packet sent");
-                }
+            return;
+        }
+        if let Some(room) = self.typing.due(now) {
+            let packet = self.identity.sign(Packet::Typing {
+                id: self.session_id,
+                counter: self.sequencer.next_counter(),
+                room,
+                signature: [0; 64],
+            });
+            if let Err(err) = self.channel.try_send(packet) {
+                log::error!("After sending Typing: {}", err);
             }
+        }
+    }
 
-            let mut to_remove = vec![];
-            for (id, (user_last_heartbeat, username)) in online.iter() {
-                if user_last_heartbeat.elapsed() > OFFLINE_TIMEOUT {
-                    offline.insert(*id);
-                    if let Err(err) = ui_tx.try_send(UICommand::RemovePresence {
-                        id: *id,
-                        username: username.clone(),
-                    }) {
-                        log::error!(
-                            "After sending Heartbeat RemovePresence: {}",
-                            err.to_string()
-                        );
-                    }
-                    to_remove.push(*id);
-                } else if last_heartbeat.elapsed() > INACTIVE_TIMEOUT {
-                    if let Err(err) = ui_tx.try_send(UICommand::PresenceUpdate {
+    fn send_typing(&mut self, room: String) {
+        let mut usernames: Vec<String> = self
+            .typing
+            .typing_in(&room)
+            .iter()
+            .map(|id| self.username_of(id))
+            .collect();
+        usernames.sort();
+        let _ = self
+            .ui_tx
+            .try_send(UICommand::UpdateTyping { room, usernames });
+    }
+
+    fn send_room_listings(&mut self) {
+        if let Some(listings) = self.room_directory.take_listings() {
+            let _ = self.ui_tx.try_send(UICommand::UpdateRooms(listings));
+        }
+    }
+
+    // The peer is gone, along with its usernames and the warnings about it.
+    // Its key stays until it leaves for good, or too many keys are kept.
+    fn forget_peer(&mut self, id: &ktp::Id) {
+        self.leave_rooms(id);
+        self.direct_sessions.forget(id);
+        self.outbox.forget_peer(id);
+        self.transfers.forget_peer(id);
+        self.peer_capabilities.remove(id);
+        self.channel.forget_peer(id);
+        self.username_owners.retain(|_, owner| owner != id);
+        self.key_mismatches.remove(id);
+        self.outdated_peers.remove(id);
+    }
+
+    // The peer left the whole chat, so it left every room too.
+    fn leave_rooms(&mut self, id: &ktp::Id) {
+        self.room_directory.forget_peer(id);
+        for (room, members) in self.rooms.iter_mut() {
+            if members.remove(id).is_some() {
+                let _ = self.ui_tx.try_send(UICommand::RemovePresence {
+                    room: room.clone(),
+                    id: *id,
+                    username: username_in(&self.online, id),
+                });
+            }
+        }
+    }
+
+    // Peers that messages of the room are meant for, the main room has everybody.
+    fn members(&self, room: &str) -> Vec<ktp::Id> {
+        match self.rooms.get(room) {
+            Some(members) => members.keys().copied().collect(),
+            None => self.online.keys().copied().collect(),
+        }
+    }
+
+    /// Features that every online peer supports, so broadcasts may use them.
+    fn common_capabilities(&self) -> Capabilities {
+        self.online
+            .keys()
+            .filter_map(|id| self.peer_capabilities.get(id))
+            .fold(self.capabilities, |common, capabilities| {
+                common.intersection(*capabilities)
+            })
+    }
+
+    // Lets the transport use framings that every online peer reads.
+    fn update_peer_capabilities(&mut self) {
+        let common = self.common_capabilities();
+        self.channel.set_peer_capabilities(common);
+    }
+
+    // Peers we haven't heard presence from yet are given the benefit of the doubt.
+    fn peer_supports(&self, id: &ktp::Id, capability: Capabilities) -> bool {
+        self.peer_capabilities
+            .get(id)
+            .is_none_or(|capabilities| capabilities.contains(capability))
+    }
+
+    // Warns once per peer, whose client lacks some of our features.
+    fn check_version(
+        &mut self, id: ktp::Id, version: ktp::Version, capabilities: Capabilities,
+        username: &str,
+    ) {
+        let missing = self.capabilities.difference(capabilities);
+        if id == self.session_id
+            || (version >= ktp::PROTOCOL_VERSION && missing.is_empty())
+            || !self.outdated_peers.insert(id)
+        {
+            return;
+        }
+
+        log::warn!("{username} uses protocol version {version}, missing {missing:?}.");
+        let _ = self.ui_tx.try_send(UICommand::ShowOutdatedPeerWarning {
+            username: username.to_string(),
+            missing,
+        });
+    }
+
+    fn trust_of(
+        &mut self, id: ktp::Id, username: &str, sighting: Option<Sighting>,
+    ) -> Trust {
+        let owner = self
+            .username_owners
+            .entry(username.to_string())
+            .or_insert(id);
+
+        match sighting {
+            _ if *owner != id => Trust::Impersonator,
+            Some(Sighting::KeyMismatch) => Trust::Impersonator,
+            Some(Sighting::Known { is_verified: true }) => Trust::Trusted,
+            _ => Trust::Verified,
+        }
+    }
+
+    /// Returns `None` for our own presence, or if the book is busy.
+    fn record_contact(
+        &mut self, id: ktp::Id, public_key: &ktp::PublicKey, username: &str,
+    ) -> Option<Sighting> {
+        if id == self.session_id {
+            return None;
+        }
+
+        let mac = self.channel.peer_address(&id);
+        let now = chrono::Utc::now().timestamp();
+        let sighting = self
+            .contacts
+            .try_lock()
+            .ok()?
+            .record(public_key, username, mac, now);
+
+        if sighting == Sighting::KeyMismatch && self.key_mismatches.insert(id) {
+            log::warn!("Known username {username} came with a different key.");
+            let _ = self.ui_tx.try_send(UICommand::AlertUser);
+            let _ = self.ui_tx.try_send(UICommand::ShowKeyMismatchWarning {
+                username: username.to_string(),
+            });
+        }
+
+        Some(sighting)
+    }
+
+    fn release_username(&mut self, id: &ktp::Id, username: &str) {
+        if self.username_owners.get(username) == Some(id) {
+            self.username_owners.remove(username);
+        }
+    }
+
+    fn username_of(&self, id: &ktp::Id) -> String {
+        username_in(&self.online, id)
+    }
+
+    fn heartbeat(&mut self) {
+        if !self.pause_heartbeat {
+            let presence = self.presence("", false);
+            if let Err(err) = self.channel.try_send(presence) {
+                log::error!("After sending Heartbeat PresenceInformation: {}", err);
+            } else {
+                log::debug!("Heartbeat: PresenceInformation packet sent");
+            }
+            self.announce_rooms(false);
+            self.announce_topics(self.rooms.keys().cloned().collect());
+        }
+
+        let now = self.clock.now();
+        self.room_directory.expire(now);
+        for (room, members) in self.rooms.iter_mut() {
+            members.retain(|id, last_heartbeat| {
+                let is_online = now.duration_since(*last_heartbeat) <= OFFLINE_TIMEOUT;
+                if !is_online {
+                    let _ = self.ui_tx.try_send(UICommand::RemovePresence {
+                        room: room.clone(),
                         id: *id,
-                        username: username.clone(),
-                        is_inactive: true,
-                        kind: UpdatePresenceKind::Boring,
-                    }) {
-                        log::error!(
-                            "After sending Heartbeat PresenceUpdate: {}",
-                            err.to_string()
-                        );
-                    }
+                        username: username_in(&self.online, id),
+                    });
+                }
+                is_online
+            });
+        }
+
+        let mut to_remove = vec![];
+        for (id, (user_last_heartbeat, username)) in self.online.iter() {
+            if now.duration_since(*user_last_heartbeat) > OFFLINE_TIMEOUT {
+                self.offline.insert(*id);
+                if let Err(err) = self.ui_tx.try_send(UICommand::RemovePresence {
+                    room: String::new(),
+                    id: *id,
+                    username: username.clone(),
+                }) {
+                    log::error!("After sending Heartbeat RemovePresence: {}", err);
+                }
+                to_remove.push(*id);
+            } else if now.duration_since(*user_last_heartbeat) > INACTIVE_TIMEOUT {
+                let trust = match self.username_owners.get(username) {
+                    Some(owner) if owner != id => Trust::Impersonator,
+                    _ => Trust::Verified,
+                };
+
+                if let Err(err) = self.ui_tx.try_send(UICommand::PresenceUpdate {
+                    room: String::new(),
+                    id: *id,
+                    username: username.clone(),
+                    is_inactive: true,
+                    trust,
+                    kind: UpdatePresenceKind::Boring,
+                }) {
+                    log::error!("After sending Heartbeat PresenceUpdate: {}", err);
                 }
             }
+        }
+
+        for id in to_remove {
+            self.forget_peer(&id);
+            self.online.remove(&id);
+        }
+        // Keys of peers that timed out still verify them when they come back.
+        if self.keys.len() > MAX_KEPT_KEYS {
+            self.keys.retain(|id, _| self.online.contains_key(id));
+        }
+        self.update_peer_capabilities();
+        self.send_transfer_packets(vec![]);
+        self.send_room_listings();
 
-            for id in to_remove {
-                online.remove(&id);
+        if let Ok(mut contacts) = self.contacts.try_lock() {
+            if let Err(err) = contacts.save_if_dirty() {
+                log::error!("Couldn't save contacts: {err}");
             }
+        }
 
-            last_heartbeat = Instant::now();
+        let (packets, failed) = self.direct_sessions.retry(&mut self.sequencer, now);
+        self.send_direct_packets(packets);
+        for _ in failed {
+            let _ = self
+                .ui_tx
+                .try_send(UICommand::SendNetWarning(NetError::KeyExchangeFailed));
         }
+
+        self.last_heartbeat = now;
+    }
+}
+
+fn username_in(online: &HashMap<ktp::Id, (Instant, String)>, id: &ktp::Id) -> String {
+    match online.get(id) {
+        Some((_, username)) => username.clone(),
+        None => session_settings::UNKNOWN_USERNAME.to_string(),
     }
 }
 
@@ -0,0 +1,169 @@
+use crate::error::net::NetError;
+use crate::net::ktp;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Everybody in the room derives the same key from the passphrase,
+// so the salt can't be random.
+const ROOM_KEY_SALT: &[u8] = b"arpchat-rs room key";
+
+// Mixed into direct session keys, so they never collide with anything else.
+const SESSION_KEY_CONTEXT: &[u8] = b"arpchat-rs direct session";
+
+const NONCE_SIZE: usize = 12;
+
+/// Key of a room protected by a pre-shared passphrase. <br>
+/// Payloads are sealed with ChaCha20-Poly1305, the packet tag is authenticated too.
+#[derive(Clone)]
+pub struct RoomKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl RoomKey {
+    pub fn derive(passphrase: &str) -> Result<Self, NetError> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), ROOM_KEY_SALT, &mut key)
+            .map_err(|_| NetError::KeyDerivationFailed)?;
+
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Returns the nonce followed by the ciphertext.
+    pub fn seal(&self, tag: ktp::Tag, plaintext: &[u8]) -> Result<Vec<u8>, NetError> {
+        seal(&self.cipher, &[tag], plaintext)
+    }
+
+    /// Returns `None` if the data wasn't sealed with this key or was tampered with.
+    pub fn open(&self, tag: ktp::Tag, data: &[u8]) -> Option<Vec<u8>> {
+        open(&self.cipher, &[tag], data)
+    }
+}
+
+/// Ephemeral X25519 key pair of one side of a direct session handshake.
+pub struct KeyPair {
+    secret: StaticSecret,
+    pub public: [u8; 32],
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let public = PublicKey::from(&secret).to_bytes();
+
+        Self { secret, public }
+    }
+
+    /// Agrees on a session key with the other side. <br>
+    /// Returns `None` for low order public keys, which would give away the key.
+    pub fn agree(&self, their_public: &[u8; 32]) -> Option<SessionKey> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*their_public));
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        // Both sides have to hash the public keys in the same order.
+        let (first, second) = match self.public < *their_public {
+            true => (&self.public, their_public),
+            false => (their_public, &self.public),
+        };
+        let key = Sha256::new()
+            .chain_update(SESSION_KEY_CONTEXT)
+            .chain_update(shared.as_bytes())
+            .chain_update(first)
+            .chain_update(second)
+            .finalize();
+
+        Some(SessionKey {
+            cipher: ChaCha20Poly1305::new(&key),
+        })
+    }
+}
+
+/// Key of an end-to-end encrypted direct session between two peers. <br>
+/// It changes with every rotation, users compare the identity keys instead.
+pub struct SessionKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SessionKey {
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, NetError> {
+        seal(&self.cipher, aad, plaintext)
+    }
+
+    pub fn open(&self, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        open(&self.cipher, aad, data)
+    }
+}
+
+fn seal(
+    cipher: &ChaCha20Poly1305, aad: &[u8], plaintext: &[u8],
+) -> Result<Vec<u8>, NetError> {
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| NetError::EncryptionFailed)?;
+
+    Ok([&nonce, ciphertext.as_slice()].concat())
+}
+
+fn open(cipher: &ChaCha20Poly1305, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < NONCE_SIZE {
+        return None;
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    let payload = Payload {
+        msg: ciphertext,
+        aad,
+    };
+    cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tampered_data_is_rejected() {
+        let key = RoomKey::derive("correct horse").unwrap();
+        let mut sealed = key.seal(0, b"battery staple").unwrap();
+        assert_eq!(key.open(0, &sealed).unwrap(), b"battery staple");
+
+        assert!(key.open(2, &sealed).is_none());
+        assert!(
+            RoomKey::derive("wrong horse")
+                .unwrap()
+                .open(0, &sealed)
+                .is_none()
+        );
+
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(key.open(0, &sealed).is_none());
+    }
+
+    #[test]
+    fn both_sides_agree_on_session_key() {
+        let (alice, bob) = (KeyPair::generate(), KeyPair::generate());
+        let alice_key = alice.agree(&bob.public).unwrap();
+        let bob_key = bob.agree(&alice.public).unwrap();
+
+        let sealed = alice_key.seal(b"aad", b"hello").unwrap();
+        assert_eq!(bob_key.open(b"aad", &sealed).unwrap(), b"hello");
+
+        let mallory = KeyPair::generate();
+        let mallory_key = mallory.agree(&bob.public).unwrap();
+        assert!(mallory_key.open(b"aad", &sealed).is_none());
+        assert!(alice.agree(&[0; 32]).is_none());
+    }
+}
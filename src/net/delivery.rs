@@ -0,0 +1,215 @@
+use crate::net::ktp;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// Waiting time before the first retransmission, doubled after every next one.
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+pub const MAX_TRANSMISSIONS: u32 = 4;
+
+/// What the user sees next to an outgoing message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeliveryState {
+    /// Frames of a large message are still going out.
+    Transmitting {
+        percent: u8,
+    },
+    Sending,
+    Delivered {
+        peers: usize,
+    },
+    /// Nobody confirmed the message, it can be retried.
+    Failed,
+}
+
+/// What is sent again on every retransmission.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutgoingMessage {
+    /// Empty for the main room.
+    pub room: String,
+    pub message_text: String,
+    pub reply_to: Option<ktp::Id>,
+}
+
+struct Pending {
+    message: OutgoingMessage,
+    // Online peers at the moment of sending, only their acknowledgements count.
+    recipients: HashSet<ktp::Id>,
+    // Recipients that went away, nobody waits for them anymore.
+    gone: HashSet<ktp::Id>,
+    acked: HashSet<ktp::Id>,
+    transmissions: u32,
+    next_attempt: Instant,
+    is_failed: bool,
+}
+
+impl Pending {
+    fn state(&self) -> DeliveryState {
+        match self.acked.len() {
+            _ if self.is_failed => DeliveryState::Failed,
+            0 => DeliveryState::Sending,
+            peers => DeliveryState::Delivered { peers },
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        let mut waiting = self.recipients.difference(&self.gone).peekable();
+        waiting.peek().is_some() && waiting.all(|peer| self.acked.contains(peer))
+    }
+}
+
+/// Outgoing messages waiting for acknowledgements. <br>
+/// Messages are retransmitted with exponential backoff until every peer,
+/// that was online at the moment of sending, acknowledges them.
+#[derive(Default)]
+pub struct Outbox {
+    pending: HashMap<ktp::Id, Pending>,
+}
+
+impl Outbox {
+    /// The message is expected to be sent for the first time right away.
+    pub fn push(
+        &mut self, message_id: ktp::Id, message: OutgoingMessage,
+        recipients: HashSet<ktp::Id>, now: Instant,
+    ) {
+        self.pending.insert(
+            message_id,
+            Pending {
+                message,
+                recipients,
+                gone: HashSet::new(),
+                acked: HashSet::new(),
+                transmissions: 1,
+                next_attempt: now + RETRANSMIT_TIMEOUT,
+                is_failed: false,
+            },
+        );
+    }
+
+    /// Returns the new state, if the acknowledgement changed it. <br>
+    /// Only the peers the message was sent to count.
+    pub fn acknowledge(
+        &mut self, message_id: &ktp::Id, peer: ktp::Id,
+    ) -> Option<DeliveryState> {
+        let pending = self.pending.get_mut(message_id)?;
+        if !pending.recipients.contains(&peer) || !pending.acked.insert(peer) {
+            return None;
+        }
+        // Late acknowledgements still count.
+        pending.is_failed = false;
+
+        let state = pending.state();
+        if pending.is_done() {
+            self.pending.remove(message_id);
+        }
+
+        Some(state)
+    }
+
+    /// The peer went away, so there is no point in waiting for it.
+    pub fn forget_peer(&mut self, peer: &ktp::Id) {
+        for pending in self.pending.values_mut() {
+            if pending.recipients.contains(peer) {
+                pending.gone.insert(*peer);
+            }
+        }
+        self.pending.retain(|_, pending| !pending.is_done());
+    }
+
+    /// The message is still going out, so nobody could acknowledge it yet.
+    pub fn postpone(&mut self, message_id: &ktp::Id, now: Instant) {
+        if let Some(pending) = self.pending.get_mut(message_id) {
+            pending.next_attempt = now + backoff(pending.transmissions);
+        }
+    }
+
+    /// Returns ids and contents of messages to send again,
+    /// and ids of the ones that just failed. <br>
+    /// Messages delivered to some of the peers are given up on silently.
+    pub fn due(
+        &mut self, now: Instant,
+    ) -> (Vec<(ktp::Id, OutgoingMessage)>, Vec<ktp::Id>) {
+        let mut resend = vec![];
+        let mut failed = vec![];
+
+        self.pending.retain(|message_id, pending| {
+            if pending.is_failed || now < pending.next_attempt {
+                return true;
+            }
+
+            if pending.transmissions < MAX_TRANSMISSIONS {
+                pending.transmissions += 1;
+                pending.next_attempt = now + backoff(pending.transmissions);
+                resend.push((*message_id, pending.message.clone()));
+                return true;
+            }
+
+            // The last transmission wasn't acknowledged in time either.
+            if pending.acked.is_empty() {
+                pending.is_failed = true;
+                failed.push(*message_id);
+                return true;
+            }
+            false
+        });
+
+        (resend, failed)
+    }
+
+    /// Starts the failed messages over, returns them to send again.
+    pub fn retry_failed(&mut self, now: Instant) -> Vec<(ktp::Id, OutgoingMessage)> {
+        self.pending
+            .iter_mut()
+            .filter(|(_, pending)| pending.is_failed)
+            .map(|(message_id, pending)| {
+                pending.is_failed = false;
+                pending.transmissions = 1;
+                pending.next_attempt = now + RETRANSMIT_TIMEOUT;
+                (*message_id, pending.message.clone())
+            })
+            .collect()
+    }
+}
+
+fn backoff(transmissions: u32) -> Duration {
+    RETRANSMIT_TIMEOUT * 2u32.pow(transmissions - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unacknowledged_message_backs_off_then_fails() {
+        let mut outbox = Outbox::default();
+        let (message_id, bob) = ([1; 8], [2; 8]);
+        let start = Instant::now();
+        let message = OutgoingMessage {
+            room: String::new(),
+            message_text: "hi".into(),
+            reply_to: None,
+        };
+        outbox.push(message_id, message, HashSet::from([bob]), start);
+
+        let mut transmissions = vec![];
+        let mut failed_at = None;
+        for second in 0..20 {
+            let now = start + Duration::from_secs(second);
+            let (resend, failed) = outbox.due(now);
+            transmissions.extend(resend.iter().map(|_| second));
+            if !failed.is_empty() {
+                failed_at = Some(second);
+            }
+        }
+
+        assert_eq!(transmissions, vec![1, 3, 7]);
+        assert_eq!(failed_at, Some(15));
+
+        assert_eq!(outbox.retry_failed(start).len(), 1);
+        assert_eq!(outbox.acknowledge(&message_id, [3; 8]), None);
+        assert_eq!(
+            outbox.acknowledge(&message_id, bob),
+            Some(DeliveryState::Delivered { peers: 1 })
+        );
+        assert!(outbox.pending.is_empty());
+    }
+}
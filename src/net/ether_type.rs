@@ -29,4 +29,12 @@ impl EtherType {
             EtherType::IPv4 => &[0x08, 0x00],
         }
     }
+
+    /// Plain Ethernet frames don't carry IPv4, so they stay experimental.
+    pub fn ethernet_bytes(&self) -> &[u8] {
+        match self {
+            EtherType::IPv4 => EtherType::Experimental1.bytes(),
+            _ => self.bytes(),
+        }
+    }
 }
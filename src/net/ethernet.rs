@@ -0,0 +1,129 @@
+//! KTP frames carried right in the payload of Ethernet II frames. <br>
+//! Unlike ARP, a frame isn't limited to 255 bytes and the data isn't written twice.
+
+use crate::error::ktp::KtpError;
+use crate::net::ktp;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::util::MacAddr;
+use std::borrow::Cow;
+
+// Leaves some room below the usual MTU of 1500 bytes for tunnels and VLAN tags.
+pub const MAX_FRAME_SIZE: usize = 1400;
+pub const PART_SIZE: usize = MAX_FRAME_SIZE - ktp::PACKET_HEADER_SIZE;
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// KTP data found in an Ethernet frame, with the addresses it came with.
+pub struct Frame<'a> {
+    pub sender_mac: MacAddr,
+    pub target_mac: MacAddr,
+    pub data: Cow<'a, [u8]>,
+}
+
+/// Ethernet II frame with the given payload.
+pub fn build(
+    destination: MacAddr, source: MacAddr, ether_type: &[u8], payload: &[u8],
+) -> Vec<u8> {
+    [
+        destination.octets().as_slice(),
+        &source.octets(),
+        ether_type,
+        payload,
+    ]
+    .concat()
+}
+
+/// Short frames are padded on the wire, so the length of the KTP data goes first.
+pub fn build_ktp(
+    destination: MacAddr, source: MacAddr, ether_type: &[u8], data: &[u8],
+) -> Vec<u8> {
+    debug_assert!(data.len() <= MAX_FRAME_SIZE, "Frame is too large");
+
+    let len = (data.len() as u16).to_be_bytes();
+    build(
+        destination,
+        source,
+        ether_type,
+        &[len.as_slice(), data].concat(),
+    )
+}
+
+/// Returns `None` for frames of other EtherTypes. <br>
+/// Never panics, whatever the frame is.
+pub fn parse<'a>(
+    ethernet_frame: &'a [u8], ether_type: &[u8],
+) -> Result<Option<Frame<'a>>, KtpError> {
+    let Some(ethernet) = EthernetPacket::new(ethernet_frame) else {
+        return Ok(None);
+    };
+    if ethernet_frame[12..ETHERNET_HEADER_SIZE] != *ether_type {
+        return Ok(None);
+    }
+
+    let payload = &ethernet_frame[ETHERNET_HEADER_SIZE..];
+    let Some((len, payload)) = payload.split_first_chunk::<2>() else {
+        return Err(KtpError::Truncated);
+    };
+    let data = payload
+        .get(..u16::from_be_bytes(*len) as usize)
+        .ok_or(KtpError::Truncated)?;
+
+    // Broadcasts carry no target.
+    let target_mac = match ethernet.get_destination() {
+        mac if mac.is_broadcast() => MacAddr::zero(),
+        mac => mac,
+    };
+
+    Ok(Some(Frame {
+        sender_mac: ethernet.get_source(),
+        target_mac,
+        data: Cow::Borrowed(data),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const ETHER_TYPE: &[u8] = &[0x88, 0xB5];
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(data in prop::collection::vec(any::<u8>(), 0..128)) {
+            let _ = parse(&data, ETHER_TYPE);
+
+            let mut ktp_frame = data;
+            ktp_frame.resize(ktp_frame.len().max(14), 0);
+            ktp_frame[12..14].copy_from_slice(ETHER_TYPE);
+            let _ = parse(&ktp_frame, ETHER_TYPE);
+        }
+    }
+
+    #[test]
+    fn padding_is_cut_off() {
+        let source = MacAddr::new(2, 2, 2, 2, 2, 2);
+        let mut frame = build_ktp(MacAddr::broadcast(), source, ETHER_TYPE, b"ktp");
+        frame.resize(60, 0);
+
+        let parsed = parse(&frame, ETHER_TYPE).unwrap().unwrap();
+        assert_eq!(*parsed.data, *b"ktp");
+        assert_eq!(parsed.sender_mac, source);
+        assert_eq!(parsed.target_mac, MacAddr::zero());
+
+        assert!(parse(&frame, &[0x88, 0xB6]).unwrap().is_none());
+        assert!(matches!(
+            parse(&frame[..15], ETHER_TYPE),
+            Err(KtpError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn fragments_fit_into_a_frame() {
+        let frames = ktp::fragment(0, &[7; 3000], PART_SIZE, false).unwrap();
+
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|frame| frame.len() <= MAX_FRAME_SIZE));
+        assert_eq!(frames[0].len(), MAX_FRAME_SIZE);
+    }
+}
@@ -0,0 +1,54 @@
+use crate::net::ktp;
+use std::collections::{HashMap, VecDeque};
+
+/// Senders of recent messages, along with their rooms. <br>
+/// Edits and deletions are only accepted from the sender of the message,
+/// the oldest messages are forgotten first, and can't be changed anymore.
+pub struct MessageAuthors {
+    authors: HashMap<ktp::Id, (ktp::Id, String)>,
+    order: VecDeque<ktp::Id>,
+    capacity: usize,
+}
+
+impl MessageAuthors {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            authors: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// The first author of a message stays, returns `false` if the message
+    /// was already recorded as somebody else's.
+    pub fn record(&mut self, message_id: ktp::Id, author: ktp::Id, room: String) -> bool {
+        let recorded = self.authors.entry(message_id).or_insert_with(|| {
+            self.order.push_back(message_id);
+            (author, room.clone())
+        });
+        if *recorded != (author, room) {
+            return false;
+        }
+
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.authors.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Whether the message of the room was sent by the author.
+    pub fn is_author(&self, message_id: &ktp::Id, author: &ktp::Id, room: &str) -> bool {
+        self.authors
+            .get(message_id)
+            .is_some_and(|(sender, sender_room)| sender == author && sender_room == room)
+    }
+
+    /// Deleted messages can't be changed anymore.
+    pub fn forget(&mut self, message_id: &ktp::Id) {
+        if self.authors.remove(message_id).is_some() {
+            self.order.retain(|id| id != message_id);
+        }
+    }
+}
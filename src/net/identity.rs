@@ -0,0 +1,210 @@
+use crate::config::Config;
+use crate::error::config::ConfigError;
+use crate::net::ktp::{self, Packet};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::ErrorKind;
+
+const IDENTITY_FILENAME: &str = "identity.key";
+
+/// Long-term Ed25519 key of the user. <br>
+/// The session id is derived from the public key, so nobody can take
+/// somebody else's id without the secret key.
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Verification {
+    Verified,
+    /// The packet is signed, but the key of the sender isn't known yet.
+    Unknown,
+    Forged,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    /// Loads the identity kept next to the config, or creates a new one.
+    pub fn load_or_create() -> Result<Self, ConfigError> {
+        let path = Config::get_config_directory()?.join(IDENTITY_FILENAME);
+
+        match fs::read(&path) {
+            Ok(data) => {
+                let secret: [u8; 32] = data
+                    .try_into()
+                    .map_err(|_| ConfigError::InvalidIdentityFile)?;
+                Ok(Self {
+                    signing_key: SigningKey::from_bytes(&secret),
+                })
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let identity = Self::generate();
+
+                if let Some(parent_path) = path.parent() {
+                    fs::create_dir_all(parent_path).map_err(|err| {
+                        ConfigError::CannotRecreatePath(err.to_string())
+                    })?;
+                }
+                fs::write(&path, identity.signing_key.to_bytes())
+                    .map_err(|err| ConfigError::CannotCreateFile(err.to_string()))?;
+                restrict_permissions(&path);
+
+                Ok(identity)
+            },
+            Err(err) => Err(ConfigError::CannotCreateFile(err.to_string())),
+        }
+    }
+
+    pub fn id(&self) -> ktp::Id {
+        id_of(&self.public_key())
+    }
+
+    pub fn public_key(&self) -> ktp::PublicKey {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Fills in the signature of presence, message, message edit, disconnect,
+    /// key exchange, ack, file control and room announce packets.
+    pub fn sign(&self, mut packet: Packet) -> Packet {
+        let signed = self.signing_key.sign(&packet.signed_data()).to_bytes();
+
+        match &mut packet {
+            Packet::Message { signature, .. }
+            | Packet::PresenceInformation { signature, .. }
+            | Packet::Disconnect { signature, .. }
+            | Packet::KeyExchange { signature, .. }
+            | Packet::Ack { signature, .. }
+            | Packet::FileOffer { signature, .. }
+            | Packet::FileAccept { signature, .. }
+            | Packet::FileComplete { signature, .. }
+            | Packet::FileCancel { signature, .. }
+            | Packet::RoomAnnounce { signature, .. }
+            | Packet::Typing { signature, .. }
+            | Packet::EditMessage { signature, .. }
+            | Packet::DeleteMessage { signature, .. } => *signature = signed,
+            _ => {},
+        }
+
+        packet
+    }
+}
+
+/// Short form of a public key for users to compare out of band.
+pub fn fingerprint(public_key: &ktp::PublicKey) -> String {
+    Sha256::digest(public_key)[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn id_of(public_key: &ktp::PublicKey) -> ktp::Id {
+    let hash = Sha256::digest(public_key);
+    let mut id = ktp::Id::default();
+    id.copy_from_slice(&hash[..size_of::<ktp::Id>()]);
+    id
+}
+
+/// Presence packets carry their key, the rest needs the key of the sender from
+/// an earlier presence.
+pub fn verify(packet: &Packet, known_key: Option<&ktp::PublicKey>) -> Verification {
+    let Some(signature) = packet.signature() else {
+        return Verification::Unknown;
+    };
+
+    let public_key = match packet {
+        Packet::PresenceInformation { id, public_key, .. } => {
+            if *id != id_of(public_key) {
+                return Verification::Forged;
+            }
+            public_key
+        },
+        _ => match known_key {
+            Some(public_key) => public_key,
+            None => return Verification::Unknown,
+        },
+    };
+
+    let Ok(verifying_key) = VerifyingKey::from_bytes(public_key) else {
+        return Verification::Forged;
+    };
+    let signature = Signature::from_bytes(signature);
+
+    match verifying_key.verify_strict(&packet.signed_data(), &signature) {
+        Ok(()) => Verification::Verified,
+        Err(_) => Verification::Forged,
+    }
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &std::path::Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Err(err) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+        log::warn!("Couldn't restrict access to the identity key: {err}");
+    }
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &std::path::Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::capabilities::Capabilities;
+
+    #[test]
+    fn forged_packets_are_detected() {
+        let alice = Identity::generate();
+        let mallory = Identity::generate();
+
+        let presence = alice.sign(Packet::PresenceInformation {
+            id: alice.id(),
+            counter: 1,
+            room: String::new(),
+            is_join: false,
+            version: ktp::PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            username: "alice".into(),
+            public_key: alice.public_key(),
+            signature: [0; 64],
+        });
+        assert_eq!(verify(&presence, None), Verification::Verified);
+
+        // Alice's id with Mallory's key.
+        let stolen_id = mallory.sign(Packet::PresenceInformation {
+            id: alice.id(),
+            counter: 1,
+            room: String::new(),
+            is_join: false,
+            version: ktp::PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            username: "alice".into(),
+            public_key: mallory.public_key(),
+            signature: [0; 64],
+        });
+        assert_eq!(verify(&stolen_id, None), Verification::Forged);
+
+        let message = mallory.sign(Packet::Message {
+            id: alice.id(),
+            counter: 1,
+            room: String::new(),
+            message_id: ktp::generate_id(),
+            reply_to: None,
+            compression: ktp::Compression::Smaz,
+            message_text: "hi".into(),
+            signature: [0; 64],
+        });
+        assert_eq!(verify(&message, None), Verification::Unknown);
+        assert_eq!(
+            verify(&message, Some(&alice.public_key())),
+            Verification::Forged
+        );
+    }
+}
@@ -1,6 +1,13 @@
 use crate::config::CONFIG;
 use crate::error::net::NetError;
+use crate::net::bridge::Bridge;
+use crate::net::carrier::Carrier;
 use crate::net::channel::Channel;
+use crate::net::crypto::RoomKey;
+use crate::net::ktp;
+use crate::net::transport::Transport;
+use crate::net::udp;
+use crate::net::udp::UdpTransport;
 use pnet::datalink::NetworkInterface;
 
 /// Usable interfaces. <br>
@@ -18,18 +25,47 @@ pub fn usable_sorted() -> Vec<NetworkInterface> {
     interfaces
 }
 
-pub fn channel_from_name(interface_name: String) -> Result<Channel, NetError> {
+/// Opens the carrier chosen in the config on the interface, unless it is overridden.
+pub fn transport_from_name(
+    interface_name: &str, carrier: Option<Carrier>,
+) -> Result<Box<dyn Transport>, NetError> {
     let interface = usable_sorted()
         .into_iter()
         .find(|interface| interface.name.eq(&interface_name))
-        .ok_or(NetError::InvalidInterface(interface_name))?;
+        .ok_or(NetError::InvalidInterface(interface_name.to_string()))?;
 
-    let mut channel = Channel::from_interface(interface)?;
-    if let Ok(config) = CONFIG.try_lock() {
-        if let Some(ether_type) = config.ether_type {
-            channel.set_ether_type(ether_type);
-        }
+    // A poisoned config would silently bring up the defaults instead.
+    let config = CONFIG.lock().map_err(|_| NetError::ConfigUnavailable)?;
+    let carrier = carrier.or(config.carrier).unwrap_or_default();
+
+    let port = config.udp_port.unwrap_or(udp::DEFAULT_PORT);
+    let mut transport: Box<dyn Transport> = match carrier.is_link() {
+        true if config.udp_bridge == Some(true) => Box::new(Bridge::new(
+            Channel::from_interface(interface.clone())?,
+            UdpTransport::from_interface(&interface, port, config.udp_group)?,
+        )),
+        true => Box::new(Channel::from_interface(interface)?),
+        false => Box::new(UdpTransport::from_interface(
+            &interface,
+            port,
+            config.udp_group,
+        )?),
+    };
+    transport.set_carrier(carrier);
+    transport.set_max_packet_size(
+        config
+            .max_message_size
+            .unwrap_or(ktp::DEFAULT_MAX_PACKET_SIZE),
+    );
+    if let Some(arp_framing) = config.arp_framing {
+        transport.set_arp_framing(arp_framing);
+    }
+    if let Some(ether_type) = config.ether_type {
+        transport.set_ether_type(ether_type);
+    }
+    if let Some(passphrase) = &config.room_passphrase {
+        transport.set_room_key(Some(RoomKey::derive(passphrase)?));
     }
 
-    Ok(channel)
+    Ok(transport)
 }
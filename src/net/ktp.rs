@@ -1,93 +1,1473 @@
-/// ARP Chat is based on the ARP protocol. <br>
-/// But, there is a need to use a transport protocol.
-/// I chose the name KTP - kognise's transport protocol.
+//! ARP Chat is based on the ARP protocol. <br>
+//! But, there is a need to use a transport protocol.
+//! I chose the name KTP - kognise's transport protocol.
 
-// Custom packet prefix
-pub const PACKET_PREFIX: &[u8] = b"ktp";
+use crate::error::ktp::KtpError;
+use crate::error::net::NetError;
+use crate::net::capabilities::Capabilities;
+use crate::net::crypto::RoomKey;
+use std::collections::HashSet;
+
+// Custom packet prefix, followed by the version of the frame.
+// Clients from before the version read `ktp` frames without one, so versioned
+// frames take their own prefix, which those clients skip.
+pub const PACKET_PREFIX: &[u8] = b"ktv";
+// Frames of packets with more than 256 parts, their Seq and Total take two bytes.
+// Older versions skip them, as they don't know the prefix.
+pub const EXTENDED_PACKET_PREFIX: &[u8] = b"ktx";
+
+// Version of the wire format, bumped on every incompatible change.
+pub const PROTOCOL_VERSION: Version = 2;
+// Frames of older versions can't be read anymore.
+pub const MIN_PROTOCOL_VERSION: Version = 1;
+// Extended frames and `Capabilities::LARGE_PACKETS` came with this version.
+pub const EXTENDED_FRAMES_VERSION: Version = 2;
+
+// Set in the tag of packets with a payload sealed by the room key.
+pub const SEALED_TAG_FLAG: Tag = 0x80;
+// Set in the compression byte of replies, the id of the parent message follows it.
+// Older versions drop replies, as they don't know the compression.
+const REPLY_FLAG: u8 = 0x80;
 
 pub type Id = [u8; 8];
+pub type Version = u8;
 pub type Tag = u8;
-pub type Seq = u8;
-pub type Total = u8;
+pub type Seq = u16;
+pub type Total = u16;
+pub type PublicKey = [u8; 32];
+pub type Signature = [u8; 64];
+pub type Counter = u64;
+pub type Hash = [u8; 32];
+
+// Tags of message, presence and disconnect packets of a room other than the main one.
+// Older versions skip them, as they don't know the tags.
+pub const ROOM_TAGS: std::ops::RangeInclusive<Tag> = 14..=16;
 
-// Packet Header size consists of packet prefix, Id, Tag, Seq and Total fields.
+// Packet Header size consists of packet prefix, Version, Id, Tag, Seq and Total fields.
+// Seq and Total take a byte each, unless the frame is extended.
 pub const PACKET_HEADER_SIZE: usize = PACKET_PREFIX.len()
+    + size_of::<Version>()
     + size_of::<Id>()
-    + size_of::<Seq>()
+    + size_of::<u8>()
     + size_of::<Tag>()
-    + size_of::<Total>();
+    + size_of::<u8>();
+pub const EXTENDED_PACKET_HEADER_SIZE: usize = PACKET_HEADER_SIZE + 2;
 
-// Possible packet payload size
+// Possible packet payload size in a frame of ARP
 pub const PACKET_DATA_SIZE: usize = (u8::MAX as usize) - PACKET_HEADER_SIZE;
 
+// Whatever the carrier, packets of 256 frames stay as large as 256 frames of ARP,
+// so they fit into the reassembly limits of every peer.
+pub const MAX_PACKET_SIZE: usize = (u8::MAX as usize + 1) * PACKET_DATA_SIZE;
+// Larger packets are only sent to peers with `Capabilities::LARGE_PACKETS`.
+pub const MAX_EXTENDED_PARTS: usize = Total::MAX as usize + 1;
+
+// Packets larger than this are neither sent nor reassembled, unless configured.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 256 * 1024;
+
+// Room names are sent after a byte of their length.
+pub const MAX_ROOM_NAME_SIZE: usize = u8::MAX as usize;
+
+/// Every packet with a sender carries the sender's counter, see `replay`.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Packet {
+    /// Stays the same over retransmissions, unlike the counter. <br>
+    /// Messages of the main room have an empty room name.
+    /// Replies carry the id of the message they answer.
     Message {
         id: Id,
+        counter: Counter,
+        room: String,
+        message_id: Id,
+        reply_to: Option<Id>,
+        compression: Compression,
         message_text: String,
+        signature: Signature,
     },
     PresenceBroadcastRequest,
+    /// Carries the identity key, which the id is derived from,
+    /// and what the client is able to do. <br>
+    /// Presence in a room only tells the members of the room about the peer.
     PresenceInformation {
         id: Id,
+        counter: Counter,
+        room: String,
         is_join: bool,
+        version: Version,
+        capabilities: Capabilities,
         username: String,
+        public_key: PublicKey,
+        signature: Signature,
+    },
+    /// Leaving a room, or the whole chat if the room is the main one.
+    Disconnect {
+        id: Id,
+        counter: Counter,
+        room: String,
+        signature: Signature,
+    },
+    /// Message text is sealed with the key of the direct session.
+    DirectMessage {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        ciphertext: Vec<u8>,
+    },
+    /// Half of the handshake of a direct session. <br>
+    /// Signed, so nobody else can put themselves between the peers.
+    KeyExchange {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        is_reply: bool,
+        public_key: PublicKey,
+        signature: Signature,
+    },
+    /// Confirms that the message reached the peer.
+    Ack {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        message_id: Id,
+        signature: Signature,
+    },
+    /// Asks the sender of a packet to send the missing frames again. <br>
+    /// Answered by the transport, the net thread never sees it. <br>
+    /// Seqs beyond a byte are only missing from packets of extended frames,
+    /// they are carried with a tag of their own.
+    Nack {
+        frame_id: Id,
+        missing: Vec<Seq>,
+    },
+    /// Proposes a file to the target, the hash is SHA-256 of the whole file.
+    FileOffer {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        transfer_id: Id,
+        size: u64,
+        hash: Hash,
+        name: String,
+        signature: Signature,
+    },
+    /// Asks for the file from the offset on. <br>
+    /// Sent on acceptance, after every received chunk and to resume the transfer.
+    FileAccept {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        transfer_id: Id,
+        offset: u64,
+        signature: Signature,
+    },
+    /// Unsigned, the data is checked against the hash of the offer instead.
+    FileChunk {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        transfer_id: Id,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// The file is received and matches the hash.
+    FileComplete {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        transfer_id: Id,
+        signature: Signature,
+    },
+    /// Either side gives up on the transfer.
+    FileCancel {
+        id: Id,
+        counter: Counter,
+        target: Id,
+        transfer_id: Id,
+        signature: Signature,
+    },
+    /// Rooms the sender is in, with the topics it knows, sent along with heartbeats.
+    /// <br> Everybody reads it, members of the rooms or not.
+    RoomAnnounce {
+        id: Id,
+        counter: Counter,
+        rooms: Vec<RoomTopic>,
+        signature: Signature,
+    },
+    /// Sent now and then while the user has an unsent message in the input.
+    /// <br> Signed, so nobody is shown typing under another name.
+    Typing {
+        id: Id,
+        counter: Counter,
+        room: String,
+        signature: Signature,
     },
-    Disconnect(Id),
+    /// New text of a message sent before. <br>
+    /// Only the sender of the message may edit it, so it is signed.
+    EditMessage {
+        id: Id,
+        counter: Counter,
+        room: String,
+        message_id: Id,
+        message_text: String,
+        signature: Signature,
+    },
+    DeleteMessage {
+        id: Id,
+        counter: Counter,
+        room: String,
+        message_id: Id,
+        signature: Signature,
+    },
+}
+
+/// Topic of a room, as far as the announcer knows. <br>
+/// The revision is 0 for rooms without a topic. Of two topics, the one with
+/// the higher revision wins, and the one of the higher setter id, if they tie.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomTopic {
+    pub room: String,
+    pub revision: u64,
+    pub setter: Id,
+    pub topic: String,
+}
+
+impl RoomTopic {
+    pub fn wins_over(&self, other: &RoomTopic) -> bool {
+        (self.revision, self.setter) > (other.revision, other.setter)
+    }
+}
+
+/// How the text of a message is packed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Smaz = 1,
+}
+
+impl Compression {
+    fn compress(self, text: &str) -> Vec<u8> {
+        match self {
+            Compression::None => text.as_bytes().to_vec(),
+            Compression::Smaz => smaz::compress(text.as_bytes()),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, KtpError> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Smaz => decompress(data),
+        }
+    }
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = KtpError;
+
+    fn try_from(value: u8) -> Result<Self, KtpError> {
+        match value {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Smaz),
+            _ => Err(KtpError::BadCompression(value)),
+        }
+    }
 }
 
 impl Packet {
     pub fn tag(&self) -> Tag {
         match self {
-            Packet::Message { .. } => 0,
+            Packet::Message { room, .. } if room.is_empty() => 0,
+            Packet::Message { .. } => 14,
             Packet::PresenceBroadcastRequest => 1,
-            Packet::PresenceInformation { .. } => 2,
-            Packet::Disconnect(_) => 3,
+            Packet::PresenceInformation { room, .. } if room.is_empty() => 2,
+            Packet::PresenceInformation { .. } => 15,
+            Packet::Disconnect { room, .. } if room.is_empty() => 3,
+            Packet::Disconnect { .. } => 16,
+            Packet::DirectMessage { .. } => 4,
+            Packet::KeyExchange { .. } => 5,
+            Packet::Ack { .. } => 6,
+            Packet::Nack { missing, .. }
+                if missing.iter().all(|seq| *seq <= u8::MAX as Seq) =>
+            {
+                7
+            },
+            Packet::Nack { .. } => 8,
+            Packet::FileOffer { .. } => 9,
+            Packet::FileAccept { .. } => 10,
+            Packet::FileChunk { .. } => 11,
+            Packet::FileComplete { .. } => 12,
+            Packet::FileCancel { .. } => 13,
+            Packet::RoomAnnounce { .. } => 17,
+            Packet::Typing { .. } => 18,
+            Packet::EditMessage { .. } => 19,
+            Packet::DeleteMessage { .. } => 20,
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
+    /// Id of the chat message the packet carries or changes.
+    pub fn message_id(&self) -> Option<Id> {
         match self {
-            Packet::Message { id, message_text } => {
-                [id as &[u8], &smaz::compress(message_text.as_bytes())].concat()
-            },
+            Packet::Message { message_id, .. }
+            | Packet::EditMessage { message_id, .. }
+            | Packet::DeleteMessage { message_id, .. } => Some(*message_id),
+            _ => None,
+        }
+    }
+
+    /// Name of the room the packet belongs to, empty for the main room.
+    pub fn room(&self) -> &str {
+        match self {
+            Packet::Message { room, .. }
+            | Packet::PresenceInformation { room, .. }
+            | Packet::Disconnect { room, .. }
+            | Packet::Typing { room, .. }
+            | Packet::EditMessage { room, .. }
+            | Packet::DeleteMessage { room, .. } => room,
+            _ => "",
+        }
+    }
+
+    /// Id of the peer that sent the packet, if the packet carries it.
+    pub fn sender(&self) -> Option<Id> {
+        self.header().map(|(id, _)| id)
+    }
+
+    pub fn counter(&self) -> Option<Counter> {
+        self.header().map(|(_, counter)| counter)
+    }
+
+    fn header(&self) -> Option<(Id, Counter)> {
+        match self {
+            Packet::Message { id, counter, .. }
+            | Packet::PresenceInformation { id, counter, .. }
+            | Packet::Disconnect { id, counter, .. }
+            | Packet::DirectMessage { id, counter, .. }
+            | Packet::KeyExchange { id, counter, .. }
+            | Packet::Ack { id, counter, .. }
+            | Packet::FileOffer { id, counter, .. }
+            | Packet::FileAccept { id, counter, .. }
+            | Packet::FileChunk { id, counter, .. }
+            | Packet::FileComplete { id, counter, .. }
+            | Packet::FileCancel { id, counter, .. }
+            | Packet::RoomAnnounce { id, counter, .. }
+            | Packet::Typing { id, counter, .. }
+            | Packet::EditMessage { id, counter, .. }
+            | Packet::DeleteMessage { id, counter, .. } => Some((*id, *counter)),
+            Packet::PresenceBroadcastRequest | Packet::Nack { .. } => None,
+        }
+    }
+
+    /// Id of the only peer that should receive the packet.
+    pub fn target(&self) -> Option<Id> {
+        match self {
+            Packet::DirectMessage { target, .. }
+            | Packet::KeyExchange { target, .. }
+            | Packet::Ack { target, .. }
+            | Packet::FileOffer { target, .. }
+            | Packet::FileAccept { target, .. }
+            | Packet::FileChunk { target, .. }
+            | Packet::FileComplete { target, .. }
+            | Packet::FileCancel { target, .. } => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// Signature of the identity key, if the packet is signed.
+    pub fn signature(&self) -> Option<&Signature> {
+        match self {
+            Packet::Message { signature, .. }
+            | Packet::PresenceInformation { signature, .. }
+            | Packet::Disconnect { signature, .. }
+            | Packet::KeyExchange { signature, .. }
+            | Packet::Ack { signature, .. }
+            | Packet::FileOffer { signature, .. }
+            | Packet::FileAccept { signature, .. }
+            | Packet::FileComplete { signature, .. }
+            | Packet::FileCancel { signature, .. }
+            | Packet::RoomAnnounce { signature, .. }
+            | Packet::Typing { signature, .. }
+            | Packet::EditMessage { signature, .. }
+            | Packet::DeleteMessage { signature, .. } => Some(signature),
+            _ => None,
+        }
+    }
+
+    /// Everything the signature covers: the tag and the packet without the signature.
+    pub fn signed_data(&self) -> Vec<u8> {
+        [&[self.tag()], self.serialize_unsigned().as_slice()].concat()
+    }
+
+    /// Room names that don't fit their length byte would be cut on the wire.
+    pub fn has_valid_rooms(&self) -> bool {
+        match self {
+            Packet::RoomAnnounce { rooms, .. } => rooms
+                .iter()
+                .all(|topic| topic.room.len() <= MAX_ROOM_NAME_SIZE),
+            packet => packet.room().len() <= MAX_ROOM_NAME_SIZE,
+        }
+    }
+
+    /// The signature, if any, goes last.
+    pub fn serialize(&self) -> Vec<u8> {
+        match self.signature() {
+            Some(signature) => [self.serialize_unsigned().as_slice(), signature].concat(),
+            None => self.serialize_unsigned(),
+        }
+    }
+
+    // Packets with a sender start with its id and counter,
+    // packets of a room follow them with the length of the room name and the name.
+    fn serialize_unsigned(&self) -> Vec<u8> {
+        let mut header = match self.header() {
+            Some((id, counter)) => [id.as_slice(), &counter.to_be_bytes()].concat(),
+            None => vec![],
+        };
+        if ROOM_TAGS.contains(&self.tag()) {
+            header.push(self.room().len() as u8);
+            header.extend_from_slice(self.room().as_bytes());
+        }
+
+        let body = match self {
+            Packet::Message {
+                message_id,
+                reply_to: None,
+                compression,
+                message_text,
+                ..
+            } => [
+                message_id.as_slice(),
+                &[*compression as u8],
+                &compression.compress(message_text),
+            ]
+            .concat(),
+            Packet::Message {
+                message_id,
+                reply_to: Some(reply_to),
+                compression,
+                message_text,
+                ..
+            } => [
+                message_id.as_slice(),
+                &[*compression as u8 | REPLY_FLAG],
+                reply_to,
+                &compression.compress(message_text),
+            ]
+            .concat(),
             Packet::PresenceBroadcastRequest => vec![],
             Packet::PresenceInformation {
-                id,
                 is_join,
+                version,
+                capabilities,
                 username,
-            } => [id as &[u8], &[*is_join as u8], username.as_bytes()].concat(),
-            Packet::Disconnect(id) => id.to_vec(),
-        }
+                public_key,
+                ..
+            } => [
+                &[*is_join as u8, *version],
+                capabilities.bits().to_be_bytes().as_slice(),
+                public_key.as_slice(),
+                username.as_bytes(),
+            ]
+            .concat(),
+            Packet::Disconnect { .. } => vec![],
+            Packet::DirectMessage {
+                target, ciphertext, ..
+            } => [target.as_slice(), ciphertext].concat(),
+            Packet::KeyExchange {
+                target,
+                is_reply,
+                public_key,
+                ..
+            } => [target.as_slice(), &[*is_reply as u8], public_key].concat(),
+            Packet::Ack {
+                target, message_id, ..
+            } => [target.as_slice(), message_id].concat(),
+            Packet::Nack { frame_id, missing } => {
+                let missing: Vec<u8> = match self.tag() {
+                    7 => missing.iter().map(|seq| *seq as u8).collect(),
+                    _ => missing.iter().flat_map(|seq| seq.to_be_bytes()).collect(),
+                };
+                [frame_id.as_slice(), &missing].concat()
+            },
+            Packet::FileOffer {
+                target,
+                transfer_id,
+                size,
+                hash,
+                name,
+                ..
+            } => [
+                target.as_slice(),
+                transfer_id,
+                &size.to_be_bytes(),
+                hash,
+                name.as_bytes(),
+            ]
+            .concat(),
+            Packet::FileAccept {
+                target,
+                transfer_id,
+                offset,
+                ..
+            } => [target.as_slice(), transfer_id, &offset.to_be_bytes()].concat(),
+            Packet::FileChunk {
+                target,
+                transfer_id,
+                offset,
+                data,
+                ..
+            } => [target.as_slice(), transfer_id, &offset.to_be_bytes(), data].concat(),
+            Packet::FileComplete {
+                target,
+                transfer_id,
+                ..
+            }
+            | Packet::FileCancel {
+                target,
+                transfer_id,
+                ..
+            } => [target.as_slice(), transfer_id].concat(),
+            Packet::RoomAnnounce { rooms, .. } => rooms
+                .iter()
+                .flat_map(|room| {
+                    [
+                        &[room.room.len() as u8],
+                        room.room.as_bytes(),
+                        &room.revision.to_be_bytes(),
+                        &room.setter,
+                        &(room.topic.len() as u16).to_be_bytes(),
+                        room.topic.as_bytes(),
+                    ]
+                    .concat()
+                })
+                .collect(),
+            Packet::Typing { room, .. } => room.as_bytes().to_vec(),
+            Packet::EditMessage {
+                room,
+                message_id,
+                message_text,
+                ..
+            } => [
+                message_id.as_slice(),
+                &[room.len() as u8],
+                room.as_bytes(),
+                message_text.as_bytes(),
+            ]
+            .concat(),
+            Packet::DeleteMessage {
+                room, message_id, ..
+            } => [message_id.as_slice(), room.as_bytes()].concat(),
+        };
+
+        [header, body].concat()
     }
 
-    pub fn deserialize(tag: u8, data: &[u8]) -> Option<Self> {
+    /// Never panics, whatever the data is.
+    pub fn deserialize(tag: Tag, data: &[u8]) -> Result<Self, KtpError> {
         match tag {
-            0 => {
-                let id: Id = data[..size_of::<Id>()].try_into().ok()?;
-                let raw_str = smaz::decompress(&data[size_of::<Id>()..]).ok()?;
-                let str = String::from_utf8(raw_str).ok()?;
-                Some(Packet::Message {
+            1 => return Ok(Packet::PresenceBroadcastRequest),
+            7 => {
+                let (frame_id, missing) = split_array(data)?;
+                return Ok(Packet::Nack {
+                    frame_id,
+                    missing: missing.iter().map(|seq| *seq as Seq).collect(),
+                });
+            },
+            8 => {
+                let (frame_id, missing) = split_array(data)?;
+                let missing = missing.chunks_exact(size_of::<Seq>());
+                if !missing.remainder().is_empty() {
+                    return Err(KtpError::TrailingData);
+                }
+                return Ok(Packet::Nack {
+                    frame_id,
+                    missing: missing
+                        .map(|seq| Seq::from_be_bytes([seq[0], seq[1]]))
+                        .collect(),
+                });
+            },
+            0 | 2..=6 | 9..=20 => {},
+            _ => return Err(KtpError::BadTag(tag)),
+        }
+
+        // Unsigned packets don't use the signature.
+        let (data, signature) = match tag {
+            0 | 2 | 3 | 5 | 6 | 9 | 10 | 12..=20 => split_signature(data)?,
+            _ => (data, [0; 64]),
+        };
+        let (id, data) = split_array(data)?;
+        let (counter, data) = split_array(data)?;
+        let counter = Counter::from_be_bytes(counter);
+        let (room, data) = match ROOM_TAGS.contains(&tag) {
+            true => split_room(data)?,
+            false => (String::new(), data),
+        };
+
+        match tag {
+            0 | 14 => {
+                let (message_id, data) = split_array(data)?;
+                let (&compression, data) =
+                    data.split_first().ok_or(KtpError::Truncated)?;
+                let (reply_to, data) = match compression & REPLY_FLAG {
+                    0 => (None, data),
+                    _ => split_array(data)
+                        .map(|(reply_to, data)| (Some(reply_to), data))?,
+                };
+                let compression = Compression::try_from(compression & !REPLY_FLAG)?;
+                let raw_str = compression.decompress(data)?;
+                Ok(Packet::Message {
                     id,
-                    message_text: str,
+                    counter,
+                    room,
+                    message_id,
+                    reply_to,
+                    compression,
+                    message_text: String::from_utf8(raw_str)
+                        .map_err(|_| KtpError::BadUtf8)?,
+                    signature,
                 })
             },
-            1 => Some(Packet::PresenceBroadcastRequest),
-            2 => {
-                let id: Id = data[..size_of::<Id>()].try_into().ok()?;
-                let is_join = data[size_of::<Id>()] > 0;
-                let str = String::from_utf8(data[size_of::<Id>() + 1..].to_vec()).ok()?;
-                Some(Packet::PresenceInformation {
+            2 | 15 => {
+                let ([is_join, version], data) = split_array(data)?;
+                let (capabilities, data) = split_array(data)?;
+                let (public_key, username) = split_array(data)?;
+                Ok(Packet::PresenceInformation {
                     id,
-                    is_join,
-                    username: str,
+                    counter,
+                    room,
+                    is_join: is_join > 0,
+                    version,
+                    capabilities: Capabilities::from_bits(u32::from_be_bytes(
+                        capabilities,
+                    )),
+                    username: String::from_utf8(username.to_vec())
+                        .map_err(|_| KtpError::BadUtf8)?,
+                    public_key,
+                    signature,
                 })
             },
-            3 => Some(Packet::Disconnect(data.try_into().ok()?)),
-            _ => None,
+            3 | 16 => {
+                exact_array::<0>(data)?;
+                Ok(Packet::Disconnect {
+                    id,
+                    counter,
+                    room,
+                    signature,
+                })
+            },
+            4 => {
+                let (target, ciphertext) = split_array(data)?;
+                Ok(Packet::DirectMessage {
+                    id,
+                    counter,
+                    target,
+                    ciphertext: ciphertext.to_vec(),
+                })
+            },
+            5 => {
+                let (target, data) = split_array(data)?;
+                let (&is_reply, data) = data.split_first().ok_or(KtpError::Truncated)?;
+                Ok(Packet::KeyExchange {
+                    id,
+                    counter,
+                    target,
+                    is_reply: is_reply > 0,
+                    public_key: exact_array(data)?,
+                    signature,
+                })
+            },
+            6 => {
+                let (target, data) = split_array(data)?;
+                Ok(Packet::Ack {
+                    id,
+                    counter,
+                    target,
+                    message_id: exact_array(data)?,
+                    signature,
+                })
+            },
+            9 => {
+                let (target, data) = split_array(data)?;
+                let (transfer_id, data) = split_array(data)?;
+                let (size, data) = split_array(data)?;
+                let (hash, name) = split_array(data)?;
+                Ok(Packet::FileOffer {
+                    id,
+                    counter,
+                    target,
+                    transfer_id,
+                    size: u64::from_be_bytes(size),
+                    hash,
+                    name: String::from_utf8(name.to_vec())
+                        .map_err(|_| KtpError::BadUtf8)?,
+                    signature,
+                })
+            },
+            10 => {
+                let (target, data) = split_array(data)?;
+                let (transfer_id, data) = split_array(data)?;
+                Ok(Packet::FileAccept {
+                    id,
+                    counter,
+                    target,
+                    transfer_id,
+                    offset: u64::from_be_bytes(exact_array(data)?),
+                    signature,
+                })
+            },
+            11 => {
+                let (target, data) = split_array(data)?;
+                let (transfer_id, data) = split_array(data)?;
+                let (offset, data) = split_array(data)?;
+                Ok(Packet::FileChunk {
+                    id,
+                    counter,
+                    target,
+                    transfer_id,
+                    offset: u64::from_be_bytes(offset),
+                    data: data.to_vec(),
+                })
+            },
+            12 => {
+                let (target, data) = split_array(data)?;
+                Ok(Packet::FileComplete {
+                    id,
+                    counter,
+                    target,
+                    transfer_id: exact_array(data)?,
+                    signature,
+                })
+            },
+            13 => {
+                let (target, data) = split_array(data)?;
+                Ok(Packet::FileCancel {
+                    id,
+                    counter,
+                    target,
+                    transfer_id: exact_array(data)?,
+                    signature,
+                })
+            },
+            17 => {
+                let mut rooms = vec![];
+                let mut data = data;
+                while !data.is_empty() {
+                    let (room, rest) = split_room(data)?;
+                    let (revision, rest) = split_array(rest)?;
+                    let (setter, rest) = split_array(rest)?;
+                    let (topic_length, rest) = split_array(rest)?;
+                    let (topic, rest) = rest
+                        .split_at_checked(u16::from_be_bytes(topic_length) as usize)
+                        .ok_or(KtpError::Truncated)?;
+                    rooms.push(RoomTopic {
+                        room,
+                        revision: u64::from_be_bytes(revision),
+                        setter,
+                        topic: String::from_utf8(topic.to_vec())
+                            .map_err(|_| KtpError::BadUtf8)?,
+                    });
+                    data = rest;
+                }
+                Ok(Packet::RoomAnnounce {
+                    id,
+                    counter,
+                    rooms,
+                    signature,
+                })
+            },
+            18 => Ok(Packet::Typing {
+                id,
+                counter,
+                room: String::from_utf8(data.to_vec()).map_err(|_| KtpError::BadUtf8)?,
+                signature,
+            }),
+            19 => {
+                let (message_id, data) = split_array(data)?;
+                let (&length, data) = data.split_first().ok_or(KtpError::Truncated)?;
+                let (room, message_text) = data
+                    .split_at_checked(length as usize)
+                    .ok_or(KtpError::Truncated)?;
+                Ok(Packet::EditMessage {
+                    id,
+                    counter,
+                    room: String::from_utf8(room.to_vec())
+                        .map_err(|_| KtpError::BadUtf8)?,
+                    message_id,
+                    message_text: String::from_utf8(message_text.to_vec())
+                        .map_err(|_| KtpError::BadUtf8)?,
+                    signature,
+                })
+            },
+            20 => {
+                let (message_id, room) = split_array(data)?;
+                Ok(Packet::DeleteMessage {
+                    id,
+                    counter,
+                    room: String::from_utf8(room.to_vec())
+                        .map_err(|_| KtpError::BadUtf8)?,
+                    message_id,
+                    signature,
+                })
+            },
+            _ => Err(KtpError::BadTag(tag)),
         }
     }
 }
 
+/// smaz panics on escapes cut short at the end, so they are checked first. <br>
+/// Everything received goes through this instead of `smaz::decompress`.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, KtpError> {
+    let mut i = 0;
+    while i < data.len() {
+        i += match data[i] {
+            254 => 2,
+            255 => 3 + *data.get(i + 1).ok_or(KtpError::DecompressionFailed)? as usize,
+            _ => 1,
+        };
+    }
+    if i > data.len() {
+        return Err(KtpError::DecompressionFailed);
+    }
+
+    smaz::decompress(data).map_err(|_| KtpError::DecompressionFailed)
+}
+
+fn split_array<const N: usize>(data: &[u8]) -> Result<([u8; N], &[u8]), KtpError> {
+    let (array, rest) = data.split_first_chunk::<N>().ok_or(KtpError::Truncated)?;
+    Ok((*array, rest))
+}
+
+fn exact_array<const N: usize>(data: &[u8]) -> Result<[u8; N], KtpError> {
+    match split_array(data)? {
+        (array, []) => Ok(array),
+        _ => Err(KtpError::TrailingData),
+    }
+}
+
+// An empty name would make the packet one of the main room.
+fn split_room(data: &[u8]) -> Result<(String, &[u8]), KtpError> {
+    let (&length, data) = data.split_first().ok_or(KtpError::Truncated)?;
+    let (room, data) = data
+        .split_at_checked(length as usize)
+        .ok_or(KtpError::Truncated)?;
+    if room.is_empty() {
+        return Err(KtpError::MissingRoom);
+    }
+    let room = String::from_utf8(room.to_vec()).map_err(|_| KtpError::BadUtf8)?;
+    Ok((room, data))
+}
+
+/// Name of the room of the unsealed packet, read without deserializing it. <br>
+/// `None` for packets of the main room and for ones too short to tell.
+pub fn room_of(tag: Tag, data: &[u8]) -> Option<&[u8]> {
+    if !ROOM_TAGS.contains(&tag) {
+        return None;
+    }
+    let (&length, data) = data
+        .get(size_of::<Id>() + size_of::<Counter>()..)?
+        .split_first()?;
+    data.get(..length as usize)
+}
+
+fn is_other_room(tag: Tag, data: &[u8], rooms: &HashSet<String>) -> bool {
+    room_of(tag, data)
+        .is_some_and(|room| !rooms.iter().any(|joined| joined.as_bytes() == room))
+}
+
+/// Whether the frame starts a packet of a room that isn't in `rooms`. <br>
+/// The rest of such packets isn't worth reassembling. Sealed packets
+/// can only be told apart once they are opened, in `decode`.
+pub fn starts_other_room(frame: &Frame, rooms: &HashSet<String>) -> bool {
+    frame.seq == 0
+        && frame.tag & SEALED_TAG_FLAG == 0
+        && is_other_room(frame.tag, frame.part, rooms)
+}
+
+fn split_signature(data: &[u8]) -> Result<(&[u8], Signature), KtpError> {
+    let (data, signature) = data.split_last_chunk().ok_or(KtpError::Truncated)?;
+    Ok((data, *signature))
+}
+
+/// Serializes the packet and seals it, if there is a room key. <br>
+/// Returns the tag to put on the wire along with the data.
+pub fn encode(
+    packet: &Packet, room_key: Option<&RoomKey>,
+) -> Result<(Tag, Vec<u8>), NetError> {
+    if !packet.has_valid_rooms() {
+        return Err(NetError::RoomNameTooLong);
+    }
+    let data = packet.serialize();
+
+    match room_key {
+        // PresenceBroadcastRequest carries nothing, so there's nothing to hide.
+        Some(room_key) if packet.tag() != 1 => Ok((
+            packet.tag() | SEALED_TAG_FLAG,
+            room_key.seal(packet.tag(), &data)?,
+        )),
+        _ => Ok((packet.tag(), data)),
+    }
+}
+
+/// Opposite of `encode`. <br>
+/// Packets sealed for other passphrases, or not sealed while we have one,
+/// are skipped quietly. So are packets of rooms that aren't in `rooms`,
+/// before they are deserialized. `None` lets packets of every room through.
+pub fn decode(
+    tag: Tag, data: &[u8], room_key: Option<&RoomKey>, rooms: Option<&HashSet<String>>,
+) -> Result<Option<Packet>, KtpError> {
+    let is_sealed = tag & SEALED_TAG_FLAG != 0;
+    let tag = tag & !SEALED_TAG_FLAG;
+
+    let data = match (is_sealed, room_key) {
+        (true, Some(room_key)) => match room_key.open(tag, data) {
+            Some(data) => data,
+            None => {
+                log::debug!("Skipped packet that can't be opened with the room key.");
+                return Ok(None);
+            },
+        },
+        (false, Some(_)) if tag != 1 => return Ok(None),
+        (true, None) => return Ok(None),
+        (false, _) => data.to_vec(),
+    };
+
+    if rooms.is_some_and(|rooms| is_other_room(tag, &data, rooms)) {
+        log::trace!("Skipped packet of a room we aren't in.");
+        return Ok(None);
+    }
+
+    Packet::deserialize(tag, &data).map(Some)
+}
+
+/// Splits the encoded packet into KTP frames. <br>
+/// Every frame consists of the packet prefix, Version, Tag, Seq, Total, Id
+/// and a part of the data, up to `part_size` bytes. <br>
+/// Packets, that don't fit into 256 frames, take extended frames with two-byte
+/// Seq and Total, if they are allowed. Their parts are two bytes shorter.
+pub fn fragment(
+    tag: Tag, data: &[u8], part_size: usize, allow_extended: bool,
+) -> Result<Vec<Vec<u8>>, NetError> {
+    // Older versions only reassemble packets of up to 256 frames and `MAX_PACKET_SIZE`.
+    let is_extended = data.len() > MAX_PACKET_SIZE
+        || data.len().div_ceil(part_size) > u8::MAX as usize + 1;
+    if is_extended && !allow_extended {
+        return Err(NetError::MessageTooLong);
+    }
+    let part_size = match is_extended {
+        true => part_size - (EXTENDED_PACKET_HEADER_SIZE - PACKET_HEADER_SIZE),
+        false => part_size,
+    };
+    let mut parts: Vec<&[u8]> = data.chunks(part_size).collect();
+
+    // In case of PresenceBroadcastRequest packet.
+    if tag == 1 {
+        parts.push(b".");
+    }
+
+    if parts.len() > MAX_EXTENDED_PARTS {
+        return Err(NetError::MessageTooLong);
+    }
+
+    let total = parts.len() - 1;
+    let id: Id = generate_id();
+    let frames = parts
+        .into_iter()
+        .enumerate()
+        .map(|(seq, part)| match is_extended {
+            false => [
+                PACKET_PREFIX,
+                &[PROTOCOL_VERSION, tag, seq as u8, total as u8],
+                &id,
+                part,
+            ]
+            .concat(),
+            true => [
+                EXTENDED_PACKET_PREFIX,
+                &[PROTOCOL_VERSION, tag],
+                &(seq as Seq).to_be_bytes(),
+                &(total as Total).to_be_bytes(),
+                &id,
+                part,
+            ]
+            .concat(),
+        })
+        .collect();
+
+    Ok(frames)
+}
+
+/// Header fields of a KTP frame, along with its part of the data.
+pub struct Frame<'a> {
+    pub version: Version,
+    pub tag: Tag,
+    pub seq: Seq,
+    pub total: Total,
+    pub id: Id,
+    pub part: &'a [u8],
+}
+
+/// Returns `None` for data without a KTP prefix. <br>
+/// Never panics, whatever the data is.
+pub fn parse_frame(data: &[u8]) -> Result<Option<Frame<'_>>, KtpError> {
+    let (version, tag, seq, total, rest) =
+        if let Some(frame) = data.strip_prefix(PACKET_PREFIX) {
+            let &[version, tag, seq, total, ref rest @ ..] = frame else {
+                return Err(KtpError::Truncated);
+            };
+            (version, tag, seq as Seq, total as Total, rest)
+        } else if let Some(frame) = data.strip_prefix(EXTENDED_PACKET_PREFIX) {
+            let &[
+                version,
+                tag,
+                seq_high,
+                seq_low,
+                total_high,
+                total_low,
+                ref rest @ ..,
+            ] = frame
+            else {
+                return Err(KtpError::Truncated);
+            };
+            if version < EXTENDED_FRAMES_VERSION {
+                return Err(KtpError::UnsupportedVersion(version));
+            }
+            (
+                version,
+                tag,
+                Seq::from_be_bytes([seq_high, seq_low]),
+                Total::from_be_bytes([total_high, total_low]),
+                rest,
+            )
+        } else {
+            return Ok(None);
+        };
+    let (id, part) = split_array(rest)?;
+
+    Ok(Some(Frame {
+        version,
+        tag,
+        seq,
+        total,
+        id,
+        part,
+    }))
+}
+
+/// Id of the packet the KTP frame is a part of.
+pub fn frame_id(frame: &[u8]) -> Option<Id> {
+    parse_frame(frame).ok().flatten().map(|frame| frame.id)
+}
+
 pub fn generate_id() -> Id {
     rand::random()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn packets() -> impl Strategy<Value = Packet> {
+        let id = any::<Id>();
+        let counter = any::<Counter>();
+        let signature = prop::array::uniform::<_, 64>(any::<u8>());
+        let text = "\\PC{0,300}";
+        // Empty for the main room.
+        let room = "[a-z]{0,20}";
+
+        prop_oneof![
+            (
+                (id, counter, room),
+                (id, prop::option::of(id)),
+                any::<bool>(),
+                text,
+                signature
+            )
+                .prop_map(
+                    |(
+                        (id, counter, room),
+                        (message_id, reply_to),
+                        is_compressed,
+                        message_text,
+                        signature,
+                    )| {
+                        Packet::Message {
+                            id,
+                            counter,
+                            room,
+                            message_id,
+                            reply_to,
+                            compression: if is_compressed {
+                                Compression::Smaz
+                            } else {
+                                Compression::None
+                            },
+                            message_text,
+                            signature,
+                        }
+                    }
+                ),
+            Just(Packet::PresenceBroadcastRequest),
+            (
+                (id, counter, room),
+                (any::<bool>(), any::<Version>(), any::<u32>()),
+                text,
+                any::<PublicKey>(),
+                signature
+            )
+                .prop_map(
+                    |(
+                        (id, counter, room),
+                        (is_join, version, bits),
+                        username,
+                        public_key,
+                        signature,
+                    )| {
+                        Packet::PresenceInformation {
+                            id,
+                            counter,
+                            room,
+                            is_join,
+                            version,
+                            capabilities: Capabilities::from_bits(bits),
+                            username,
+                            public_key,
+                            signature,
+                        }
+                    }
+                ),
+            (id, counter, room, signature).prop_map(|(id, counter, room, signature)| {
+                Packet::Disconnect {
+                    id,
+                    counter,
+                    room,
+                    signature,
+                }
+            }),
+            (id, counter, id, prop::collection::vec(any::<u8>(), 0..300)).prop_map(
+                |(id, counter, target, ciphertext)| Packet::DirectMessage {
+                    id,
+                    counter,
+                    target,
+                    ciphertext,
+                }
+            ),
+            (
+                id,
+                counter,
+                id,
+                any::<bool>(),
+                any::<PublicKey>(),
+                signature
+            )
+                .prop_map(
+                    |(id, counter, target, is_reply, public_key, signature)| {
+                        Packet::KeyExchange {
+                            id,
+                            counter,
+                            target,
+                            is_reply,
+                            public_key,
+                            signature,
+                        }
+                    }
+                ),
+            (id, counter, id, id, signature).prop_map(
+                |(id, counter, target, message_id, signature)| Packet::Ack {
+                    id,
+                    counter,
+                    target,
+                    message_id,
+                    signature,
+                }
+            ),
+            (id, prop::collection::vec(any::<Seq>(), 0..256))
+                .prop_map(|(frame_id, missing)| Packet::Nack { frame_id, missing }),
+            (
+                id,
+                counter,
+                (id, id),
+                any::<u64>(),
+                any::<Hash>(),
+                text,
+                signature
+            )
+                .prop_map(
+                    |(
+                        id,
+                        counter,
+                        (target, transfer_id),
+                        size,
+                        hash,
+                        name,
+                        signature,
+                    )| {
+                        Packet::FileOffer {
+                            id,
+                            counter,
+                            target,
+                            transfer_id,
+                            size,
+                            hash,
+                            name,
+                            signature,
+                        }
+                    }
+                ),
+            (id, counter, id, id, any::<u64>(), signature).prop_map(
+                |(id, counter, target, transfer_id, offset, signature)| {
+                    Packet::FileAccept {
+                        id,
+                        counter,
+                        target,
+                        transfer_id,
+                        offset,
+                        signature,
+                    }
+                }
+            ),
+            (
+                id,
+                counter,
+                id,
+                id,
+                any::<u64>(),
+                prop::collection::vec(any::<u8>(), 0..300)
+            )
+                .prop_map(
+                    |(id, counter, target, transfer_id, offset, data)| {
+                        Packet::FileChunk {
+                            id,
+                            counter,
+                            target,
+                            transfer_id,
+                            offset,
+                            data,
+                        }
+                    }
+                ),
+            (
+                id,
+                counter,
+                prop::collection::vec(("[a-z]{1,20}", any::<u64>(), id, text), 0..4),
+                signature
+            )
+                .prop_map(|(id, counter, rooms, signature)| {
+                    Packet::RoomAnnounce {
+                        id,
+                        counter,
+                        rooms: rooms
+                            .into_iter()
+                            .map(|(room, revision, setter, topic)| RoomTopic {
+                                room,
+                                revision,
+                                setter,
+                                topic,
+                            })
+                            .collect(),
+                        signature,
+                    }
+                }),
+            ((id, counter, room), id, text, signature).prop_map(
+                |((id, counter, room), message_id, message_text, signature)| {
+                    Packet::EditMessage {
+                        id,
+                        counter,
+                        room,
+                        message_id,
+                        message_text,
+                        signature,
+                    }
+                }
+            ),
+            (id, counter, room, id, signature).prop_map(
+                |(id, counter, room, message_id, signature)| Packet::DeleteMessage {
+                    id,
+                    counter,
+                    room,
+                    message_id,
+                    signature,
+                }
+            ),
+            (id, counter, room, signature).prop_map(|(id, counter, room, signature)| {
+                Packet::Typing {
+                    id,
+                    counter,
+                    room,
+                    signature,
+                }
+            }),
+            (id, counter, id, id, signature, any::<bool>()).prop_map(
+                |(id, counter, target, transfer_id, signature, is_complete)| {
+                    if is_complete {
+                        Packet::FileComplete {
+                            id,
+                            counter,
+                            target,
+                            transfer_id,
+                            signature,
+                        }
+                    } else {
+                        Packet::FileCancel {
+                            id,
+                            counter,
+                            target,
+                            transfer_id,
+                            signature,
+                        }
+                    }
+                }
+            ),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn packets_survive_serialization(packet in packets()) {
+            prop_assert_eq!(
+                Packet::deserialize(packet.tag(), &packet.serialize()),
+                Ok(packet)
+            );
+        }
+
+        #[test]
+        fn deserialize_never_panics(
+            tag: Tag,
+            data in prop::collection::vec(any::<u8>(), 0..600),
+        ) {
+            let _ = Packet::deserialize(tag, &data);
+            let _ = decode(tag, &data, None, None);
+        }
+
+        #[test]
+        fn decompress_never_panics(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = decompress(&data);
+        }
+    }
+
+    // How clients from before the version read a frame, tag first.
+    fn parse_unversioned(data: &[u8]) -> Option<(Tag, u8, u8)> {
+        let &[tag, seq, total, ..] = data.strip_prefix(b"ktp".as_slice())? else {
+            return None;
+        };
+        Some((tag, seq, total))
+    }
+
+    #[test]
+    fn unversioned_clients_skip_versioned_frames() {
+        let data = vec![7; MAX_PACKET_SIZE + 1];
+        for tag in 0..=20 {
+            let frames = [
+                fragment(tag, &data[..100], PACKET_DATA_SIZE, false).unwrap(),
+                fragment(tag, &data, PACKET_DATA_SIZE, true).unwrap(),
+            ];
+            for frame in frames.iter().flatten() {
+                assert_eq!(parse_unversioned(frame), None);
+                assert!(parse_frame(frame).unwrap().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn packets_of_other_rooms_are_skipped() {
+        let packet = Packet::Disconnect {
+            id: [1; 8],
+            counter: 7,
+            room: "ops".into(),
+            signature: [0; 64],
+        };
+        let (tag, data) = encode(&packet, None).unwrap();
+        let rooms = HashSet::from(["ops".to_string()]);
+
+        assert_eq!(decode(tag, &data, None, Some(&rooms)), Ok(Some(packet)));
+        assert_eq!(decode(tag, &data, None, Some(&HashSet::new())), Ok(None));
+    }
+
+    #[test]
+    fn packets_of_other_rooms_are_told_by_their_first_frame() {
+        let packet = Packet::Message {
+            id: [1; 8],
+            counter: 7,
+            room: "ops".into(),
+            message_id: [2; 8],
+            reply_to: None,
+            compression: Compression::None,
+            message_text: "x".repeat(PACKET_DATA_SIZE * 2),
+            signature: [0; 64],
+        };
+        let (tag, data) = encode(&packet, None).unwrap();
+        let frames = fragment(tag, &data, PACKET_DATA_SIZE, false).unwrap();
+        let first = parse_frame(&frames[0]).unwrap().unwrap();
+        let second = parse_frame(&frames[1]).unwrap().unwrap();
+
+        let rooms = HashSet::from(["dev".to_string()]);
+        assert!(starts_other_room(&first, &rooms));
+        assert!(!starts_other_room(&second, &rooms));
+        assert!(!starts_other_room(
+            &first,
+            &HashSet::from(["ops".to_string()])
+        ));
+    }
+
+    #[test]
+    fn room_names_must_fit_their_length_byte() {
+        let packet = Packet::Disconnect {
+            id: [1; 8],
+            counter: 7,
+            room: "x".repeat(MAX_ROOM_NAME_SIZE + 1),
+            signature: [0; 64],
+        };
+        assert!(matches!(
+            encode(&packet, None),
+            Err(NetError::RoomNameTooLong)
+        ));
+
+        let packet = Packet::RoomAnnounce {
+            id: [1; 8],
+            counter: 7,
+            rooms: vec![RoomTopic {
+                room: "x".repeat(MAX_ROOM_NAME_SIZE + 1),
+                revision: 0,
+                setter: [0; 8],
+                topic: String::new(),
+            }],
+            signature: [0; 64],
+        };
+        assert!(matches!(
+            encode(&packet, None),
+            Err(NetError::RoomNameTooLong)
+        ));
+    }
+
+    #[test]
+    fn malformed_packets_are_typed_errors() {
+        let header = [[1; 8].as_slice(), &7u64.to_be_bytes()].concat();
+        let signature = [0; 64];
+
+        assert_eq!(Packet::deserialize(42, &[]), Err(KtpError::BadTag(42)));
+        assert_eq!(Packet::deserialize(3, &[0; 10]), Err(KtpError::Truncated));
+        assert_eq!(
+            Packet::deserialize(3, &[header.as_slice(), &[0], &signature].concat()),
+            Err(KtpError::TrailingData)
+        );
+        assert_eq!(
+            Packet::deserialize(
+                0,
+                &[header.as_slice(), &[0; 8], &[1, 255], &signature].concat()
+            ),
+            Err(KtpError::DecompressionFailed)
+        );
+        assert_eq!(
+            Packet::deserialize(
+                0,
+                &[header.as_slice(), &[0; 8], &[1, 254, 0xff], &signature].concat()
+            ),
+            Err(KtpError::BadUtf8)
+        );
+        assert_eq!(
+            Packet::deserialize(
+                0,
+                &[header.as_slice(), &[0; 8], &[9], &signature].concat()
+            ),
+            Err(KtpError::BadCompression(9))
+        );
+    }
+}
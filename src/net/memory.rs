@@ -0,0 +1,257 @@
+use crate::net::channel::Channel;
+use crate::net::clock::{Clock, SystemClock};
+use crate::net::ktp;
+use crate::net::transport::Transport;
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use pnet::util::MacAddr;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Faults applied to every frame on its way to every single receiver.
+/// Probabilities are in range 0.0..=1.0.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Faults {
+    pub loss: f64,
+    pub duplication: f64,
+    pub reordering: f64,
+    pub max_delay: Duration,
+}
+
+struct Frame {
+    deliver_at: Instant,
+    data: Vec<u8>,
+}
+
+struct SegmentState {
+    // None for detached links.
+    queues: Vec<Option<VecDeque<Frame>>>,
+    faults: Faults,
+    rng: StdRng,
+}
+
+/// Virtual broadcast segment. <br>
+/// Every frame sent over an attached link is delivered to all of them,
+/// including the sender, just like on a hub. Receivers drop frames
+/// for somebody else by their MAC address, as they do on a real LAN.
+#[derive(Clone)]
+pub struct MemorySegment {
+    state: Arc<Mutex<SegmentState>>,
+    clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl Default for MemorySegment {
+    fn default() -> Self {
+        Self::new(SystemClock, 0)
+    }
+}
+
+impl MemorySegment {
+    /// Faults are drawn from a generator seeded with `seed`,
+    /// so the same seed always gives the same losses and delays.
+    pub fn new(clock: impl Clock + Send + Sync + 'static, seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SegmentState {
+                queues: vec![],
+                faults: Faults::default(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+            clock: Arc::new(clock),
+        }
+    }
+
+    /// The link gets a locally administered MAC address of its own,
+    /// the rest is the same channel as over a real interface.
+    pub fn attach(&self) -> Channel {
+        let index = {
+            let mut state = self.lock();
+            state.queues.push(Some(VecDeque::new()));
+            state.queues.len() - 1
+        };
+        let [.., high, low] = (index as u64 + 1).to_be_bytes();
+
+        Channel::new(
+            MacAddr::new(0x02, 0, 0, 0, high, low),
+            Box::new(MemorySender {
+                segment: self.clone(),
+            }),
+            Box::new(MemoryReceiver {
+                segment: self.clone(),
+                index,
+                frame: vec![],
+            }),
+            self.clock.clone(),
+        )
+    }
+
+    /// Puts a raw frame on the segment, as if some other host sent it.
+    pub fn inject(&self, frame: &[u8]) {
+        self.broadcast(frame);
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        self.lock().faults = faults;
+    }
+
+    /// Whether any attached link has a frame ready to be received.
+    pub fn has_deliverable(&self) -> bool {
+        let now = self.clock.now();
+        self.lock()
+            .queues
+            .iter()
+            .flatten()
+            .any(|queue| queue.iter().any(|frame| frame.deliver_at <= now))
+    }
+
+    fn broadcast(&self, data: &[u8]) {
+        let now = self.clock.now();
+        let mut state = self.lock();
+        let SegmentState {
+            queues,
+            faults,
+            rng,
+        } = &mut *state;
+
+        for queue in queues.iter_mut().flatten() {
+            if rng.random_bool(faults.loss) {
+                continue;
+            }
+
+            let copies = if rng.random_bool(faults.duplication) {
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let frame = Frame {
+                    deliver_at: now + rng.random_range(Duration::ZERO..=faults.max_delay),
+                    data: data.to_vec(),
+                };
+
+                if !queue.is_empty() && rng.random_bool(faults.reordering) {
+                    let position = rng.random_range(0..queue.len());
+                    queue.insert(position, frame);
+                } else {
+                    queue.push_back(frame);
+                }
+            }
+        }
+    }
+
+    fn pop(&self, index: usize) -> Option<Vec<u8>> {
+        let now = self.clock.now();
+        let mut state = self.lock();
+        let queue = state.queues[index].as_mut()?;
+
+        let position = queue.iter().position(|frame| frame.deliver_at <= now)?;
+        queue.remove(position).map(|frame| frame.data)
+    }
+
+    fn detach(&self, index: usize) {
+        self.lock().queues[index] = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SegmentState> {
+        self.state.lock().expect("Memory segment is poisoned")
+    }
+}
+
+// More than any test puts on the segment at once.
+const MAX_PENDING_FRAMES: usize = 1000;
+
+/// Packets assembled from the frames that are ready for the channel. <br>
+/// A single `try_recv` reads one frame, which may be a part of a packet.
+/// Their senders are taken at their word, and their addresses learned.
+pub fn receive_all(channel: &mut Channel) -> Vec<ktp::Packet> {
+    (0..MAX_PENDING_FRAMES)
+        .filter_map(|_| {
+            let packet = channel.try_recv().expect("Memory links don't fail")?;
+            if let Some(sender) = packet.sender() {
+                channel.learn_peer(&sender);
+            }
+            Some(packet)
+        })
+        .collect()
+}
+
+/// Sending half of a link, every frame goes to the whole segment.
+struct MemorySender {
+    segment: MemorySegment,
+}
+
+impl DataLinkSender for MemorySender {
+    fn build_and_send(
+        &mut self, num_packets: usize, packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut frame = vec![0; packet_size];
+            func(&mut frame);
+            self.segment.broadcast(&frame);
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self, packet: &[u8], _dst: Option<NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        self.segment.broadcast(packet);
+        Some(Ok(()))
+    }
+}
+
+/// Receiving half of a link, it reads the frames of the segment
+/// like a capture of a real interface does.
+struct MemoryReceiver {
+    segment: MemorySegment,
+    index: usize,
+    frame: Vec<u8>,
+}
+
+impl DataLinkReceiver for MemoryReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        // Same as the read timeout of a capture with nothing to read.
+        self.frame = self
+            .segment
+            .pop(self.index)
+            .ok_or_else(|| io::Error::from(ErrorKind::TimedOut))?;
+        Ok(&self.frame)
+    }
+}
+
+impl Drop for MemoryReceiver {
+    fn drop(&mut self) {
+        self.segment.detach(self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcast_reaches_every_node() {
+        let segment = MemorySegment::default();
+        let mut alice = segment.attach();
+        let mut bob = segment.attach();
+
+        let packet = ktp::Packet::Message {
+            id: ktp::generate_id(),
+            counter: 1,
+            room: String::new(),
+            message_id: ktp::generate_id(),
+            reply_to: None,
+            compression: ktp::Compression::Smaz,
+            message_text: (0..1000).map(|i| i.to_string()).collect(),
+            signature: [0; 64],
+        };
+        alice.try_send(packet.clone()).unwrap();
+
+        assert_eq!(receive_all(&mut alice), vec![packet.clone()]);
+        assert_eq!(receive_all(&mut bob), vec![packet]);
+        assert_eq!(receive_all(&mut bob), vec![]);
+    }
+}
@@ -0,0 +1,248 @@
+//! On IPv6 networks Neighbor Discovery is what every host must accept. <br>
+//! KTP frames are carried in an experimental option of Neighbor Solicitations,
+//! sent to the all-nodes multicast address.
+
+use crate::error::ktp::KtpError;
+use crate::net::ethernet;
+use crate::net::ethernet::Frame;
+use constants::*;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::util::MacAddr;
+use std::borrow::Cow;
+use std::net::Ipv6Addr;
+
+/// Numbers of Ethernet, IPv6 and Neighbor Discovery, RFC 4861.
+pub mod constants {
+    use std::net::Ipv6Addr;
+
+    // EtherType of IPv6
+    pub const ETHER_TYPE_IPV6: &[u8] = &[0x86, 0xDD];
+
+    // Link-layer address of the all-nodes multicast group, RFC 2464.
+    pub const ALL_NODES_MAC: [u8; 6] = [0x33, 0x33, 0x00, 0x00, 0x00, 0x01];
+    pub const ALL_NODES_ADDRESS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+    pub const NEXT_HEADER_ICMPV6: u8 = 58;
+
+    // Neighbor Discovery packets with any other hop limit came from another link.
+    pub const HOP_LIMIT: u8 = 255;
+
+    pub const TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+
+    pub const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+    // Option type reserved for experiments, RFC 4727.
+    pub const OPTION_KTP: u8 = 253;
+    // Options are measured in units of 8 bytes.
+    pub const OPTION_UNIT: usize = 8;
+    // Type, length and the length of KTP data in bytes.
+    pub const KTP_OPTION_HEADER_SIZE: usize = 4;
+
+    pub const ETHERNET_HEADER_SIZE: usize = 14;
+    pub const IPV6_HEADER_SIZE: usize = 40;
+    // Type, code, checksum, reserved field and target address.
+    pub const NEIGHBOR_SOLICITATION_SIZE: usize = 24;
+}
+
+// Fits into the MTU with the IPv6 and ICMPv6 headers, same as Ethernet II frames.
+pub const PART_SIZE: usize = ethernet::PART_SIZE;
+
+/// Link-local address derived from the MAC address, RFC 4291.
+pub fn link_local(mac: MacAddr) -> Ipv6Addr {
+    let o = mac.octets();
+    Ipv6Addr::from([
+        0xfe,
+        0x80,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        o[0] ^ 0x02,
+        o[1],
+        o[2],
+        0xff,
+        0xfe,
+        o[3],
+        o[4],
+        o[5],
+    ])
+}
+
+/// Packets for a single peer are sent to its MAC address, the rest to all nodes.
+pub fn build(target_mac: MacAddr, source_mac: MacAddr, data: &[u8]) -> Vec<u8> {
+    let source = link_local(source_mac);
+
+    let units = (KTP_OPTION_HEADER_SIZE + data.len()).div_ceil(OPTION_UNIT);
+    let mut ktp_option = [
+        &[OPTION_KTP, units as u8],
+        (data.len() as u16).to_be_bytes().as_slice(),
+        data,
+    ]
+    .concat();
+    ktp_option.resize(units * OPTION_UNIT, 0);
+
+    let mut icmp = [
+        &[TYPE_NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0],
+        source.octets().as_slice(),
+        &[OPTION_SOURCE_LINK_LAYER_ADDRESS, 1],
+        &source_mac.octets(),
+        &ktp_option,
+    ]
+    .concat();
+    let checksum = checksum(&source, &ALL_NODES_ADDRESS, &icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let ipv6_header = [
+        &[0x60, 0, 0, 0],
+        (icmp.len() as u16).to_be_bytes().as_slice(),
+        &[NEXT_HEADER_ICMPV6, HOP_LIMIT],
+        &source.octets(),
+        &ALL_NODES_ADDRESS.octets(),
+    ]
+    .concat();
+
+    let destination = match target_mac {
+        mac if mac.is_zero() => MacAddr::from(ALL_NODES_MAC),
+        mac => mac,
+    };
+    ethernet::build(
+        destination,
+        source_mac,
+        ETHER_TYPE_IPV6,
+        &[ipv6_header, icmp].concat(),
+    )
+}
+
+/// Returns `None` for anything but Neighbor Solicitations with KTP data. <br>
+/// Never panics, whatever the frame is.
+pub fn parse(ethernet_frame: &[u8]) -> Result<Option<Frame<'_>>, KtpError> {
+    let Some(ethernet) = EthernetPacket::new(ethernet_frame) else {
+        return Ok(None);
+    };
+    // Early filter for the rest of IPv6 traffic, which is most of it.
+    if &ethernet_frame[12..ETHERNET_HEADER_SIZE] != ETHER_TYPE_IPV6 {
+        return Ok(None);
+    }
+    let ipv6 = &ethernet_frame[ETHERNET_HEADER_SIZE..];
+    let Some(icmp) = ipv6.get(IPV6_HEADER_SIZE..) else {
+        return Ok(None);
+    };
+    if ipv6[6] != NEXT_HEADER_ICMPV6
+        || ipv6[7] != HOP_LIMIT
+        || icmp.first() != Some(&TYPE_NEIGHBOR_SOLICITATION)
+    {
+        return Ok(None);
+    }
+
+    let Some(data) = ktp_option(icmp.get(NEIGHBOR_SOLICITATION_SIZE..).unwrap_or(&[]))?
+    else {
+        return Ok(None);
+    };
+
+    // Multicast goes to everybody.
+    let target_mac = match ethernet.get_destination() {
+        mac if mac.octets()[..2] == ALL_NODES_MAC[..2] => MacAddr::zero(),
+        mac => mac,
+    };
+
+    Ok(Some(Frame {
+        sender_mac: ethernet.get_source(),
+        target_mac,
+        data: Cow::Borrowed(data),
+    }))
+}
+
+// Solicitations of the system itself have no KTP option, malformed ones are skipped.
+fn ktp_option(mut options: &[u8]) -> Result<Option<&[u8]>, KtpError> {
+    while let &[option_type, units, ..] = options {
+        if units == 0 {
+            return Ok(None);
+        }
+        let Some(option) = options.get(..units as usize * OPTION_UNIT) else {
+            return Ok(None);
+        };
+
+        if option_type == OPTION_KTP {
+            let (header, data) = option
+                .split_first_chunk::<KTP_OPTION_HEADER_SIZE>()
+                .ok_or(KtpError::Truncated)?;
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            return data.get(..len).map(Some).ok_or(KtpError::Truncated);
+        }
+        options = &options[option.len()..];
+    }
+
+    Ok(None)
+}
+
+// Internet checksum over the IPv6 pseudo-header and the ICMPv6 message, RFC 4443.
+fn checksum(source: &Ipv6Addr, destination: &Ipv6Addr, icmp: &[u8]) -> u16 {
+    let pseudo_header = [
+        source.octets().as_slice(),
+        &destination.octets(),
+        &(icmp.len() as u32).to_be_bytes(),
+        &[0, 0, 0, NEXT_HEADER_ICMPV6],
+    ]
+    .concat();
+
+    let sum = [pseudo_header.as_slice(), icmp]
+        .concat()
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    let folded = (sum & 0xffff) + (sum >> 16);
+    !((folded & 0xffff) + (folded >> 16)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(data in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = parse(&data);
+
+            // Random frames stop at the EtherType, these go up to the options.
+            let mut solicitation = data;
+            solicitation.resize(solicitation.len().max(78), 0);
+            solicitation[12..14].copy_from_slice(ETHER_TYPE_IPV6);
+            solicitation[20] = NEXT_HEADER_ICMPV6;
+            solicitation[21] = HOP_LIMIT;
+            solicitation[54] = TYPE_NEIGHBOR_SOLICITATION;
+            let _ = parse(&solicitation);
+        }
+    }
+
+    #[test]
+    fn ktp_data_survives_solicitation() {
+        let sender_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let frame = build(MacAddr::zero(), sender_mac, b"ktp frame");
+
+        let parsed = parse(&frame).unwrap().unwrap();
+        assert_eq!(*parsed.data, *b"ktp frame");
+        assert_eq!(parsed.sender_mac, sender_mac);
+        assert_eq!(parsed.target_mac, MacAddr::zero());
+
+        // The checksum of a correct message sums up to zero.
+        let icmp = &frame[ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE..];
+        assert_eq!(
+            checksum(&link_local(sender_mac), &ALL_NODES_ADDRESS, icmp),
+            0
+        );
+        assert_eq!((frame.len() - ETHERNET_HEADER_SIZE) % OPTION_UNIT, 0);
+    }
+
+    #[test]
+    fn solicitation_without_ktp_is_skipped() {
+        let sender_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let mut frame = build(MacAddr::zero(), sender_mac, b"ktp frame");
+        let options =
+            ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + NEIGHBOR_SOLICITATION_SIZE;
+        frame.truncate(options + OPTION_UNIT);
+
+        assert!(parse(&frame).unwrap().is_none());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::net::ktp;
+use std::collections::HashMap;
+
+// Addresses of more peers than this aren't learned, until some leave.
+pub const MAX_PEERS: usize = 1024;
+
+/// Where the packets for a single peer go, on carriers with addresses. <br>
+/// Any host can send a packet under any id, so the address a packet came from
+/// is only learned once the net thread verified it, see `Transport::learn_peer`.
+pub struct PeerAddresses<A> {
+    addresses: HashMap<ktp::Id, A>,
+    // Sender of the last received packet, with the address it came from.
+    last_heard: Option<(ktp::Id, A)>,
+}
+
+impl<A> Default for PeerAddresses<A> {
+    fn default() -> Self {
+        Self {
+            addresses: HashMap::new(),
+            last_heard: None,
+        }
+    }
+}
+
+impl<A: Copy + PartialEq> PeerAddresses<A> {
+    pub fn get(&self, id: &ktp::Id) -> Option<A> {
+        self.addresses.get(id).copied()
+    }
+
+    /// Whether a peer other than the one with the id is at the same address.
+    pub fn is_shared(&self, id: &ktp::Id, address: A) -> bool {
+        self.addresses
+            .iter()
+            .any(|(other_id, other)| other_id != id && *other == address)
+    }
+
+    /// Whether the last received packet came from the learned address of the peer.
+    pub fn is_last_from(&self, id: &ktp::Id) -> bool {
+        self.last_heard.is_some_and(|(sender, address)| {
+            sender == *id && self.get(id) == Some(address)
+        })
+    }
+
+    /// Remembers where the packet with the sender came from, until the next one.
+    pub fn heard(&mut self, sender: Option<ktp::Id>, address: A) {
+        self.last_heard = sender.map(|sender| (sender, address));
+    }
+
+    /// Learns the address of the last received packet, if the peer sent it.
+    pub fn learn(&mut self, id: &ktp::Id) {
+        let Some((sender, address)) = self.last_heard else {
+            return;
+        };
+        if sender != *id {
+            return;
+        }
+        if self.addresses.len() >= MAX_PEERS && !self.addresses.contains_key(id) {
+            log::debug!("Didn't learn the address of {id:x?}, too many peers.");
+            return;
+        }
+
+        self.addresses.insert(sender, address);
+    }
+
+    pub fn forget(&mut self, id: &ktp::Id) {
+        self.addresses.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_last_sender_is_learned() {
+        let mut peers = PeerAddresses::default();
+        let (alice, mallory) = ([1; 8], [2; 8]);
+
+        peers.heard(Some(alice), 1);
+        peers.heard(Some(alice), 2);
+        peers.learn(&mallory);
+        assert_eq!(peers.get(&mallory), None);
+        peers.learn(&alice);
+        assert_eq!(peers.get(&alice), Some(2));
+        assert!(peers.is_last_from(&alice));
+        peers.heard(Some(alice), 1);
+        assert!(!peers.is_last_from(&alice));
+
+        peers.heard(None, 3);
+        peers.learn(&alice);
+        assert_eq!(peers.get(&alice), Some(2));
+
+        peers.forget(&alice);
+        assert_eq!(peers.get(&alice), None);
+    }
+
+    #[test]
+    fn peers_are_bounded() {
+        let mut peers = PeerAddresses::default();
+        for id in 0..MAX_PEERS as u64 * 2 {
+            peers.heard(Some(id.to_be_bytes()), id);
+            peers.learn(&id.to_be_bytes());
+        }
+
+        assert_eq!(peers.addresses.len(), MAX_PEERS);
+    }
+}
@@ -4,6 +4,18 @@ pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
 pub const INACTIVE_TIMEOUT: Duration = Duration::from_secs(6);
 pub const OFFLINE_TIMEOUT: Duration = Duration::from_secs(12);
 
+/// What is known about the peer behind a presence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trust {
+    /// Signed with the key the id is derived from.
+    Verified,
+    /// Verified, and the user compared the fingerprint out of band.
+    Trusted,
+    /// Verified too, but another online peer had the username first.
+    Impersonator,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum UpdatePresenceKind {
     Boring,
     JoinOrReconnect,
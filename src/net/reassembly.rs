@@ -0,0 +1,602 @@
+use crate::error::ktp::KtpError;
+use crate::net::ktp;
+use crate::net::replay::RecentIds;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+// Ids of this many completed packets are remembered to drop their duplicates.
+const RECENT_IDS_CAPACITY: usize = 1024;
+
+// Frames of one packet are sent back to back, a longer silence means a loss.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_millis(250);
+// Unfinished packets are dropped after this many unanswered NACKs.
+pub const MAX_NACKS: u8 = 4;
+// Unfinished packets are dropped this long after their first frame, NACKs or not.
+pub const REASSEMBLY_TTL: Duration = Duration::from_secs(5);
+// Packets of many parts take longer to arrive, they get this much per part.
+pub const PART_TTL: Duration = Duration::from_millis(10);
+
+// Enough for a couple of the largest packets from one host.
+// Raised along with the largest packet, see `Reassembler::with_max_packet_size`.
+pub const MAX_SOURCE_BYTES: usize = 128 * 1024;
+pub const MAX_BUFFER_BYTES: usize = 1024 * 1024;
+// Every slot of a part is charged even before the part arrives.
+const PART_SLOT_BYTES: usize = size_of::<Vec<u8>>();
+
+// Packets of this many last sends are kept to answer NACKs.
+pub const FRAGMENT_CACHE_SIZE: usize = 64;
+// A frame is sent again at most once this often, however many NACKs ask for it.
+pub const NACK_ANSWER_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Partial<S> {
+    source: S,
+    parts: Vec<Vec<u8>>,
+    bytes: usize,
+    first_seen: Instant,
+    last_update: Instant,
+    nacks: u8,
+}
+
+#[derive(Default)]
+struct Source {
+    bytes: usize,
+    // Ids in the order of arrival, may hold ids that are already gone.
+    order: VecDeque<ktp::Id>,
+}
+
+/// Incomplete packets dropped so far, by reason.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Evictions {
+    pub expired: u64,
+    pub source_cap: u64,
+    pub global_cap: u64,
+}
+
+/// Collects KTP frames until all parts of a packet have arrived. <br>
+/// Shared by every transport, so they only have to deal with their own framing. <br>
+/// Returns the tag and the encoded data, `ktp::decode` makes a packet out of them. <br>
+/// Incomplete packets take memory, so they expire and are limited in bytes per
+/// source `S` and in total. The oldest ones are evicted first.
+pub struct Reassembler<S> {
+    buffer: HashMap<ktp::Id, Partial<S>>,
+    bytes: usize,
+    // Ids in the order of arrival, may hold ids that are already gone.
+    order: VecDeque<ktp::Id>,
+    sources: HashMap<S, Source>,
+    evictions: Evictions,
+    max_source_bytes: usize,
+    max_buffer_bytes: usize,
+
+    recent: RecentIds,
+}
+
+impl<S> Default for Reassembler<S> {
+    fn default() -> Self {
+        Self {
+            buffer: HashMap::new(),
+            bytes: 0,
+            order: VecDeque::new(),
+            sources: HashMap::new(),
+            evictions: Evictions::default(),
+            max_source_bytes: MAX_SOURCE_BYTES,
+            max_buffer_bytes: MAX_BUFFER_BYTES,
+            recent: RecentIds::with_capacity(RECENT_IDS_CAPACITY),
+        }
+    }
+}
+
+impl<S> Reassembler<S> {
+    /// Limits leave room for a couple of packets of `max_packet_size` per source.
+    pub fn with_max_packet_size(max_packet_size: usize) -> Self {
+        let max_source_bytes = MAX_SOURCE_BYTES.max(2 * max_packet_size);
+        Self {
+            max_source_bytes,
+            max_buffer_bytes: MAX_BUFFER_BYTES.max(4 * max_source_bytes),
+            ..Self::default()
+        }
+    }
+}
+
+impl<S: Copy + Eq + Hash + Debug> Reassembler<S> {
+    /// Never panics, whatever the frame is. <br>
+    /// Frames without the KTP prefix are skipped quietly.
+    pub fn accept(
+        &mut self, data: &[u8], source: S, now: Instant,
+    ) -> Result<Option<(ktp::Tag, Vec<u8>)>, KtpError> {
+        let Some(ktp::Frame {
+            version,
+            tag,
+            seq,
+            total,
+            id,
+            part: inner,
+        }) = ktp::parse_frame(data)?
+        else {
+            return Ok(None);
+        };
+        // Newer versions keep the frame layout, older ones can't be trusted to.
+        if version < ktp::MIN_PROTOCOL_VERSION {
+            return Err(KtpError::UnsupportedVersion(version));
+        }
+        if seq > total {
+            return Err(KtpError::SeqOutOfRange { seq, total });
+        }
+
+        // Skip if we already have this packet
+        if self.recent.contains(&id) {
+            return Ok(None);
+        }
+
+        if !self.buffer.contains_key(&id) {
+            self.start(id, source, total, now);
+        }
+        let Some(partial) = self.buffer.get_mut(&id) else {
+            return Ok(None);
+        };
+        // Somebody else can't add to the packet.
+        if partial.source != source {
+            return Ok(None);
+        }
+
+        // Another frame of the packet could have told a different total.
+        let known_total = (partial.parts.len() - 1) as ktp::Total;
+        let Some(part) = partial.parts.get_mut(seq as usize) else {
+            return Err(KtpError::SeqOutOfRange {
+                seq,
+                total: known_total,
+            });
+        };
+        let previous_len = part.len();
+        *part = inner.to_vec();
+        partial.last_update = now;
+        partial.bytes = partial.bytes - previous_len + inner.len();
+        self.charge(source, inner.len(), previous_len);
+        self.enforce_caps(source);
+
+        let Some(partial) = self.buffer.get(&id) else {
+            log::debug!("Packet {id:x?} was evicted while being reassembled.");
+            return Ok(None);
+        };
+
+        // Short-circuit if we don't have all the parts yet.
+        if !partial.parts.iter().all(|p| !p.is_empty()) {
+            return Ok(None);
+        }
+
+        // Put the packet together
+        let data = partial.parts.concat();
+        self.remove(&id);
+        self.recent.insert(id);
+        Ok(Some((tag, data)))
+    }
+
+    /// Drops the packet along with the frames of it that are yet to come.
+    pub fn skip(&mut self, id: ktp::Id) {
+        self.remove(&id);
+        self.recent.insert(id);
+    }
+
+    /// Drops expired packets and returns NACKs for the ones that stopped receiving
+    /// frames. <br>
+    /// Packets that stay incomplete after `MAX_NACKS` are dropped too.
+    pub fn poll(&mut self, now: Instant) -> Vec<ktp::Packet> {
+        let mut nacks = vec![];
+        let mut expired = vec![];
+
+        for (frame_id, partial) in self.buffer.iter_mut() {
+            let ttl = REASSEMBLY_TTL.max(PART_TTL * partial.parts.len() as u32);
+            if now.duration_since(partial.first_seen) >= ttl {
+                expired.push(*frame_id);
+                continue;
+            }
+            if now.duration_since(partial.last_update) < REASSEMBLY_TIMEOUT {
+                continue;
+            }
+            if partial.nacks >= MAX_NACKS {
+                expired.push(*frame_id);
+                continue;
+            }
+
+            nacks.push(ktp::Packet::Nack {
+                frame_id: *frame_id,
+                missing: (0..partial.parts.len())
+                    .filter(|seq| partial.parts[*seq].is_empty())
+                    .map(|seq| seq as ktp::Seq)
+                    .collect(),
+            });
+            partial.nacks += 1;
+            partial.last_update = now;
+        }
+
+        for frame_id in expired {
+            log::debug!("Dropped incomplete packet {frame_id:x?}.");
+            self.remove(&frame_id);
+            self.evictions.expired += 1;
+        }
+
+        nacks
+    }
+
+    fn start(&mut self, id: ktp::Id, source: S, total: ktp::Total, now: Instant) {
+        let slots = total as usize + 1;
+        self.buffer.insert(
+            id,
+            Partial {
+                source,
+                parts: vec![vec![]; slots],
+                bytes: slots * PART_SLOT_BYTES,
+                first_seen: now,
+                last_update: now,
+                nacks: 0,
+            },
+        );
+        self.charge(source, slots * PART_SLOT_BYTES, 0);
+
+        self.order.push_back(id);
+        let source_order = &mut self.sources.entry(source).or_default().order;
+        source_order.push_back(id);
+
+        // Completed packets leave their ids behind, so the queues are compacted
+        // once they get much longer than the buffer.
+        let buffer = &self.buffer;
+        if source_order.len() > 2 * buffer.len() + 64 {
+            source_order.retain(|id| buffer.contains_key(id));
+        }
+        if self.order.len() > 2 * buffer.len() + 64 {
+            self.order.retain(|id| buffer.contains_key(id));
+        }
+    }
+
+    fn charge(&mut self, source: S, added: usize, removed: usize) {
+        self.bytes = self.bytes + added - removed;
+        let source = self.sources.entry(source).or_default();
+        source.bytes = source.bytes + added - removed;
+    }
+
+    fn enforce_caps(&mut self, source: S) {
+        while self
+            .sources
+            .get(&source)
+            .is_some_and(|state| state.bytes > self.max_source_bytes)
+        {
+            let Some(id) = self
+                .sources
+                .get_mut(&source)
+                .and_then(|state| state.order.pop_front())
+            else {
+                break;
+            };
+            if self.remove(&id) {
+                self.evictions.source_cap += 1;
+                log::debug!(
+                    "Reassembly: {source:?} is over its limit, evictions so far: {:?}",
+                    self.evictions
+                );
+            }
+        }
+
+        while self.bytes > self.max_buffer_bytes {
+            let Some(id) = self.order.pop_front() else {
+                break;
+            };
+            if self.remove(&id) {
+                self.evictions.global_cap += 1;
+                log::debug!(
+                    "Reassembly: buffer is over its limit, evictions so far: {:?}",
+                    self.evictions
+                );
+            }
+        }
+    }
+
+    // Returns `false` if the packet was already gone.
+    fn remove(&mut self, id: &ktp::Id) -> bool {
+        let Some(partial) = self.buffer.remove(id) else {
+            return false;
+        };
+
+        self.bytes -= partial.bytes;
+        if let Some(source) = self.sources.get_mut(&partial.source) {
+            source.bytes -= partial.bytes;
+            if source.bytes == 0 {
+                self.sources.remove(&partial.source);
+            }
+        }
+        if self.buffer.is_empty() {
+            self.order.clear();
+        }
+
+        true
+    }
+}
+
+/// Frames of recently sent packets, along with where they were sent to.
+pub struct FragmentCache<A> {
+    packets: VecDeque<Sent<A>>,
+}
+
+struct Sent<A> {
+    id: ktp::Id,
+    address: A,
+    frames: Vec<Vec<u8>>,
+    // Seqs sent again since the start of the interval, see `NACK_ANSWER_INTERVAL`.
+    resent: HashSet<ktp::Seq>,
+    interval_start: Option<Instant>,
+}
+
+impl<A> Default for FragmentCache<A> {
+    fn default() -> Self {
+        Self {
+            packets: VecDeque::with_capacity(FRAGMENT_CACHE_SIZE),
+        }
+    }
+}
+
+impl<A: Copy> FragmentCache<A> {
+    pub fn insert(&mut self, address: A, frames: &[Vec<u8>]) {
+        let Some(frame_id) = frames.first().and_then(|frame| ktp::frame_id(frame)) else {
+            return;
+        };
+
+        if self.packets.len() >= FRAGMENT_CACHE_SIZE {
+            self.packets.pop_front();
+        }
+        self.packets.push_back(Sent {
+            id: frame_id,
+            address,
+            frames: frames.to_vec(),
+            resent: HashSet::new(),
+            interval_start: None,
+        });
+    }
+
+    /// Missing frames of the packet, if it's still cached. <br>
+    /// Anybody can send a NACK, so frames already sent again within the interval
+    /// are left out, and so are repeated seqs.
+    pub fn missing(
+        &mut self, frame_id: &ktp::Id, missing: &[ktp::Seq], now: Instant,
+    ) -> Option<(A, Vec<Vec<u8>>)> {
+        let sent = self.packets.iter_mut().find(|sent| sent.id == *frame_id)?;
+        let is_new_interval = sent
+            .interval_start
+            .is_none_or(|start| now.duration_since(start) >= NACK_ANSWER_INTERVAL);
+        if is_new_interval {
+            sent.resent.clear();
+            sent.interval_start = Some(now);
+        }
+
+        let frames = missing
+            .iter()
+            .filter(|seq| sent.resent.insert(**seq))
+            .filter_map(|seq| sent.frames.get(*seq as usize).cloned())
+            .collect();
+
+        Some((sent.address, frames))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn accept_never_panics(
+            frames in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64), 1..32),
+        ) {
+            let mut reassembler = Reassembler::default();
+            let now = Instant::now();
+
+            for frame in frames {
+                let frame = [ktp::PACKET_PREFIX, &frame].concat();
+                let _ = reassembler.accept(&frame, (), now);
+            }
+            reassembler.poll(now + REASSEMBLY_TTL);
+            prop_assert_eq!(reassembler.bytes, 0);
+        }
+    }
+
+    #[test]
+    fn seq_beyond_total_is_rejected() {
+        let mut reassembler = Reassembler::default();
+        let id = ktp::generate_id();
+        let frame = |seq, total| {
+            [
+                ktp::PACKET_PREFIX,
+                &[ktp::PROTOCOL_VERSION, 0, seq, total],
+                &id,
+                b"data",
+            ]
+            .concat()
+        };
+        let now = Instant::now();
+
+        assert_eq!(
+            reassembler.accept(&frame(3, 2), (), now),
+            Err(KtpError::SeqOutOfRange { seq: 3, total: 2 })
+        );
+        assert_eq!(reassembler.accept(&frame(0, 1), (), now), Ok(None));
+        assert_eq!(
+            reassembler.accept(&frame(5, 9), (), now),
+            Err(KtpError::SeqOutOfRange { seq: 5, total: 1 })
+        );
+        assert_eq!(
+            reassembler.accept(&[ktp::PACKET_PREFIX, &[0, 0]].concat(), (), now),
+            Err(KtpError::Truncated)
+        );
+        assert_eq!(
+            reassembler.accept(
+                &[ktp::PACKET_PREFIX, &[0, 0, 0, 0], &id].concat(),
+                (),
+                now
+            ),
+            Err(KtpError::UnsupportedVersion(0))
+        );
+        assert_eq!(
+            reassembler.accept(
+                &[ktp::EXTENDED_PACKET_PREFIX, &[1, 0, 0, 0, 0, 0], &id].concat(),
+                (),
+                now
+            ),
+            Err(KtpError::UnsupportedVersion(1))
+        );
+    }
+
+    #[test]
+    fn nack_brings_back_lost_frame() {
+        let mut reassembler = Reassembler::default();
+        let mut cache = FragmentCache::default();
+        let start = Instant::now();
+
+        let data = vec![7; ktp::PACKET_DATA_SIZE * 3];
+        let frames = ktp::fragment(0, &data, ktp::PACKET_DATA_SIZE, false).unwrap();
+        cache.insert((), &frames);
+
+        for (seq, frame) in frames.iter().enumerate() {
+            if seq != 1 {
+                assert_eq!(reassembler.accept(frame, (), start).unwrap(), None);
+            }
+        }
+        assert!(reassembler.poll(start).is_empty());
+
+        let nacks = reassembler.poll(start + REASSEMBLY_TIMEOUT);
+        let [ktp::Packet::Nack { frame_id, missing }] = nacks.as_slice() else {
+            panic!("Expected one NACK, got {nacks:?}");
+        };
+        assert_eq!(missing, &vec![1]);
+
+        let (_, resent) = cache.missing(frame_id, missing, start).unwrap();
+        assert_eq!(
+            reassembler.accept(&resent[0], (), start).unwrap(),
+            Some((0, data))
+        );
+    }
+
+    #[test]
+    fn nacks_are_answered_once_per_interval() {
+        let mut cache = FragmentCache::default();
+        let start = Instant::now();
+        let frames = ktp::fragment(0, &[7; 1000], ktp::PACKET_DATA_SIZE, false).unwrap();
+        let frame_id = ktp::frame_id(&frames[0]).unwrap();
+        cache.insert((), &frames);
+
+        let (_, resent) = cache.missing(&frame_id, &[1, 1, 1, 2], start).unwrap();
+        assert_eq!(resent, vec![frames[1].clone(), frames[2].clone()]);
+        let (_, resent) = cache.missing(&frame_id, &[1, 2, 3], start).unwrap();
+        assert_eq!(resent, vec![frames[3].clone()]);
+
+        let later = start + NACK_ANSWER_INTERVAL;
+        let (_, resent) = cache.missing(&frame_id, &[1], later).unwrap();
+        assert_eq!(resent, vec![frames[1].clone()]);
+    }
+
+    #[test]
+    fn large_packet_takes_extended_frames() {
+        let mut reassembler =
+            Reassembler::with_max_packet_size(ktp::DEFAULT_MAX_PACKET_SIZE);
+        let mut cache = FragmentCache::default();
+        let start = Instant::now();
+
+        let data: Vec<u8> = (0..ktp::MAX_PACKET_SIZE * 2).map(|i| i as u8).collect();
+        assert!(ktp::fragment(0, &data, ktp::PACKET_DATA_SIZE, false).is_err());
+        let frames = ktp::fragment(0, &data, ktp::PACKET_DATA_SIZE, true).unwrap();
+        assert!(frames.len() > 512);
+        assert!(frames.iter().all(|frame| {
+            frame.starts_with(ktp::EXTENDED_PACKET_PREFIX)
+                && frame.len() <= ktp::PACKET_HEADER_SIZE + ktp::PACKET_DATA_SIZE
+        }));
+        cache.insert((), &frames);
+
+        for (seq, frame) in frames.iter().enumerate() {
+            if seq != 300 {
+                assert_eq!(reassembler.accept(frame, (), start).unwrap(), None);
+            }
+        }
+        let nacks = reassembler.poll(start + REASSEMBLY_TIMEOUT);
+        let [ktp::Packet::Nack { frame_id, missing }] = nacks.as_slice() else {
+            panic!("Expected one NACK, got {nacks:?}");
+        };
+        assert_eq!(missing, &vec![300]);
+
+        let (_, resent) = cache.missing(frame_id, missing, start).unwrap();
+        assert_eq!(
+            reassembler.accept(&resent[0], (), start).unwrap(),
+            Some((0, data))
+        );
+    }
+
+    // First of 256 frames, the rest never comes.
+    fn first_frame() -> Vec<u8> {
+        [
+            ktp::PACKET_PREFIX,
+            &[ktp::PROTOCOL_VERSION, 0, 0, u8::MAX],
+            &ktp::generate_id(),
+            &[0; ktp::PACKET_DATA_SIZE],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn flood_from_one_source_stays_within_its_limit() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        for _ in 0..10_000 {
+            reassembler.accept(&first_frame(), 1, now).unwrap();
+        }
+
+        assert!(reassembler.sources[&1].bytes <= MAX_SOURCE_BYTES);
+        assert!(reassembler.sources[&1].order.len() <= 2 * reassembler.buffer.len() + 64);
+        assert!(reassembler.evictions.source_cap > 9_000);
+        assert_eq!(reassembler.evictions.global_cap, 0);
+
+        // Others still get through.
+        let frames = ktp::fragment(0, &[1; 1000], ktp::PACKET_DATA_SIZE, false).unwrap();
+        let packets: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| reassembler.accept(frame, 2, now).unwrap())
+            .collect();
+        assert_eq!(packets, vec![(0, vec![1; 1000])]);
+    }
+
+    #[test]
+    fn flood_from_many_sources_stays_within_global_limit() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        for source in 0..10_000 {
+            reassembler.accept(&first_frame(), source, now).unwrap();
+            assert!(reassembler.bytes <= MAX_BUFFER_BYTES);
+        }
+
+        assert!(reassembler.evictions.global_cap > 9_000);
+        assert!(reassembler.order.len() <= 2 * reassembler.buffer.len() + 64);
+        assert!(reassembler.sources.len() <= reassembler.buffer.len());
+    }
+
+    #[test]
+    fn incomplete_packets_expire() {
+        let mut reassembler = Reassembler::default();
+        let start = Instant::now();
+
+        for source in 0..100 {
+            reassembler.accept(&first_frame(), source, start).unwrap();
+        }
+        // NACKs go unanswered, so the packets run out of them before the TTL.
+        for step in 1..=MAX_NACKS as u32 + 1 {
+            reassembler.poll(start + REASSEMBLY_TIMEOUT * step);
+        }
+
+        assert!(reassembler.buffer.is_empty());
+        assert!(reassembler.sources.is_empty());
+        assert_eq!(reassembler.bytes, 0);
+        assert_eq!(reassembler.evictions.expired, 100);
+
+        reassembler.accept(&first_frame(), 0, start).unwrap();
+        reassembler.poll(start + REASSEMBLY_TTL);
+        assert_eq!(reassembler.evictions.expired, 101);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::net::ktp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Counters this far behind the newest one of the sender are too old to tell apart.
+pub const REPLAY_WINDOW_SIZE: ktp::Counter = u128::BITS as ktp::Counter;
+
+// Windows of senders beyond this are evicted, least recently heard first.
+pub const MAX_TRACKED_SENDERS: usize = 1024;
+// Highest counters of evicted senders, the oldest one is forgotten first.
+pub const MAX_EVICTED_SENDERS: usize = 16 * MAX_TRACKED_SENDERS;
+
+/// Hands out the counters of our packets. <br>
+/// Starts from the current time in microseconds, so counters keep growing
+/// over restarts with the same identity.
+pub struct Sequencer {
+    next: ktp::Counter,
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_micros() as ktp::Counter)
+            .unwrap_or_default();
+
+        Self { next: now }
+    }
+}
+
+impl Sequencer {
+    pub fn next_counter(&mut self) -> ktp::Counter {
+        let counter = self.next;
+        self.next += 1;
+        counter
+    }
+}
+
+/// Sliding window over the counters of one sender.
+struct Window {
+    highest: ktp::Counter,
+    // Bit `n` is set if `highest - n` was accepted.
+    seen: u128,
+    last_heard: u64,
+}
+
+impl Window {
+    fn accept(&mut self, counter: ktp::Counter) -> bool {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = match shift < REPLAY_WINDOW_SIZE {
+                true => (self.seen << shift) | 1,
+                false => 1,
+            };
+            self.highest = counter;
+            return true;
+        }
+
+        let offset = self.highest - counter;
+        if offset >= REPLAY_WINDOW_SIZE || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// Rejects reassembled packets that were already accepted once,
+/// or are too old to tell.
+#[derive(Default)]
+pub struct ReplayGuard {
+    windows: HashMap<ktp::Id, Window>,
+    // Logical time for the eviction of the least recently heard sender.
+    ticks: u64,
+    // Evicted senders start over only above their highest counter,
+    // so their recorded packets stay rejected.
+    evicted: HashMap<ktp::Id, ktp::Counter>,
+    evicted_order: VecDeque<ktp::Id>,
+}
+
+impl ReplayGuard {
+    pub fn accept(&mut self, sender: ktp::Id, counter: ktp::Counter) -> bool {
+        self.ticks += 1;
+
+        if let Some(window) = self.windows.get_mut(&sender) {
+            window.last_heard = self.ticks;
+            return window.accept(counter);
+        }
+        if self
+            .evicted
+            .get(&sender)
+            .is_some_and(|highest| counter <= *highest)
+        {
+            return false;
+        }
+
+        if self.windows.len() >= MAX_TRACKED_SENDERS {
+            self.evict();
+        }
+        self.windows.insert(
+            sender,
+            Window {
+                highest: counter,
+                seen: 1,
+                last_heard: self.ticks,
+            },
+        );
+
+        true
+    }
+
+    // Only runs with a full map, so the scan is rare.
+    fn evict(&mut self) {
+        let oldest = self
+            .windows
+            .iter()
+            .min_by_key(|(_, window)| window.last_heard)
+            .map(|(id, window)| (*id, window.highest));
+
+        if let Some((id, highest)) = oldest {
+            self.windows.remove(&id);
+            self.remember_evicted(id, highest);
+        }
+    }
+
+    fn remember_evicted(&mut self, id: ktp::Id, highest: ktp::Counter) {
+        // A sender evicted again keeps its place in the order.
+        if self.evicted.insert(id, highest).is_none() {
+            self.evicted_order.push_back(id);
+        }
+        if self.evicted_order.len() > MAX_EVICTED_SENDERS {
+            if let Some(oldest) = self.evicted_order.pop_front() {
+                self.evicted.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Bounded set of packet or message ids, the oldest one is forgotten first.
+pub struct RecentIds {
+    ids: HashSet<ktp::Id>,
+    order: VecDeque<ktp::Id>,
+    capacity: usize,
+}
+
+impl RecentIds {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, id: &ktp::Id) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Returns `false` if the id is already there.
+    pub fn insert(&mut self, id: ktp::Id) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_accepts_reordered_and_rejects_replayed() {
+        let mut guard = ReplayGuard::default();
+        let sender = [1; 8];
+
+        assert!(guard.accept(sender, 100));
+        assert!(guard.accept(sender, 102));
+        assert!(guard.accept(sender, 101));
+        assert!(!guard.accept(sender, 101));
+        assert!(!guard.accept(sender, 100));
+
+        assert!(guard.accept(sender, 100 + REPLAY_WINDOW_SIZE * 3));
+        assert!(!guard.accept(sender, 102));
+        assert!(guard.accept([2; 8], 5));
+    }
+
+    #[test]
+    fn senders_are_bounded() {
+        let mut guard = ReplayGuard::default();
+        for sender in 0..MAX_TRACKED_SENDERS as u64 * 2 {
+            guard.accept(sender.to_be_bytes(), 1);
+        }
+
+        assert_eq!(guard.windows.len(), MAX_TRACKED_SENDERS);
+    }
+
+    #[test]
+    fn evicted_senders_stay_above_their_counters() {
+        let mut guard = ReplayGuard::default();
+        let sender = [1; 8];
+        assert!(guard.accept(sender, 100));
+        for other in 0..MAX_TRACKED_SENDERS as u64 {
+            guard.accept((other + 2).to_be_bytes(), 1);
+        }
+        assert!(!guard.windows.contains_key(&sender));
+
+        assert!(!guard.accept(sender, 100));
+        assert!(!guard.accept(sender, 99));
+        assert!(guard.accept(sender, 101));
+        assert!(!guard.accept(sender, 101));
+    }
+
+    #[test]
+    fn recent_ids_are_bounded() {
+        let mut recent = RecentIds::with_capacity(2);
+        for id in 0..3u64 {
+            recent.insert(id.to_be_bytes());
+        }
+
+        assert!(!recent.contains(&0u64.to_be_bytes()));
+        assert!(recent.contains(&2u64.to_be_bytes()));
+        assert_eq!(recent.ids.len(), 2);
+    }
+}
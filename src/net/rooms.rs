@@ -0,0 +1,265 @@
+use crate::net::ktp::{self, RoomTopic};
+use crate::net::presence::OFFLINE_TIMEOUT;
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Nobody sets a topic this often, higher revisions would only freeze the topic.
+const MAX_TOPIC_REVISION: u64 = u32::MAX as u64;
+// Rooms announced by others beyond this many aren't listed, until some are forgotten.
+pub const MAX_ROOMS: usize = 256;
+
+/// What the room list shows about a room.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomListing {
+    pub room: String,
+    pub members: usize,
+    pub topic: Option<String>,
+}
+
+struct KnownRoom {
+    // Revision 0 while nobody set the topic.
+    topic: RoomTopic,
+    // Members that announced the room, by the time of their last announce.
+    announcers: HashMap<ktp::Id, Instant>,
+}
+
+/// Rooms active on the segment, learned from announces. <br>
+/// Topics set at once by different members are resolved the same way
+/// everywhere, see `RoomTopic::wins_over`, so every member ends up with one.
+#[derive(Default)]
+pub struct RoomDirectory {
+    rooms: HashMap<String, KnownRoom>,
+    is_changed: bool,
+}
+
+impl RoomDirectory {
+    /// Returns the topic, if it replaced the one known before. <br>
+    /// Only verified announces count, anybody could announce rooms under
+    /// somebody else's id. Topics are only taken from their setters, so nobody
+    /// passes off a topic of their own as somebody else's.
+    pub fn record(
+        &mut self, announcer: ktp::Id, announced: RoomTopic, is_verified: bool,
+        now: Instant,
+    ) -> Option<RoomTopic> {
+        if !is_verified {
+            return None;
+        }
+        if self.rooms.len() >= MAX_ROOMS && !self.rooms.contains_key(&announced.room) {
+            log::debug!("Didn't list an announced room, too many rooms.");
+            return None;
+        }
+
+        let known = self.known(&announced.room);
+        if known.announcers.insert(announcer, now).is_none() {
+            self.is_changed = true;
+        }
+
+        if announced.setter != announcer {
+            return None;
+        }
+        self.replace_topic(announced)
+    }
+
+    /// Our own topic, one revision above the known one.
+    pub fn set_topic(&mut self, room: &str, topic: String, setter: ktp::Id) -> RoomTopic {
+        let revision = self
+            .known(room)
+            .topic
+            .revision
+            .saturating_add(1)
+            .min(MAX_TOPIC_REVISION);
+        let topic = RoomTopic {
+            room: room.to_string(),
+            revision,
+            setter,
+            topic,
+        };
+        self.replace_topic(topic.clone());
+
+        topic
+    }
+
+    /// The topic to announce, with revision 0 if nobody set it yet.
+    pub fn topic(&self, room: &str) -> RoomTopic {
+        match self.rooms.get(room) {
+            Some(known) => known.topic.clone(),
+            None => RoomTopic {
+                room: room.to_string(),
+                revision: 0,
+                setter: ktp::Id::default(),
+                topic: String::new(),
+            },
+        }
+    }
+
+    /// The member parted the room.
+    pub fn leave(&mut self, room: &str, id: &ktp::Id) {
+        if let Some(known) = self.rooms.get_mut(room) {
+            if known.announcers.remove(id).is_some() {
+                self.is_changed = true;
+            }
+            if known.announcers.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Rooms are forgotten along with their last member.
+    pub fn forget_peer(&mut self, id: &ktp::Id) {
+        for known in self.rooms.values_mut() {
+            if known.announcers.remove(id).is_some() {
+                self.is_changed = true;
+            }
+        }
+        self.rooms.retain(|_, known| !known.announcers.is_empty());
+    }
+
+    /// Members stop counting after they didn't announce the room for a while.
+    pub fn expire(&mut self, now: Instant) {
+        for known in self.rooms.values_mut() {
+            let count = known.announcers.len();
+            known.announcers.retain(|_, last_announce| {
+                now.duration_since(*last_announce) <= OFFLINE_TIMEOUT
+            });
+            if known.announcers.len() != count {
+                self.is_changed = true;
+            }
+        }
+        self.rooms.retain(|_, known| !known.announcers.is_empty());
+    }
+
+    /// The rooms by name, if anything about them changed since the last call.
+    pub fn take_listings(&mut self) -> Option<Vec<RoomListing>> {
+        if !std::mem::take(&mut self.is_changed) {
+            return None;
+        }
+
+        let mut listings: Vec<RoomListing> = self
+            .rooms
+            .iter()
+            .map(|(room, known)| RoomListing {
+                room: room.clone(),
+                members: known.announcers.len(),
+                topic: (known.topic.revision > 0).then(|| known.topic.topic.clone()),
+            })
+            .collect();
+        listings.sort_by(|a, b| a.room.cmp(&b.room));
+
+        Some(listings)
+    }
+
+    fn known(&mut self, room: &str) -> &mut KnownRoom {
+        self.rooms.entry(room.to_string()).or_insert_with(|| {
+            self.is_changed = true;
+            KnownRoom {
+                topic: RoomTopic {
+                    room: room.to_string(),
+                    revision: 0,
+                    setter: ktp::Id::default(),
+                    topic: String::new(),
+                },
+                announcers: HashMap::new(),
+            }
+        })
+    }
+
+    fn replace_topic(&mut self, topic: RoomTopic) -> Option<RoomTopic> {
+        if topic.revision > MAX_TOPIC_REVISION {
+            return None;
+        }
+        let known = self.known(&topic.room);
+        if !topic.wins_over(&known.topic) {
+            return None;
+        }
+
+        known.topic = topic.clone();
+        self.is_changed = true;
+        Some(topic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_set_at_once_end_up_the_same() {
+        let (alice, bob) = ([1; 8], [2; 8]);
+        let now = Instant::now();
+        let mut alices = RoomDirectory::default();
+        let mut bobs = RoomDirectory::default();
+
+        let from_alice = alices.set_topic("ops", "deploy on friday".into(), alice);
+        let from_bob = bobs.set_topic("ops", "no deploys".into(), bob);
+        assert_eq!(from_alice.revision, from_bob.revision);
+
+        assert_eq!(
+            alices.record(bob, from_bob.clone(), true, now),
+            Some(from_bob.clone())
+        );
+        assert_eq!(bobs.record(alice, from_alice, true, now), None);
+        assert_eq!(alices.topic("ops"), bobs.topic("ops"));
+
+        // The next topic is set above both of them.
+        let next = alices.set_topic("ops", "deploy on monday".into(), alice);
+        assert_eq!(bobs.record(alice, next.clone(), true, now), Some(next));
+
+        let listings = bobs.take_listings().unwrap();
+        assert_eq!(listings[0].members, 1);
+        assert_eq!(listings[0].topic.as_deref(), Some("deploy on monday"));
+        assert!(bobs.take_listings().is_none());
+    }
+
+    #[test]
+    fn only_setters_change_topics() {
+        let (alice, bob, mallory) = ([1; 8], [2; 8], [3; 8]);
+        let now = Instant::now();
+        let mut alices = RoomDirectory::default();
+        let mut bobs = RoomDirectory::default();
+        let topic = alices.set_topic("ops", "deploy on friday".into(), alice);
+        bobs.record(alice, topic.clone(), true, now);
+
+        let mut forged = topic.clone();
+        forged.revision += 1;
+        forged.topic = "deploys are cancelled".into();
+        assert_eq!(bobs.record(mallory, forged.clone(), true, now), None);
+        assert_eq!(bobs.record(alice, forged.clone(), false, now), None);
+        assert_eq!(bobs.record([4; 8], forged.clone(), false, now), None);
+
+        // Nor is the topic frozen with a revision nobody gets above.
+        forged.setter = mallory;
+        forged.revision = u64::MAX;
+        assert_eq!(bobs.record(mallory, forged, true, now), None);
+        assert_eq!(bobs.topic("ops"), topic);
+        assert_eq!(bobs.take_listings().unwrap()[0].members, 2);
+
+        // Topics at the highest revision can still be replaced by setting one.
+        let mut last = topic;
+        last.revision = MAX_TOPIC_REVISION;
+        assert_eq!(bobs.record(alice, last.clone(), true, now), Some(last));
+        let next = bobs.set_topic("ops", "deploy on monday".into(), bob);
+        assert_eq!(next.revision, MAX_TOPIC_REVISION);
+        assert_eq!(bobs.topic("ops"), next);
+    }
+
+    #[test]
+    fn rooms_are_bounded() {
+        let (alice, mallory) = ([1; 8], [3; 8]);
+        let now = Instant::now();
+        let mut alices = RoomDirectory::default();
+        let ops = alices.topic("ops");
+        for room in 0..MAX_ROOMS * 2 {
+            let mut announced = ops.clone();
+            announced.room = room.to_string();
+            alices.record(mallory, announced, true, now);
+        }
+        assert_eq!(alices.rooms.len(), MAX_ROOMS);
+
+        // Known rooms are still announced, and freed slots taken again.
+        alices.record(mallory, alices.topic("0"), true, now);
+        assert_eq!(alices.rooms["0"].announcers.len(), 1);
+        alices.forget_peer(&mallory);
+        alices.record(alice, ops, true, now);
+        assert_eq!(alices.take_listings().unwrap().len(), 1);
+    }
+}
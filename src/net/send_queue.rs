@@ -0,0 +1,111 @@
+use crate::net::ktp;
+use std::collections::VecDeque;
+
+// Frames put on the wire at once, the rest of a packet waits for the next burst.
+pub const BURST_SIZE: usize = 64;
+
+/// How much of a large message is on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendProgress {
+    pub message_id: ktp::Id,
+    pub sent: usize,
+    pub total: usize,
+}
+
+struct Queued<A> {
+    address: A,
+    message_id: Option<ktp::Id>,
+    frames: VecDeque<Vec<u8>>,
+    total: usize,
+}
+
+/// Frames of large packets, sent a burst at a time. <br>
+/// Otherwise thousands of frames at once would overflow the buffers
+/// of the link and of the peers. Packets are sent in the order of arrival.
+pub struct SendQueue<A> {
+    packets: VecDeque<Queued<A>>,
+}
+
+impl<A> Default for SendQueue<A> {
+    fn default() -> Self {
+        Self {
+            packets: VecDeque::new(),
+        }
+    }
+}
+
+impl<A: Copy> SendQueue<A> {
+    /// Returns the frames back, if they fit into one burst and may be sent right away.
+    /// Small packets, like acks and presence, don't wait behind large ones.
+    pub fn push(
+        &mut self, address: A, message_id: Option<ktp::Id>, frames: Vec<Vec<u8>>,
+    ) -> Option<Vec<Vec<u8>>> {
+        if frames.len() <= BURST_SIZE {
+            return Some(frames);
+        }
+
+        self.packets.push_back(Queued {
+            address,
+            message_id,
+            total: frames.len(),
+            frames: frames.into(),
+        });
+        None
+    }
+
+    /// Next burst of frames, along with the progress of the message they belong to.
+    pub fn next_burst(&mut self) -> Option<(A, Vec<Vec<u8>>, Option<SendProgress>)> {
+        let queued = self.packets.front_mut()?;
+        let burst_size = queued.frames.len().min(BURST_SIZE);
+        let burst: Vec<_> = queued.frames.drain(..burst_size).collect();
+
+        let address = queued.address;
+        let progress = queued.message_id.map(|message_id| SendProgress {
+            message_id,
+            sent: queued.total - queued.frames.len(),
+            total: queued.total,
+        });
+        if queued.frames.is_empty() {
+            self.packets.pop_front();
+        }
+
+        Some((address, burst, progress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_packets_go_out_in_bursts() {
+        let mut queue = SendQueue::default();
+        let message_id = [1; 8];
+
+        assert_eq!(
+            queue.push((), None, vec![vec![0]; 3]).map(|f| f.len()),
+            Some(3)
+        );
+        assert!(
+            queue
+                .push((), Some(message_id), vec![vec![0]; 150])
+                .is_none()
+        );
+        assert!(queue.push((), None, vec![vec![0]; 70]).is_none());
+
+        let mut bursts = vec![];
+        while let Some(((), frames, progress)) = queue.next_burst() {
+            bursts.push((frames.len(), progress.map(|progress| progress.sent)));
+        }
+        assert_eq!(
+            bursts,
+            vec![
+                (64, Some(64)),
+                (64, Some(128)),
+                (22, Some(150)),
+                (64, None),
+                (6, None)
+            ]
+        );
+    }
+}
@@ -0,0 +1,1660 @@
+use crate::net::capabilities::Capabilities;
+use crate::net::channel::Channel;
+use crate::net::clock::Clock;
+use crate::net::commands::NetCommand;
+use crate::net::contacts::ContactBook;
+use crate::net::core::NetThread;
+use crate::net::identity::Identity;
+use crate::net::memory::{Faults, MemorySegment};
+use crate::ui::commands::UICommand;
+use crossbeam::channel::{Receiver, Sender, unbounded};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Clock that only moves when the simulation says so.
+#[derive(Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+impl ManualClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().expect("Clock is poisoned") += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().expect("Clock is poisoned")
+    }
+}
+
+pub struct Node {
+    net_tx: Sender<NetCommand>,
+    ui_rx: Receiver<UICommand>,
+
+    // None after the node is terminated.
+    thread: Option<NetThread<Channel, ManualClock>>,
+}
+
+/// Runs several net threads over one virtual segment. <br>
+/// Everything happens on the calling thread and time only moves with `run_for`,
+/// so a scenario with the same seed always plays out the same way.
+pub struct Simulation {
+    clock: ManualClock,
+    segment: MemorySegment,
+    nodes: Vec<Node>,
+}
+
+impl Simulation {
+    pub const TICK: Duration = Duration::from_millis(100);
+
+    // Protection from endless ping-pong between nodes within one tick.
+    const MAX_ROUNDS_PER_TICK: usize = 10_000;
+
+    pub fn new(seed: u64) -> Self {
+        let clock = ManualClock(Arc::new(Mutex::new(Instant::now())));
+
+        Self {
+            segment: MemorySegment::new(clock.clone(), seed),
+            clock,
+            nodes: vec![],
+        }
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        self.segment.set_faults(faults);
+    }
+
+    /// Adds a node that joins the segment with the given username.
+    pub fn add_node(&mut self, username: &str) -> usize {
+        self.add_node_with_capabilities(username, Capabilities::SUPPORTED)
+    }
+
+    /// Adds a node of an older client, which lacks some features.
+    pub fn add_node_with_capabilities(
+        &mut self, username: &str, capabilities: Capabilities,
+    ) -> usize {
+        let (ui_tx, ui_rx) = unbounded::<UICommand>();
+        let (net_tx, net_rx) = unbounded::<NetCommand>();
+
+        let thread = NetThread::new(
+            self.segment.attach(),
+            self.clock.clone(),
+            Identity::generate(),
+            Arc::new(Mutex::new(ContactBook::default())),
+            ui_tx,
+            net_rx,
+        )
+        .with_capabilities(capabilities);
+        self.nodes.push(Node {
+            net_tx,
+            ui_rx,
+            thread: Some(thread),
+        });
+
+        let index = self.nodes.len() - 1;
+        self.command(index, NetCommand::UpdateUsername(username.to_string()));
+        self.settle();
+
+        index
+    }
+
+    pub fn command(&self, node: usize, command: NetCommand) {
+        self.nodes[node]
+            .net_tx
+            .try_send(command)
+            .expect("Net thread receiver is alive");
+    }
+
+    /// Advances the clock tick by tick, letting every node process its traffic.
+    pub fn run_for(&mut self, duration: Duration) {
+        let mut elapsed = Duration::ZERO;
+        while elapsed < duration {
+            self.clock.advance(Self::TICK);
+            elapsed += Self::TICK;
+            self.settle();
+        }
+    }
+
+    /// Drains everything the node has sent to its UI so far.
+    pub fn ui_events(&self, node: usize) -> Vec<UICommand> {
+        self.nodes[node].ui_rx.try_iter().collect()
+    }
+
+    // Steps every node until there are no commands or deliverable frames left.
+    fn settle(&mut self) {
+        for _ in 0..Self::MAX_ROUNDS_PER_TICK {
+            for node in self.nodes.iter_mut() {
+                if let Some(thread) = node.thread.as_mut() {
+                    if !thread.step() {
+                        node.thread = None;
+                    }
+                }
+            }
+
+            let has_commands = self
+                .nodes
+                .iter()
+                .any(|node| node.thread.is_some() && !node.net_tx.is_empty());
+            if !has_commands && !self.segment.has_deliverable() {
+                return;
+            }
+        }
+
+        panic!("Simulation didn't settle within a tick.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::net::NetError;
+    use crate::net::arp;
+    use crate::net::delivery::{DeliveryState, RETRANSMIT_TIMEOUT};
+    use crate::net::ether_type::EtherType;
+    use crate::net::ktp::{self, Packet};
+    use crate::net::memory;
+    use crate::net::presence::{
+        HEARTBEAT_INTERVAL, INACTIVE_TIMEOUT, OFFLINE_TIMEOUT, Trust, UpdatePresenceKind,
+    };
+    use crate::net::transfer::{self, STALL_TIMEOUT, TransferFailure, TransferState};
+    use crate::net::transport::Transport;
+    use crate::net::typing::TYPING_TIMEOUT;
+    use pnet::util::MacAddr;
+    use sha2::{Digest, Sha256};
+    use std::fs;
+
+    fn presence_updates(events: &[UICommand]) -> Vec<(String, bool, UpdatePresenceKind)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::PresenceUpdate {
+                    username,
+                    is_inactive,
+                    kind,
+                    ..
+                } => Some((username.clone(), *is_inactive, kind.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn removals(events: &[UICommand]) -> Vec<String> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::RemovePresence { username, .. } => Some(username.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Who is typing in the main room, after every change.
+    fn typing(events: &[UICommand]) -> Vec<Vec<String>> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::UpdateTyping { room, usernames } if room.is_empty() => {
+                    Some(usernames.clone())
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    // New texts of messages, `None` for deleted ones.
+    fn changes(events: &[UICommand]) -> Vec<(ktp::Id, Option<String>)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::EditMessage {
+                    message_id,
+                    message_text,
+                } => Some((*message_id, Some(message_text.clone()))),
+                UICommand::DeleteMessage { message_id } => Some((*message_id, None)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn last_topic(events: &[UICommand]) -> Option<(String, String)> {
+        events.iter().rev().find_map(|event| match event {
+            UICommand::ShowTopic {
+                username, topic, ..
+            } => Some((username.clone(), topic.clone())),
+            _ => None,
+        })
+    }
+
+    fn messages(events: &[UICommand]) -> Vec<(String, String)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::ShowMessage {
+                    username,
+                    message,
+                    is_outgoing_message: false,
+                    ..
+                } => Some((username.clone(), message.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn delivery_states(events: &[UICommand]) -> Vec<DeliveryState> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::UpdateDeliveryState { state, .. } => Some(*state),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn direct_messages(events: &[UICommand]) -> Vec<(String, String)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::ShowDirectMessage {
+                    peer_username,
+                    message,
+                    ..
+                } => Some((peer_username.clone(), message.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn fingerprints(events: &[UICommand]) -> Vec<String> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::ShowFingerprint { fingerprint, .. } => {
+                    Some(fingerprint.clone())
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn transfer_states(events: &[UICommand]) -> Vec<TransferState> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::UpdateTransfer { state, .. } => Some(state.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn two_nodes(seed: u64) -> Simulation {
+        let mut simulation = Simulation::new(seed);
+        let alice = simulation.add_node("alice");
+        let bob = simulation.add_node("bob");
+        simulation.run_for(Simulation::TICK);
+        simulation.ui_events(alice);
+        simulation.ui_events(bob);
+
+        simulation
+    }
+
+    #[test]
+    fn join() {
+        let mut simulation = Simulation::new(1);
+        let alice = simulation.add_node("alice");
+        let alice_events = simulation.ui_events(alice);
+        assert_eq!(
+            presence_updates(&alice_events),
+            vec![("alice".into(), false, UpdatePresenceKind::JoinOrReconnect)]
+        );
+
+        let bob = simulation.add_node("bob");
+        simulation.run_for(Simulation::TICK);
+
+        let alice_events = presence_updates(&simulation.ui_events(alice));
+        let about_bob: Vec<_> = alice_events
+            .into_iter()
+            .filter(|(username, _, _)| username == "bob")
+            .collect();
+        assert_eq!(
+            about_bob,
+            vec![("bob".into(), false, UpdatePresenceKind::JoinOrReconnect)]
+        );
+
+        let bob_events = presence_updates(&simulation.ui_events(bob));
+        assert!(bob_events.contains(&(
+            "alice".into(),
+            false,
+            UpdatePresenceKind::Boring
+        )));
+        assert!(bob_events.contains(&(
+            "bob".into(),
+            false,
+            UpdatePresenceKind::JoinOrReconnect
+        )));
+    }
+
+    #[test]
+    fn rename() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(2);
+
+        simulation.command(bob, NetCommand::UpdateUsername("robert".into()));
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+
+        let updates = presence_updates(&simulation.ui_events(alice));
+        assert!(updates.contains(&(
+            "robert".into(),
+            false,
+            UpdatePresenceKind::UsernameChange {
+                previous_username: "bob".into()
+            }
+        )));
+    }
+
+    #[test]
+    fn inactive_then_offline_then_reconnect() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(3);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.ui_events(alice);
+
+        simulation.command(bob, NetCommand::PauseHeartbeat(true));
+        simulation.run_for(INACTIVE_TIMEOUT + HEARTBEAT_INTERVAL);
+
+        let events = simulation.ui_events(alice);
+        assert!(presence_updates(&events).contains(&(
+            "bob".into(),
+            true,
+            UpdatePresenceKind::Boring
+        )));
+        assert!(removals(&events).is_empty());
+
+        simulation.run_for(OFFLINE_TIMEOUT);
+        assert_eq!(
+            removals(&simulation.ui_events(alice)),
+            vec!["bob".to_string()]
+        );
+
+        simulation.command(bob, NetCommand::PauseHeartbeat(false));
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        assert!(presence_updates(&simulation.ui_events(alice)).contains(&(
+            "bob".into(),
+            false,
+            UpdatePresenceKind::JoinOrReconnect
+        )));
+    }
+
+    #[test]
+    fn disconnect() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(4);
+
+        simulation.command(bob, NetCommand::Terminate);
+        simulation.run_for(Simulation::TICK);
+
+        assert_eq!(
+            removals(&simulation.ui_events(alice)),
+            vec!["bob".to_string()]
+        );
+    }
+
+    #[test]
+    fn message_survives_duplication_reordering_and_delay() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(5);
+        simulation.set_faults(Faults {
+            loss: 0.0,
+            duplication: 0.5,
+            reordering: 0.5,
+            max_delay: Duration::from_millis(500),
+        });
+
+        let long_text: String = (0..2000).map(|i| i.to_string()).collect();
+        simulation.command(
+            bob,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: long_text.clone(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Duration::from_secs(1));
+
+        assert_eq!(
+            messages(&simulation.ui_events(alice)),
+            vec![("bob".to_string(), long_text)]
+        );
+    }
+
+    #[test]
+    fn direct_message_reaches_only_addressee() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(7);
+        let carol = simulation.add_node("carol");
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.ui_events(carol);
+
+        let alice_id = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::PresenceUpdate { id, username, .. } if username == "alice" => {
+                    Some(*id)
+                },
+                _ => None,
+            })
+            .unwrap();
+        simulation.command(
+            bob,
+            NetCommand::SendDirectMessage {
+                target: alice_id,
+                message_text: "psst".into(),
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+
+        let (alice_events, bob_events) =
+            (simulation.ui_events(alice), simulation.ui_events(bob));
+        assert_eq!(
+            direct_messages(&alice_events),
+            vec![("bob".to_string(), "psst".to_string())]
+        );
+        assert_eq!(
+            direct_messages(&bob_events),
+            vec![("alice".to_string(), "psst".to_string())]
+        );
+        assert!(direct_messages(&simulation.ui_events(carol)).is_empty());
+
+        // Each side is shown the identity key of the other one.
+        let alice_fingerprints = fingerprints(&alice_events);
+        assert_eq!(alice_fingerprints.len(), 1);
+        assert_eq!(fingerprints(&bob_events).len(), 1);
+        assert_ne!(alice_fingerprints, fingerprints(&bob_events));
+
+        // A handshake that isn't signed by Bob doesn't replace the session.
+        let bob_id = alice_events
+            .iter()
+            .find_map(|event| match event {
+                UICommand::ShowDirectMessage { peer, .. } => Some(*peer),
+                _ => None,
+            })
+            .unwrap();
+        // Mallory learns where Alice is from a message of hers.
+        let mut mallory = simulation.segment.attach();
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "brb".into(),
+                reply_to: None,
+            },
+        );
+        simulation.nodes[alice].thread.as_mut().unwrap().step();
+        // Bob acknowledges the message before Mallory speaks up.
+        for _ in 0..100 {
+            for node in [alice, bob] {
+                simulation.nodes[node].thread.as_mut().unwrap().step();
+            }
+        }
+        memory::receive_all(&mut mallory);
+        mallory
+            .try_send(Packet::KeyExchange {
+                id: bob_id,
+                counter: 1,
+                target: alice_id,
+                is_reply: false,
+                public_key: [9; 32],
+                signature: [0; 64],
+            })
+            .unwrap();
+        drop(mallory);
+        simulation.run_for(Simulation::TICK);
+        // Nor does it tell Alice where Bob is.
+        simulation.ui_events(bob);
+        simulation.command(
+            alice,
+            NetCommand::SendDirectMessage {
+                target: bob_id,
+                message_text: "good".into(),
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        assert_eq!(
+            direct_messages(&simulation.ui_events(bob)),
+            vec![("alice".to_string(), "good".to_string())]
+        );
+        simulation.ui_events(alice);
+
+        simulation.command(
+            bob,
+            NetCommand::SendDirectMessage {
+                target: alice_id,
+                message_text: "still us".into(),
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+
+        let alice_events = simulation.ui_events(alice);
+        assert!(fingerprints(&alice_events).is_empty());
+        assert_eq!(
+            direct_messages(&alice_events),
+            vec![("bob".to_string(), "still us".to_string())]
+        );
+    }
+
+    #[test]
+    fn presence_survives_loss() {
+        let mut simulation = Simulation::new(6);
+        simulation.set_faults(Faults {
+            loss: 0.3,
+            ..Default::default()
+        });
+        let alice = simulation.add_node("alice");
+        simulation.add_node("bob");
+        simulation.run_for(HEARTBEAT_INTERVAL * 4);
+
+        let updates = presence_updates(&simulation.ui_events(alice));
+        assert!(updates.iter().any(|(username, _, _)| username == "bob"));
+    }
+
+    #[test]
+    fn room_passphrase_hides_messages_from_outsiders() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(8);
+        let carol = simulation.add_node("carol");
+        for node in [alice, bob] {
+            simulation
+                .command(node, NetCommand::SetRoomPassphrase(Some("hunter2".into())));
+        }
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.ui_events(carol);
+
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "secret".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+
+        assert_eq!(
+            messages(&simulation.ui_events(bob)),
+            vec![("alice".to_string(), "secret".to_string())]
+        );
+        let carol_events = simulation.ui_events(carol);
+        assert!(messages(&carol_events).is_empty());
+        assert!(presence_updates(&carol_events).is_empty());
+    }
+
+    #[test]
+    fn rooms_keep_their_traffic_to_members() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(18);
+        let carol = simulation.add_node("carol");
+        for node in [alice, bob] {
+            simulation.command(node, NetCommand::JoinRoom("ops".into()));
+        }
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+
+        let members: Vec<String> = simulation
+            .ui_events(alice)
+            .into_iter()
+            .filter_map(|event| match event {
+                UICommand::PresenceUpdate { room, username, .. } if room == "ops" => {
+                    Some(username)
+                },
+                _ => None,
+            })
+            .collect();
+        assert!(members.contains(&"bob".to_string()));
+        assert!(!members.contains(&"carol".to_string()));
+        simulation.ui_events(bob);
+        simulation.ui_events(carol);
+
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: "ops".into(),
+                message_text: "deploy".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK * 5);
+
+        let bob_events = simulation.ui_events(bob);
+        assert_eq!(
+            messages(&bob_events),
+            vec![("alice".to_string(), "deploy".to_string())]
+        );
+        assert!(bob_events.iter().any(|event| matches!(
+            event,
+            UICommand::ShowMessage { room, .. } if room == "ops"
+        )));
+        assert!(messages(&simulation.ui_events(carol)).is_empty());
+        assert_eq!(
+            delivery_states(&simulation.ui_events(alice)).last(),
+            Some(&DeliveryState::Delivered { peers: 1 })
+        );
+
+        simulation.command(bob, NetCommand::PartRoom("ops".into()));
+        simulation.run_for(Simulation::TICK);
+        assert!(simulation.ui_events(alice).iter().any(|event| matches!(
+            event,
+            UICommand::RemovePresence { room, username, .. }
+                if room == "ops" && username == "bob"
+        )));
+
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: "ops".into(),
+                message_text: "again".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        assert!(messages(&simulation.ui_events(bob)).is_empty());
+    }
+
+    #[test]
+    fn topics_set_at_once_settle_on_one_everywhere() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(22);
+        for node in [alice, bob] {
+            simulation.command(node, NetCommand::JoinRoom("ops".into()));
+        }
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+
+        for (node, topic) in [(alice, "deploy on friday"), (bob, "no deploys")] {
+            simulation.command(
+                node,
+                NetCommand::SetTopic {
+                    room: "ops".into(),
+                    topic: topic.into(),
+                },
+            );
+        }
+        simulation.run_for(Simulation::TICK * 5);
+        let winner = last_topic(&simulation.ui_events(alice));
+        assert!(winner.is_some());
+        assert_eq!(last_topic(&simulation.ui_events(bob)), winner);
+
+        // A newcomer learns both the room and its topic.
+        let carol = simulation.add_node("carol");
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        let carol_events = simulation.ui_events(carol);
+        let listings = carol_events
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                UICommand::UpdateRooms(listings) => Some(listings.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(listings.len(), 1);
+        assert_eq!(listings[0].members, 2);
+        assert_eq!(listings[0].topic, winner.clone().map(|(_, topic)| topic));
+        assert_eq!(last_topic(&carol_events), None);
+
+        simulation.command(carol, NetCommand::JoinRoom("ops".into()));
+        simulation.run_for(Simulation::TICK);
+        assert_eq!(last_topic(&simulation.ui_events(carol)), winner);
+    }
+
+    #[test]
+    fn typing_shows_while_the_input_has_text_and_expires() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(23);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.ui_events(bob);
+
+        // Peers that can't prove who they are aren't shown.
+        let mut mallory = simulation.segment.attach();
+        mallory
+            .try_send(Packet::Typing {
+                id: ktp::generate_id(),
+                counter: 1,
+                room: String::new(),
+                signature: [0; 64],
+            })
+            .unwrap();
+        drop(mallory);
+        simulation.run_for(Simulation::TICK);
+        assert!(typing(&simulation.ui_events(bob)).is_empty());
+
+        // Typing lasts longer than a single typing packet would.
+        simulation.command(alice, NetCommand::SetTyping(Some(String::new())));
+        simulation.run_for(TYPING_TIMEOUT * 2);
+        let bob_events = simulation.ui_events(bob);
+        assert_eq!(typing(&bob_events), vec![vec!["alice".to_string()]]);
+        assert!(
+            !bob_events
+                .iter()
+                .any(|event| matches!(event, UICommand::AlertUser))
+        );
+
+        // The message ends typing right away, as the input is cleared.
+        simulation.command(alice, NetCommand::SetTyping(None));
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "hi".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK * 2);
+        assert_eq!(
+            typing(&simulation.ui_events(bob)),
+            vec![Vec::<String>::new()]
+        );
+
+        // Without a message, it expires.
+        simulation.command(alice, NetCommand::SetTyping(Some(String::new())));
+        simulation.run_for(Simulation::TICK * 2);
+        simulation.command(alice, NetCommand::SetTyping(None));
+        simulation.run_for(TYPING_TIMEOUT * 2);
+        assert_eq!(
+            typing(&simulation.ui_events(bob)),
+            vec![vec!["alice".to_string()], vec![]]
+        );
+    }
+
+    #[test]
+    fn only_the_sender_changes_a_message() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(24);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "helo".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK * 5);
+        let (alice_id, message_id) = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::ShowMessage { id, message_id, .. } => Some((*id, *message_id)),
+                _ => None,
+            })
+            .unwrap();
+        let edit = |message_text: &str| NetCommand::EditMessage {
+            room: String::new(),
+            message_id,
+            message_text: message_text.into(),
+        };
+
+        // Neither another peer, nor somebody posing as the sender.
+        simulation.command(bob, edit("pwned"));
+        let mut mallory = simulation.segment.attach();
+        // Nor somebody who claims the message with one of their own.
+        mallory
+            .try_send(Packet::Message {
+                id: ktp::generate_id(),
+                counter: 1,
+                room: String::new(),
+                message_id,
+                reply_to: None,
+                compression: ktp::Compression::Smaz,
+                message_text: "mine now".into(),
+                signature: [0; 64],
+            })
+            .unwrap();
+        for id in [alice_id, ktp::generate_id()] {
+            mallory
+                .try_send(Packet::EditMessage {
+                    id,
+                    counter: 1,
+                    room: String::new(),
+                    message_id,
+                    message_text: "pwned".into(),
+                    signature: [0; 64],
+                })
+                .unwrap();
+        }
+        drop(mallory);
+        simulation.run_for(Simulation::TICK * 2);
+        assert!(changes(&simulation.ui_events(bob)).is_empty());
+
+        simulation.command(alice, edit("hello"));
+        simulation.run_for(Simulation::TICK * 2);
+        let expected = vec![(message_id, Some("hello".to_string()))];
+        assert_eq!(changes(&simulation.ui_events(bob)), expected);
+        assert_eq!(changes(&simulation.ui_events(alice)), expected);
+
+        // Deleted messages stay deleted.
+        simulation.command(
+            alice,
+            NetCommand::DeleteMessage {
+                room: String::new(),
+                message_id,
+            },
+        );
+        simulation.command(alice, edit("hello again"));
+        simulation.run_for(Simulation::TICK * 2);
+        assert_eq!(
+            changes(&simulation.ui_events(bob)),
+            vec![(message_id, None)]
+        );
+    }
+
+    #[test]
+    fn replies_point_at_their_parent() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(25);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "lunch?".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK * 5);
+        let parent = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::ShowMessage { message_id, .. } => Some(*message_id),
+                _ => None,
+            })
+            .unwrap();
+
+        // Parents that never arrived are passed on, the UI quotes them as missing.
+        let missing = ktp::generate_id();
+        for reply_to in [parent, missing] {
+            simulation.command(
+                bob,
+                NetCommand::SendMessage {
+                    room: String::new(),
+                    message_text: "sure".into(),
+                    reply_to: Some(reply_to),
+                },
+            );
+        }
+        simulation.run_for(Simulation::TICK * 5);
+
+        let replies = |events: &[UICommand], is_outgoing: bool| -> Vec<Option<ktp::Id>> {
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    UICommand::ShowMessage {
+                        reply_to,
+                        is_outgoing_message,
+                        ..
+                    } if *is_outgoing_message == is_outgoing => Some(*reply_to),
+                    _ => None,
+                })
+                .collect()
+        };
+        let expected = vec![Some(parent), Some(missing)];
+        assert_eq!(replies(&simulation.ui_events(alice), false), expected);
+        assert_eq!(replies(&simulation.ui_events(bob), true), expected);
+    }
+
+    #[test]
+    fn forged_and_impersonating_peers_are_flagged() {
+        let bob = 1;
+        let mut simulation = two_nodes(9);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        let alice_id = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::PresenceUpdate { id, username, .. } if username == "alice" => {
+                    Some(*id)
+                },
+                _ => None,
+            })
+            .unwrap();
+
+        let mut mallory = simulation.segment.attach();
+        mallory
+            .try_send(Packet::Message {
+                id: alice_id,
+                counter: 1,
+                room: String::new(),
+                message_id: ktp::generate_id(),
+                reply_to: None,
+                compression: ktp::Compression::Smaz,
+                message_text: "send me your password".into(),
+                signature: [0; 64],
+            })
+            .unwrap();
+        // Nobody reads for Mallory, so the segment wouldn't settle.
+        drop(mallory);
+        simulation.run_for(Simulation::TICK);
+
+        let bob_events = simulation.ui_events(bob);
+        assert!(messages(&bob_events).is_empty());
+        assert!(bob_events.iter().any(|event| matches!(
+            event,
+            UICommand::ShowForgeryWarning { id, .. } if *id == alice_id
+        )));
+
+        simulation.add_node("alice");
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        let bob_events = simulation.ui_events(bob);
+        let trusts: Vec<(ktp::Id, Trust)> = bob_events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::PresenceUpdate {
+                    id,
+                    username,
+                    trust,
+                    ..
+                } if username == "alice" => Some((*id, *trust)),
+                _ => None,
+            })
+            .collect();
+        assert!(trusts.contains(&(alice_id, Trust::Verified)));
+        assert!(
+            trusts
+                .iter()
+                .any(|(id, trust)| *id != alice_id && *trust == Trust::Impersonator)
+        );
+        assert!(!trusts.contains(&(alice_id, Trust::Impersonator)));
+        let warnings = bob_events
+            .iter()
+            .filter(|event| matches!(event, UICommand::ShowKeyMismatchWarning { .. }))
+            .count();
+        assert_eq!(warnings, 1);
+    }
+
+    #[test]
+    fn room_members_are_trusted_like_everybody() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(28);
+        for node in [alice, bob] {
+            simulation.command(node, NetCommand::JoinRoom("ops".into()));
+        }
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.command(alice, NetCommand::Terminate);
+        simulation.run_for(Simulation::TICK);
+        simulation.ui_events(bob);
+
+        // Somebody else takes the username once Alice is gone, with another key.
+        let impostor = simulation.add_node("alice");
+        simulation.command(impostor, NetCommand::JoinRoom("ops".into()));
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        let trusts: Vec<(String, Trust)> = simulation
+            .ui_events(bob)
+            .into_iter()
+            .filter_map(|event| match event {
+                UICommand::PresenceUpdate {
+                    room,
+                    username,
+                    trust,
+                    ..
+                } if username == "alice" => Some((room, trust)),
+                _ => None,
+            })
+            .collect();
+        assert!(trusts.iter().any(|(room, _)| room == "ops"));
+        assert!(
+            trusts
+                .iter()
+                .all(|(_, trust)| *trust == Trust::Impersonator)
+        );
+    }
+
+    #[test]
+    fn garbage_on_the_segment_is_dropped() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(12);
+
+        // A message packet with a body that doesn't decode, every tick.
+        let garbage: Vec<Vec<u8>> =
+            ktp::fragment(0, &[0xff; 40], ktp::PACKET_DATA_SIZE, false)
+                .unwrap()
+                .iter()
+                .map(|part| {
+                    arp::build(
+                        arp::OPCODE_REQUEST,
+                        MacAddr::broadcast(),
+                        MacAddr::new(0x02, 0, 0, 0, 0xff, 0xff),
+                        MacAddr::zero(),
+                        EtherType::default().bytes(),
+                        part,
+                    )
+                })
+                .collect();
+        let mut elapsed = Duration::ZERO;
+        while elapsed < INACTIVE_TIMEOUT + HEARTBEAT_INTERVAL {
+            for frame in &garbage {
+                simulation.segment.inject(frame);
+            }
+            simulation.run_for(Simulation::TICK);
+            elapsed += Simulation::TICK;
+        }
+        simulation.command(
+            bob,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "still here".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+
+        let events = simulation.ui_events(alice);
+        assert!(
+            !presence_updates(&events)
+                .iter()
+                .any(|(_, is_inactive, _)| *is_inactive)
+        );
+        assert_eq!(
+            messages(&events),
+            vec![("bob".to_string(), "still here".to_string())]
+        );
+    }
+
+    #[test]
+    fn replayed_message_is_shown_once() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(10);
+
+        // Mallory records the signed message and sends it again later.
+        let mut mallory = simulation.segment.attach();
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "pay bob 10".into(),
+                reply_to: None,
+            },
+        );
+        simulation.nodes[alice].thread.as_mut().unwrap().step();
+        let recorded = memory::receive_all(&mut mallory)
+            .into_iter()
+            .find(|packet| matches!(packet, Packet::Message { .. }))
+            .unwrap();
+
+        mallory.try_send(recorded.clone()).unwrap();
+        mallory.try_send(recorded).unwrap();
+        // Nobody reads for Mallory, so the segment wouldn't settle.
+        drop(mallory);
+        simulation.run_for(HEARTBEAT_INTERVAL);
+
+        assert_eq!(
+            messages(&simulation.ui_events(bob)),
+            vec![("alice".to_string(), "pay bob 10".to_string())]
+        );
+    }
+
+    #[test]
+    fn message_is_acknowledged_by_every_peer() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(11);
+        let carol = simulation.add_node("carol");
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.ui_events(alice);
+
+        // Acks get lost too, retransmissions bring them back.
+        simulation.set_faults(Faults {
+            loss: 0.3,
+            ..Faults::default()
+        });
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "hello".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Duration::from_secs(20));
+
+        let states = delivery_states(&simulation.ui_events(alice));
+        assert_eq!(states.last(), Some(&DeliveryState::Delivered { peers: 2 }));
+        for node in [bob, carol] {
+            assert_eq!(
+                messages(&simulation.ui_events(node)),
+                vec![("alice".to_string(), "hello".to_string())]
+            );
+        }
+    }
+
+    #[test]
+    fn failed_message_can_be_retried() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(12);
+
+        simulation.set_faults(Faults {
+            loss: 1.0,
+            ..Faults::default()
+        });
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "anyone?".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Duration::from_secs(16));
+        assert_eq!(
+            delivery_states(&simulation.ui_events(alice)),
+            vec![DeliveryState::Failed]
+        );
+
+        simulation.set_faults(Faults::default());
+        simulation.command(alice, NetCommand::RetryFailedMessages);
+        simulation.run_for(Simulation::TICK);
+        assert_eq!(
+            delivery_states(&simulation.ui_events(alice)),
+            vec![
+                DeliveryState::Sending,
+                DeliveryState::Delivered { peers: 1 }
+            ]
+        );
+        assert_eq!(messages(&simulation.ui_events(bob)).len(), 1);
+    }
+
+    #[test]
+    fn only_recipients_confirm_delivery() {
+        let alice = 0;
+        let mut simulation = two_nodes(27);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+
+        // Mallory learns the message id and where Alice is from the message.
+        let mut mallory = simulation.segment.attach();
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "did it arrive?".into(),
+                reply_to: None,
+            },
+        );
+        simulation.nodes[alice].thread.as_mut().unwrap().step();
+        let Some(Packet::Message {
+            id: alice_id,
+            message_id,
+            ..
+        }) = memory::receive_all(&mut mallory).pop()
+        else {
+            panic!("Mallory didn't hear the message");
+        };
+        mallory
+            .try_send(Packet::Ack {
+                id: ktp::generate_id(),
+                counter: 1,
+                target: alice_id,
+                message_id,
+                signature: [0; 64],
+            })
+            .unwrap();
+        drop(mallory);
+        simulation.run_for(Simulation::TICK * 2);
+
+        assert_eq!(
+            delivery_states(&simulation.ui_events(alice)),
+            vec![DeliveryState::Delivered { peers: 1 }]
+        );
+    }
+
+    #[test]
+    fn lost_fragments_are_sent_again() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(13);
+        simulation.set_faults(Faults {
+            loss: 0.1,
+            ..Faults::default()
+        });
+
+        let long_text: String = (0..2000).map(|i| i.to_string()).collect();
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: long_text.clone(),
+                reply_to: None,
+            },
+        );
+        // Less than the retransmission timeout of the whole message.
+        simulation.run_for(RETRANSMIT_TIMEOUT - Simulation::TICK);
+
+        assert_eq!(
+            messages(&simulation.ui_events(bob)),
+            vec![("alice".to_string(), long_text)]
+        );
+    }
+
+    #[test]
+    fn long_message_goes_out_in_bursts() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(15);
+
+        // Well over 256 frames, even after compression.
+        let long_text: String = (0..20_000).map(|i| i.to_string()).collect();
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: long_text.clone(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Duration::from_secs(2));
+
+        let states = delivery_states(&simulation.ui_events(alice));
+        let percents: Vec<u8> = states
+            .iter()
+            .filter_map(|state| match state {
+                DeliveryState::Transmitting { percent } => Some(*percent),
+                _ => None,
+            })
+            .collect();
+        assert!(percents.len() > 1);
+        assert!(percents.is_sorted());
+        assert_eq!(states.last(), Some(&DeliveryState::Delivered { peers: 1 }));
+        assert_eq!(
+            messages(&simulation.ui_events(bob)),
+            vec![("alice".to_string(), long_text.clone())]
+        );
+
+        // Older clients can't put it together.
+        simulation.add_node_with_capabilities("carol", Capabilities::default());
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        simulation.ui_events(alice);
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: long_text,
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        assert!(simulation.ui_events(alice).iter().any(|event| matches!(
+            event,
+            UICommand::SendNetError(NetError::MessageTooLong)
+        )));
+    }
+
+    #[test]
+    fn extended_frames_need_a_newer_version() {
+        let alice = 0;
+        let mut simulation = two_nodes(26);
+
+        // A client of the first version, announcing every feature.
+        let dave = Identity::generate();
+        let mut channel = simulation.segment.attach();
+        channel
+            .try_send(dave.sign(Packet::PresenceInformation {
+                id: dave.id(),
+                counter: 1,
+                room: String::new(),
+                is_join: true,
+                version: 1,
+                capabilities: Capabilities::SUPPORTED,
+                username: "dave".into(),
+                public_key: dave.public_key(),
+                signature: [0; 64],
+            }))
+            .unwrap();
+        drop(channel);
+        simulation.run_for(Simulation::TICK);
+        simulation.ui_events(alice);
+
+        let long_text: String = (0..20_000).map(|i| i.to_string()).collect();
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: long_text,
+                reply_to: None,
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        assert!(simulation.ui_events(alice).iter().any(|event| matches!(
+            event,
+            UICommand::SendNetError(NetError::MessageTooLong)
+        )));
+    }
+
+    #[test]
+    fn features_are_turned_off_for_outdated_peers() {
+        let mut simulation = Simulation::new(13);
+        let alice = simulation.add_node("alice");
+        let bob = simulation.add_node_with_capabilities("bob", Capabilities::default());
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+
+        let alice_events = simulation.ui_events(alice);
+        let warnings: Vec<_> = alice_events
+            .iter()
+            .filter_map(|event| match event {
+                UICommand::ShowOutdatedPeerWarning { username, missing } => {
+                    Some((username.clone(), *missing))
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(warnings, vec![("bob".to_string(), Capabilities::SUPPORTED)]);
+        let bob_events = simulation.ui_events(bob);
+        assert!(
+            !bob_events
+                .iter()
+                .any(|event| matches!(event, UICommand::ShowOutdatedPeerWarning { .. }))
+        );
+
+        // Bob never acknowledges, so the message isn't tracked at all.
+        simulation.command(
+            alice,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "hi".into(),
+                reply_to: None,
+            },
+        );
+        simulation.run_for(RETRANSMIT_TIMEOUT * 20);
+        assert!(delivery_states(&simulation.ui_events(alice)).is_empty());
+        assert_eq!(
+            messages(&simulation.ui_events(bob)),
+            vec![("alice".to_string(), "hi".to_string())]
+        );
+
+        let bob_id = bob_events
+            .iter()
+            .find_map(|event| match event {
+                UICommand::PresenceUpdate { id, username, .. } if username == "bob" => {
+                    Some(*id)
+                },
+                _ => None,
+            })
+            .unwrap();
+        simulation.command(
+            alice,
+            NetCommand::SendDirectMessage {
+                target: bob_id,
+                message_text: "psst".into(),
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        assert!(simulation.ui_events(alice).iter().any(|event| matches!(
+            event,
+            UICommand::SendNetWarning(NetError::PeerTooOld)
+        )));
+        assert!(direct_messages(&simulation.ui_events(bob)).is_empty());
+    }
+
+    #[test]
+    fn file_transfer_resumes_and_survives_loss() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(17);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        let bob_id = simulation
+            .ui_events(alice)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::PresenceUpdate { id, username, .. } if username == "bob" => {
+                    Some(*id)
+                },
+                _ => None,
+            })
+            .unwrap();
+        simulation.ui_events(bob);
+
+        let directory =
+            std::env::temp_dir().join(format!("arpchat-transfer-{}", std::process::id()));
+        let received = directory.join("received");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&received).unwrap();
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let source = directory.join("notes.txt");
+        fs::write(&source, &content).unwrap();
+        // Half of the file is left from an earlier attempt.
+        let part_path = transfer::part_path(&received, &Sha256::digest(&content).into());
+        fs::write(&part_path, &content[..50_000]).unwrap();
+
+        simulation.set_faults(Faults {
+            loss: 0.1,
+            ..Faults::default()
+        });
+        simulation.command(
+            alice,
+            NetCommand::SendFile {
+                target: bob_id,
+                path: source,
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        let transfer_id = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::ShowTransfer {
+                    transfer_id,
+                    name,
+                    size,
+                    is_outgoing: false,
+                    ..
+                } if name == "notes.txt" && *size == 100_000 => Some(*transfer_id),
+                _ => None,
+            })
+            .unwrap();
+
+        simulation.command(
+            bob,
+            NetCommand::AcceptFile {
+                transfer_id,
+                directory: received.clone(),
+            },
+        );
+        simulation.run_for(STALL_TIMEOUT * 10);
+
+        let bob_states = transfer_states(&simulation.ui_events(bob));
+        assert_eq!(
+            bob_states.first(),
+            Some(&TransferState::Transferring { percent: 50 })
+        );
+        assert_eq!(
+            bob_states.last(),
+            Some(&TransferState::Done {
+                path: Some(received.join("notes.txt"))
+            })
+        );
+        assert_eq!(
+            transfer_states(&simulation.ui_events(alice)).last(),
+            Some(&TransferState::Done { path: None })
+        );
+        assert_eq!(fs::read(received.join("notes.txt")).unwrap(), content);
+        assert!(!part_path.exists());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn file_chunks_come_only_from_the_sender() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(29);
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        let bob_id = simulation
+            .ui_events(alice)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::PresenceUpdate { id, username, .. } if username == "bob" => {
+                    Some(*id)
+                },
+                _ => None,
+            })
+            .unwrap();
+        let alice_id = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::PresenceUpdate { id, username, .. } if username == "alice" => {
+                    Some(*id)
+                },
+                _ => None,
+            })
+            .unwrap();
+
+        let directory =
+            std::env::temp_dir().join(format!("arpchat-chunks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("report.txt");
+        fs::write(&source, vec![7; 1000]).unwrap();
+        simulation.command(
+            alice,
+            NetCommand::SendFile {
+                target: bob_id,
+                path: source,
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        let transfer_id = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::ShowTransfer { transfer_id, .. } => Some(*transfer_id),
+                _ => None,
+            })
+            .unwrap();
+
+        // Mallory learns where Bob is from a message of his,
+        // and answers his acceptance before Alice does.
+        let mut mallory = simulation.segment.attach();
+        simulation.command(
+            bob,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "sending it?".into(),
+                reply_to: None,
+            },
+        );
+        simulation.nodes[bob].thread.as_mut().unwrap().step();
+        memory::receive_all(&mut mallory);
+        simulation.command(
+            bob,
+            NetCommand::AcceptFile {
+                transfer_id,
+                directory: directory.join("received"),
+            },
+        );
+        simulation.nodes[bob].thread.as_mut().unwrap().step();
+        mallory
+            .try_send(Packet::FileChunk {
+                id: alice_id,
+                counter: 1,
+                target: bob_id,
+                transfer_id,
+                offset: 0,
+                data: vec![6; 1000],
+            })
+            .unwrap();
+        drop(mallory);
+        simulation.run_for(Simulation::TICK * 2);
+
+        assert_eq!(
+            transfer_states(&simulation.ui_events(bob)).last(),
+            Some(&TransferState::Done {
+                path: Some(directory.join("received").join("report.txt"))
+            })
+        );
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn only_verified_offers_within_the_limit_are_accepted() {
+        let (alice, bob) = (0, 1);
+        let mut simulation = two_nodes(25);
+        let node = &mut simulation.nodes[bob];
+        node.thread = node
+            .thread
+            .take()
+            .map(|thread| thread.with_max_file_size(1000));
+        simulation.run_for(HEARTBEAT_INTERVAL * 2);
+        let bob_id = simulation
+            .ui_events(alice)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::PresenceUpdate { id, username, .. } if username == "bob" => {
+                    Some(*id)
+                },
+                _ => None,
+            })
+            .unwrap();
+        // Mallory learns where Bob is from one of Bob's messages.
+        let mut mallory = simulation.segment.attach();
+        simulation.command(
+            bob,
+            NetCommand::SendMessage {
+                room: String::new(),
+                message_text: "anyone got the invoice?".into(),
+                reply_to: None,
+            },
+        );
+        simulation.nodes[bob].thread.as_mut().unwrap().step();
+        memory::receive_all(&mut mallory);
+        simulation.ui_events(bob);
+
+        mallory
+            .try_send(Packet::FileOffer {
+                id: ktp::generate_id(),
+                counter: 1,
+                target: bob_id,
+                transfer_id: ktp::generate_id(),
+                size: 10,
+                hash: [0; 32],
+                name: "invoice.pdf.exe".into(),
+                signature: [0; 64],
+            })
+            .unwrap();
+        drop(mallory);
+        simulation.run_for(Simulation::TICK * 2);
+        assert!(!simulation.ui_events(bob).iter().any(|event| matches!(
+            event,
+            UICommand::AlertUser | UICommand::ShowTransfer { .. }
+        )));
+
+        let source = std::env::temp_dir()
+            .join(format!("arpchat-too-large-{}.bin", std::process::id()));
+        fs::write(&source, vec![7; 2000]).unwrap();
+        simulation.command(
+            alice,
+            NetCommand::SendFile {
+                target: bob_id,
+                path: source.clone(),
+            },
+        );
+        simulation.run_for(Simulation::TICK);
+        let transfer_id = simulation
+            .ui_events(bob)
+            .iter()
+            .find_map(|event| match event {
+                UICommand::ShowTransfer { transfer_id, .. } => Some(*transfer_id),
+                _ => None,
+            })
+            .unwrap();
+        simulation.command(
+            bob,
+            NetCommand::AcceptFile {
+                transfer_id,
+                directory: std::env::temp_dir(),
+            },
+        );
+        simulation.run_for(Simulation::TICK * 2);
+
+        assert_eq!(
+            transfer_states(&simulation.ui_events(bob)),
+            vec![TransferState::Failed(TransferFailure::TooLarge)]
+        );
+        assert_eq!(
+            transfer_states(&simulation.ui_events(alice)).last(),
+            Some(&TransferState::Cancelled)
+        );
+
+        let _ = fs::remove_file(&source);
+    }
+}
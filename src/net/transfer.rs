@@ -0,0 +1,701 @@
+use crate::error::net::NetError;
+use crate::net::ktp::{self, Packet};
+use crate::net::replay::{RecentIds, Sequencer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Data of a chunk, a few dozen frames of ARP.
+pub const CHUNK_SIZE: usize = 8 * 1024;
+// Chunks on the way at once, the sender waits for acceptance of the oldest one.
+pub const WINDOW_CHUNKS: u64 = 4;
+// Without any progress the sender goes back to the last accepted offset.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+pub const MAX_STALLS: u32 = 5;
+// An offer without an answer is sent again, as the peer might have missed it.
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(5);
+pub const MAX_OFFERS: u32 = 3;
+// Files are read into memory whole before sending.
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+// Ids of this many received files are remembered to repeat lost completions.
+const FINISHED_CAPACITY: usize = 256;
+
+/// What the user sees next to a transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferState {
+    /// Waiting for the receiver to accept.
+    Offered,
+    Transferring {
+        percent: u8,
+    },
+    /// Received files are saved to the path.
+    Done {
+        path: Option<PathBuf>,
+    },
+    Failed(TransferFailure),
+    Cancelled,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransferFailure {
+    /// The received file didn't match the hash of the offer, it was deleted.
+    Corrupted,
+    PeerLeft,
+    /// The peer stopped answering.
+    Stalled,
+    /// The offered size is above the limit for received files.
+    TooLarge,
+    WriteFailed,
+}
+
+/// A new transfer to show.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    pub transfer_id: ktp::Id,
+    pub peer: ktp::Id,
+    pub name: String,
+    pub size: u64,
+}
+
+struct Outgoing {
+    peer: ktp::Id,
+    name: String,
+    hash: ktp::Hash,
+    data: Vec<u8>,
+    is_accepted: bool,
+    // Everything before it is received by the peer.
+    accepted: u64,
+    sent: u64,
+    // Offers before acceptance, stalls after it.
+    attempts: u32,
+    deadline: Instant,
+    percent: u8,
+}
+
+struct Incoming {
+    peer: ktp::Id,
+    name: String,
+    size: u64,
+    hash: ktp::Hash,
+    // Set once the user accepts the file.
+    destination: Option<Destination>,
+    received: u64,
+    percent: u8,
+}
+
+struct Destination {
+    directory: PathBuf,
+    part_path: PathBuf,
+    file: File,
+}
+
+/// File transfers with the peers. <br>
+/// The file is sent in chunks, each of them a packet fragmented by the transport.
+/// The receiver accepts every chunk by asking for the next offset, so a transfer
+/// resumes after lost chunks and, thanks to the partial file kept on disk,
+/// after the peer goes away. The whole file is checked against the hash of the offer.
+pub struct Transfers {
+    own_id: ktp::Id,
+    outgoing: HashMap<ktp::Id, Outgoing>,
+    incoming: HashMap<ktp::Id, Incoming>,
+    // Received files, their senders may still wait for the completion.
+    finished: RecentIds,
+    // States to show, collected until the net thread takes them.
+    updates: Vec<(ktp::Id, TransferState)>,
+    // Larger offers can't be accepted.
+    max_incoming_size: u64,
+}
+
+impl Transfers {
+    pub fn new(own_id: ktp::Id) -> Self {
+        Self {
+            own_id,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            finished: RecentIds::with_capacity(FINISHED_CAPACITY),
+            updates: vec![],
+            max_incoming_size: MAX_FILE_SIZE,
+        }
+    }
+
+    pub fn set_max_incoming_size(&mut self, max_incoming_size: u64) {
+        self.max_incoming_size = max_incoming_size;
+    }
+
+    /// Reads the file and offers it to the target.
+    pub fn offer(
+        &mut self, target: ktp::Id, path: &Path, sequencer: &mut Sequencer, now: Instant,
+    ) -> Result<(Offer, Packet), NetError> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(NetError::FileReadFailed(
+                std::io::ErrorKind::InvalidInput.into(),
+            ))?
+            .to_string();
+        let size = fs::metadata(path).map_err(NetError::FileReadFailed)?.len();
+        if size > MAX_FILE_SIZE {
+            return Err(NetError::FileTooLarge);
+        }
+        let data = fs::read(path).map_err(NetError::FileReadFailed)?;
+
+        let transfer_id = ktp::generate_id();
+        let outgoing = Outgoing {
+            peer: target,
+            name,
+            hash: Sha256::digest(&data).into(),
+            data,
+            is_accepted: false,
+            accepted: 0,
+            sent: 0,
+            attempts: 1,
+            deadline: now + OFFER_TIMEOUT,
+            percent: 0,
+        };
+        let packet = offer_packet(self.own_id, transfer_id, &outgoing, sequencer);
+        let offer = Offer {
+            transfer_id,
+            peer: target,
+            name: outgoing.name.clone(),
+            size: outgoing.data.len() as u64,
+        };
+        self.outgoing.insert(transfer_id, outgoing);
+
+        Ok((offer, packet))
+    }
+
+    /// Returns the offer to show, unless it was seen already or names no file. <br>
+    /// An accepted offer sent again means our acceptance was lost, so it is repeated.
+    pub fn receive_offer(
+        &mut self, sender: ktp::Id, transfer_id: ktp::Id, name: &str, size: u64,
+        hash: ktp::Hash, sequencer: &mut Sequencer,
+    ) -> (Option<Offer>, Vec<Packet>) {
+        if let Some(incoming) = self.incoming.get(&transfer_id) {
+            let packets = match incoming.destination {
+                Some(_) if incoming.peer == sender => vec![accept_packet(
+                    self.own_id,
+                    sender,
+                    transfer_id,
+                    incoming.received,
+                    sequencer,
+                )],
+                _ => vec![],
+            };
+            return (None, packets);
+        }
+        let Some(name) = file_name(name) else {
+            log::warn!("Dropped file offer without a valid name.");
+            return (None, vec![]);
+        };
+
+        self.incoming.insert(
+            transfer_id,
+            Incoming {
+                peer: sender,
+                name: name.clone(),
+                size,
+                hash,
+                destination: None,
+                received: 0,
+                percent: 0,
+            },
+        );
+        let offer = Offer {
+            transfer_id,
+            peer: sender,
+            name,
+            size,
+        };
+        (Some(offer), vec![])
+    }
+
+    /// Starts receiving into the directory, from where an earlier attempt stopped.
+    pub fn accept(
+        &mut self, transfer_id: ktp::Id, directory: &Path, sequencer: &mut Sequencer,
+    ) -> Vec<Packet> {
+        let own_id = self.own_id;
+        let Some(incoming) = self.incoming.get_mut(&transfer_id) else {
+            return vec![];
+        };
+        if incoming.destination.is_some() {
+            return vec![];
+        }
+        if incoming.size > self.max_incoming_size {
+            log::warn!("Declined file of {} bytes, above the limit.", incoming.size);
+            return self.fail(transfer_id, TransferFailure::TooLarge, sequencer);
+        }
+
+        let part_path = part_path(directory, &incoming.hash);
+        let opened = fs::create_dir_all(directory).and_then(|()| {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&part_path)?;
+            // Whatever is there beyond the size isn't part of the file.
+            if file.metadata()?.len() > incoming.size {
+                file.set_len(0)?;
+            }
+            Ok(file)
+        });
+        let file = match opened {
+            Ok(file) => file,
+            Err(err) => {
+                log::error!("Couldn't open {}: {err}", part_path.display());
+                return self.fail(transfer_id, TransferFailure::WriteFailed, sequencer);
+            },
+        };
+
+        incoming.received = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        incoming.destination = Some(Destination {
+            directory: directory.to_path_buf(),
+            part_path,
+            file,
+        });
+        if incoming.received > 0 {
+            log::info!(
+                "Resuming {} from {} bytes.",
+                incoming.name,
+                incoming.received
+            );
+        }
+        incoming.percent = percent(incoming.received, incoming.size);
+        self.updates.push((
+            transfer_id,
+            TransferState::Transferring {
+                percent: incoming.percent,
+            },
+        ));
+
+        let (peer, received) = (incoming.peer, incoming.received);
+        if received == incoming.size {
+            return self.finish(transfer_id, sequencer);
+        }
+        vec![accept_packet(
+            own_id,
+            peer,
+            transfer_id,
+            received,
+            sequencer,
+        )]
+    }
+
+    /// The peer asks for the file from the offset on.
+    pub fn receive_accept(
+        &mut self, sender: ktp::Id, transfer_id: ktp::Id, offset: u64, now: Instant,
+    ) {
+        let Some(outgoing) = self.outgoing.get_mut(&transfer_id) else {
+            return;
+        };
+        if outgoing.peer != sender || offset > outgoing.data.len() as u64 {
+            return;
+        }
+
+        let is_first = !outgoing.is_accepted;
+        if is_first || offset > outgoing.accepted {
+            outgoing.attempts = 0;
+            outgoing.deadline = now + STALL_TIMEOUT;
+        }
+        // Asking for less than before means the receiver started over.
+        if is_first || offset < outgoing.accepted {
+            outgoing.sent = offset;
+        }
+        outgoing.is_accepted = true;
+        outgoing.accepted = offset;
+        outgoing.sent = outgoing.sent.max(offset);
+
+        let percent = percent(offset, outgoing.data.len() as u64);
+        if percent != outgoing.percent || is_first {
+            outgoing.percent = percent;
+            self.updates
+                .push((transfer_id, TransferState::Transferring { percent }));
+        }
+    }
+
+    /// Writes the chunk, if it is the next one. Returns the packets to answer with.
+    pub fn receive_chunk(
+        &mut self, sender: ktp::Id, transfer_id: ktp::Id, offset: u64, data: &[u8],
+        sequencer: &mut Sequencer,
+    ) -> Vec<Packet> {
+        let own_id = self.own_id;
+        let Some(incoming) = self.incoming.get_mut(&transfer_id) else {
+            // Our completion was lost.
+            if self.finished.contains(&transfer_id) {
+                return vec![complete_packet(own_id, sender, transfer_id, sequencer)];
+            }
+            return vec![];
+        };
+        let Some(destination) = incoming.destination.as_mut() else {
+            return vec![];
+        };
+        if incoming.peer != sender {
+            return vec![];
+        }
+
+        // Chunks after a lost one are dropped, the sender goes back for them.
+        if offset > incoming.received {
+            return vec![];
+        }
+        // Our acceptance was lost, the sender needs another one.
+        if offset < incoming.received {
+            return vec![accept_packet(
+                own_id,
+                sender,
+                transfer_id,
+                incoming.received,
+                sequencer,
+            )];
+        }
+        if incoming.received + data.len() as u64 > incoming.size {
+            log::warn!("Dropped file chunk beyond the offered size.");
+            return vec![];
+        }
+
+        if let Err(err) = destination.file.write_all(data) {
+            log::error!("Couldn't write {}: {err}", destination.part_path.display());
+            return self.fail(transfer_id, TransferFailure::WriteFailed, sequencer);
+        }
+        incoming.received += data.len() as u64;
+
+        let percent = percent(incoming.received, incoming.size);
+        if percent != incoming.percent {
+            incoming.percent = percent;
+            self.updates
+                .push((transfer_id, TransferState::Transferring { percent }));
+        }
+
+        if incoming.received == incoming.size {
+            return self.finish(transfer_id, sequencer);
+        }
+        vec![accept_packet(
+            own_id,
+            sender,
+            transfer_id,
+            incoming.received,
+            sequencer,
+        )]
+    }
+
+    pub fn receive_complete(&mut self, sender: ktp::Id, transfer_id: ktp::Id) {
+        if self
+            .outgoing
+            .get(&transfer_id)
+            .is_some_and(|outgoing| outgoing.peer == sender)
+        {
+            self.outgoing.remove(&transfer_id);
+            self.updates
+                .push((transfer_id, TransferState::Done { path: None }));
+        }
+    }
+
+    /// The partial file is kept, so the same file offered again resumes.
+    pub fn receive_cancel(&mut self, sender: ktp::Id, transfer_id: ktp::Id) {
+        let is_removed = match self.outgoing.get(&transfer_id) {
+            Some(outgoing) if outgoing.peer == sender => {
+                self.outgoing.remove(&transfer_id).is_some()
+            },
+            _ => match self.incoming.get(&transfer_id) {
+                Some(incoming) if incoming.peer == sender => {
+                    self.incoming.remove(&transfer_id).is_some()
+                },
+                _ => false,
+            },
+        };
+
+        if is_removed {
+            self.updates.push((transfer_id, TransferState::Cancelled));
+        }
+    }
+
+    /// Declines an offer or stops a transfer, returns the packet to tell the peer.
+    pub fn cancel(
+        &mut self, transfer_id: ktp::Id, sequencer: &mut Sequencer,
+    ) -> Option<Packet> {
+        let peer = match self.outgoing.remove(&transfer_id) {
+            Some(outgoing) => outgoing.peer,
+            None => self.incoming.remove(&transfer_id)?.peer,
+        };
+
+        self.updates.push((transfer_id, TransferState::Cancelled));
+        Some(cancel_packet(self.own_id, peer, transfer_id, sequencer))
+    }
+
+    /// The peer went away, its transfers can only be resumed with a new offer.
+    pub fn forget_peer(&mut self, peer: &ktp::Id) {
+        let mut failed = vec![];
+        self.outgoing.retain(|transfer_id, outgoing| {
+            let is_kept = outgoing.peer != *peer;
+            if !is_kept {
+                failed.push(*transfer_id);
+            }
+            is_kept
+        });
+        self.incoming.retain(|transfer_id, incoming| {
+            let is_kept = incoming.peer != *peer;
+            if !is_kept {
+                failed.push(*transfer_id);
+            }
+            is_kept
+        });
+
+        for transfer_id in failed {
+            self.updates.push((
+                transfer_id,
+                TransferState::Failed(TransferFailure::PeerLeft),
+            ));
+        }
+    }
+
+    /// Chunks within the window, offers sent again and transfers that stalled.
+    pub fn poll(&mut self, sequencer: &mut Sequencer, now: Instant) -> Vec<Packet> {
+        let own_id = self.own_id;
+        let mut packets = vec![];
+        let mut stalled = vec![];
+
+        for (transfer_id, outgoing) in self.outgoing.iter_mut() {
+            if now >= outgoing.deadline {
+                match outgoing.is_accepted {
+                    // Offers that nobody answered simply stay unanswered.
+                    false if outgoing.attempts >= MAX_OFFERS => {},
+                    false => {
+                        outgoing.attempts += 1;
+                        outgoing.deadline = now + OFFER_TIMEOUT;
+                        packets.push(offer_packet(
+                            own_id,
+                            *transfer_id,
+                            outgoing,
+                            sequencer,
+                        ));
+                    },
+                    true if outgoing.attempts >= MAX_STALLS => {
+                        stalled.push(*transfer_id);
+                        continue;
+                    },
+                    true => {
+                        log::debug!("File transfer stalled, going back.");
+                        outgoing.attempts += 1;
+                        outgoing.sent = outgoing.accepted;
+                        outgoing.deadline = now + STALL_TIMEOUT;
+                    },
+                }
+            }
+
+            if !outgoing.is_accepted {
+                continue;
+            }
+            let size = outgoing.data.len() as u64;
+            let window_end =
+                size.min(outgoing.accepted + WINDOW_CHUNKS * CHUNK_SIZE as u64);
+            while outgoing.sent < window_end {
+                let end = window_end.min(outgoing.sent + CHUNK_SIZE as u64);
+                packets.push(Packet::FileChunk {
+                    id: own_id,
+                    counter: sequencer.next_counter(),
+                    target: outgoing.peer,
+                    transfer_id: *transfer_id,
+                    offset: outgoing.sent,
+                    data: outgoing.data[outgoing.sent as usize..end as usize].to_vec(),
+                });
+                outgoing.sent = end;
+            }
+        }
+
+        for transfer_id in stalled {
+            packets.extend(self.fail(transfer_id, TransferFailure::Stalled, sequencer));
+        }
+
+        packets
+    }
+
+    /// States changed since the last call.
+    pub fn take_updates(&mut self) -> Vec<(ktp::Id, TransferState)> {
+        std::mem::take(&mut self.updates)
+    }
+
+    // The whole file is there, it is kept only if it matches the hash.
+    fn finish(&mut self, transfer_id: ktp::Id, sequencer: &mut Sequencer) -> Vec<Packet> {
+        let Some(incoming) = self.incoming.remove(&transfer_id) else {
+            return vec![];
+        };
+        let Some(destination) = incoming.destination else {
+            return vec![];
+        };
+        drop(destination.file);
+
+        let hash: Option<ktp::Hash> = fs::read(&destination.part_path)
+            .ok()
+            .map(|data| Sha256::digest(data).into());
+        if hash != Some(incoming.hash) {
+            log::warn!("Received {} doesn't match its hash.", incoming.name);
+            let _ = fs::remove_file(&destination.part_path);
+            self.updates.push((
+                transfer_id,
+                TransferState::Failed(TransferFailure::Corrupted),
+            ));
+            return vec![cancel_packet(
+                self.own_id,
+                incoming.peer,
+                transfer_id,
+                sequencer,
+            )];
+        }
+
+        let path = available_path(&destination.directory, &incoming.name);
+        if let Err(err) = fs::rename(&destination.part_path, &path) {
+            log::error!("Couldn't save {}: {err}", path.display());
+            self.updates.push((
+                transfer_id,
+                TransferState::Failed(TransferFailure::WriteFailed),
+            ));
+            return vec![cancel_packet(
+                self.own_id,
+                incoming.peer,
+                transfer_id,
+                sequencer,
+            )];
+        }
+
+        log::info!("Received {}.", path.display());
+        self.finished.insert(transfer_id);
+        self.updates
+            .push((transfer_id, TransferState::Done { path: Some(path) }));
+        vec![complete_packet(
+            self.own_id,
+            incoming.peer,
+            transfer_id,
+            sequencer,
+        )]
+    }
+
+    fn fail(
+        &mut self, transfer_id: ktp::Id, failure: TransferFailure,
+        sequencer: &mut Sequencer,
+    ) -> Vec<Packet> {
+        let peer = match self.outgoing.remove(&transfer_id) {
+            Some(outgoing) => outgoing.peer,
+            None => match self.incoming.remove(&transfer_id) {
+                Some(incoming) => incoming.peer,
+                None => return vec![],
+            },
+        };
+
+        self.updates
+            .push((transfer_id, TransferState::Failed(failure)));
+        vec![cancel_packet(self.own_id, peer, transfer_id, sequencer)]
+    }
+}
+
+/// Where the file with the hash is kept while it is being received.
+pub fn part_path(directory: &Path, hash: &ktp::Hash) -> PathBuf {
+    let hex: String = hash[..8].iter().map(|byte| format!("{byte:02x}")).collect();
+    directory.join(format!("{hex}.part"))
+}
+
+fn offer_packet(
+    own_id: ktp::Id, transfer_id: ktp::Id, outgoing: &Outgoing, sequencer: &mut Sequencer,
+) -> Packet {
+    Packet::FileOffer {
+        id: own_id,
+        counter: sequencer.next_counter(),
+        target: outgoing.peer,
+        transfer_id,
+        size: outgoing.data.len() as u64,
+        hash: outgoing.hash,
+        name: outgoing.name.clone(),
+        signature: [0; 64],
+    }
+}
+
+fn accept_packet(
+    own_id: ktp::Id, target: ktp::Id, transfer_id: ktp::Id, offset: u64,
+    sequencer: &mut Sequencer,
+) -> Packet {
+    Packet::FileAccept {
+        id: own_id,
+        counter: sequencer.next_counter(),
+        target,
+        transfer_id,
+        offset,
+        signature: [0; 64],
+    }
+}
+
+fn complete_packet(
+    own_id: ktp::Id, target: ktp::Id, transfer_id: ktp::Id, sequencer: &mut Sequencer,
+) -> Packet {
+    Packet::FileComplete {
+        id: own_id,
+        counter: sequencer.next_counter(),
+        target,
+        transfer_id,
+        signature: [0; 64],
+    }
+}
+
+fn cancel_packet(
+    own_id: ktp::Id, target: ktp::Id, transfer_id: ktp::Id, sequencer: &mut Sequencer,
+) -> Packet {
+    Packet::FileCancel {
+        id: own_id,
+        counter: sequencer.next_counter(),
+        target,
+        transfer_id,
+        signature: [0; 64],
+    }
+}
+
+// Only the last component of the offered name, so the file can't land elsewhere.
+fn file_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    match name.contains('\\') {
+        true => None,
+        false => Some(name.to_string()),
+    }
+}
+
+// Files with the same name aren't overwritten, the new one gets a number.
+fn available_path(directory: &Path, name: &str) -> PathBuf {
+    let path = directory.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let name = Path::new(name);
+    let stem = name
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let extension = name
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| format!(".{extension}"))
+        .unwrap_or_default();
+    (1..)
+        .map(|number| directory.join(format!("{stem} ({number}){extension}")))
+        .find(|path| !path.exists())
+        .unwrap_or(path)
+}
+
+fn percent(done: u64, total: u64) -> u8 {
+    match total {
+        0 => 100,
+        _ => (done * 100 / total) as u8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offered_names_stay_in_the_directory() {
+        assert_eq!(file_name("notes.txt"), Some("notes.txt".to_string()));
+        assert_eq!(file_name("../../.bashrc"), Some(".bashrc".to_string()));
+        assert_eq!(file_name("/etc/passwd"), Some("passwd".to_string()));
+        assert_eq!(file_name(".."), None);
+        assert_eq!(file_name(""), None);
+        assert_eq!(file_name("..\\evil.exe"), None);
+    }
+}
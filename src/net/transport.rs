@@ -0,0 +1,123 @@
+use crate::error::net::NetError;
+use crate::net::arp_framing::ArpFraming;
+use crate::net::capabilities::Capabilities;
+use crate::net::carrier::Carrier;
+use crate::net::crypto::RoomKey;
+use crate::net::ether_type::EtherType;
+use crate::net::ktp;
+use crate::net::send_queue::SendProgress;
+use std::collections::HashSet;
+
+/// Carrier of KTP packets. <br>
+/// The net thread only talks to this trait, so it doesn't care
+/// whether packets travel over ARP or something else.
+pub trait Transport {
+    fn try_send(&mut self, packet: ktp::Packet) -> Result<(), NetError>;
+
+    /// Returns the next fully assembled packet, if there is one.
+    fn try_recv(&mut self) -> Result<Option<ktp::Packet>, NetError>;
+
+    /// Sends the next burst of frames of large packets, see `SendQueue`. <br>
+    /// Returns how much of the message they belong to is sent.
+    fn poll_send(&mut self) -> Result<Option<SendProgress>, NetError>;
+
+    /// Larger packets are neither sent nor reassembled.
+    fn set_max_packet_size(&mut self, max_packet_size: usize);
+
+    /// Only meaningful for carriers that put the EtherType on the wire.
+    fn set_ether_type(&mut self, _ether_type: EtherType) {}
+
+    /// Only meaningful for carriers over ARP.
+    fn set_arp_framing(&mut self, _arp_framing: ArpFraming) {}
+
+    /// Only meaningful for transports over a real link.
+    fn set_carrier(&mut self, _carrier: Carrier) {}
+
+    /// Features every online peer supports, some carriers frame packets
+    /// differently for them.
+    fn set_peer_capabilities(&mut self, _capabilities: Capabilities) {}
+
+    /// Hardware address the peer was last heard from, if the carrier has one.
+    fn peer_address(&self, _id: &ktp::Id) -> Option<String> {
+        None
+    }
+
+    /// The last received packet is verified to come from the peer,
+    /// so packets for it go where that one came from.
+    fn learn_peer(&mut self, _id: &ktp::Id) {}
+
+    /// The peer left, packets for it have nowhere to go.
+    fn forget_peer(&mut self, _id: &ktp::Id) {}
+
+    /// Whether the last received packet came from where the peer was learned,
+    /// the only check there is for unsigned packets.
+    fn is_from_peer(&self, _id: &ktp::Id) -> bool {
+        false
+    }
+
+    /// With a room key, only packets sealed with the same key get through.
+    fn set_room_key(&mut self, room_key: Option<RoomKey>);
+
+    /// Packets of rooms other than these and the main one are dropped
+    /// before they are deserialized.
+    fn set_rooms(&mut self, rooms: HashSet<String>);
+}
+
+/// The carrier is chosen at runtime, from the config.
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn try_send(&mut self, packet: ktp::Packet) -> Result<(), NetError> {
+        (**self).try_send(packet)
+    }
+
+    fn try_recv(&mut self) -> Result<Option<ktp::Packet>, NetError> {
+        (**self).try_recv()
+    }
+
+    fn poll_send(&mut self) -> Result<Option<SendProgress>, NetError> {
+        (**self).poll_send()
+    }
+
+    fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        (**self).set_max_packet_size(max_packet_size)
+    }
+
+    fn set_ether_type(&mut self, ether_type: EtherType) {
+        (**self).set_ether_type(ether_type)
+    }
+
+    fn set_arp_framing(&mut self, arp_framing: ArpFraming) {
+        (**self).set_arp_framing(arp_framing)
+    }
+
+    fn set_carrier(&mut self, carrier: Carrier) {
+        (**self).set_carrier(carrier)
+    }
+
+    fn set_peer_capabilities(&mut self, capabilities: Capabilities) {
+        (**self).set_peer_capabilities(capabilities)
+    }
+
+    fn peer_address(&self, id: &ktp::Id) -> Option<String> {
+        (**self).peer_address(id)
+    }
+
+    fn learn_peer(&mut self, id: &ktp::Id) {
+        (**self).learn_peer(id)
+    }
+
+    fn forget_peer(&mut self, id: &ktp::Id) {
+        (**self).forget_peer(id)
+    }
+
+    fn is_from_peer(&self, id: &ktp::Id) -> bool {
+        (**self).is_from_peer(id)
+    }
+
+    fn set_room_key(&mut self, room_key: Option<RoomKey>) {
+        (**self).set_room_key(room_key)
+    }
+
+    fn set_rooms(&mut self, rooms: HashSet<String>) {
+        (**self).set_rooms(rooms)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::net::ktp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Typing packets are sent at most this often.
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+// Peers stop typing, if nothing was heard from them for this long.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Who is typing where, us included. <br>
+/// Rooms are empty for the main one.
+#[derive(Default)]
+pub struct Typing {
+    // Room of the unsent message in the input, if there is one.
+    own: Option<String>,
+    last_sent: Option<Instant>,
+    peers: HashMap<(String, ktp::Id), Instant>,
+}
+
+impl Typing {
+    /// A message left in the input of another room is announced right away.
+    pub fn set_own(&mut self, room: Option<String>) {
+        if self.own != room {
+            self.last_sent = None;
+        }
+        self.own = room;
+    }
+
+    /// Room to send a typing packet to, if it's time for one.
+    pub fn due(&mut self, now: Instant) -> Option<String> {
+        let room = self.own.clone()?;
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.duration_since(last_sent) < TYPING_INTERVAL)
+        {
+            return None;
+        }
+
+        self.last_sent = Some(now);
+        Some(room)
+    }
+
+    /// Returns `true` if the peer just started typing.
+    pub fn record(&mut self, room: String, id: ktp::Id, now: Instant) -> bool {
+        self.peers.insert((room, id), now).is_none()
+    }
+
+    /// Returns `true` if the peer was typing, e.g. before its message came.
+    pub fn stop(&mut self, room: &str, id: &ktp::Id) -> bool {
+        self.peers.remove(&(room.to_string(), *id)).is_some()
+    }
+
+    /// Rooms where somebody stopped typing.
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut rooms = vec![];
+        self.peers.retain(|(room, _), last_typing| {
+            let is_typing = now.duration_since(*last_typing) <= TYPING_TIMEOUT;
+            if !is_typing && !rooms.contains(room) {
+                rooms.push(room.clone());
+            }
+            is_typing
+        });
+
+        rooms
+    }
+
+    pub fn typing_in(&self, room: &str) -> Vec<ktp::Id> {
+        self.peers
+            .keys()
+            .filter(|(typing_room, _)| typing_room == room)
+            .map(|(_, id)| *id)
+            .collect()
+    }
+}
@@ -0,0 +1,334 @@
+use crate::error::net::NetError;
+use crate::net::capabilities::Capabilities;
+use crate::net::crypto::RoomKey;
+use crate::net::peers::PeerAddresses;
+use crate::net::reassembly::{FragmentCache, Reassembler};
+use crate::net::send_queue::{SendProgress, SendQueue};
+use crate::net::transport::Transport;
+use crate::net::{ethernet, ktp};
+use pnet::datalink::NetworkInterface;
+use pnet::ipnetwork::IpNetwork;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashSet;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+// Same digits as the experimental EtherType 0x88B5.
+pub const DEFAULT_PORT: u16 = 34997;
+
+// Datagrams stay as large as Ethernet frames, so IP doesn't fragment them.
+pub const PART_SIZE: usize = ethernet::PART_SIZE;
+
+/// Carries KTP frames in UDP datagrams, sent to the broadcast address
+/// of the interface or to a multicast group. Packets for a single peer
+/// go straight to its address. <br>
+/// Unlike the datalink channel, it doesn't need root or CAP_NET_RAW.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    // Frames for everybody go here, peers on one machine may share the port.
+    destination: SocketAddrV4,
+
+    reassembler: Reassembler<SocketAddr>,
+    sent_fragments: FragmentCache<SocketAddr>,
+    send_queue: SendQueue<SocketAddr>,
+    max_packet_size: usize,
+    room_key: Option<RoomKey>,
+    rooms: HashSet<String>,
+    peer_capabilities: Capabilities,
+
+    // Learned from the source address of verified packets.
+    peers: PeerAddresses<SocketAddr>,
+}
+
+impl UdpTransport {
+    /// Broadcasts on the network of the interface, if there is no multicast group.
+    pub fn from_interface(
+        interface: &NetworkInterface, port: u16, group: Option<Ipv4Addr>,
+    ) -> Result<Self, NetError> {
+        let network = interface
+            .ips
+            .iter()
+            .find_map(|ip| match ip {
+                IpNetwork::V4(network) => Some(*network),
+                IpNetwork::V6(_) => None,
+            })
+            .ok_or(NetError::NoIpv4)?;
+
+        let destination = group.unwrap_or(network.broadcast());
+        let socket =
+            open_socket(network.ip(), port, group).map_err(NetError::SocketFailed)?;
+
+        Ok(Self {
+            socket,
+            destination: SocketAddrV4::new(destination, port),
+            reassembler: Reassembler::with_max_packet_size(ktp::DEFAULT_MAX_PACKET_SIZE),
+            sent_fragments: FragmentCache::default(),
+            send_queue: SendQueue::default(),
+            max_packet_size: ktp::DEFAULT_MAX_PACKET_SIZE,
+            room_key: None,
+            rooms: HashSet::new(),
+            peer_capabilities: Capabilities::default(),
+            peers: PeerAddresses::default(),
+        })
+    }
+
+    // A datagram to an address shared by several peers, on one machine,
+    // reaches just one of them, so they only get the frames for everybody.
+    fn destination_of(&self, packet: &ktp::Packet) -> Result<SocketAddr, NetError> {
+        let Some(target) = packet.target() else {
+            return Ok(self.destination.into());
+        };
+        let address = self.peers.get(&target).ok_or(NetError::UnknownPeer)?;
+        Ok(match self.peers.is_shared(&target, address) {
+            true => self.destination.into(),
+            false => address,
+        })
+    }
+
+    fn try_send_part(
+        &self, frame: &[u8], destination: SocketAddr,
+    ) -> Result<(), NetError> {
+        self.socket
+            .send_to(frame, destination)
+            .map(|_| ())
+            .map_err(|_| NetError::FrameSendFailed)
+    }
+}
+
+fn open_socket(
+    interface_ip: Ipv4Addr, port: u16, group: Option<Ipv4Addr>,
+) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Several clients on one machine listen on the same port.
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    match group {
+        Some(group) => {
+            socket.join_multicast_v4(&group, &interface_ip)?;
+            socket.set_multicast_if_v4(&interface_ip)?;
+            // Own packets coming back tell that everything works.
+            socket.set_multicast_loop_v4(true)?;
+        },
+        None => socket.set_broadcast(true)?,
+    }
+
+    Ok(socket.into())
+}
+
+impl Transport for UdpTransport {
+    fn try_send(&mut self, packet: ktp::Packet) -> Result<(), NetError> {
+        let destination = self.destination_of(&packet)?;
+        let (tag, data) = ktp::encode(&packet, self.room_key.as_ref())?;
+        if data.len() > self.max_packet_size {
+            return Err(NetError::MessageTooLong);
+        }
+        let allow_extended = self.peer_capabilities.contains(Capabilities::LARGE_PACKETS);
+        let frames = ktp::fragment(tag, &data, PART_SIZE, allow_extended)?;
+        self.sent_fragments.insert(destination, &frames);
+        if let Some(frames) =
+            self.send_queue
+                .push(destination, packet.message_id(), frames)
+        {
+            for frame in frames {
+                self.try_send_part(&frame, destination)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> Result<Option<SendProgress>, NetError> {
+        let Some((destination, frames, progress)) = self.send_queue.next_burst() else {
+            return Ok(None);
+        };
+        for frame in frames {
+            self.try_send_part(&frame, destination)?;
+        }
+
+        Ok(progress)
+    }
+
+    fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+        self.reassembler = Reassembler::with_max_packet_size(max_packet_size);
+    }
+
+    fn try_recv(&mut self) -> Result<Option<ktp::Packet>, NetError> {
+        for nack in self.reassembler.poll(Instant::now()) {
+            self.try_send(nack)?;
+        }
+
+        let mut buffer = [0; ethernet::MAX_FRAME_SIZE];
+        let (len, source) = match self.socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err) => {
+                return match err.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => Ok(None),
+                    _ => Err(NetError::CaptureFailed),
+                };
+            },
+        };
+
+        // Packets of rooms we aren't in are dropped at their first frame.
+        if let Ok(Some(ktp_frame)) = ktp::parse_frame(&buffer[..len]) {
+            if ktp::starts_other_room(&ktp_frame, &self.rooms) {
+                log::trace!("Skipped packet of a room we aren't in.");
+                self.reassembler.skip(ktp_frame.id);
+                return Ok(None);
+            }
+        }
+
+        // Packets for a single peer are filtered out by the net thread.
+        // Anybody can send garbage to the port, it only costs them the datagram.
+        let assembled = self
+            .reassembler
+            .accept(&buffer[..len], source, Instant::now());
+        let packet = match assembled.and_then(|assembled| match assembled {
+            Some((tag, data)) => {
+                ktp::decode(tag, &data, self.room_key.as_ref(), Some(&self.rooms))
+            },
+            None => Ok(None),
+        }) {
+            Ok(packet) => packet,
+            Err(err) => {
+                log::debug!("Dropped undecodable packet from {source}: {err}");
+                return Ok(None);
+            },
+        };
+        if let Some(ktp::Packet::Nack { frame_id, missing }) = &packet {
+            if let Some((destination, frames)) =
+                self.sent_fragments
+                    .missing(frame_id, missing, Instant::now())
+            {
+                for frame in frames {
+                    self.try_send_part(&frame, destination)?;
+                }
+            }
+            return Ok(None);
+        }
+        if let Some(packet) = &packet {
+            self.peers.heard(packet.sender(), source);
+        }
+
+        Ok(packet)
+    }
+
+    fn peer_address(&self, id: &ktp::Id) -> Option<String> {
+        self.peers.get(id).map(|address| address.to_string())
+    }
+
+    fn learn_peer(&mut self, id: &ktp::Id) {
+        self.peers.learn(id);
+    }
+
+    fn forget_peer(&mut self, id: &ktp::Id) {
+        self.peers.forget(id);
+    }
+
+    fn is_from_peer(&self, id: &ktp::Id) -> bool {
+        self.peers.is_last_from(id)
+    }
+
+    fn set_peer_capabilities(&mut self, capabilities: Capabilities) {
+        self.peer_capabilities = capabilities;
+    }
+
+    fn set_room_key(&mut self, room_key: Option<RoomKey>) {
+        self.room_key = room_key;
+    }
+
+    fn set_rooms(&mut self, rooms: HashSet<String>) {
+        self.rooms = rooms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::ipnetwork::Ipv4Network;
+
+    fn loopback() -> NetworkInterface {
+        NetworkInterface {
+            name: "lo".into(),
+            description: String::new(),
+            index: 1,
+            mac: None,
+            ips: vec![IpNetwork::V4(
+                Ipv4Network::new(Ipv4Addr::LOCALHOST, 8).unwrap(),
+            )],
+            flags: 0,
+        }
+    }
+
+    fn recv(transport: &mut UdpTransport) -> Option<ktp::Packet> {
+        (0..10).find_map(|_| transport.try_recv().unwrap())
+    }
+
+    #[test]
+    fn clients_on_one_machine_share_the_port() {
+        let port = DEFAULT_PORT + rand::random::<u16>() % 1000;
+        let mut alice = UdpTransport::from_interface(&loopback(), port, None).unwrap();
+        let mut bob = UdpTransport::from_interface(&loopback(), port, None).unwrap();
+
+        let packet = ktp::Packet::Disconnect {
+            id: ktp::generate_id(),
+            counter: 1,
+            room: String::new(),
+            signature: [0; 64],
+        };
+        alice.try_send(packet.clone()).unwrap();
+
+        assert_eq!(recv(&mut alice), Some(packet.clone()));
+        assert_eq!(recv(&mut bob), Some(packet));
+    }
+
+    #[test]
+    fn packets_for_one_peer_go_to_its_address() {
+        let port = DEFAULT_PORT + rand::random::<u16>() % 1000;
+        let mut alice = UdpTransport::from_interface(&loopback(), port, None).unwrap();
+
+        // Bob is on another machine, Alice learns his address from a packet of his.
+        let bob = UdpSocket::bind((Ipv4Addr::new(127, 0, 0, 2), 0)).unwrap();
+        bob.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let bob_id = ktp::generate_id();
+        let disconnect = ktp::Packet::Disconnect {
+            id: bob_id,
+            counter: 1,
+            room: String::new(),
+            signature: [0; 64],
+        };
+        let (tag, data) = ktp::encode(&disconnect, None).unwrap();
+        for frame in ktp::fragment(tag, &data, PART_SIZE, false).unwrap() {
+            bob.send_to(&frame, (Ipv4Addr::LOCALHOST, port)).unwrap();
+        }
+        assert_eq!(recv(&mut alice), Some(disconnect));
+        alice.learn_peer(&bob_id);
+
+        let ack = ktp::Packet::Ack {
+            id: ktp::generate_id(),
+            counter: 1,
+            target: bob_id,
+            message_id: ktp::generate_id(),
+            signature: [0; 64],
+        };
+        alice.try_send(ack.clone()).unwrap();
+
+        let mut buffer = [0; ethernet::MAX_FRAME_SIZE];
+        let len = bob.recv(&mut buffer).unwrap();
+        let mut reassembler = Reassembler::default();
+        let (tag, data) = reassembler
+            .accept(&buffer[..len], (), Instant::now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(ktp::decode(tag, &data, None, None), Ok(Some(ack)));
+        // Nothing was broadcast.
+        assert_eq!(recv(&mut alice), None);
+    }
+}
@@ -1,6 +1,8 @@
 pub const INITIAL_USERNAME: &str = "Anonymous";
 pub const MAX_USERNAME_LENGTH: usize = 25;
 pub const MIN_USERNAME_LENGTH: usize = 2;
+pub const MAX_ROOM_LENGTH: usize = 24;
+pub const MAX_TOPIC_LENGTH: usize = 120;
 
 // Username for offline users that send messages
 pub const UNKNOWN_USERNAME: &str = "Unknown";
@@ -18,3 +20,18 @@ pub fn normalize_username(username: &str) -> String {
 
     result
 }
+
+/// Room names are case-insensitive and may be typed with a leading '#'. <br>
+/// Returns `None` for names that can't be a room, the empty main room included.
+pub fn normalize_room(room: &str) -> Option<String> {
+    let room = room.trim().trim_start_matches('#').to_lowercase();
+
+    if room.is_empty()
+        || room.chars().count() > MAX_ROOM_LENGTH
+        || room.contains(char::is_whitespace)
+    {
+        return None;
+    }
+
+    Some(room)
+}
@@ -1,15 +1,22 @@
 pub mod colors;
 pub mod commands;
+pub mod conversations;
 pub mod core;
 pub mod main_window;
+pub mod messages;
 pub mod view_updater;
 
 pub mod dialog {
+    pub mod contacts;
     pub mod error;
     pub mod ether_type;
     pub mod export_messages;
     pub mod interface;
     pub mod localization;
     pub mod logger_settings;
+    pub mod room_passphrase;
+    pub mod rooms;
+    pub mod send_file;
+    pub mod transfers;
     pub mod username;
 }
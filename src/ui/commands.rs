@@ -1,52 +1,151 @@
 use crate::config::CONFIG;
 use crate::error::net::NetError;
+use crate::net::arp_framing::ArpFraming;
+use crate::net::capabilities::Capabilities;
+use crate::net::carrier::Carrier;
 use crate::net::commands::NetCommand;
+use crate::net::delivery::DeliveryState;
 use crate::net::ether_type::EtherType;
 use crate::net::ktp;
-use crate::net::presence::UpdatePresenceKind;
-use crate::{session_settings, ui};
+use crate::net::presence::{Trust, UpdatePresenceKind};
+use crate::net::rooms::RoomListing;
+use crate::net::transfer::{TransferFailure, TransferState};
+use crate::ui::messages::{ShownMessage, ShownMessages};
+use crate::{config, session_settings, ui};
 use chrono::Timelike;
 use crossbeam::channel::Sender;
 use cursive::backends::crossterm::crossterm::style::Stylize;
 use cursive::utils::markup;
-use cursive::views::{Dialog, LinearLayout, NamedView, TextView};
+use cursive::views::{Dialog, LinearLayout, NamedView, SelectView, TextView};
 use cursive::Cursive;
 use log::LevelFilter;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
+use std::path::PathBuf;
 
 pub enum UICommand {
+    AcceptFile {
+        transfer_id: ktp::Id,
+    },
     AlertUser,
+    BrowseRooms,
+    CancelTransfer {
+        transfer_id: ktp::Id,
+    },
 
+    /// Messages are only changed by their sender.
+    DeleteMessage {
+        message_id: ktp::Id,
+    },
+    EditMessage {
+        message_id: ktp::Id,
+        message_text: String,
+    },
     ExportMessages(File),
+    JoinRoom(String),
+    /// Moves the message to reply to through the conversation.
+    PickMessage {
+        is_older: bool,
+    },
 
     SendNetError(NetError),
+    SendNetWarning(NetError),
 
+    /// The room is empty for the main one.
     SendMessage {
+        room: String,
+        message_text: String,
+    },
+    SendDirectMessage {
+        target: ktp::Id,
         message_text: String,
     },
+    SendFile {
+        username: String,
+        path: PathBuf,
+    },
 
+    SetArpFraming(ArpFraming),
+    SetCarrier(Carrier),
     SetEtherType(EtherType),
     SetInterface(String),
     SetLanguage(String),
     SetLogLevel(LevelFilter),
+    SetRoomPassphrase(Option<String>),
+    /// Room of the unsent message in the chat input, if there is one.
+    SetTyping(Option<String>),
     SetUsername(String),
 
     ShowMessage {
+        room: String,
         id: ktp::Id,
+        message_id: ktp::Id,
         username: String,
         message: String,
+        reply_to: Option<ktp::Id>,
         is_outgoing_message: bool,
     },
+    UpdateDeliveryState {
+        message_id: ktp::Id,
+        state: DeliveryState,
+    },
+    ShowDirectMessage {
+        peer: ktp::Id,
+        peer_username: String,
+        id: ktp::Id,
+        username: String,
+        message: String,
+    },
+    ShowKeyMismatchWarning {
+        username: String,
+    },
+    ShowForgeryWarning {
+        id: ktp::Id,
+        username: String,
+    },
+    ShowOutdatedPeerWarning {
+        username: String,
+        missing: Capabilities,
+    },
+    ShowFingerprint {
+        peer: ktp::Id,
+        peer_username: String,
+        fingerprint: String,
+    },
+    ShowTopic {
+        room: String,
+        topic: String,
+        username: String,
+    },
+    ShowTransfer {
+        transfer_id: ktp::Id,
+        peer_username: String,
+        name: String,
+        size: u64,
+        is_outgoing: bool,
+    },
+    UpdateTransfer {
+        transfer_id: ktp::Id,
+        state: TransferState,
+    },
+    UpdateRooms(Vec<RoomListing>),
+    UpdateTyping {
+        room: String,
+        usernames: Vec<String>,
+    },
 
     PresenceUpdate {
+        room: String,
         id: ktp::Id,
         username: String,
         is_inactive: bool,
+        trust: Trust,
         kind: UpdatePresenceKind,
     },
 
     RemovePresence {
+        room: String,
         id: ktp::Id,
         username: String,
     },
@@ -123,10 +222,84 @@ pub fn export_messages(siv: &mut Cursive, mut file: File) {
     }
 }
 
+/// Messages of a room are sent to the room, commands work from every room.
+/// `/edit` and `/delete` change our last message in the room,
+/// other messages reply to the picked one.
 pub fn send_message(
-    message_text: String, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
+    room: String, message_text: String, peers: &HashMap<ktp::Id, String>,
+    rooms: &mut Vec<String>, messages: &mut ShownMessages, siv: &mut Cursive,
+    net_tx: &Sender<NetCommand>,
 ) {
-    if message_text.eq("/offline") {
+    if let Some(arguments) = command_arguments(&message_text, "/join") {
+        let Some(room) = parse_room(arguments, siv) else {
+            return;
+        };
+
+        join_room(room.clone(), rooms, siv, net_tx);
+        ui::conversations::select(siv, &ui::conversations::Conversation::Room(room));
+    } else if let Some(arguments) = command_arguments(&message_text, "/part") {
+        let room = match arguments.trim() {
+            "" if room.is_empty() => {
+                ui::dialog::error::show(siv, t!("text.part_main"));
+                return;
+            },
+            "" => room,
+            _ => match parse_room(arguments, siv) {
+                Some(room) => room,
+                None => return,
+            },
+        };
+
+        part_room(room, rooms, siv, net_tx);
+    } else if !room.is_empty() && !rooms.contains(&room) {
+        show_room_not_joined(&room, siv);
+    } else if let Some(arguments) = command_arguments(&message_text, "/topic") {
+        set_topic(room, arguments, siv, net_tx);
+    } else if let Some(arguments) = command_arguments(&message_text, "/edit") {
+        let Some(message_id) = messages.last_own(&room) else {
+            ui::dialog::error::show(siv, t!("text.nothing_to_change"));
+            return;
+        };
+        let message_text = arguments.trim().to_string();
+        if message_text.is_empty() {
+            ui::dialog::error::show(siv, t!("text.edit_usage"));
+            return;
+        }
+
+        let result = net_tx.try_send(NetCommand::EditMessage {
+            room,
+            message_id,
+            message_text,
+        });
+        if let Err(err) = result {
+            ui::dialog::error::show(siv, err);
+        }
+    } else if message_text.eq("/delete") {
+        let Some(message_id) = messages.last_own(&room) else {
+            ui::dialog::error::show(siv, t!("text.nothing_to_change"));
+            return;
+        };
+
+        let result = net_tx.try_send(NetCommand::DeleteMessage { room, message_id });
+        if let Err(err) = result {
+            ui::dialog::error::show(siv, err);
+        }
+    } else if let Some(arguments) = message_text.strip_prefix("/msg ") {
+        let Some((username, text)) = arguments.trim_start().split_once(' ') else {
+            ui::dialog::error::show(siv, t!("text.msg_usage"));
+            return;
+        };
+
+        if let Some(target) = find_peer(peers, username, siv) {
+            send_direct_message(target, text.to_string(), siv, net_tx);
+        }
+    } else if message_text.eq("/retry") {
+        net_tx
+            .try_send(NetCommand::RetryFailedMessages)
+            .unwrap_or_else(|err| {
+                log::error!("Error sending RetryFailedMessages with /retry: {}", err);
+            });
+    } else if message_text.eq("/offline") {
         net_tx
             .try_send(NetCommand::PauseHeartbeat(true))
             .unwrap_or_else(|err| {
@@ -139,14 +312,253 @@ pub fn send_message(
                 log::error!("Error sending PauseHeartbeat with /online: {}", err);
             });
     } else if !message_text.is_empty() {
-        let result = net_tx.try_send(NetCommand::SendMessage { message_text });
+        let reply_to = messages.picked_in(&room);
+        let result = net_tx.try_send(NetCommand::SendMessage {
+            room,
+            message_text,
+            reply_to,
+        });
 
         if let Err(err) = result {
             ui::dialog::error::show(siv, err);
+            return;
+        }
+        if reply_to.is_some() {
+            let unpicked = messages.set_picked(None);
+            show_picked(unpicked, messages, siv);
         }
     }
 }
 
+/// Rooms picked from the room list are named by other clients,
+/// so their names are checked just like typed ones.
+pub fn join_room(
+    room: String, rooms: &mut Vec<String>, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
+) {
+    if session_settings::normalize_room(&room).as_ref() != Some(&room) {
+        show_room_invalid(&room, siv);
+        return;
+    }
+
+    let result = net_tx.try_send(NetCommand::JoinRoom(room.clone()));
+
+    if let Err(err) = result {
+        ui::dialog::error::show(siv, err);
+        return;
+    }
+
+    ui::conversations::open_room(siv, &room);
+    if !rooms.contains(&room) {
+        rooms.push(room);
+        save_rooms(rooms);
+    }
+}
+
+fn part_room(
+    room: String, rooms: &mut Vec<String>, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
+) {
+    if !rooms.contains(&room) {
+        show_room_not_joined(&room, siv);
+        return;
+    }
+
+    let result = net_tx.try_send(NetCommand::PartRoom(room.clone()));
+
+    if let Err(err) = result {
+        ui::dialog::error::show(siv, err);
+        return;
+    }
+
+    ui::conversations::leave_room(siv, &room);
+    rooms.retain(|joined| *joined != room);
+    save_rooms(rooms);
+}
+
+// Topics are cut to the length that fits the room list.
+fn set_topic(
+    room: String, arguments: &str, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
+) {
+    if room.is_empty() {
+        ui::dialog::error::show(siv, t!("text.topic_main"));
+        return;
+    }
+    let topic = arguments.trim();
+    if topic.is_empty() {
+        ui::dialog::error::show(siv, t!("text.topic_usage"));
+        return;
+    }
+
+    let topic = topic
+        .chars()
+        .take(session_settings::MAX_TOPIC_LENGTH)
+        .collect();
+    let result = net_tx.try_send(NetCommand::SetTopic { room, topic });
+
+    if let Err(err) = result {
+        ui::dialog::error::show(siv, err);
+    }
+}
+
+fn save_rooms(rooms: &[String]) {
+    if let Ok(mut config) = CONFIG.try_lock() {
+        config.rooms = Some(rooms.to_vec());
+        config.save().unwrap_or_default();
+    }
+}
+
+// Arguments of the command, which may have none.
+fn command_arguments<'a>(message_text: &'a str, command: &str) -> Option<&'a str> {
+    message_text
+        .strip_prefix(command)
+        .filter(|arguments| arguments.is_empty() || arguments.starts_with(' '))
+}
+
+// Shows the usage or why the name can't be a room.
+fn parse_room(arguments: &str, siv: &mut Cursive) -> Option<String> {
+    let arguments = arguments.trim();
+    if arguments.is_empty() {
+        ui::dialog::error::show(siv, t!("text.join_usage"));
+        return None;
+    }
+
+    let room = session_settings::normalize_room(arguments);
+    if room.is_none() {
+        show_room_invalid(arguments, siv);
+    }
+
+    room
+}
+
+fn show_room_invalid(room: &str, siv: &mut Cursive) {
+    let translated = rust_i18n::replace_patterns(
+        &t!("text.room_invalid"),
+        &["room"],
+        &[room.to_string()],
+    );
+    ui::dialog::error::show(siv, translated);
+}
+
+fn show_room_not_joined(room: &str, siv: &mut Cursive) {
+    let translated = rust_i18n::replace_patterns(
+        &t!("text.room_not_joined"),
+        &["room"],
+        &[room.to_string()],
+    );
+    ui::dialog::error::show(siv, translated);
+}
+
+pub fn send_direct_message(
+    target: ktp::Id, message_text: String, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
+) {
+    if message_text.is_empty() {
+        return;
+    }
+
+    let result = net_tx.try_send(NetCommand::SendDirectMessage {
+        target,
+        message_text,
+    });
+
+    if let Err(err) = result {
+        ui::dialog::error::show(siv, err);
+    }
+}
+
+pub fn send_file(
+    username: String, path: PathBuf, peers: &HashMap<ktp::Id, String>, siv: &mut Cursive,
+    net_tx: &Sender<NetCommand>,
+) {
+    let Some(target) = find_peer(peers, &username, siv) else {
+        return;
+    };
+
+    let result = net_tx.try_send(NetCommand::SendFile { target, path });
+
+    if let Err(err) = result {
+        ui::dialog::error::show(siv, err);
+    }
+}
+
+pub fn accept_file(transfer_id: ktp::Id, siv: &mut Cursive, net_tx: &Sender<NetCommand>) {
+    let result = net_tx.try_send(NetCommand::AcceptFile {
+        transfer_id,
+        directory: config::lock_get_receive_directory(),
+    });
+
+    if let Err(err) = result {
+        ui::dialog::error::show(siv, err);
+    }
+}
+
+pub fn cancel_transfer(
+    transfer_id: ktp::Id, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
+) {
+    let result = net_tx.try_send(NetCommand::CancelTransfer { transfer_id });
+
+    if let Err(err) = result {
+        ui::dialog::error::show(siv, err);
+    }
+}
+
+// Shows an error, if nobody online goes by the username.
+fn find_peer(
+    peers: &HashMap<ktp::Id, String>, username: &str, siv: &mut Cursive,
+) -> Option<ktp::Id> {
+    let target = peers
+        .iter()
+        .find(|(_, peer_username)| peer_username.as_str() == username)
+        .map(|(id, _)| *id);
+
+    if target.is_none() {
+        let translated = rust_i18n::replace_patterns(
+            &t!("text.user_not_found"),
+            &["username"],
+            &[username.to_string()],
+        );
+        ui::dialog::error::show(siv, translated);
+    }
+
+    target
+}
+
+pub fn set_arp_framing(
+    arp_framing: ArpFraming, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
+) {
+    let result = net_tx.try_send(NetCommand::SetArpFraming(arp_framing));
+
+    if let Err(err) = result {
+        ui::dialog::error::show_try_again(siv, err.to_string());
+        return;
+    }
+
+    if let Ok(mut config) = CONFIG.try_lock() {
+        config.arp_framing = Some(arp_framing);
+        config.save().unwrap_or_default();
+    }
+}
+
+pub fn set_carrier(carrier: Carrier, siv: &mut Cursive, net_tx: &Sender<NetCommand>) {
+    let result = net_tx.try_send(NetCommand::SetCarrier(carrier));
+
+    if let Err(err) = result {
+        ui::dialog::error::show_try_again(siv, err.to_string());
+        return;
+    }
+
+    if config::lock_get_carrier().is_link() != carrier.is_link() {
+        ui::view_updater::append_txt(
+            siv,
+            ui::main_window::ELEMENT_NAME_CHAT_AREA,
+            markup::ansi::parse(t!("text.carrier_restart").yellow().to_string()),
+        );
+    }
+
+    if let Ok(mut config) = CONFIG.try_lock() {
+        config.carrier = Some(carrier);
+        config.save().unwrap_or_default();
+    }
+}
+
 pub fn set_ether_type(
     ether_type: EtherType, siv: &mut Cursive, net_tx: &Sender<NetCommand>,
 ) {
@@ -197,6 +609,25 @@ pub fn set_log_level(level: LevelFilter) {
     }
 }
 
+pub fn set_room_passphrase(
+    passphrase: Option<String>, ui_username: &str, siv: &mut Cursive,
+    net_tx: &Sender<NetCommand>,
+) {
+    let result = net_tx.try_send(NetCommand::SetRoomPassphrase(passphrase.clone()));
+
+    if let Err(err) = result {
+        ui::dialog::error::show_try_again(siv, err.to_string());
+        return;
+    }
+
+    if let Ok(mut config) = CONFIG.try_lock() {
+        config.room_passphrase = passphrase;
+        config.save().unwrap_or_default();
+    }
+
+    ui::main_window::update_username_title(siv, ui_username);
+}
+
 pub fn set_username(
     new_username: String, ui_username: &mut String, siv: &mut Cursive,
     net_tx: &Sender<NetCommand>,
@@ -224,9 +655,212 @@ pub fn set_username(
 }
 
 pub fn show_message(
-    id: ktp::Id, username: String, message: String, is_outgoing_message: bool,
+    message_id: ktp::Id, message: ShownMessage, reply_to: Option<ktp::Id>,
+    messages: &mut ShownMessages, siv: &mut Cursive,
+) {
+    let shown = messages.insert(message_id, message, reply_to, false);
+    shown.render(&message_id, siv);
+
+    let conversation = match shown.room() {
+        "" => ui::conversations::Conversation::Main,
+        room => ui::conversations::Conversation::Room(room.to_string()),
+    };
+    ui::conversations::mark_unread(siv, &conversation);
+}
+
+/// Outgoing messages get their delivery state after the text.
+pub fn show_outgoing_message(
+    message_id: ktp::Id, message: ShownMessage, reply_to: Option<ktp::Id>,
+    messages: &mut ShownMessages, siv: &mut Cursive,
+) {
+    messages.insert(message_id, message, reply_to, true);
+    update_delivery_state(message_id, DeliveryState::Sending, messages, siv);
+}
+
+/// Direct messages can't be replied to.
+pub fn pick_message(is_older: bool, messages: &mut ShownMessages, siv: &mut Cursive) {
+    let room = match ui::conversations::selected(siv) {
+        ui::conversations::Conversation::Main => String::new(),
+        ui::conversations::Conversation::Room(room) => room,
+        ui::conversations::Conversation::Direct(_) => return,
+    };
+
+    let changed = messages.pick(&room, is_older);
+    show_picked(changed, messages, siv);
+}
+
+// Renders the lines that were picked or unpicked, and tells in the title of the
+// input what the next message replies to.
+fn show_picked(changed: Vec<ktp::Id>, messages: &ShownMessages, siv: &mut Cursive) {
+    let mut title = String::new();
+    for message_id in changed {
+        let Some(message) = messages.get(&message_id) else {
+            continue;
+        };
+        message.render(&message_id, siv);
+
+        if messages.picked_in(message.room()) == Some(message_id) {
+            title = rust_i18n::replace_patterns(
+                &t!("text.replying_to"),
+                &["username", "snippet"],
+                &[message.username().to_string(), message.snippet()],
+            );
+        }
+    }
+
+    ui::main_window::update_input_title(siv, title);
+}
+
+pub fn update_delivery_state(
+    message_id: ktp::Id, state: DeliveryState,
+    messages: &mut ShownMessages, siv: &mut Cursive,
+) {
+    let marker = match state {
+        DeliveryState::Transmitting { percent } => rust_i18n::replace_patterns(
+            &t!("text.message_transmitting"),
+            &["percent"],
+            &[percent.to_string()],
+        )
+        .dark_grey()
+        .to_string(),
+        DeliveryState::Sending => t!("text.message_sending").dark_grey().to_string(),
+        DeliveryState::Delivered { peers } => rust_i18n::replace_patterns(
+            &t!("text.message_delivered"),
+            &["peers"],
+            &[peers.to_string()],
+        )
+        .dark_grey()
+        .to_string(),
+        DeliveryState::Failed => t!("text.message_failed").red().to_string(),
+    };
+
+    if let Some(message) = messages.set_marker(&message_id, marker) {
+        message.render(&message_id, siv);
+    }
+}
+
+pub fn edit_message(
+    message_id: ktp::Id, message_text: String, messages: &mut ShownMessages,
     siv: &mut Cursive,
 ) {
+    if let Some(message) = messages.edit(&message_id, message_text) {
+        message.render(&message_id, siv);
+    }
+}
+
+/// The line stays as a tombstone, so the chat doesn't shift.
+pub fn delete_message(
+    message_id: ktp::Id, messages: &mut ShownMessages, siv: &mut Cursive,
+) {
+    if let Some(message) = messages.delete(&message_id) {
+        message.render(&message_id, siv);
+    }
+}
+
+pub fn show_transfer(
+    transfer_id: ktp::Id, peer_username: &str, name: String, is_outgoing: bool,
+    transfers: &mut HashMap<ktp::Id, (String, String)>, siv: &mut Cursive,
+) {
+    let direction = if is_outgoing { "↑" } else { "↓" };
+    let label = format!("{direction} {peer_username}: {name}");
+
+    siv.call_on_name(
+        ui::main_window::ELEMENT_NAME_TRANSFERS,
+        |transfers: &mut SelectView<ktp::Id>| {
+            transfers.add_item(label.clone(), transfer_id);
+        },
+    );
+    transfers.insert(transfer_id, (label, name));
+    update_transfer(transfer_id, TransferState::Offered, transfers, siv);
+}
+
+pub fn update_transfer(
+    transfer_id: ktp::Id, state: TransferState,
+    transfers: &HashMap<ktp::Id, (String, String)>, siv: &mut Cursive,
+) {
+    let Some((label, name)) = transfers.get(&transfer_id) else {
+        return;
+    };
+
+    let marker = match &state {
+        TransferState::Offered => t!("text.transfer_offered").to_string(),
+        TransferState::Transferring { percent } => format!("{percent}%"),
+        TransferState::Done { .. } => t!("text.transfer_done").to_string(),
+        TransferState::Failed(_) => t!("text.transfer_failed").to_string(),
+        TransferState::Cancelled => t!("text.transfer_cancelled").to_string(),
+    };
+    siv.call_on_name(
+        ui::main_window::ELEMENT_NAME_TRANSFERS,
+        |transfers: &mut SelectView<ktp::Id>| {
+            let position = transfers.iter().position(|(_, item)| *item == transfer_id);
+            if let Some((item_label, _)) =
+                position.and_then(|index| transfers.get_item_mut(index))
+            {
+                *item_label = format!("{label} {marker}").into();
+            }
+        },
+    );
+
+    let notice = match state {
+        TransferState::Done { path: Some(path) } => rust_i18n::replace_patterns(
+            &t!("text.file_saved"),
+            &["name", "path"],
+            &[name.clone(), path.display().to_string()],
+        )
+        .dark_grey(),
+        TransferState::Failed(failure) => {
+            let key = match failure {
+                TransferFailure::Corrupted => "text.transfer_corrupted",
+                TransferFailure::PeerLeft => "text.transfer_peer_left",
+                TransferFailure::Stalled => "text.transfer_stalled",
+                TransferFailure::TooLarge => "text.transfer_too_large",
+                TransferFailure::WriteFailed => "text.transfer_write_failed",
+            };
+            rust_i18n::replace_patterns(&t!(key), &["name"], &[name.clone()]).yellow()
+        },
+        _ => return,
+    };
+    ui::view_updater::append_txt(
+        siv,
+        ui::main_window::ELEMENT_NAME_CHAT_AREA,
+        markup::ansi::parse(notice.to_string()),
+    );
+}
+
+pub fn show_direct_message(
+    peer: ktp::Id, peer_username: String, id: ktp::Id, username: String, message: String,
+    siv: &mut Cursive,
+) {
+    ui::conversations::open_direct(siv, peer, &peer_username);
+
+    let print = markup::ansi::parse(format_message(&id, username, &message));
+
+    ui::view_updater::append_txt(
+        siv,
+        &ui::conversations::direct_chat_area_name(&peer),
+        print,
+    );
+}
+
+pub fn show_fingerprint(
+    peer: ktp::Id, peer_username: String, fingerprint: String, siv: &mut Cursive,
+) {
+    ui::conversations::open_direct(siv, peer, &peer_username);
+
+    let translated = rust_i18n::replace_patterns(
+        &t!("text.session_key_fingerprint"),
+        &["fingerprint"],
+        &[fingerprint],
+    );
+
+    ui::view_updater::append_txt(
+        siv,
+        &ui::conversations::direct_chat_area_name(&peer),
+        markup::ansi::parse(translated.dark_grey().to_string()),
+    );
+}
+
+pub fn format_message(id: &ktp::Id, username: String, message: &str) -> String {
     let now = chrono::offset::Local::now();
     let time = format!(
         "{hours:02}:{minutes:02}:{seconds:02}",
@@ -235,44 +869,28 @@ pub fn show_message(
         seconds = now.second()
     )
     .dark_grey();
-    let username = username.with(ui::colors::from_id(&id));
-
-    let mut print = format!("{time} [{username}] {message}");
-
-    if is_outgoing_message {
-        print += &t!("text.message_sending").dark_grey().to_string();
-    }
+    let username = username.with(ui::colors::from_id(id));
 
-    let print = markup::ansi::parse(print);
-
-    ui::view_updater::update_or_append_txt(
-        siv,
-        ui::main_window::ELEMENT_NAME_CHAT_AREA,
-        &message,
-        print,
-    );
-    if !is_outgoing_message {
-        siv.call_on_name(&message, |child: &mut NamedView<TextView>| {
-            child.set_name("");
-        });
-    }
+    format!("{time} [{username}] {message}")
 }
 
+/// Presence of a room only shows up in the room.
 pub fn presence_update(
-    id: ktp::Id, username: String, is_inactive: bool, kind: UpdatePresenceKind,
-    siv: &mut Cursive,
+    room: &str, id: ktp::Id, username: String, is_inactive: bool, trust: Trust,
+    kind: UpdatePresenceKind, siv: &mut Cursive,
 ) {
     match kind {
         UpdatePresenceKind::JoinOrReconnect => {
-            let translated = rust_i18n::replace_patterns(
-                &t!("text.user_connected"),
-                &["username"],
-                &[username.clone()],
-            );
+            let key = match room {
+                "" => "text.user_connected",
+                _ => "text.user_joined_room",
+            };
+            let translated =
+                rust_i18n::replace_patterns(&t!(key), &["username"], &[username.clone()]);
 
             ui::view_updater::append_txt(
                 siv,
-                ui::main_window::ELEMENT_NAME_CHAT_AREA,
+                &ui::conversations::room_chat_area_name(room),
                 markup::ansi::parse(translated.dark_grey().to_string()),
             );
         },
@@ -292,7 +910,7 @@ pub fn presence_update(
 
             ui::view_updater::append_txt(
                 siv,
-                ui::main_window::ELEMENT_NAME_CHAT_AREA,
+                &ui::conversations::room_chat_area_name(room),
                 markup::ansi::parse(translated.dark_grey().to_string()),
             );
         },
@@ -302,37 +920,134 @@ pub fn presence_update(
     // Update username in presences list.
     ui::view_updater::update_or_append_txt(
         siv,
-        ui::main_window::ELEMENT_NAME_ONLINE_PANEL,
-        &format!("{id:x?}_presence"),
-        match is_inactive {
-            true => markup::ansi::parse(format!("- {username}").dark_grey().to_string()),
-            false => markup::ansi::parse(format!(
+        &ui::conversations::online_panel_name(room),
+        &ui::conversations::presence_name(room, &id),
+        match (is_inactive, trust) {
+            (_, Trust::Impersonator) => {
+                markup::ansi::parse(format!("! {username}").red().to_string())
+            },
+            (true, _) => {
+                markup::ansi::parse(format!("- {username}").dark_grey().to_string())
+            },
+            (false, Trust::Verified) => markup::ansi::parse(format!(
                 "{} {username}",
                 "*".with(ui::colors::from_id(&id))
             )),
+            (false, Trust::Trusted) => markup::ansi::parse(format!(
+                "{} {username}",
+                "✓".with(ui::colors::from_id(&id))
+            )),
+        },
+    );
+}
+
+/// Replaces the line under the chat area of the room.
+pub fn update_typing(room: &str, usernames: Vec<String>, siv: &mut Cursive) {
+    let line = match usernames.as_slice() {
+        [] => String::new(),
+        [username] => rust_i18n::replace_patterns(
+            &t!("text.typing_one"),
+            &["username"],
+            &[username.clone()],
+        ),
+        _ => rust_i18n::replace_patterns(
+            &t!("text.typing_many"),
+            &["usernames"],
+            &[usernames.join(", ")],
+        ),
+    };
+
+    siv.call_on_name(
+        &ui::conversations::typing_name(room),
+        |typing: &mut TextView| {
+            typing.set_content(markup::ansi::parse(line.dark_grey().to_string()));
         },
     );
 }
 
-pub fn remove_presence(id: ktp::Id, username: String, siv: &mut Cursive) {
+pub fn show_topic(room: &str, topic: String, username: String, siv: &mut Cursive) {
+    let translated = rust_i18n::replace_patterns(
+        &t!("text.topic_set"),
+        &["username", "topic"],
+        &[username, topic],
+    );
+
+    ui::view_updater::append_txt(
+        siv,
+        &ui::conversations::room_chat_area_name(room),
+        markup::ansi::parse(translated.dark_grey().to_string()),
+    );
+}
+
+pub fn show_forgery_warning(username: String, siv: &mut Cursive) {
     let translated = rust_i18n::replace_patterns(
-        &t!("text.user_disconnected"),
+        &t!("text.forged_packet"),
         &["username"],
-        &[username.clone()],
+        &[username],
     );
 
     ui::view_updater::append_txt(
         siv,
         ui::main_window::ELEMENT_NAME_CHAT_AREA,
+        markup::ansi::parse(translated.red().to_string()),
+    );
+}
+
+pub fn show_outdated_peer_warning(
+    username: String, missing: Capabilities, siv: &mut Cursive,
+) {
+    let features: Vec<String> = missing
+        .names()
+        .iter()
+        .map(|name| t!(*name).to_string())
+        .collect();
+    let translated = rust_i18n::replace_patterns(
+        &t!("text.outdated_peer"),
+        &["username", "features"],
+        &[username, features.join(", ")],
+    );
+
+    ui::view_updater::append_txt(
+        siv,
+        ui::main_window::ELEMENT_NAME_CHAT_AREA,
+        markup::ansi::parse(translated.yellow().to_string()),
+    );
+}
+
+pub fn show_key_mismatch_warning(username: String, siv: &mut Cursive) {
+    let translated = rust_i18n::replace_patterns(
+        &t!("text.key_mismatch"),
+        &["username"],
+        &[username],
+    );
+
+    ui::view_updater::append_txt(
+        siv,
+        ui::main_window::ELEMENT_NAME_CHAT_AREA,
+        markup::ansi::parse(translated.clone().red().to_string()),
+    );
+    ui::dialog::contacts::show_key_mismatch_dialog(siv, translated);
+}
+
+pub fn remove_presence(room: &str, id: ktp::Id, username: String, siv: &mut Cursive) {
+    let key = match room {
+        "" => "text.user_disconnected",
+        _ => "text.user_left_room",
+    };
+    let translated = rust_i18n::replace_patterns(&t!(key), &["username"], &[username]);
+
+    ui::view_updater::append_txt(
+        siv,
+        &ui::conversations::room_chat_area_name(room),
         markup::ansi::parse(translated.dark_grey().to_string()),
     );
 
     // Remove from presences list.
     siv.call_on_name(
-        ui::main_window::ELEMENT_NAME_ONLINE_PANEL,
+        &ui::conversations::online_panel_name(room),
         |presences: &mut LinearLayout| {
             presences
-                .find_child_from_name(&format!("{id:x?}_presence"))
+                .find_child_from_name(&ui::conversations::presence_name(room, &id))
                 .map(|presence| presences.remove_child(presence));
         },
     );
@@ -0,0 +1,263 @@
+use crate::net::ktp;
+use crate::ui::main_window::{ELEMENT_NAME_CHAT_AREA, ELEMENT_NAME_ONLINE_PANEL};
+use cursive::Cursive;
+use cursive::traits::{Nameable, Resizable, Scrollable};
+use cursive::view::ScrollStrategy;
+use cursive::views::{BoxedView, LinearLayout, ScreensView, SelectView, TextView};
+
+pub const ELEMENT_NAME_CHAT_SCREENS: &str = "chat_screens";
+pub const ELEMENT_NAME_CONVERSATIONS: &str = "conversations";
+pub const ELEMENT_NAME_ONLINE_SCREENS: &str = "online_screens";
+pub const ELEMENT_NAME_TYPING: &str = "typing";
+
+const UNREAD_MARK: &str = " *";
+
+/// Every item of the conversations list has a chat screen with the same index. <br>
+/// Rooms have online panels of their own too, in the order the rooms were joined.
+/// Rooms stay in the list after they are left, so the indexes stay in place.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Conversation {
+    Main,
+    Direct(ktp::Id),
+    Room(String),
+}
+
+pub fn chat_area(name: &str) -> BoxedView {
+    BoxedView::boxed(
+        LinearLayout::vertical()
+            .with_name(name)
+            .full_height()
+            .full_width()
+            .scrollable()
+            .scroll_strategy(ScrollStrategy::StickToBottom),
+    )
+}
+
+/// Chat area of the room, with the line of who is typing there under it.
+pub fn chat_screen(room: &str) -> BoxedView {
+    BoxedView::boxed(
+        LinearLayout::vertical()
+            .child(chat_area(&room_chat_area_name(room)))
+            .child(TextView::new("").with_name(typing_name(room))),
+    )
+}
+
+pub fn direct_chat_area_name(id: &ktp::Id) -> String {
+    format!("{id:x?}_direct")
+}
+
+/// The main room is the empty one.
+pub fn room_chat_area_name(room: &str) -> String {
+    match room {
+        "" => ELEMENT_NAME_CHAT_AREA.to_string(),
+        room => format!("{room}_room"),
+    }
+}
+
+pub fn typing_name(room: &str) -> String {
+    match room {
+        "" => ELEMENT_NAME_TYPING.to_string(),
+        room => format!("{room}_typing"),
+    }
+}
+
+pub fn online_panel_name(room: &str) -> String {
+    match room {
+        "" => ELEMENT_NAME_ONLINE_PANEL.to_string(),
+        room => format!("{room}_online"),
+    }
+}
+
+/// Names are looked up in every panel, so the peer has one per room.
+pub fn presence_name(room: &str, id: &ktp::Id) -> String {
+    match room {
+        "" => format!("{id:x?}_presence"),
+        room => format!("{id:x?}_{room}_presence"),
+    }
+}
+
+pub fn selected(siv: &mut Cursive) -> Conversation {
+    siv.call_on_name(
+        ELEMENT_NAME_CONVERSATIONS,
+        |conversations: &mut SelectView<Conversation>| conversations.selection(),
+    )
+    .flatten()
+    .map(|conversation| (*conversation).clone())
+    .unwrap_or(Conversation::Main)
+}
+
+/// Adds the room with its chat screen and online panel, if there is none yet.
+/// A room left before gets its label back.
+pub fn open_room(siv: &mut Cursive, room: &str) {
+    let conversation = Conversation::Room(room.to_string());
+
+    let is_new = siv
+        .call_on_name(
+            ELEMENT_NAME_CONVERSATIONS,
+            |conversations: &mut SelectView<Conversation>| {
+                let position = conversations
+                    .iter()
+                    .position(|(_, item)| *item == conversation);
+
+                match position.and_then(|index| conversations.get_item_mut(index)) {
+                    Some((label, _)) => {
+                        *label = format!("#{room}").into();
+                        false
+                    },
+                    None => {
+                        conversations.add_item(format!("#{room}"), conversation);
+                        true
+                    },
+                }
+            },
+        )
+        .unwrap_or(false);
+
+    if is_new {
+        siv.call_on_name(ELEMENT_NAME_CHAT_SCREENS, |screens: &mut ScreensView| {
+            screens.add_screen(chat_screen(room));
+        });
+        // Online panels scroll the same way as chat areas.
+        siv.call_on_name(ELEMENT_NAME_ONLINE_SCREENS, |screens: &mut ScreensView| {
+            screens.add_screen(chat_area(&online_panel_name(room)));
+        });
+    }
+}
+
+/// Keeps the history of the room readable, but nobody is online there anymore.
+pub fn leave_room(siv: &mut Cursive, room: &str) {
+    let conversation = Conversation::Room(room.to_string());
+
+    siv.call_on_name(
+        ELEMENT_NAME_CONVERSATIONS,
+        |conversations: &mut SelectView<Conversation>| {
+            let position = conversations
+                .iter()
+                .position(|(_, item)| *item == conversation);
+
+            if let Some((label, _)) =
+                position.and_then(|index| conversations.get_item_mut(index))
+            {
+                *label = format!("#{room} {}", t!("text.conversation.left")).into();
+            }
+        },
+    );
+    siv.call_on_name(&online_panel_name(room), |presences: &mut LinearLayout| {
+        presences.clear();
+    });
+}
+
+/// Selects the conversation, as if the user did.
+pub fn select(siv: &mut Cursive, conversation: &Conversation) {
+    siv.call_on_name(
+        ELEMENT_NAME_CONVERSATIONS,
+        |conversations: &mut SelectView<Conversation>| {
+            let position = conversations
+                .iter()
+                .position(|(_, item)| item == conversation);
+            if let Some(index) = position {
+                conversations.set_selection(index);
+            }
+        },
+    );
+    switch(siv, conversation);
+}
+
+/// Marks the conversation as unread, unless it is open.
+pub fn mark_unread(siv: &mut Cursive, conversation: &Conversation) {
+    if selected(siv) == *conversation {
+        return;
+    }
+
+    siv.call_on_name(
+        ELEMENT_NAME_CONVERSATIONS,
+        |conversations: &mut SelectView<Conversation>| {
+            let position = conversations
+                .iter()
+                .position(|(_, item)| item == conversation);
+
+            if let Some((label, _)) =
+                position.and_then(|index| conversations.get_item_mut(index))
+            {
+                if !label.source().ends_with(UNREAD_MARK) {
+                    *label = format!("{}{UNREAD_MARK}", label.source()).into();
+                }
+            }
+        },
+    );
+}
+
+/// Adds a conversation with the peer, if there is none yet.
+/// A conversation that isn't open gets marked as unread.
+pub fn open_direct(siv: &mut Cursive, peer: ktp::Id, peer_username: &str) {
+    let conversation = Conversation::Direct(peer);
+    let is_open = selected(siv) == conversation;
+
+    siv.call_on_name(
+        ELEMENT_NAME_CONVERSATIONS,
+        |conversations: &mut SelectView<Conversation>| {
+            let position = conversations
+                .iter()
+                .position(|(_, item)| *item == conversation);
+
+            let mut label = format!("@{peer_username}");
+            if !is_open {
+                label += UNREAD_MARK;
+            }
+
+            match position {
+                Some(index) => {
+                    if let Some((item_label, _)) = conversations.get_item_mut(index) {
+                        *item_label = label.into();
+                    }
+                },
+                None => {
+                    conversations.add_item(label, conversation);
+                },
+            }
+        },
+    );
+
+    let area_name = direct_chat_area_name(&peer);
+    if siv.find_name::<LinearLayout>(&area_name).is_none() {
+        siv.call_on_name(ELEMENT_NAME_CHAT_SCREENS, |screens: &mut ScreensView| {
+            screens.add_screen(chat_area(&area_name));
+        });
+    }
+}
+
+/// Shows the chat screen of the selected conversation and clears its unread mark.
+/// Rooms show their online panel, the rest show the one of the main room.
+pub fn switch(siv: &mut Cursive, _: &Conversation) {
+    let indexes = siv.call_on_name(
+        ELEMENT_NAME_CONVERSATIONS,
+        |conversations: &mut SelectView<Conversation>| {
+            let index = conversations.selected_id()?;
+            let online_index = match conversations.get_item(index) {
+                Some((_, Conversation::Room(_))) => conversations
+                    .iter()
+                    .take(index + 1)
+                    .filter(|(_, item)| matches!(item, Conversation::Room(_)))
+                    .count(),
+                _ => 0,
+            };
+            if let Some((label, _)) = conversations.get_item_mut(index) {
+                let source = label.source().strip_suffix(UNREAD_MARK).map(str::to_string);
+                if let Some(source) = source {
+                    *label = source.into();
+                }
+            }
+
+            Some((index, online_index))
+        },
+    );
+
+    if let Some(Some((index, online_index))) = indexes {
+        siv.call_on_name(ELEMENT_NAME_CHAT_SCREENS, |screens: &mut ScreensView| {
+            screens.set_active_screen(index);
+        });
+        siv.call_on_name(ELEMENT_NAME_ONLINE_SCREENS, |screens: &mut ScreensView| {
+            screens.set_active_screen(online_index);
+        });
+    }
+}
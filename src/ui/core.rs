@@ -1,10 +1,13 @@
 use crate::config::CONFIG;
 use crate::net::commands::NetCommand;
+use crate::net::presence::Trust;
 use crate::ui::commands::UICommand;
 use crate::ui::dialog;
-use crate::{net, session_settings, ui};
+use crate::ui::messages::{ShownMessage, ShownMessages};
+use crate::{config, net, session_settings, ui};
 use crossbeam::channel::unbounded;
 use cursive::Cursive;
+use std::collections::HashMap;
 use std::thread;
 
 pub fn start() {
@@ -13,6 +16,18 @@ pub fn start() {
     log::info!("Main UI and Net channels created.");
 
     let mut ui_thread_username = String::from(session_settings::INITIAL_USERNAME);
+    // Online peers, used to find addressees of direct messages.
+    let mut ui_thread_peers = HashMap::new();
+    // Messages of the main chat and rooms, which may change later.
+    let mut ui_thread_messages = ShownMessages::default();
+    // Rooms joined besides the main one.
+    let mut ui_thread_rooms = vec![];
+    // Room of the unsent message in the chat input, told to the net thread on change.
+    let mut ui_thread_typing = None;
+    // Rooms announced on the segment, for the room list.
+    let mut ui_thread_room_listings = vec![];
+    // Labels of transfers without their state, along with the file names.
+    let mut ui_thread_transfers = HashMap::new();
 
     let net_thread = thread::Builder::new()
         .name("Net Thread".to_string())
@@ -31,7 +46,7 @@ pub fn start() {
         .expect("Styles are not loaded. Please, provide ./assets/styles.toml");
     log::info!("Main styles from assets loaded.");
 
-    dialog::localization::show_select_dialog(&mut siv, ui_tx);
+    dialog::localization::show_select_dialog(&mut siv, ui_tx.clone());
 
     let mut event_loop = siv.runner();
     event_loop.refresh();
@@ -40,10 +55,46 @@ pub fn start() {
     while event_loop.is_running() {
         while let Ok(command) = ui_rx.try_recv() {
             match command {
+                UICommand::AcceptFile { transfer_id } => {
+                    log::info!("UI Command: Accept File: {transfer_id:x?}");
+                    ui::commands::accept_file(transfer_id, &mut event_loop, &net_tx);
+                },
                 UICommand::AlertUser => {
                     log::info!("UI Command: Alert User.");
                     ui::commands::alert_user()
                 },
+                UICommand::BrowseRooms => {
+                    log::info!("UI Command: Browse Rooms.");
+                    dialog::rooms::show_browse_dialog(
+                        &mut event_loop,
+                        ui_tx.clone(),
+                        &ui_thread_room_listings,
+                    );
+                },
+                UICommand::CancelTransfer { transfer_id } => {
+                    log::info!("UI Command: Cancel Transfer: {transfer_id:x?}");
+                    ui::commands::cancel_transfer(transfer_id, &mut event_loop, &net_tx);
+                },
+                UICommand::DeleteMessage { message_id } => {
+                    log::info!("UI Command: Delete Message: {message_id:x?}");
+                    ui::commands::delete_message(
+                        message_id,
+                        &mut ui_thread_messages,
+                        &mut event_loop,
+                    );
+                },
+                UICommand::EditMessage {
+                    message_id,
+                    message_text,
+                } => {
+                    log::debug!("UI Command: Edit Message: {message_id:x?}");
+                    ui::commands::edit_message(
+                        message_id,
+                        message_text,
+                        &mut ui_thread_messages,
+                        &mut event_loop,
+                    );
+                },
                 UICommand::ExportMessages(file) => {
                     log::info!("UI Command: Export Dialog.");
                     ui::commands::export_messages(&mut event_loop, file);
@@ -53,9 +104,67 @@ pub fn start() {
                     dialog::error::show_breaking(&mut event_loop, err);
                     break;
                 },
-                UICommand::SendMessage { message_text } => {
-                    log::info!("UI Command: Send message: {message_text}");
-                    ui::commands::send_message(message_text, &mut event_loop, &net_tx)
+                UICommand::SendNetWarning(err) => {
+                    log::warn!("UI Command: Net warning. {}", err);
+                    dialog::error::show(&mut event_loop, err);
+                },
+                UICommand::JoinRoom(room) => {
+                    log::info!("UI Command: Join Room: {room}");
+                    ui::commands::join_room(
+                        room,
+                        &mut ui_thread_rooms,
+                        &mut event_loop,
+                        &net_tx,
+                    );
+                },
+                UICommand::PickMessage { is_older } => {
+                    ui::commands::pick_message(
+                        is_older,
+                        &mut ui_thread_messages,
+                        &mut event_loop,
+                    );
+                },
+                UICommand::SendMessage { room, message_text } => {
+                    log::debug!("UI Command: Send message. Room: {room}");
+                    ui::commands::send_message(
+                        room,
+                        message_text,
+                        &ui_thread_peers,
+                        &mut ui_thread_rooms,
+                        &mut ui_thread_messages,
+                        &mut event_loop,
+                        &net_tx,
+                    )
+                },
+                UICommand::SendDirectMessage {
+                    target,
+                    message_text,
+                } => {
+                    log::debug!("UI Command: Send direct message: {target:x?}");
+                    ui::commands::send_direct_message(
+                        target,
+                        message_text,
+                        &mut event_loop,
+                        &net_tx,
+                    )
+                },
+                UICommand::SendFile { username, path } => {
+                    log::info!("UI Command: Send file {} to {username}", path.display());
+                    ui::commands::send_file(
+                        username,
+                        path,
+                        &ui_thread_peers,
+                        &mut event_loop,
+                        &net_tx,
+                    );
+                },
+                UICommand::SetArpFraming(arp_framing) => {
+                    log::info!("UI Command: Set ARP Framing: {arp_framing}");
+                    ui::commands::set_arp_framing(arp_framing, &mut event_loop, &net_tx);
+                },
+                UICommand::SetCarrier(carrier) => {
+                    log::info!("UI Command: Set Carrier: {carrier}");
+                    ui::commands::set_carrier(carrier, &mut event_loop, &net_tx);
                 },
                 UICommand::SetEtherType(ether_type) => {
                     log::info!("UI Command: Set EtherType: {ether_type}");
@@ -73,6 +182,29 @@ pub fn start() {
                     log::info!("UI Command: Set Log Level: {level}");
                     ui::commands::set_log_level(level);
                 },
+                UICommand::SetRoomPassphrase(passphrase) => {
+                    log::info!(
+                        "UI Command: Set Room Passphrase: {}",
+                        passphrase.is_some()
+                    );
+                    ui::commands::set_room_passphrase(
+                        passphrase,
+                        &ui_thread_username,
+                        &mut event_loop,
+                        &net_tx,
+                    );
+                },
+                UICommand::SetTyping(room) => {
+                    if room != ui_thread_typing {
+                        log::debug!("UI Command: Set Typing: {room:?}");
+                        ui_thread_typing = room.clone();
+                        net_tx
+                            .try_send(NetCommand::SetTyping(room))
+                            .unwrap_or_else(|err| {
+                                log::error!("Error sending SetTyping: {err}");
+                            });
+                    }
+                },
                 UICommand::SetUsername(username) => {
                     log::info!("UI Command: Set Username: {username}");
                     ui::commands::set_username(
@@ -83,38 +215,174 @@ pub fn start() {
                     );
                 },
                 UICommand::ShowMessage {
+                    room,
                     id,
+                    message_id,
                     username,
                     message,
+                    reply_to,
                     is_outgoing_message,
                 } => {
-                    log::info!("UI Command: Show Message: [{username}] {message}");
-                    ui::commands::show_message(
+                    log::debug!("UI Command: Show Message from {username}");
+                    let shown = ShownMessage::new(&room, id, username, message);
+                    if is_outgoing_message {
+                        ui::commands::show_outgoing_message(
+                            message_id,
+                            shown,
+                            reply_to,
+                            &mut ui_thread_messages,
+                            &mut event_loop,
+                        );
+                    } else {
+                        ui::commands::show_message(
+                            message_id,
+                            shown,
+                            reply_to,
+                            &mut ui_thread_messages,
+                            &mut event_loop,
+                        );
+                    }
+                },
+                UICommand::UpdateDeliveryState { message_id, state } => {
+                    log::info!("UI Command: Update Delivery State: {message_id:x?} {state:?}");
+                    ui::commands::update_delivery_state(
+                        message_id,
+                        state,
+                        &mut ui_thread_messages,
+                        &mut event_loop,
+                    );
+                },
+                UICommand::ShowDirectMessage {
+                    peer,
+                    peer_username,
+                    id,
+                    username,
+                    message,
+                } => {
+                    log::debug!("UI Command: Show Direct Message from {username}");
+                    ui::commands::show_direct_message(
+                        peer,
+                        peer_username,
                         id,
                         username,
                         message,
-                        is_outgoing_message,
                         &mut event_loop,
                     );
                 },
+                UICommand::ShowFingerprint {
+                    peer,
+                    peer_username,
+                    fingerprint,
+                } => {
+                    log::info!(
+                        "UI Command: Show Fingerprint: {peer_username} {fingerprint}"
+                    );
+                    ui::commands::show_fingerprint(
+                        peer,
+                        peer_username,
+                        fingerprint,
+                        &mut event_loop,
+                    );
+                },
+                UICommand::ShowTopic {
+                    room,
+                    topic,
+                    username,
+                } => {
+                    log::debug!("UI Command: Show Topic: #{room}");
+                    ui::commands::show_topic(&room, topic, username, &mut event_loop);
+                },
+                UICommand::ShowTransfer {
+                    transfer_id,
+                    peer_username,
+                    name,
+                    size,
+                    is_outgoing,
+                } => {
+                    log::info!("UI Command: Show Transfer: {name} with {peer_username}");
+                    ui::commands::show_transfer(
+                        transfer_id,
+                        &peer_username,
+                        name.clone(),
+                        is_outgoing,
+                        &mut ui_thread_transfers,
+                        &mut event_loop,
+                    );
+                    // Incoming files are received only if the user agrees.
+                    if !is_outgoing {
+                        dialog::transfers::show_offer_dialog(
+                            &mut event_loop,
+                            ui_tx.clone(),
+                            transfer_id,
+                            &peer_username,
+                            &name,
+                            size,
+                            &config::lock_get_receive_directory(),
+                        );
+                    }
+                },
+                UICommand::UpdateTransfer { transfer_id, state } => {
+                    log::info!("UI Command: Update Transfer: {transfer_id:x?} {state:?}");
+                    ui::commands::update_transfer(
+                        transfer_id,
+                        state,
+                        &ui_thread_transfers,
+                        &mut event_loop,
+                    );
+                },
+                UICommand::UpdateRooms(listings) => {
+                    log::info!("UI Command: Update Rooms: {}", listings.len());
+                    ui_thread_room_listings = listings;
+                },
+                UICommand::UpdateTyping { room, usernames } => {
+                    log::debug!("UI Command: Update Typing: #{room} {usernames:?}");
+                    ui::commands::update_typing(&room, usernames, &mut event_loop);
+                },
                 UICommand::PresenceUpdate {
+                    room,
                     id,
                     username,
                     is_inactive,
+                    trust,
                     kind,
                 } => {
-                    log::info!("UI Command: Presence Update. {username}: is inactive ({is_inactive})");
+                    log::info!("UI Command: Presence Update. {username}: is inactive ({is_inactive}), {trust:?}");
+                    // /msg shouldn't reach an impersonator by the name it took.
+                    match trust {
+                        _ if !room.is_empty() => None,
+                        Trust::Verified | Trust::Trusted => {
+                            ui_thread_peers.insert(id, username.clone())
+                        },
+                        Trust::Impersonator => ui_thread_peers.remove(&id),
+                    };
                     ui::commands::presence_update(
+                        &room,
                         id,
                         username,
                         is_inactive,
+                        trust,
                         kind,
                         &mut event_loop,
                     );
                 },
-                UICommand::RemovePresence { id, username } => {
+                UICommand::ShowKeyMismatchWarning { username } => {
+                    log::warn!("UI Command: Key mismatch for {username}");
+                    ui::commands::show_key_mismatch_warning(username, &mut event_loop);
+                },
+                UICommand::ShowForgeryWarning { id, username } => {
+                    log::warn!("UI Command: Forged packet from {id:x?} ({username})");
+                    ui::commands::show_forgery_warning(username, &mut event_loop);
+                },
+                UICommand::ShowOutdatedPeerWarning { username, missing } => {
+                    log::warn!("UI Command: Outdated peer {username}");
+                    ui::commands::show_outdated_peer_warning(username, missing, &mut event_loop);
+                },
+                UICommand::RemovePresence { room, id, username } => {
                     log::info!("UI Command: Remove Presence: {username}");
-                    ui::commands::remove_presence(id, username, &mut event_loop);
+                    if room.is_empty() {
+                        ui_thread_peers.remove(&id);
+                    }
+                    ui::commands::remove_presence(&room, id, username, &mut event_loop);
                 },
             }
 
@@ -0,0 +1,241 @@
+use crate::net::contacts::{CONTACTS, Contact, ContactBook};
+use chrono::{Local, TimeZone};
+use cursive::Cursive;
+use cursive::traits::{Nameable, Resizable, Scrollable};
+use cursive::views::{Dialog, EditView, LinearLayout, SelectView, TextView};
+
+pub const ELEMENT_NAME_CONTACTS_LIST: &str = "contacts_list";
+pub const ELEMENT_NAME_CONTACT_DETAILS: &str = "contact_details";
+pub const ELEMENT_NAME_CONTACT_ALIAS_INPUT: &str = "contact_alias_input";
+
+pub fn show_dialog(siv: &mut Cursive) {
+    let own_fingerprint = CONTACTS
+        .lock()
+        .ok()
+        .and_then(|contacts| contacts.own_fingerprint().map(str::to_string))
+        .unwrap_or_default();
+
+    siv.add_layer(
+        Dialog::new()
+            .title(t!("title.contacts"))
+            .content(
+                LinearLayout::vertical()
+                    .child(TextView::new(rust_i18n::replace_patterns(
+                        &t!("text.own_fingerprint"),
+                        &["fingerprint"],
+                        &[own_fingerprint],
+                    )))
+                    .child(
+                        LinearLayout::horizontal()
+                            .child(
+                                SelectView::<String>::new()
+                                    .on_select(|siv, public_key| {
+                                        show_details(siv, public_key);
+                                    })
+                                    .with_name(ELEMENT_NAME_CONTACTS_LIST)
+                                    .scrollable()
+                                    .fixed_width(28),
+                            )
+                            .child(
+                                TextView::new("")
+                                    .with_name(ELEMENT_NAME_CONTACT_DETAILS)
+                                    .full_width(),
+                            )
+                            .fixed_height(10),
+                    ),
+            )
+            .button(t!("button.rename"), |siv| {
+                if let Some(public_key) = selected(siv) {
+                    show_rename_dialog(siv, public_key);
+                }
+            })
+            .button(t!("button.verify"), |siv| {
+                if let Some(public_key) = selected(siv) {
+                    if let Ok(mut contacts) = CONTACTS.lock() {
+                        let is_verified = find(contacts.contacts(), &public_key)
+                            .is_some_and(|contact| contact.is_verified);
+                        contacts.set_verified(&public_key, !is_verified);
+                        save(&mut contacts);
+                    }
+                    refresh(siv);
+                }
+            })
+            .button(t!("button.forget"), |siv| {
+                if let Some(public_key) = selected(siv) {
+                    if let Ok(mut contacts) = CONTACTS.lock() {
+                        contacts.forget(&public_key);
+                        save(&mut contacts);
+                    }
+                    refresh(siv);
+                }
+            })
+            .button(t!("button.close"), |siv| {
+                siv.pop_layer();
+            })
+            .min_width(72)
+            .max_width(96),
+    );
+
+    refresh(siv);
+}
+
+/// Shown when a known username comes back with another key.
+pub fn show_key_mismatch_dialog(siv: &mut Cursive, text: String) {
+    siv.add_layer(
+        Dialog::text(text)
+            .title(t!("title.key_mismatch"))
+            .button(t!("button.contacts"), |siv| {
+                siv.pop_layer();
+                show_dialog(siv);
+            })
+            .button(t!("button.ok"), |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn show_rename_dialog(siv: &mut Cursive, public_key: String) {
+    let alias = CONTACTS
+        .lock()
+        .ok()
+        .and_then(|contacts| find(contacts.contacts(), &public_key)?.alias.clone())
+        .unwrap_or_default();
+
+    siv.add_layer(
+        Dialog::new()
+            .title(t!("title.rename_contact"))
+            .content(
+                EditView::new()
+                    .content(alias)
+                    .with_name(ELEMENT_NAME_CONTACT_ALIAS_INPUT)
+                    .fixed_width(28),
+            )
+            .button(t!("button.save"), move |siv| {
+                let alias = siv
+                    .call_on_name(
+                        ELEMENT_NAME_CONTACT_ALIAS_INPUT,
+                        |input: &mut EditView| input.get_content(),
+                    )
+                    .unwrap();
+                // Empty alias brings the username back.
+                let alias = match alias.trim().is_empty() {
+                    true => None,
+                    false => Some(alias.trim().to_string()),
+                };
+
+                if let Ok(mut contacts) = CONTACTS.lock() {
+                    contacts.rename(&public_key, alias);
+                    save(&mut contacts);
+                }
+                siv.pop_layer();
+                refresh(siv);
+            })
+            .button(t!("button.close"), |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn refresh(siv: &mut Cursive) {
+    let items: Vec<(String, String)> = match CONTACTS.lock() {
+        Ok(contacts) => contacts
+            .contacts()
+            .iter()
+            .map(|contact| {
+                let mark = if contact.is_verified { "✓" } else { " " };
+                (
+                    format!("{mark} {}", contact.name()),
+                    contact.public_key.clone(),
+                )
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+
+    let selected = siv
+        .call_on_name(
+            ELEMENT_NAME_CONTACTS_LIST,
+            |list: &mut SelectView<String>| {
+                let index = list.selected_id().unwrap_or_default();
+                list.clear();
+                list.add_all(items);
+                let _ = list.set_selection(index);
+                list.selection()
+            },
+        )
+        .flatten();
+
+    match selected {
+        Some(public_key) => show_details(siv, &public_key),
+        None => {
+            siv.call_on_name(ELEMENT_NAME_CONTACT_DETAILS, |details: &mut TextView| {
+                details.set_content(t!("text.no_contacts"));
+            });
+        },
+    }
+}
+
+fn show_details(siv: &mut Cursive, public_key: &str) {
+    let Some(contact) = CONTACTS
+        .lock()
+        .ok()
+        .and_then(|contacts| find(contacts.contacts(), public_key).cloned())
+    else {
+        return;
+    };
+
+    let text = rust_i18n::replace_patterns(
+        &t!("text.contact_details"),
+        &[
+            "username",
+            "fingerprint",
+            "mac",
+            "first_seen",
+            "last_seen",
+            "verified",
+        ],
+        &[
+            contact.username.clone(),
+            contact.fingerprint(),
+            contact.mac.clone().unwrap_or("-".to_string()),
+            format_time(contact.first_seen),
+            format_time(contact.last_seen),
+            match contact.is_verified {
+                true => t!("text.yes").to_string(),
+                false => t!("text.no").to_string(),
+            },
+        ],
+    );
+
+    siv.call_on_name(ELEMENT_NAME_CONTACT_DETAILS, |details: &mut TextView| {
+        details.set_content(text);
+    });
+}
+
+fn selected(siv: &mut Cursive) -> Option<String> {
+    siv.call_on_name(
+        ELEMENT_NAME_CONTACTS_LIST,
+        |list: &mut SelectView<String>| list.selection(),
+    )
+    .flatten()
+    .map(|public_key| public_key.to_string())
+}
+
+fn find<'a>(contacts: &'a [Contact], public_key: &str) -> Option<&'a Contact> {
+    contacts
+        .iter()
+        .find(|contact| contact.public_key == public_key)
+}
+
+fn save(contacts: &mut ContactBook) {
+    if let Err(err) = contacts.save() {
+        log::error!("Couldn't save contacts: {err}");
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => "-".to_string(),
+    }
+}
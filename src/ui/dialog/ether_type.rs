@@ -1,13 +1,21 @@
+use crate::net::arp_framing::ArpFraming;
+use crate::net::carrier::Carrier;
 use crate::net::ether_type::EtherType;
 use crate::ui::commands::UICommand;
 use crate::{config, ui};
 use crossbeam::channel::Sender;
 use cursive::traits::Resizable;
-use cursive::views::{Dialog, LinearLayout, SelectView, TextView};
+use cursive::views::{Dialog, DummyView, LinearLayout, SelectView, TextView};
 use cursive::Cursive;
 use strum::IntoEnumIterator;
 
 pub fn show_select_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
+    let preferred_carrier_index = Carrier::iter()
+        .position(|carrier| carrier == config::lock_get_carrier())
+        .unwrap_or_default();
+    let preferred_arp_framing_index = ArpFraming::iter()
+        .position(|arp_framing| arp_framing == config::lock_get_arp_framing())
+        .unwrap_or_default();
     let preferred_ether_type_index = EtherType::iter()
         .position(|ether_type| {
             ether_type
@@ -21,6 +29,64 @@ pub fn show_select_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
             .title(t!("title.protocol_selection"))
             .content(
                 LinearLayout::vertical()
+                    .child(TextView::new(t!("text.carriers")))
+                    .child(
+                        SelectView::new()
+                            .with_all(
+                                Carrier::iter()
+                                    .map(|carrier| (carrier.to_string(), carrier)),
+                            )
+                            .selected(preferred_carrier_index)
+                            .on_submit({
+                                let ui_tx = ui_tx.clone();
+                                move |siv, carrier: &Carrier| {
+                                    let result =
+                                        ui_tx.try_send(UICommand::SetCarrier(*carrier));
+
+                                    match result {
+                                        Ok(_) => {
+                                            siv.pop_layer();
+                                        },
+                                        Err(err) => {
+                                            ui::dialog::error::show_try_again(
+                                                siv,
+                                                err.to_string(),
+                                            );
+                                        },
+                                    }
+                                }
+                            }),
+                    )
+                    .child(DummyView)
+                    .child(TextView::new(t!("text.arp_framings")))
+                    .child(
+                        SelectView::new()
+                            .with_all(
+                                ArpFraming::iter()
+                                    .map(|arp_framing| (arp_framing.to_string(), arp_framing)),
+                            )
+                            .selected(preferred_arp_framing_index)
+                            .on_submit({
+                                let ui_tx = ui_tx.clone();
+                                move |siv, arp_framing: &ArpFraming| {
+                                    let result =
+                                        ui_tx.try_send(UICommand::SetArpFraming(*arp_framing));
+
+                                    match result {
+                                        Ok(_) => {
+                                            siv.pop_layer();
+                                        },
+                                        Err(err) => {
+                                            ui::dialog::error::show_try_again(
+                                                siv,
+                                                err.to_string(),
+                                            );
+                                        },
+                                    }
+                                }
+                            }),
+                    )
+                    .child(DummyView)
                     .child(TextView::new(t!("text.ether_types")))
                     .child(
                         SelectView::new()
@@ -0,0 +1,63 @@
+use crate::ui::commands::UICommand;
+use crate::{config, ui};
+use crossbeam::channel::Sender;
+use cursive::Cursive;
+use cursive::view::{Nameable, Resizable};
+use cursive::views::{Dialog, EditView, LinearLayout, TextView};
+
+pub const ELEMENT_NAME_ROOM_PASSPHRASE_INPUT: &str = "room_passphrase_input";
+
+pub fn show_input_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
+    siv.add_layer(
+        Dialog::new()
+            .title(t!("title.room_passphrase"))
+            .content(
+                LinearLayout::vertical()
+                    .child(TextView::new(t!("text.room_passphrase")))
+                    .child(
+                        EditView::new()
+                            .secret()
+                            .content(
+                                config::lock_get_room_passphrase().unwrap_or_default(),
+                            )
+                            .on_submit({
+                                let ui_tx = ui_tx.clone();
+                                move |siv, passphrase| {
+                                    submit(siv, &ui_tx, passphrase);
+                                }
+                            })
+                            .with_name(ELEMENT_NAME_ROOM_PASSPHRASE_INPUT),
+                    ),
+            )
+            .button(t!("button.save"), move |siv| {
+                let passphrase = siv
+                    .call_on_name(
+                        ELEMENT_NAME_ROOM_PASSPHRASE_INPUT,
+                        |input: &mut EditView| input.get_content(),
+                    )
+                    .unwrap();
+
+                submit(siv, &ui_tx, &passphrase);
+            })
+            .button(t!("button.close"), |siv| {
+                siv.pop_layer();
+            })
+            .min_width(56)
+            .max_width(72),
+    );
+}
+
+// Empty passphrase turns encryption off.
+fn submit(siv: &mut Cursive, ui_tx: &Sender<UICommand>, passphrase: &str) {
+    let passphrase = match passphrase.is_empty() {
+        true => None,
+        false => Some(passphrase.to_string()),
+    };
+
+    match ui_tx.try_send(UICommand::SetRoomPassphrase(passphrase)) {
+        Ok(_) => {
+            siv.pop_layer();
+        },
+        Err(err) => ui::dialog::error::show_try_again(siv, err),
+    }
+}
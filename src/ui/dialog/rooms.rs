@@ -0,0 +1,49 @@
+use crate::net::rooms::RoomListing;
+use crate::ui;
+use crate::ui::commands::UICommand;
+use crossbeam::channel::Sender;
+use cursive::Cursive;
+use cursive::traits::{Resizable, Scrollable};
+use cursive::views::{Dialog, SelectView};
+
+/// Lists the rooms announced on the segment, choosing one joins it.
+pub fn show_browse_dialog(
+    siv: &mut Cursive, ui_tx: Sender<UICommand>, listings: &[RoomListing],
+) {
+    if listings.is_empty() {
+        siv.add_layer(
+            Dialog::text(t!("text.no_rooms"))
+                .title(t!("title.browse_rooms"))
+                .button(t!("button.close"), |siv| {
+                    siv.pop_layer();
+                }),
+        );
+        return;
+    }
+
+    let mut rooms = SelectView::<String>::new();
+    for listing in listings {
+        let label = match &listing.topic {
+            Some(topic) => format!("#{} ({}) {topic}", listing.room, listing.members),
+            None => format!("#{} ({})", listing.room, listing.members),
+        };
+        rooms.add_item(label, listing.room.clone());
+    }
+    rooms.set_on_submit(move |siv, room: &String| {
+        match ui_tx.try_send(UICommand::JoinRoom(room.clone())) {
+            Ok(_) => {
+                siv.pop_layer();
+            },
+            Err(err) => ui::dialog::error::show_try_again(siv, err),
+        }
+    });
+
+    siv.add_layer(
+        Dialog::around(rooms.scrollable().max_height(12))
+            .title(t!("title.browse_rooms"))
+            .button(t!("button.close"), |siv| {
+                siv.pop_layer();
+            })
+            .max_width(72),
+    );
+}
@@ -0,0 +1,59 @@
+use crate::ui;
+use crate::ui::commands::UICommand;
+use crossbeam::channel::Sender;
+use cursive::Cursive;
+use cursive::view::{Nameable, Resizable};
+use cursive::views::{Dialog, EditView, LinearLayout, TextView};
+use std::path::PathBuf;
+
+pub const ELEMENT_NAME_SEND_FILE_RECIPIENT_INPUT: &str = "send_file_recipient_input";
+pub const ELEMENT_NAME_SEND_FILE_PATH_INPUT: &str = "send_file_path_input";
+
+pub fn show_dialog(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
+    siv.add_layer(
+        Dialog::new()
+            .title(t!("title.send_file"))
+            .content(
+                LinearLayout::vertical()
+                    .child(TextView::new(t!("text.send_file_recipient")))
+                    .child(
+                        EditView::new().with_name(ELEMENT_NAME_SEND_FILE_RECIPIENT_INPUT),
+                    )
+                    .child(TextView::new(t!("text.send_file_path")))
+                    .child(EditView::new().with_name(ELEMENT_NAME_SEND_FILE_PATH_INPUT)),
+            )
+            .button(t!("button.send"), move |siv| {
+                let username = siv
+                    .call_on_name(
+                        ELEMENT_NAME_SEND_FILE_RECIPIENT_INPUT,
+                        |input: &mut EditView| input.get_content(),
+                    )
+                    .unwrap();
+                let path = siv
+                    .call_on_name(
+                        ELEMENT_NAME_SEND_FILE_PATH_INPUT,
+                        |input: &mut EditView| input.get_content(),
+                    )
+                    .unwrap();
+                if username.is_empty() || path.is_empty() {
+                    return;
+                }
+
+                let command = UICommand::SendFile {
+                    username: username.trim().to_string(),
+                    path: PathBuf::from(path.as_str()),
+                };
+                match ui_tx.try_send(command) {
+                    Ok(_) => {
+                        siv.pop_layer();
+                    },
+                    Err(err) => ui::dialog::error::show_try_again(siv, err),
+                }
+            })
+            .button(t!("button.close"), |siv| {
+                siv.pop_layer();
+            })
+            .min_width(56)
+            .max_width(72),
+    );
+}
@@ -0,0 +1,80 @@
+use crate::net::ktp;
+use crate::ui;
+use crate::ui::commands::UICommand;
+use crossbeam::channel::Sender;
+use cursive::Cursive;
+use cursive::view::Resizable;
+use cursive::views::Dialog;
+use std::path::Path;
+
+/// Asks whether to receive the offered file.
+pub fn show_offer_dialog(
+    siv: &mut Cursive, ui_tx: Sender<UICommand>, transfer_id: ktp::Id,
+    peer_username: &str, name: &str, size: u64, directory: &Path,
+) {
+    let text = rust_i18n::replace_patterns(
+        &t!("text.file_offer"),
+        &["username", "name", "size", "directory"],
+        &[
+            peer_username.to_string(),
+            name.to_string(),
+            format_size(size),
+            directory.display().to_string(),
+        ],
+    );
+
+    siv.add_layer(
+        Dialog::text(text)
+            .title(t!("title.file_offer"))
+            .button(t!("button.accept"), {
+                let ui_tx = ui_tx.clone();
+                move |siv| {
+                    submit(siv, &ui_tx, UICommand::AcceptFile { transfer_id });
+                }
+            })
+            .button(t!("button.decline"), move |siv| {
+                submit(siv, &ui_tx, UICommand::CancelTransfer { transfer_id });
+            })
+            .max_width(72),
+    );
+}
+
+pub fn show_cancel_dialog(
+    siv: &mut Cursive, ui_tx: Sender<UICommand>, transfer_id: ktp::Id,
+) {
+    siv.add_layer(
+        Dialog::text(t!("text.cancel_transfer"))
+            .title(t!("title.transfers"))
+            .button(t!("button.cancel_transfer"), move |siv| {
+                submit(siv, &ui_tx, UICommand::CancelTransfer { transfer_id });
+            })
+            .button(t!("button.close"), |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{size} {}", UNITS[0]),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+fn submit(siv: &mut Cursive, ui_tx: &Sender<UICommand>, command: UICommand) {
+    match ui_tx.try_send(command) {
+        Ok(_) => {
+            siv.pop_layer();
+        },
+        Err(err) => ui::dialog::error::show_try_again(siv, err),
+    }
+}
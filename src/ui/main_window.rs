@@ -1,18 +1,25 @@
+use crate::net::ktp;
 use crate::ui::commands::UICommand;
-use crate::{config, ui};
+use crate::ui::conversations::{
+    Conversation, ELEMENT_NAME_CHAT_SCREENS, ELEMENT_NAME_CONVERSATIONS,
+    ELEMENT_NAME_ONLINE_SCREENS,
+};
+use crate::{config, session_settings, ui};
 use crossbeam::channel::Sender;
-use cursive::event::Key;
+use cursive::event::{Event, Key};
 use cursive::traits::{Nameable, Resizable, Scrollable};
-use cursive::view::ScrollStrategy;
 use cursive::views::{
-    Dialog, EditView, LinearLayout, NamedView, Panel, ResizedView, ScrollView,
+    Dialog, EditView, LinearLayout, NamedView, OnEventView, Panel, ScreensView,
+    SelectView,
 };
 use cursive::{menu, Cursive};
 
 pub const ELEMENT_NAME_CHAT_AREA: &str = "chat_area";
 pub const ELEMENT_NAME_CHAT_INPUT: &str = "chat_input";
+pub const ELEMENT_NAME_INPUT_PANEL: &str = "input_panel";
 pub const ELEMENT_NAME_CHAT_PANEL: &str = "chat_panel";
 pub const ELEMENT_NAME_ONLINE_PANEL: &str = "online_panel";
+pub const ELEMENT_NAME_TRANSFERS: &str = "transfers";
 
 pub fn init(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
     const AUTO_HIDE_MENU: bool = false;
@@ -33,6 +40,15 @@ pub fn init(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
                         );
                     }
                 })
+                .leaf(t!("menu.contacts"), ui::dialog::contacts::show_dialog)
+                .leaf(t!("menu.browse_rooms"), {
+                    let ui_tx = ui_tx.clone();
+                    move |_| {
+                        if let Err(err) = ui_tx.try_send(UICommand::BrowseRooms) {
+                            log::error!("Error sending BrowseRooms: {}", err);
+                        }
+                    }
+                })
                 .delimiter()
                 .leaf(t!("menu.switch_protocol"), {
                     let ui_tx = ui_tx.clone();
@@ -40,6 +56,12 @@ pub fn init(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
                         ui::dialog::ether_type::show_select_dialog(siv, ui_tx.clone());
                     }
                 })
+                .leaf(t!("menu.room_passphrase"), {
+                    let ui_tx = ui_tx.clone();
+                    move |siv| {
+                        ui::dialog::room_passphrase::show_input_dialog(siv, ui_tx.clone());
+                    }
+                })
                 .delimiter()
                 .leaf(t!("menu.log_level"), {
                     let ui_tx = ui_tx.clone();
@@ -58,33 +80,47 @@ pub fn init(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
                 }),
         )
         .add_delimiter()
+        .add_leaf(t!("menu.send_file"), {
+            let ui_tx = ui_tx.clone();
+            move |siv| {
+                ui::dialog::send_file::show_dialog(siv, ui_tx.clone());
+            }
+        })
+        .add_delimiter()
         .add_leaf(t!("menu.help"), show_help_dialog)
         .add_delimiter()
         .add_leaf(t!("menu.quit"), ui::core::quit);
     siv.set_autohide_menu(AUTO_HIDE_MENU);
     siv.add_global_callback(Key::Esc, |siv| siv.select_menubar());
 
+    // Rooms of the last session are joined again.
+    for room in config::lock_get_rooms() {
+        if let Some(room) = session_settings::normalize_room(&room) {
+            let _ = ui_tx.try_send(UICommand::JoinRoom(room));
+        }
+    }
+
+    let transfers_ui_tx = ui_tx.clone();
+    let pick_ui_tx = ui_tx.clone();
+
     siv.add_fullscreen_layer(
         LinearLayout::horizontal()
             .child(
                 LinearLayout::vertical()
                     .child(
                         Panel::new(
-                            LinearLayout::vertical()
-                                .with_name(ELEMENT_NAME_CHAT_AREA)
-                                .full_height()
-                                .full_width()
-                                .scrollable()
-                                .scroll_strategy(ScrollStrategy::StickToBottom),
+                            ScreensView::single_screen(ui::conversations::chat_screen(""))
+                                .with_name(ELEMENT_NAME_CHAT_SCREENS),
                         )
-                        .title(format!("arpchat: {initial_username}"))
+                        .title(chat_title(&initial_username))
                         .with_name(ELEMENT_NAME_CHAT_PANEL)
                         .full_height()
                         .full_width(),
                     )
                     .child(
-                        Panel::new(
+                        Panel::new(with_message_picking(
                             EditView::new()
+                                .on_edit(on_chat_input_edit(ui_tx.clone()))
                                 .on_submit(move |siv, msg| {
                                     siv.call_on_name(
                                         ELEMENT_NAME_CHAT_INPUT,
@@ -92,10 +128,25 @@ pub fn init(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
                                             input.set_content("");
                                         },
                                     );
+                                    let _ = ui_tx.try_send(UICommand::SetTyping(None));
 
-                                    let result = ui_tx.try_send(UICommand::SendMessage {
-                                        message_text: msg.to_string(),
-                                    });
+                                    let message_text = msg.to_string();
+                                    let command = match ui::conversations::selected(siv) {
+                                        Conversation::Main => UICommand::SendMessage {
+                                            room: String::new(),
+                                            message_text,
+                                        },
+                                        Conversation::Room(room) => {
+                                            UICommand::SendMessage { room, message_text }
+                                        },
+                                        Conversation::Direct(target) => {
+                                            UICommand::SendDirectMessage {
+                                                target,
+                                                message_text,
+                                            }
+                                        },
+                                    };
+                                    let result = ui_tx.try_send(command);
 
                                     if let Err(err) = result {
                                         ui::dialog::error::show_try_again(
@@ -105,23 +156,54 @@ pub fn init(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
                                     }
                                 })
                                 .with_name(ELEMENT_NAME_CHAT_INPUT),
-                        )
+                            pick_ui_tx,
+                        ))
+                        .with_name(ELEMENT_NAME_INPUT_PANEL)
                         .full_width(),
                     )
                     .full_width(),
             )
             .child(
-                Panel::new(
-                    LinearLayout::vertical()
-                        .with_name(ELEMENT_NAME_ONLINE_PANEL)
-                        .full_height()
-                        .full_width()
-                        .scrollable()
-                        .scroll_strategy(ScrollStrategy::StickToBottom),
-                )
-                .title(t!("title.online_users"))
-                .full_height()
-                .fixed_width(32),
+                LinearLayout::vertical()
+                    .child(
+                        Panel::new(
+                            SelectView::new()
+                                .item(t!("text.conversation.main"), Conversation::Main)
+                                .on_select(ui::conversations::switch)
+                                .with_name(ELEMENT_NAME_CONVERSATIONS)
+                                .scrollable(),
+                        )
+                        .title(t!("title.conversations"))
+                        .max_height(8),
+                    )
+                    .child(
+                        Panel::new(
+                            SelectView::<ktp::Id>::new()
+                                .on_submit(move |siv, transfer_id| {
+                                    ui::dialog::transfers::show_cancel_dialog(
+                                        siv,
+                                        transfers_ui_tx.clone(),
+                                        *transfer_id,
+                                    );
+                                })
+                                .with_name(ELEMENT_NAME_TRANSFERS)
+                                .scrollable(),
+                        )
+                        .title(t!("title.transfers"))
+                        .max_height(6),
+                    )
+                    .child(
+                        // Rooms add online panels of their own.
+                        Panel::new(
+                            ScreensView::single_screen(ui::conversations::chat_area(
+                                ELEMENT_NAME_ONLINE_PANEL,
+                            ))
+                            .with_name(ELEMENT_NAME_ONLINE_SCREENS),
+                        )
+                        .title(t!("title.online_users"))
+                        .full_height(),
+                    )
+                    .fixed_width(32),
             ),
     );
 
@@ -129,8 +211,8 @@ pub fn init(siv: &mut Cursive, ui_tx: Sender<UICommand>) {
 }
 
 pub fn update_username_title(siv: &mut Cursive, username: &str) {
-    let title = format!("arpchat: {username}");
-    type ChatPanel = Panel<ScrollView<ResizedView<ResizedView<NamedView<LinearLayout>>>>>;
+    let title = chat_title(username);
+    type ChatPanel = Panel<NamedView<ScreensView>>;
 
     siv.set_window_title(&title);
     siv.call_on_name(ELEMENT_NAME_CHAT_PANEL, |chat_panel: &mut ChatPanel| {
@@ -138,6 +220,52 @@ pub fn update_username_title(siv: &mut Cursive, username: &str) {
     });
 }
 
+/// The title is empty while the next message replies to nothing.
+pub fn update_input_title(siv: &mut Cursive, title: String) {
+    type InputPanel = Panel<OnEventView<NamedView<EditView>>>;
+
+    siv.call_on_name(ELEMENT_NAME_INPUT_PANEL, |input_panel: &mut InputPanel| {
+        input_panel.set_title(title);
+    });
+}
+
+// Ctrl+Up and Ctrl+Down pick a message of the conversation to reply to.
+fn with_message_picking(
+    input: NamedView<EditView>, ui_tx: Sender<UICommand>,
+) -> OnEventView<NamedView<EditView>> {
+    let older_ui_tx = ui_tx.clone();
+
+    OnEventView::new(input)
+        .on_event(Event::Ctrl(Key::Up), move |_| {
+            let _ = older_ui_tx.try_send(UICommand::PickMessage { is_older: true });
+        })
+        .on_event(Event::Ctrl(Key::Down), move |_| {
+            let _ = ui_tx.try_send(UICommand::PickMessage { is_older: false });
+        })
+}
+
+// Tells whether there is an unsent message, and in which room.
+fn on_chat_input_edit(ui_tx: Sender<UICommand>) -> impl Fn(&mut Cursive, &str, usize) {
+    move |siv, content, _| {
+        // Direct messages have no typing indicator.
+        let room = match ui::conversations::selected(siv) {
+            _ if content.is_empty() => None,
+            Conversation::Main => Some(String::new()),
+            Conversation::Room(room) => Some(room),
+            Conversation::Direct(_) => None,
+        };
+        let _ = ui_tx.try_send(UICommand::SetTyping(room));
+    }
+}
+
+// The lock tells whether messages are sealed with the room passphrase.
+fn chat_title(username: &str) -> String {
+    match config::lock_get_room_passphrase() {
+        Some(_) => format!("arpchat: {username} 🔒"),
+        None => format!("arpchat: {username}"),
+    }
+}
+
 fn show_help_dialog(siv: &mut Cursive) {
     siv.add_layer(
         Dialog::text(t!("text.help"))
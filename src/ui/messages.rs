@@ -0,0 +1,230 @@
+use crate::net::ktp;
+use crate::ui;
+use cursive::Cursive;
+use cursive::backends::crossterm::crossterm::style::Stylize;
+use cursive::utils::markup;
+use std::collections::HashMap;
+
+// Quotes of replies show this many characters of the message.
+const QUOTE_LENGTH: usize = 40;
+
+/// Chat line of a message of the main chat or a room. <br>
+/// It is rendered again as the delivery state changes, the sender edits
+/// or deletes the message, or it's picked to be replied to.
+pub struct ShownMessage {
+    room: String,
+    area_name: String,
+    author: ktp::Id,
+    username: String,
+    // Time and username, which stay as they are.
+    header: String,
+    // Quote of the message replied to, shown above.
+    quote: Option<String>,
+    text: String,
+    is_edited: bool,
+    is_deleted: bool,
+    is_picked: bool,
+    // Delivery state of our own messages.
+    marker: String,
+}
+
+impl ShownMessage {
+    pub fn new(room: &str, author: ktp::Id, username: String, text: String) -> Self {
+        Self {
+            room: room.to_string(),
+            area_name: ui::conversations::room_chat_area_name(room),
+            author,
+            header: ui::commands::format_message(&author, username.clone(), ""),
+            username,
+            quote: None,
+            text,
+            is_edited: false,
+            is_deleted: false,
+            is_picked: false,
+            marker: String::new(),
+        }
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// First line of the text, cut to fit a quote.
+    pub fn snippet(&self) -> String {
+        let line = self.text.lines().next().unwrap_or_default();
+        match line.chars().count() > QUOTE_LENGTH || self.text.contains('\n') {
+            true => format!("{}…", line.chars().take(QUOTE_LENGTH).collect::<String>()),
+            false => line.to_string(),
+        }
+    }
+
+    /// Lines are named by the id of the message, so they can be found again.
+    pub fn render(&self, message_id: &ktp::Id, siv: &mut Cursive) {
+        let mut line = match self.is_deleted {
+            true => format!("{}{}", self.header, t!("text.message_deleted").dark_grey()),
+            false if self.is_edited => format!(
+                "{}{}{}{}",
+                self.header,
+                self.text,
+                t!("text.message_edited").dark_grey(),
+                self.marker
+            ),
+            false => format!("{}{}{}", self.header, self.text, self.marker),
+        };
+        if self.is_picked {
+            line = format!("{}{line}", "↩ ".yellow());
+        }
+        if let Some(quote) = &self.quote {
+            line = format!("{quote}\n{line}");
+        }
+
+        ui::view_updater::update_or_append_txt(
+            siv,
+            &self.area_name,
+            &format!("{message_id:x?}_message"),
+            markup::ansi::parse(line),
+        );
+    }
+}
+
+/// Messages shown so far, by their ids.
+#[derive(Default)]
+pub struct ShownMessages {
+    messages: HashMap<ktp::Id, ShownMessage>,
+    // Every message, in the order it was shown.
+    order: Vec<ktp::Id>,
+    // Our own messages, in the order they were sent.
+    own: Vec<ktp::Id>,
+    // Message to reply to with the next one.
+    picked: Option<ktp::Id>,
+}
+
+impl ShownMessages {
+    /// Replies quote their parent, as far as it was shown here.
+    pub fn insert(
+        &mut self, message_id: ktp::Id, mut message: ShownMessage,
+        reply_to: Option<ktp::Id>, is_own: bool,
+    ) -> &ShownMessage {
+        message.quote = reply_to.map(|parent| self.quote(&parent));
+        if is_own {
+            self.own.push(message_id);
+        }
+        self.order.push(message_id);
+        self.messages
+            .entry(message_id)
+            .insert_entry(message)
+            .into_mut()
+    }
+
+    pub fn get(&self, message_id: &ktp::Id) -> Option<&ShownMessage> {
+        self.messages.get(message_id)
+    }
+
+    pub fn set_marker(
+        &mut self, message_id: &ktp::Id, marker: String,
+    ) -> Option<&ShownMessage> {
+        let message = self.messages.get_mut(message_id)?;
+        message.marker = marker;
+        Some(message)
+    }
+
+    /// Deleted messages stay deleted.
+    pub fn edit(&mut self, message_id: &ktp::Id, text: String) -> Option<&ShownMessage> {
+        let message = self.messages.get_mut(message_id)?;
+        if message.is_deleted {
+            return None;
+        }
+        message.text = text;
+        message.is_edited = true;
+        Some(message)
+    }
+
+    pub fn delete(&mut self, message_id: &ktp::Id) -> Option<&ShownMessage> {
+        let message = self.messages.get_mut(message_id)?;
+        message.is_deleted = true;
+        Some(message)
+    }
+
+    /// Our last message in the room, which isn't deleted.
+    pub fn last_own(&self, room: &str) -> Option<ktp::Id> {
+        self.own.iter().rev().copied().find(|message_id| {
+            self.messages
+                .get(message_id)
+                .is_some_and(|message| message.room == room && !message.is_deleted)
+        })
+    }
+
+    /// Moves the pick to an older or a newer message of the room. <br>
+    /// Moving past the newest message drops the pick.
+    /// Returns the messages to render again.
+    pub fn pick(&mut self, room: &str, is_older: bool) -> Vec<ktp::Id> {
+        let pickable: Vec<ktp::Id> = self
+            .order
+            .iter()
+            .copied()
+            .filter(|message_id| self.is_pickable(message_id, room))
+            .collect();
+        let position = self.picked.and_then(|picked| {
+            pickable.iter().position(|message_id| *message_id == picked)
+        });
+
+        let picked = match (position, is_older) {
+            (None, true) => pickable.last().copied(),
+            (None, false) => None,
+            (Some(position), true) => pickable.get(position.saturating_sub(1)).copied(),
+            (Some(position), false) => pickable.get(position + 1).copied(),
+        };
+        self.set_picked(picked)
+    }
+
+    /// The picked message, if it can still be replied to in the room.
+    pub fn picked_in(&self, room: &str) -> Option<ktp::Id> {
+        self.picked.filter(|picked| self.is_pickable(picked, room))
+    }
+
+    /// Returns the messages to render again.
+    pub fn set_picked(&mut self, picked: Option<ktp::Id>) -> Vec<ktp::Id> {
+        let previous = std::mem::replace(&mut self.picked, picked);
+        if previous == picked {
+            return vec![];
+        }
+
+        for (message_id, is_picked) in [(previous, false), (picked, true)] {
+            if let Some(message) = message_id.and_then(|id| self.messages.get_mut(&id)) {
+                message.is_picked = is_picked;
+            }
+        }
+        [previous, picked].into_iter().flatten().collect()
+    }
+
+    fn is_pickable(&self, message_id: &ktp::Id, room: &str) -> bool {
+        self.messages
+            .get(message_id)
+            .is_some_and(|message| message.room == room && !message.is_deleted)
+    }
+
+    // Parents that never arrived, e.g. sent before we came online, are quoted
+    // as missing.
+    fn quote(&self, parent: &ktp::Id) -> String {
+        let quoted = match self.messages.get(parent) {
+            Some(message) if message.is_deleted => {
+                t!("text.message_deleted").dark_grey().to_string()
+            },
+            Some(message) => format!(
+                "[{}] {}",
+                message
+                    .username
+                    .clone()
+                    .with(ui::colors::from_id(&message.author)),
+                message.snippet().dark_grey()
+            ),
+            None => t!("text.reply_missing").dark_grey().to_string(),
+        };
+
+        format!("{} {quoted}", "  ╭".dark_grey())
+    }
+}